@@ -1,6 +1,4 @@
-cd /home/ubuntu/Musidex
-/usr/bin/sqlite3 storage/db.db ".backup db.db.bak"
-/usr/bin/gzip -f db.db.bak
-/home/ubuntu/work/bin/gdrive upload db.db.bak.gz
-/home/ubuntu/work/bin/gdrive list --no-header --order "modifiedTime desc" -q "name contains 'db.db.bak.gz'" | /usr/bin/awk 'NR > 1' | /usr/bin/awk '{print $1}' | /usr/bin/xargs -I '{}' -t /home/ubuntu/work/bin/gdrive delete {}
-rm db.db.bak.gz
+# Upload hook of the daemon backups, run with BACKUP_HOOK="/home/ubuntu/Musidex/backup.sh {file}"
+# keeps the latest backup on google drive
+/home/ubuntu/work/bin/gdrive upload "$1"
+/home/ubuntu/work/bin/gdrive list --no-header --order "modifiedTime desc" -q "name contains '.db.gz'" | /usr/bin/awk 'NR > 1' | /usr/bin/awk '{print $1}' | /usr/bin/xargs -I '{}' -t /home/ubuntu/work/bin/gdrive delete {}
@@ -0,0 +1,28 @@
+CREATE TABLE IF NOT EXISTS tag_schema
+(
+    key           text primary key, -- exact tag key, or nested prefix ending with ':'
+    value_type    text    not null, -- "none", "text", "integer", "date", "vector"
+    user_editable integer not null,
+    synced        integer not null,
+    display_name  text    not null
+);
+
+INSERT INTO tag_schema (key, value_type, user_editable, synced, display_name)
+VALUES ('local_mp3', 'text', 0, 1, 'Local MP3'),
+       ('local_webm', 'text', 0, 1, 'Local WEBM'),
+       ('local_m4a', 'text', 0, 1, 'Local M4A'),
+       ('local_ogg', 'text', 0, 1, 'Local OGG'),
+       ('youtubedl_url', 'text', 0, 1, 'Youtube URL'),
+       ('youtube_video_id', 'text', 0, 1, 'Youtube video ID'),
+       ('youtube_worker_treated', 'text', 0, 1, 'Youtube download state'),
+       ('youtube_original_title', 'text', 0, 1, 'Youtube title'),
+       ('youtube_playlist', 'text', 0, 1, 'Youtube playlist'),
+       ('title', 'text', 1, 1, 'Title'),
+       ('artist', 'text', 1, 1, 'Artist'),
+       ('compressed_thumbnail', 'text', 0, 1, 'Compressed thumbnail'),
+       ('thumbnail', 'text', 0, 1, 'Thumbnail'),
+       ('duration', 'integer', 0, 1, 'Duration'),
+       ('embedding', 'vector', 0, 1, 'Embedding'),
+       ('full_embedding', 'vector', 0, 0, 'Full embedding'),
+       ('user_library:', 'integer', 1, 1, 'Library'),
+       ('user_tag:', 'none', 1, 1, 'Tag');
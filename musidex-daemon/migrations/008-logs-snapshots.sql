@@ -0,0 +1,4 @@
+ALTER TABLE logs ADD COLUMN tag_before text; -- json of the tag before the action, if tag
+ALTER TABLE logs ADD COLUMN tag_after text; -- json of the tag after the action, if tag
+
+CREATE INDEX IF NOT EXISTS idx_logs_music_id on logs (music_id);
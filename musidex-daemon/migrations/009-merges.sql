@@ -0,0 +1,28 @@
+CREATE TABLE IF NOT EXISTS merges
+(
+    id        integer primary key autoincrement,
+    timestamp text    not null,
+    into_id   integer not null, -- music that was kept, not a foreign key since it can get merged too
+    merged_id integer not null, -- music that was merged into into_id then deleted
+    unmerged  integer not null default 0
+);
+
+CREATE INDEX IF NOT EXISTS idx_merges_into_id on merges (into_id);
+CREATE INDEX IF NOT EXISTS idx_merges_merged_id on merges (merged_id);
+
+-- snapshot of every tag merged_id had at the time of the merge
+CREATE TABLE IF NOT EXISTS merge_tags
+(
+    merge_id integer references merges (id) on delete cascade,
+    key      text    not null,
+    moved    integer not null, -- 1 if moved to into_id, 0 if discarded since into_id already had the key
+
+    text     text,
+    integer  int,
+    date     text,
+    vector   blob,
+
+    primary key (merge_id, key)
+);
+
+CREATE INDEX IF NOT EXISTS idx_merge_tags_text on merge_tags (text);
@@ -0,0 +1,64 @@
+-- every change to the synced tables, so that clients can ask for what changed since a given seq
+CREATE TABLE IF NOT EXISTS changelog
+(
+    seq      integer primary key autoincrement,
+    music_id integer,       -- null when the change is not about a music (users, config)
+    key      text,          -- null when the change is about the music itself
+    kind     text not null  -- "add", "update", "remove"
+);
+
+CREATE TRIGGER IF NOT EXISTS changelog_tags_insert AFTER INSERT ON tags
+BEGIN
+    INSERT INTO changelog (music_id, key, kind) VALUES (NEW.music_id, NEW.key, 'add');
+END;
+
+CREATE TRIGGER IF NOT EXISTS changelog_tags_update AFTER UPDATE ON tags
+BEGIN
+    INSERT INTO changelog (music_id, key, kind)
+    SELECT OLD.music_id, OLD.key, 'remove'
+    WHERE OLD.music_id IS NOT NEW.music_id OR OLD.key IS NOT NEW.key;
+    INSERT INTO changelog (music_id, key, kind)
+    SELECT NEW.music_id, NEW.key, CASE
+        WHEN OLD.music_id IS NOT NEW.music_id OR OLD.key IS NOT NEW.key THEN 'add'
+        ELSE 'update' END;
+END;
+
+CREATE TRIGGER IF NOT EXISTS changelog_tags_delete AFTER DELETE ON tags
+BEGIN
+    INSERT INTO changelog (music_id, key, kind) VALUES (OLD.music_id, OLD.key, 'remove');
+END;
+
+CREATE TRIGGER IF NOT EXISTS changelog_musics_insert AFTER INSERT ON musics
+BEGIN
+    INSERT INTO changelog (music_id, kind) VALUES (NEW.id, 'add');
+END;
+
+CREATE TRIGGER IF NOT EXISTS changelog_musics_delete AFTER DELETE ON musics
+BEGIN
+    INSERT INTO changelog (music_id, kind) VALUES (OLD.id, 'remove');
+END;
+
+CREATE TRIGGER IF NOT EXISTS changelog_users_insert AFTER INSERT ON users
+BEGIN
+    INSERT INTO changelog (kind) VALUES ('add');
+END;
+
+CREATE TRIGGER IF NOT EXISTS changelog_users_update AFTER UPDATE ON users
+BEGIN
+    INSERT INTO changelog (kind) VALUES ('update');
+END;
+
+CREATE TRIGGER IF NOT EXISTS changelog_users_delete AFTER DELETE ON users
+BEGIN
+    INSERT INTO changelog (kind) VALUES ('remove');
+END;
+
+CREATE TRIGGER IF NOT EXISTS changelog_config_insert AFTER INSERT ON config
+BEGIN
+    INSERT INTO changelog (kind) VALUES ('add');
+END;
+
+CREATE TRIGGER IF NOT EXISTS changelog_config_update AFTER UPDATE ON config
+BEGIN
+    INSERT INTO changelog (kind) VALUES ('update');
+END;
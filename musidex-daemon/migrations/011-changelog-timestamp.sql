@@ -0,0 +1,10 @@
+-- when the change happened, in unix milliseconds, to resolve conflicts with offline edits
+ALTER TABLE changelog ADD COLUMN ts integer;
+
+CREATE TRIGGER IF NOT EXISTS changelog_timestamp AFTER INSERT ON changelog
+BEGIN
+    UPDATE changelog SET ts = CAST((julianday('now') - 2440587.5) * 86400000 AS integer)
+    WHERE seq = NEW.seq;
+END;
+
+CREATE INDEX IF NOT EXISTS changelog_music_id ON changelog (music_id, key);
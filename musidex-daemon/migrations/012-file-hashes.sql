@@ -0,0 +1,8 @@
+-- content hashes of the files in storage, recomputed when the size or modification time changes
+CREATE TABLE IF NOT EXISTS file_hashes
+(
+    path  text primary key,
+    size  integer not null,
+    mtime integer not null,
+    hash  text    not null
+);
@@ -0,0 +1,6 @@
+-- Subsonic token auth needs the password in clear to check md5(password + salt)
+CREATE TABLE IF NOT EXISTS subsonic_credentials
+(
+    user_id  integer primary key references users (id) on delete cascade,
+    password text not null
+);
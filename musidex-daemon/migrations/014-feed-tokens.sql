@@ -0,0 +1,6 @@
+-- podcast apps can't send headers, so feeds are authenticated by a secret token in their url
+CREATE TABLE IF NOT EXISTS feed_tokens
+(
+    user_id integer primary key references users (id) on delete cascade,
+    token   text not null
+);
@@ -0,0 +1,26 @@
+-- deleted musics stay here with all their tags until they are restored or purged
+CREATE TABLE IF NOT EXISTS trash
+(
+    music_id   integer primary key references musics (id) on delete cascade,
+    user_id    integer not null, -- who deleted it, for their trash view
+    deleted_at text    not null
+);
+
+CREATE INDEX IF NOT EXISTS idx_trash_user on trash (user_id);
+
+-- for clients, trashing removes the music and restoring adds it back
+CREATE TRIGGER IF NOT EXISTS changelog_trash_insert AFTER INSERT ON trash
+BEGIN
+    INSERT INTO changelog (music_id, key, kind)
+    SELECT music_id, key, 'remove' FROM tags WHERE music_id = NEW.music_id;
+    INSERT INTO changelog (music_id, kind) VALUES (NEW.music_id, 'remove');
+END;
+
+-- purging cascades here once the music is gone, its tags were already removed then
+CREATE TRIGGER IF NOT EXISTS changelog_trash_delete AFTER DELETE ON trash
+    WHEN EXISTS (SELECT 1 FROM musics WHERE id = OLD.music_id)
+BEGIN
+    INSERT INTO changelog (music_id, kind) VALUES (OLD.music_id, 'add');
+    INSERT INTO changelog (music_id, key, kind)
+    SELECT music_id, key, 'add' FROM tags WHERE music_id = OLD.music_id;
+END;
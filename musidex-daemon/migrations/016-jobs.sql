@@ -0,0 +1,94 @@
+-- background work of the workers, done jobs are deleted
+CREATE TABLE IF NOT EXISTS jobs
+(
+    id         integer primary key autoincrement,
+    kind       text    not null, -- "youtube_dl", "neural_embed", "thumbnail", "embedding_reduce"
+    music_id   integer references musics (id) on delete cascade, -- null for jobs about the whole library
+    status     text    not null, -- "queued", "running", "failed"
+    attempts   integer not null default 0,
+    last_error text,
+    created_at text    not null,
+    updated_at text    not null
+);
+
+-- the same work is only queued once
+CREATE UNIQUE INDEX IF NOT EXISTS idx_jobs_queued on jobs (kind, ifnull(music_id, 0)) WHERE status = 'queued';
+CREATE INDEX IF NOT EXISTS idx_jobs_status on jobs (status, kind);
+
+-- what is derived from a tag is computed again when the tag changes, whoever changed it
+CREATE TRIGGER IF NOT EXISTS jobs_tags_insert AFTER INSERT ON tags
+    WHEN NEW.key IN ('thumbnail', 'local_mp3', 'full_embedding')
+BEGIN
+    -- not OR IGNORE, the conflict policy of the tag statement would replace it
+    INSERT INTO jobs (kind, music_id, status, created_at, updated_at)
+    SELECT kind, music_id, 'queued', DATETIME('now'), DATETIME('now')
+    FROM (SELECT CASE NEW.key
+                     WHEN 'thumbnail' THEN 'thumbnail'
+                     WHEN 'local_mp3' THEN 'neural_embed'
+                     ELSE 'embedding_reduce' END AS kind,
+                 CASE NEW.key WHEN 'full_embedding' THEN NULL ELSE NEW.music_id END AS music_id) j
+    WHERE NOT EXISTS (SELECT 1 FROM jobs
+                      WHERE kind = j.kind AND music_id IS j.music_id AND status = 'queued');
+END;
+
+CREATE TRIGGER IF NOT EXISTS jobs_tags_update AFTER UPDATE ON tags
+    WHEN NEW.key IN ('thumbnail', 'local_mp3', 'full_embedding')
+BEGIN
+    -- not OR IGNORE, the conflict policy of the tag statement would replace it
+    INSERT INTO jobs (kind, music_id, status, created_at, updated_at)
+    SELECT kind, music_id, 'queued', DATETIME('now'), DATETIME('now')
+    FROM (SELECT CASE NEW.key
+                     WHEN 'thumbnail' THEN 'thumbnail'
+                     WHEN 'local_mp3' THEN 'neural_embed'
+                     ELSE 'embedding_reduce' END AS kind,
+                 CASE NEW.key WHEN 'full_embedding' THEN NULL ELSE NEW.music_id END AS music_id) j
+    WHERE NOT EXISTS (SELECT 1 FROM jobs
+                      WHERE kind = j.kind AND music_id IS j.music_id AND status = 'queued');
+END;
+
+-- clients show the musics whose download failed with youtube_worker_treated=error
+CREATE TRIGGER IF NOT EXISTS jobs_youtube_failed AFTER UPDATE OF status ON jobs
+    WHEN NEW.kind = 'youtube_dl' AND NEW.status = 'failed'
+BEGIN
+    DELETE FROM tags WHERE music_id = NEW.music_id AND key = 'youtube_worker_treated';
+    INSERT INTO tags (music_id, key, text)
+    VALUES (NEW.music_id, 'youtube_worker_treated', 'error');
+END;
+
+CREATE TRIGGER IF NOT EXISTS jobs_youtube_retried AFTER UPDATE OF status ON jobs
+    WHEN NEW.kind = 'youtube_dl' AND OLD.status = 'failed' AND NEW.status != 'failed'
+BEGIN
+    DELETE FROM tags WHERE music_id = NEW.music_id AND key = 'youtube_worker_treated' AND text = 'error';
+END;
+
+CREATE TRIGGER IF NOT EXISTS jobs_youtube_deleted AFTER DELETE ON jobs
+    WHEN OLD.kind = 'youtube_dl' AND OLD.status = 'failed'
+BEGIN
+    DELETE FROM tags WHERE music_id = OLD.music_id AND key = 'youtube_worker_treated' AND text = 'error';
+END;
+
+-- the work that was pending before the queue existed
+INSERT OR IGNORE INTO jobs (kind, music_id, status, created_at, updated_at)
+SELECT 'youtube_dl', music_id, 'queued', DATETIME('now'), DATETIME('now')
+FROM tags WHERE key = 'youtube_worker_treated' AND text = 'false';
+
+DELETE FROM tags WHERE key = 'youtube_worker_treated' AND text = 'false';
+
+INSERT INTO jobs (kind, music_id, status, attempts, last_error, created_at, updated_at)
+SELECT 'youtube_dl', music_id, 'failed', 1, 'failed before the jobs queue existed', DATETIME('now'), DATETIME('now')
+FROM tags WHERE key = 'youtube_worker_treated' AND text = 'error';
+
+INSERT OR IGNORE INTO jobs (kind, music_id, status, created_at, updated_at)
+SELECT 'thumbnail', music_id, 'queued', DATETIME('now'), DATETIME('now')
+FROM tags t WHERE key = 'thumbnail'
+  AND NOT EXISTS (SELECT 1 FROM tags WHERE music_id = t.music_id AND key = 'compressed_thumbnail');
+
+INSERT OR IGNORE INTO jobs (kind, music_id, status, created_at, updated_at)
+SELECT 'neural_embed', music_id, 'queued', DATETIME('now'), DATETIME('now')
+FROM tags t WHERE key = 'local_mp3'
+  AND NOT EXISTS (SELECT 1 FROM tags WHERE music_id = t.music_id AND key = 'full_embedding');
+
+INSERT OR IGNORE INTO jobs (kind, music_id, status, created_at, updated_at)
+SELECT 'embedding_reduce', NULL, 'queued', DATETIME('now'), DATETIME('now')
+WHERE EXISTS (SELECT 1 FROM tags t WHERE key = 'full_embedding'
+  AND NOT EXISTS (SELECT 1 FROM tags WHERE music_id = t.music_id AND key = 'embedding'));
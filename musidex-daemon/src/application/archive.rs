@@ -0,0 +1,47 @@
+use crate::domain::archive;
+use crate::infrastructure::db::Db;
+use crate::infrastructure::router::RequestExt;
+use crate::utils::res_status;
+use anyhow::Result;
+use hyper::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use nanoserde::SerJson;
+use std::path::PathBuf;
+
+/// Tar archive of the instance, with the files of the storage directory with `?files=true`
+pub async fn export(req: Request<Body>) -> Result<Response<Body>> {
+    let with_files = match req.query_params().get("files").map(|x| x.as_str()) {
+        None | Some("false") => false,
+        Some("true") => true,
+        _ => return Ok(res_status(StatusCode::BAD_REQUEST)),
+    };
+    let db = req.state::<Db>().clone();
+    let (sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let storage = PathBuf::from("./storage/");
+        if let Err(e) = archive::export(&db, &storage, with_files, sender).await {
+            log::error!("error exporting archive: {:?}", e);
+        }
+    });
+
+    let name = format!("musidex-{}.tar", chrono::Utc::now().format("%Y-%m-%d"));
+    let mut r = Response::new(body);
+    let headers = r.headers_mut();
+    headers.insert(CONTENT_TYPE, "application/x-tar".parse()?);
+    headers.insert(
+        CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}\"", name).parse()?,
+    );
+    Ok(r)
+}
+
+/// Restores an archive made by export into this instance
+pub async fn import(req: Request<Body>) -> Result<Response<Body>> {
+    let db = req.state::<Db>().clone();
+    let storage = PathBuf::from("./storage/");
+    let report = unwrap_ret!(
+        archive::import(&db, &storage, req.into_body()).await?,
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
+    Ok(Response::new(Body::from(report.serialize_json())))
+}
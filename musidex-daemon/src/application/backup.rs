@@ -0,0 +1,17 @@
+use crate::domain::backup::Backups;
+use crate::infrastructure::router::RequestExt;
+use anyhow::Result;
+use hyper::{Body, Request, Response};
+use nanoserde::SerJson;
+
+/// Takes a backup of the database now
+pub async fn create(req: Request<Body>) -> Result<Response<Body>> {
+    let info = req.state::<Backups>().run().await?;
+    Ok(Response::new(Body::from(info.serialize_json())))
+}
+
+/// The backups that are kept, most recent first
+pub async fn list(req: Request<Body>) -> Result<Response<Body>> {
+    let backups = req.state::<Backups>().list()?;
+    Ok(Response::new(Body::from(backups.serialize_json())))
+}
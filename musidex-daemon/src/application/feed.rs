@@ -0,0 +1,79 @@
+use crate::domain::entity::{User, UserID};
+use crate::domain::feed;
+use crate::infrastructure::db::Db;
+use crate::infrastructure::router::RequestExt;
+use crate::utils::{base_url, res_status};
+use anyhow::{Context, Result};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request, Response, StatusCode};
+use nanoserde::SerJson;
+use std::path::PathBuf;
+
+#[derive(SerJson)]
+pub struct FeedToken {
+    pub token: String,
+}
+
+/// The user in the url, None if there is no such user
+fn user(req: &Request<Body>, c: &rusqlite::Connection) -> Result<Option<User>> {
+    let id = req.params().get("id").context("no id in url")?;
+    let id = UserID(id.parse().context("invalid id")?);
+    Ok(User::list(c)?.into_iter().find(|u| u.id == id))
+}
+
+/// Podcast feed of the user library, or of one of its playlists with `?playlist=`
+pub async fn feed(req: Request<Body>) -> Result<Response<Body>> {
+    let q = req.query_params();
+    let c = req.state::<Db>().get().await;
+    let user = unwrap_ret!(user(&req, &c)?, Ok(res_status(StatusCode::NOT_FOUND)));
+    if !feed::check_token(&c, user.id, q.get("token").map(|x| x.as_str()))? {
+        return Ok(res_status(StatusCode::UNAUTHORIZED));
+    }
+
+    let playlist = q.get("playlist").map(|x| x.as_str());
+    let entries = feed::entries(&c, &PathBuf::from("./storage/"), user.id, playlist)?;
+    drop(c);
+    let title = match playlist {
+        Some(p) => format!("{} - {}", user.name, p),
+        None => format!("{} - musidex", user.name),
+    };
+
+    let mut r = Response::new(Body::from(feed::render(&title, &base_url(&req), &entries)));
+    r.headers_mut()
+        .insert(CONTENT_TYPE, "application/rss+xml; charset=utf-8".parse()?);
+    Ok(r)
+}
+
+/// Only the user itself can see or replace its token
+fn is_self(req: &Request<Body>, user: &User) -> bool {
+    User::from_req(req).ok() == Some(user.id)
+}
+
+/// Token to put in feed urls, created on first use
+pub async fn token(req: Request<Body>) -> Result<Response<Body>> {
+    let c = req.state::<Db>().get().await;
+    let user = unwrap_ret!(user(&req, &c)?, Ok(res_status(StatusCode::NOT_FOUND)));
+    if !is_self(&req, &user) {
+        return Ok(res_status(StatusCode::UNAUTHORIZED));
+    }
+    let token = match feed::token(&c, user.id)? {
+        Some(x) => x,
+        None => feed::new_token(&c, user.id)?,
+    };
+    Ok(Response::new(Body::from(
+        FeedToken { token }.serialize_json(),
+    )))
+}
+
+/// Replaces the token, for when a feed url leaked
+pub async fn new_token(req: Request<Body>) -> Result<Response<Body>> {
+    let c = req.state::<Db>().get().await;
+    let user = unwrap_ret!(user(&req, &c)?, Ok(res_status(StatusCode::NOT_FOUND)));
+    if !is_self(&req, &user) {
+        return Ok(res_status(StatusCode::UNAUTHORIZED));
+    }
+    let token = feed::new_token(&c, user.id)?;
+    Ok(Response::new(Body::from(
+        FeedToken { token }.serialize_json(),
+    )))
+}
@@ -0,0 +1,48 @@
+use crate::domain::fsck::{FsckOptions, FsckRuns};
+use crate::infrastructure::db::Db;
+use crate::infrastructure::router::RequestExt;
+use crate::utils::{env_or, res_status};
+use anyhow::{Context, Result};
+use hyper::{Body, Request, Response, StatusCode};
+use nanoserde::SerJson;
+use std::path::PathBuf;
+
+/// Starts looking for the musics whose tags and files disagree and answers with the run to
+/// follow at /api/admin/fsck/:id. `?repair=true` fixes what it can and `?decode=false` skips
+/// probing the audio files, which is the slow part.
+pub async fn fsck(req: Request<Body>) -> Result<Response<Body>> {
+    let q = req.query_params();
+    let flag = |name: &str, default: bool| match q.get(name).map(|x| x.as_str()) {
+        None => Some(default),
+        Some("true") => Some(true),
+        Some("false") => Some(false),
+        _ => None,
+    };
+    let (repair, decode) = match (flag("repair", false), flag("decode", true)) {
+        (Some(repair), Some(decode)) => (repair, decode),
+        _ => return Ok(res_status(StatusCode::BAD_REQUEST)),
+    };
+    let opts = FsckOptions {
+        repair,
+        probe: decode.then(|| env_or("FFPROBE", s!("ffprobe"))),
+    };
+    let runs = req.state::<FsckRuns>();
+    let id = runs.start(req.state::<Db>().clone(), PathBuf::from("./storage/"), opts);
+    let run = runs.get(id).context("fsck run already forgotten")?;
+    let mut r = Response::new(Body::from(run.serialize_json()));
+    *r.status_mut() = StatusCode::ACCEPTED;
+    Ok(r)
+}
+
+/// A run started by fsck, with its report once done
+pub async fn run(req: Request<Body>) -> Result<Response<Body>> {
+    let id = unwrap_ret!(
+        req.params().get("id").and_then(|x| x.parse().ok()),
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
+    let run = unwrap_ret!(
+        req.state::<FsckRuns>().get(id),
+        Ok(res_status(StatusCode::NOT_FOUND))
+    );
+    Ok(Response::new(Body::from(run.serialize_json())))
+}
@@ -3,31 +3,60 @@ use std::convert::TryInto;
 use anyhow::{Context, Result};
 use hyper::{Body, Request, Response, StatusCode};
 
-use crate::domain::entity::{Music, MusicID, Tag, TagKey, User, UserID};
+use crate::domain::bulk::{self, BulkEdit};
+use crate::domain::clean;
+use crate::domain::encoding::SyncEncoding;
+use crate::domain::entity::{Music, MusicID, MusicMerge, Tag, TagKey, TagSchema, User, UserID};
+use crate::domain::history::{self, Editor};
 use crate::domain::music::{delete_music, MoveDirection};
-use crate::domain::sync::{compress_meta, serve_sync_websocket, SyncBroadcastSubscriber};
-use crate::domain::{stream, sync, upload};
-use crate::infrastructure::db::{db_log, DbLog, LogAction, LogType};
+use crate::domain::mutations::{self, MutationBatch};
+use crate::domain::query::{KeyPattern, Query};
+use crate::domain::sync::{serve_sync_websocket, SyncBroadcastSubscriber, SyncOptions};
+use crate::domain::{jobs, stream, sync, trash, upload};
+use crate::infrastructure::db::{db_log, DbLog, LogAction, LogEntry, LogType};
 use crate::infrastructure::router::RequestExt;
 use crate::utils::res_status;
 use crate::Db;
 use nanoserde::{DeJson, SerJson};
 
 pub async fn metadata(req: Request<Body>) -> Result<Response<Body>> {
+    let q = req.query_params();
+    let filter = match q.get("filter").map(|x| Query::parse(x)).transpose() {
+        Ok(x) => x,
+        Err(e) => {
+            let mut r = Response::new(Body::from(format!("invalid filter: {}", e)));
+            *r.status_mut() = StatusCode::BAD_REQUEST;
+            return Ok(r);
+        }
+    };
+    let fields = q.get("fields").map(|x| KeyPattern::parse_list(x));
+    let since = q.get("since").and_then(|x| x.parse().ok());
+
     let db = req.state::<Db>();
     let c = db.get().await;
 
-    let metadata = sync::fetch_metadata(&c).context("failed fetching metadata")?;
+    let metadata = if filter.is_some() || fields.is_some() {
+        sync::fetch_metadata_filtered(&c, filter.as_ref(), fields.as_deref())
+    } else {
+        sync::fetch_since(&c, since)
+    }
+    .context("failed fetching metadata")?;
 
     Ok(Response::new(Body::from(metadata.serialize_json())))
 }
 
 pub async fn metadata_compressed(req: Request<Body>) -> Result<Response<Body>> {
+    let q = req.query_params();
+    let since = q.get("since").and_then(|x| x.parse().ok());
+    let encoding = unwrap_ret!(
+        SyncEncoding::parse(q.get("encoding").map(|x| x.as_str())),
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
     let db = req.state::<Db>();
     let c = db.get().await;
 
-    let metadata = sync::fetch_metadata(&c).context("failed fetching metadata")?;
-    let compressed = compress_meta(&metadata);
+    let metadata = sync::fetch_since(&c, since).context("failed fetching metadata")?;
+    let compressed = encoding.encode(&metadata);
 
     Ok(Response::new(Body::from(compressed)))
 }
@@ -57,10 +86,28 @@ pub async fn subscribe_sync(request: Request<Body>) -> Result<Response<Body>> {
         return Ok(Response::new(Body::empty()));
     }
     let st = request.state::<SyncBroadcastSubscriber>().clone();
+    let q = request.query_params();
+    let since = q.get("since").and_then(|x| x.parse().ok());
+    // scoping is opt-in: ?user=me uses the cur_user cookie, ?user=<id> a given user
+    let user = match q.get("user").map(|x| x.as_str()) {
+        Some("me") => User::from_req(&request).ok(),
+        Some(x) => x.parse().ok().map(UserID),
+        None => None,
+    };
+    let encoding = unwrap_ret!(
+        SyncEncoding::parse(q.get("encoding").map(|x| x.as_str())),
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
+    let opts = SyncOptions {
+        since,
+        user,
+        encoding,
+        editor: Editor::from_req(&request),
+    };
     let (response, websocket) = hyper_tungstenite::upgrade(request, None)?;
 
     tokio::spawn(async move {
-        if let Err(e) = serve_sync_websocket(websocket, st).await {
+        if let Err(e) = serve_sync_websocket(websocket, st, opts).await {
             log::error!("error in websocket connection: {}", e);
         }
     });
@@ -69,23 +116,60 @@ pub async fn subscribe_sync(request: Request<Body>) -> Result<Response<Body>> {
 }
 
 pub async fn create_tag(mut req: Request<Body>) -> Result<Response<Body>> {
-    let mut tag: Tag = parse_body(&mut req).await?;
+    let editor = Editor::from_req(&req);
+    let tag: Tag = parse_body(&mut req).await?;
 
     let db = req.state::<Db>();
     let mut c = db.get().await;
 
     let tx = c.transaction().context("transaction begin failed")?;
-    if let TagKey::UserLibrary(_) = tag.key {
-        if tag.integer.is_none() {
-            tag.integer = Some(Tag::max_integer_by_key(&tx, &tag.key)?.unwrap_or(0) + 100);
-        }
+    if let Err(violation) = editor.insert_user_tag(&tx, &TagSchema::list(&tx)?, tag)? {
+        let mut r = Response::new(Body::from(violation.to_string()));
+        *r.status_mut() = violation.status();
+        return Ok(r);
     }
-    Tag::insert(&tx, tag)?;
     tx.commit()?;
 
     Ok(Response::new(Body::empty()))
 }
 
+pub async fn bulk_tags(mut req: Request<Body>) -> Result<Response<Body>> {
+    let editor = Editor::from_req(&req);
+    let b: BulkEdit = parse_body(&mut req).await?;
+
+    let filter = match b.filter.as_deref().map(Query::parse).transpose() {
+        Ok(x) => x,
+        Err(e) => {
+            let mut r = Response::new(Body::from(format!("invalid filter: {}", e)));
+            *r.status_mut() = StatusCode::BAD_REQUEST;
+            return Ok(r);
+        }
+    };
+
+    let db = req.state::<Db>();
+    let mut c = db.get().await;
+
+    let tx = c.transaction().context("transaction begin failed")?;
+    let results = bulk::apply(&tx, &editor, b.music_ids, filter.as_ref(), b.ops)?;
+    tx.commit().context("transaction commit failed")?;
+
+    Ok(Response::new(Body::from(results.serialize_json())))
+}
+
+pub async fn apply_mutations(mut req: Request<Body>) -> Result<Response<Body>> {
+    let editor = Editor::from_req(&req);
+    let b: MutationBatch = parse_body(&mut req).await?;
+
+    let db = req.state::<Db>();
+    let mut c = db.get().await;
+
+    let tx = c.transaction().context("transaction begin failed")?;
+    let results = mutations::apply(&tx, &editor, b.mutations)?;
+    tx.commit().context("transaction commit failed")?;
+
+    Ok(Response::new(Body::from(results.serialize_json())))
+}
+
 pub async fn move_(req: Request<Body>) -> Result<Response<Body>> {
     let id_library = req
         .params()
@@ -106,12 +190,12 @@ pub async fn move_(req: Request<Body>) -> Result<Response<Body>> {
         .get("direction")
         .context("no direction in url")?;
 
-    let direction = match direction {
-        "above" => MoveDirection::Above,
-        "below" => MoveDirection::Below,
-        _ => return Ok(res_status(StatusCode::BAD_REQUEST)),
-    };
+    let direction = unwrap_ret!(
+        MoveDirection::parse(direction),
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
 
+    let editor = Editor::from_req(&req);
     let db = req.state::<Db>();
     let mut c = db.get().await;
 
@@ -121,6 +205,7 @@ pub async fn move_(req: Request<Body>) -> Result<Response<Body>> {
 
     if !Music::move_(
         &mut c,
+        &editor,
         &key,
         MusicID(id_base),
         MusicID(id_to_move),
@@ -139,36 +224,53 @@ pub struct DeleteTag {
 }
 
 pub async fn delete_tag(mut req: Request<Body>) -> Result<Response<Body>> {
-    let uid = User::from_req(&req).context("no user id")?;
+    User::from_req(&req).context("no user id")?;
+    let editor = Editor::from_req(&req);
     let tag: DeleteTag = parse_body(&mut req).await?;
 
     let db = req.state::<Db>();
     let mut c = db.get().await;
 
     let tx = c.transaction().context("transaction begin failed")?;
-    db_log(
-        &tx,
-        DbLog {
-            user_id: uid,
-            ip: req
-                .headers()
-                .get("x-real-ip")
-                .and_then(|x| x.to_str().ok())
-                .map(|x| x.to_string())
-                .unwrap_or_default(),
-            type_: LogType::Tag,
-            action: LogAction::Delete,
-            music_id: Some(tag.music_id),
-            target_key: Some(tag.key.clone()),
-            target_value: None,
-        },
-    );
-    Tag::remove(&tx, tag.music_id, &tag.key)?;
+    editor.remove_tag(&tx, tag.music_id, &tag.key)?;
     tx.commit()?;
 
     Ok(Response::new(Body::empty()))
 }
 
+pub async fn music_history(req: Request<Body>) -> Result<Response<Body>> {
+    let music_id = req.params().get("id").context("missing parameter id")?;
+    let id = MusicID(
+        music_id
+            .parse()
+            .context("couldn't parse music id as integer")?,
+    );
+    let db = req.state::<Db>();
+    let c = db.get().await;
+
+    let history = LogEntry::by_music(&c, id)?;
+
+    Ok(Response::new(Body::from(history.serialize_json())))
+}
+
+pub async fn undo(req: Request<Body>) -> Result<Response<Body>> {
+    let log_id = req
+        .params()
+        .get("log_id")
+        .context("missing parameter log_id")?;
+    let log_id: i64 = log_id.parse().context("couldn't parse log id as integer")?;
+    let editor = Editor::from_req(&req);
+
+    let db = req.state::<Db>();
+    let mut c = db.get().await;
+
+    let tx = c.transaction().context("transaction begin failed")?;
+    let code = history::undo(&tx, &editor, log_id)?;
+    tx.commit().context("transaction commit failed")?;
+
+    Ok(res_status(code))
+}
+
 #[derive(DeJson)]
 pub struct MergeMusic {
     id1: MusicID,
@@ -185,9 +287,38 @@ pub async fn merge_music(mut req: Request<Body>) -> Result<Response<Body>> {
     let db = req.state::<Db>();
     let mut c = db.get().await;
 
-    Music::merge(&mut c, id1, id2)?;
+    let merge_id = Music::merge(&mut c, id1, id2)?;
 
-    Ok(Response::new(Body::empty()))
+    Ok(Response::new(Body::from(merge_id.to_string())))
+}
+
+#[derive(DeJson)]
+pub struct UnmergeMusic {
+    merge_id: i64,
+}
+
+pub async fn unmerge_music(mut req: Request<Body>) -> Result<Response<Body>> {
+    let UnmergeMusic { merge_id } = parse_body(&mut req).await?;
+
+    let db = req.state::<Db>();
+    let mut c = db.get().await;
+
+    Ok(res_status(Music::unmerge(&mut c, merge_id)?))
+}
+
+pub async fn music_merges(req: Request<Body>) -> Result<Response<Body>> {
+    let music_id = req.params().get("id").context("missing parameter id")?;
+    let id = MusicID(
+        music_id
+            .parse()
+            .context("couldn't parse music id as integer")?,
+    );
+    let db = req.state::<Db>();
+    let c = db.get().await;
+
+    let merges = MusicMerge::by_into_id(&c, id)?;
+
+    Ok(Response::new(Body::from(merges.serialize_json())))
 }
 
 pub async fn delete_music_handler(req: Request<Body>) -> Result<Response<Body>> {
@@ -200,28 +331,58 @@ pub async fn delete_music_handler(req: Request<Body>) -> Result<Response<Body>>
             .parse()
             .context("couldn't parse music id as integer")?,
     );
-    let uid = User::from_req(&req).context("no user id")?;
+    User::from_req(&req).context("no user id")?;
+    let editor = Editor::from_req(&req);
 
     let tx = c.transaction().context("transaction begin failed")?;
     db_log(
         &tx,
         DbLog {
-            user_id: uid,
-            ip: req
-                .headers()
-                .get("x-real-ip")
-                .and_then(|x| x.to_str().ok())
-                .map(|x| x.to_string())
-                .unwrap_or_default(),
+            user_id: editor.user_id,
+            ip: editor.ip.clone(),
             type_: LogType::Music,
             action: LogAction::Delete,
             music_id: Some(id),
             target_key: None,
             target_value: None,
+            before: None,
+            after: None,
         },
     );
 
-    let code = delete_music(&tx, uid, id)?;
+    let code = delete_music(&tx, &editor, id)?;
+    tx.commit().context("transaction commit failed")?;
+
+    Ok(res_status(code))
+}
+
+/// Takes the music out of the trash of the user
+pub async fn restore_music(req: Request<Body>) -> Result<Response<Body>> {
+    let id = req.params().get("id").context("missing parameter id")?;
+    let id = MusicID(id.parse().context("couldn't parse music id as integer")?);
+    User::from_req(&req).context("no user id")?;
+    let editor = Editor::from_req(&req);
+    let db = req.state::<Db>();
+    let mut c = db.get().await;
+
+    let tx = c.transaction().context("transaction begin failed")?;
+    let code = trash::restore(&tx, editor.user_id, id)?;
+    if code == StatusCode::OK {
+        db_log(
+            &tx,
+            DbLog {
+                user_id: editor.user_id,
+                ip: editor.ip,
+                type_: LogType::Music,
+                action: LogAction::Create,
+                music_id: Some(id),
+                target_key: None,
+                target_value: None,
+                before: None,
+                after: None,
+            },
+        );
+    }
     tx.commit().context("transaction commit failed")?;
 
     Ok(res_status(code))
@@ -231,11 +392,28 @@ pub async fn retry_on_error(req: Request<Body>) -> Result<Response<Body>> {
     let db = req.state::<Db>();
     let c = db.get().await;
 
-    c.execute("UPDATE tags SET text='false' WHERE text='error'", [])?;
+    jobs::retry_failed(&c)?;
 
     Ok(res_status(StatusCode::OK))
 }
 
+/// Deletes what isn't in any library, or only reports it with `?dry_run=true`.
+/// The deleted musics go to the trash of the user cleaning.
+pub async fn clean(req: Request<Body>) -> Result<Response<Body>> {
+    let dry_run = match req.query_params().get("dry_run").map(|x| x.as_str()) {
+        None | Some("false") => false,
+        Some("true") => true,
+        _ => return Ok(res_status(StatusCode::BAD_REQUEST)),
+    };
+    let editor = Editor::from_req(&req);
+    if !dry_run && editor.user_id == UserID(-1) {
+        return Ok(res_status(StatusCode::BAD_REQUEST));
+    }
+    let storage = std::path::PathBuf::from("./storage/");
+    let report = clean::clean(req.state::<Db>(), &storage, editor.user_id, dry_run).await?;
+    Ok(Response::new(Body::from(report.serialize_json())))
+}
+
 #[derive(DeJson)]
 pub struct UploadYoutube {
     pub url: String,
@@ -255,11 +433,15 @@ pub async fn youtube_upload(mut req: Request<Body>) -> Result<Response<Body>> {
         Some(x) => UserID(x),
         None => User::from_req(&req).context("no user id")?,
     };
+    let editor = Editor {
+        user_id: uid,
+        ..Editor::from_req(&req)
+    };
     let db = req.state::<Db>();
     let mut c = db.get().await;
 
     Ok(res_status(
-        upload::youtube_upload(&mut c, b.url, uid).await?,
+        upload::youtube_upload(&mut c, &editor, b.url).await?,
     ))
 }
 
@@ -273,11 +455,15 @@ pub async fn youtube_upload_playlist(mut req: Request<Body>) -> Result<Response<
         Some(x) => UserID(x),
         None => User::from_req(&req).context("no user id")?,
     };
+    let editor = Editor {
+        user_id: uid,
+        ..Editor::from_req(&req)
+    };
 
     let db = req.state::<Db>();
     let mut c = db.get().await;
     let (status, count) =
-        upload::youtube_upload_playlist(&mut c, url, b.index_start, b.index_stop, uid).await?;
+        upload::youtube_upload_playlist(&mut c, url, b.index_start, b.index_stop, &editor).await?;
     let mut r = Response::new(Body::from(count.to_string()));
     *r.status_mut() = status;
     Ok(r)
@@ -309,9 +495,18 @@ pub async fn stream(req: Request<Body>) -> Result<Response<Body>> {
             .context("couldn't parse music id as integer")?,
     );
     let db = req.state::<Db>();
+    stream_response(db, id, req.headers().get(hyper::header::RANGE)).await
+}
+
+/// Streams the music, only the asked range if any
+pub async fn stream_response(
+    db: &Db,
+    id: MusicID,
+    range: Option<&hyper::http::HeaderValue>,
+) -> Result<Response<Body>> {
     let c = db.get().await;
 
-    let meta = stream::stream_music(c, id, req.headers().get(hyper::header::RANGE)).await?;
+    let meta = stream::stream_music(c, id, range).await?;
 
     let mut r = Response::new(Body::from(meta.buf));
 
@@ -320,7 +515,7 @@ pub async fn stream(req: Request<Body>) -> Result<Response<Body>> {
     r.headers_mut()
         .insert(hyper::header::ACCEPT_RANGES, "bytes".parse()?);
 
-    if req.headers().contains_key(hyper::header::RANGE) {
+    if range.is_some() {
         r.headers_mut().insert(
             hyper::header::CONTENT_RANGE,
             format!(
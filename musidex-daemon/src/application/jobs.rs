@@ -0,0 +1,38 @@
+use crate::domain::jobs;
+use crate::infrastructure::db::Db;
+use crate::infrastructure::router::RequestExt;
+use crate::utils::res_status;
+use anyhow::Result;
+use hyper::{Body, Request, Response, StatusCode};
+use nanoserde::SerJson;
+
+/// All the jobs, or only the `?status=queued|running|failed` ones
+pub async fn list(req: Request<Body>) -> Result<Response<Body>> {
+    let status = req.query_params().get("status").cloned();
+    if let Some(ref s) = status {
+        if !["queued", "running", "failed"].contains(&s.as_str()) {
+            return Ok(res_status(StatusCode::BAD_REQUEST));
+        }
+    }
+    let c = req.state::<Db>().get().await;
+    let v = jobs::list(&c, status.as_deref())?;
+    Ok(Response::new(Body::from(v.serialize_json())))
+}
+
+pub async fn retry(req: Request<Body>) -> Result<Response<Body>> {
+    let id = unwrap_ret!(
+        req.params().get("id").and_then(|x| x.parse().ok()),
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
+    let c = req.state::<Db>().get().await;
+    Ok(res_status(jobs::retry(&c, id)?))
+}
+
+pub async fn cancel(req: Request<Body>) -> Result<Response<Body>> {
+    let id = unwrap_ret!(
+        req.params().get("id").and_then(|x| x.parse().ok()),
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
+    let c = req.state::<Db>().get().await;
+    Ok(res_status(jobs::cancel(&c, id)?))
+}
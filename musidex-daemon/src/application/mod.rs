@@ -1,2 +1,12 @@
+pub mod archive;
+pub mod backup;
+pub mod feed;
+pub mod fsck;
 pub mod handlers;
+pub mod jobs;
+pub mod mpd;
+pub mod playlist;
+pub mod radio;
+pub mod subsonic;
+pub mod upnp;
 pub mod user_handlers;
@@ -0,0 +1,896 @@
+use crate::domain::entity::{MusicID, User, UserID};
+use crate::domain::library::{Library, Song};
+use crate::domain::player::{PlayState, Player, Subsystem};
+use crate::domain::stream::{content_type, source_path};
+use crate::infrastructure::db::Db;
+use crate::infrastructure::router::RequestExt;
+use anyhow::{Context, Result};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Request, Response};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+const GREETING: &str = "OK MPD 0.23.5\n";
+
+const ACK_ERROR_ARG: u32 = 2;
+const ACK_ERROR_UNKNOWN: u32 = 5;
+const ACK_ERROR_NO_EXIST: u32 = 50;
+
+const COMMANDS: &[&str] = &[
+    "add",
+    "addid",
+    "clear",
+    "close",
+    "commands",
+    "consume",
+    "currentsong",
+    "decoders",
+    "delete",
+    "deleteid",
+    "disableoutput",
+    "enableoutput",
+    "find",
+    "findadd",
+    "getvol",
+    "idle",
+    "list",
+    "listall",
+    "listallinfo",
+    "listplaylist",
+    "listplaylistinfo",
+    "listplaylists",
+    "load",
+    "lsinfo",
+    "move",
+    "moveid",
+    "next",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "password",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlist",
+    "playlistid",
+    "playlistinfo",
+    "plchanges",
+    "plchangesposid",
+    "previous",
+    "random",
+    "repeat",
+    "rescan",
+    "search",
+    "searchadd",
+    "seek",
+    "seekcur",
+    "seekid",
+    "setvol",
+    "single",
+    "stats",
+    "status",
+    "stop",
+    "tagtypes",
+    "update",
+    "urlhandlers",
+    "volume",
+];
+
+struct Ack {
+    code: u32,
+    msg: String,
+}
+
+type CmdResult = std::result::Result<String, Ack>;
+
+fn ack(code: u32, msg: impl ToString) -> Ack {
+    Ack {
+        code,
+        msg: msg.to_string(),
+    }
+}
+
+impl From<anyhow::Error> for Ack {
+    fn from(e: anyhow::Error) -> Self {
+        log::error!("mpd command failed: {:?}", e);
+        ack(ACK_ERROR_UNKNOWN, e)
+    }
+}
+
+/// Splits the line into the command and its arguments, which can be quoted
+fn tokenize(line: &str) -> Option<Vec<String>> {
+    let mut args = vec![];
+    let mut chars = line.trim().chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let first = match chars.next() {
+            Some(c) => c,
+            None => return Some(args),
+        };
+        let mut arg = String::new();
+        if first == '"' {
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => arg.push(chars.next()?),
+                    c => arg.push(c),
+                }
+            }
+        } else {
+            arg.push(first);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+        args.push(arg);
+    }
+}
+
+/// Songs are named by their music id and the extension of their file
+fn uri(song: &Song) -> String {
+    match song.path.as_deref().and_then(|p| p.rsplit_once('.')) {
+        Some((_, ext)) => format!("{}.{}", song.id.0, ext),
+        None => song.id.0.to_string(),
+    }
+}
+
+fn parse_uri(uri: &str) -> Option<MusicID> {
+    let uri = uri.trim_start_matches('/');
+    let id = uri.split_once('.').map(|x| x.0).unwrap_or(uri);
+    id.parse().ok().map(MusicID)
+}
+
+fn write_song(out: &mut String, song: &Song) {
+    let _ = writeln!(out, "file: {}", uri(song));
+    let _ = writeln!(out, "Title: {}", song.title);
+    let _ = writeln!(out, "Artist: {}", song.artist);
+    let _ = writeln!(out, "Album: {}", song.artist);
+    if let Some(d) = song.duration {
+        let _ = writeln!(out, "Time: {}", d);
+        let _ = writeln!(out, "duration: {}.000", d);
+    }
+}
+
+fn parse_arg<T: std::str::FromStr>(args: &[String], i: usize) -> std::result::Result<T, Ack> {
+    let v = args
+        .get(i)
+        .ok_or_else(|| ack(ACK_ERROR_ARG, "missing argument"))?;
+    v.parse()
+        .map_err(|_| ack(ACK_ERROR_ARG, format!("invalid argument: {}", v)))
+}
+
+fn parse_bool(args: &[String], i: usize) -> std::result::Result<bool, Ack> {
+    match args.get(i).map(|x| x.as_str()) {
+        Some("1") => Ok(true),
+        Some("0") => Ok(false),
+        _ => Err(ack(ACK_ERROR_ARG, "expected 0 or 1")),
+    }
+}
+
+/// "start:end", "start:" or a single position
+fn parse_range(v: &str, len: usize) -> std::result::Result<(usize, usize), Ack> {
+    let bad = || ack(ACK_ERROR_ARG, format!("invalid range: {}", v));
+    match v.split_once(':') {
+        Some((start, "")) => Ok((start.parse().map_err(|_| bad())?, len)),
+        Some((start, end)) => Ok((
+            start.parse().map_err(|_| bad())?,
+            end.parse().map_err(|_| bad())?,
+        )),
+        None => {
+            let pos: usize = v.parse().map_err(|_| bad())?;
+            Ok((pos, pos + 1))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum FilterOp {
+    Eq,
+    NotEq,
+    Contains,
+}
+
+#[derive(Debug)]
+struct Filter {
+    tag: String,
+    op: FilterOp,
+    value: String,
+}
+
+impl Filter {
+    fn matches(&self, song: &Song) -> bool {
+        let values: Vec<String> = match self.tag.as_str() {
+            "artist" | "album" | "albumartist" => vec![song.artist.clone()],
+            "title" => vec![song.title.clone()],
+            "file" => vec![uri(song)],
+            _ => vec![song.artist.clone(), song.title.clone(), uri(song)],
+        };
+        match self.op {
+            FilterOp::Eq => values.contains(&self.value),
+            FilterOp::NotEq => values.iter().all(|v| *v != self.value),
+            FilterOp::Contains => {
+                let needle = self.value.to_lowercase();
+                values.iter().any(|v| v.to_lowercase().contains(&needle))
+            }
+        }
+    }
+}
+
+/// Either the old "TYPE VALUE" pairs or a single "(TYPE OP 'VALUE')" expression
+fn parse_filters(args: &[String], exact: bool) -> std::result::Result<Vec<Filter>, Ack> {
+    let bad = || ack(ACK_ERROR_ARG, "unsupported filter");
+    if let Some(expr) = args.first().filter(|x| x.starts_with('(')) {
+        let expr = expr
+            .strip_prefix('(')
+            .and_then(|x| x.strip_suffix(')'))
+            .ok_or_else(bad)?;
+        let (tag, rest) = expr.split_once(' ').ok_or_else(bad)?;
+        let (op, value) = rest.trim().split_once(' ').ok_or_else(bad)?;
+        let value = value.trim();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'');
+        let value = match quote {
+            Some(q) => value
+                .strip_prefix(q)
+                .and_then(|x| x.strip_suffix(q))
+                .ok_or_else(bad)?,
+            None => value,
+        };
+        let mut unescaped = String::new();
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => unescaped.extend(chars.next()),
+                c => unescaped.push(c),
+            }
+        }
+        let op = match op {
+            "==" if exact => FilterOp::Eq,
+            "==" | "contains" => FilterOp::Contains,
+            "!=" => FilterOp::NotEq,
+            _ => return Err(bad()),
+        };
+        return Ok(vec![Filter {
+            tag: tag.to_lowercase(),
+            op,
+            value: unescaped,
+        }]);
+    }
+    if !args.len().is_multiple_of(2) {
+        return Err(ack(ACK_ERROR_ARG, "filters must be type value pairs"));
+    }
+    Ok(args
+        .chunks(2)
+        .map(|x| Filter {
+            tag: x[0].to_lowercase(),
+            op: if exact {
+                FilterOp::Eq
+            } else {
+                FilterOp::Contains
+            },
+            value: x[1].clone(),
+        })
+        .collect())
+}
+
+struct Session {
+    db: Db,
+    player: Player,
+    user: UserID,
+}
+
+impl Session {
+    async fn library(&self) -> Result<Library> {
+        let c = self.db.get().await;
+        let user = User::list(&c)?
+            .into_iter()
+            .find(|u| u.id == self.user)
+            .context("mpd user not found")?;
+        Library::load(&c, user)
+    }
+
+    async fn song(&self, id: MusicID) -> Result<Song> {
+        Song::load(&*self.db.get().await, id)
+    }
+
+    async fn write_queue(&self, out: &mut String, range: (usize, usize)) -> Result<()> {
+        let items: Vec<_> = {
+            let s = self.player.get();
+            s.queue
+                .iter()
+                .enumerate()
+                .skip(range.0)
+                .take(range.1.saturating_sub(range.0))
+                .map(|(pos, item)| (pos, item.clone()))
+                .collect()
+        };
+        for (pos, item) in items {
+            write_song(out, &self.song(item.music_id).await?);
+            let _ = writeln!(out, "Pos: {}", pos);
+            let _ = writeln!(out, "Id: {}", item.id);
+        }
+        Ok(())
+    }
+
+    /// Adds the musics to the queue and returns the id of the first one
+    async fn enqueue(&self, ids: Vec<MusicID>, pos: Option<usize>) -> CmdResult {
+        let asked = !ids.is_empty();
+        let mut first = None;
+        let mut i = 0;
+        for id in ids {
+            // adding the whole library shouldn't stop at the first song that was never downloaded
+            if self.song(id).await?.path.is_none() {
+                continue;
+            }
+            let added = self.player.update(|s| s.add(id, pos.map(|p| p + i)));
+            let added = added.ok_or_else(|| ack(ACK_ERROR_ARG, "bad song index"))?;
+            first.get_or_insert(added);
+            i += 1;
+        }
+        match first {
+            Some(id) => Ok(format!("Id: {}\n", id)),
+            None if asked => Err(ack(ACK_ERROR_NO_EXIST, "no playable source")),
+            None => Ok(String::new()),
+        }
+    }
+
+    async fn status(&self) -> String {
+        let mut out = String::new();
+        let s = self.player.get();
+        let _ = writeln!(out, "volume: {}", s.volume);
+        let _ = writeln!(out, "repeat: {}", s.repeat as u8);
+        let _ = writeln!(out, "random: {}", s.random as u8);
+        let _ = writeln!(out, "single: {}", s.single as u8);
+        let _ = writeln!(out, "consume: {}", s.consume as u8);
+        let _ = writeln!(out, "playlist: {}", s.version);
+        let _ = writeln!(out, "playlistlength: {}", s.queue.len());
+        let _ = writeln!(out, "state: {}", s.state.as_str());
+        if let (Some(pos), Some(item)) = (s.current, s.current_item()) {
+            let _ = writeln!(out, "song: {}", pos);
+            let _ = writeln!(out, "songid: {}", item.id);
+            if s.state != PlayState::Stop {
+                let elapsed = s.elapsed();
+                let _ = writeln!(out, "elapsed: {:.3}", elapsed.as_secs_f64());
+            }
+        }
+        if let Some(next) = s.peek_next() {
+            let _ = writeln!(out, "nextsong: {}", next);
+            let _ = writeln!(out, "nextsongid: {}", s.queue[next].id);
+        }
+        out
+    }
+
+    async fn run(&self, args: &[String]) -> CmdResult {
+        let cmd = args[0].as_str();
+        let args = &args[1..];
+        let player = &self.player;
+        let mut out = String::new();
+
+        match cmd {
+            "ping" | "password" | "enableoutput" | "disableoutput" => {}
+            "commands" => {
+                for c in COMMANDS {
+                    let _ = writeln!(out, "command: {}", c);
+                }
+            }
+            "notcommands" | "urlhandlers" | "decoders" => {}
+            "tagtypes" => {
+                if args.is_empty() {
+                    out.push_str("tagtype: Artist\ntagtype: Album\ntagtype: Title\n");
+                }
+            }
+            "outputs" => {
+                let _ = write!(
+                    out,
+                    "outputid: 0\noutputname: {}\nplugin: {}\noutputenabled: 1\n",
+                    player.output.name(),
+                    player.output.name()
+                );
+            }
+            "update" | "rescan" => {
+                out.push_str("updating_db: 1\n");
+                player.notify(Subsystem::Database);
+            }
+            "status" => out = self.status().await,
+            "stats" => {
+                let library = self.library().await?;
+                let artists = library.artists().len();
+                let playtime: i64 = library.songs.iter().filter_map(|s| s.duration).sum();
+                let _ = writeln!(out, "artists: {}", artists);
+                let _ = writeln!(out, "albums: {}", artists);
+                let _ = writeln!(out, "songs: {}", library.songs.len());
+                let _ = writeln!(out, "uptime: {}", player.started.elapsed().as_secs());
+                let _ = writeln!(out, "db_playtime: {}", playtime);
+                let _ = writeln!(out, "playtime: 0");
+            }
+            "currentsong" => {
+                let cur = player.get().current;
+                if let Some(pos) = cur {
+                    self.write_queue(&mut out, (pos, pos + 1)).await?;
+                }
+            }
+            "play" => {
+                let pos = args.first().map(|_| parse_arg(args, 0)).transpose()?;
+                if !player.update(|s| s.play(pos)) {
+                    return Err(ack(ACK_ERROR_ARG, "bad song index"));
+                }
+            }
+            "playid" => {
+                let pos = match args.first() {
+                    Some(_) => {
+                        let id = parse_arg(args, 0)?;
+                        let pos = player.get().pos_of(id);
+                        Some(pos.ok_or_else(|| ack(ACK_ERROR_NO_EXIST, "no such song"))?)
+                    }
+                    None => None,
+                };
+                if !player.update(|s| s.play(pos)) {
+                    return Err(ack(ACK_ERROR_ARG, "bad song index"));
+                }
+            }
+            "pause" => {
+                let pause = args.first().map(|_| parse_bool(args, 0)).transpose()?;
+                player.update(|s| s.pause(pause));
+            }
+            "stop" => player.update(|s| s.stop()),
+            "next" => player.update(|s| s.next()),
+            "previous" => player.update(|s| s.previous()),
+            "seek" | "seekid" | "seekcur" => {
+                let (pos, time) = match cmd {
+                    "seekcur" => {
+                        let cur = player.get().current;
+                        (cur.ok_or_else(|| ack(ACK_ERROR_ARG, "not playing"))?, 0)
+                    }
+                    "seekid" => {
+                        let id = parse_arg(args, 0)?;
+                        let pos = player.get().pos_of(id);
+                        (
+                            pos.ok_or_else(|| ack(ACK_ERROR_NO_EXIST, "no such song"))?,
+                            1,
+                        )
+                    }
+                    _ => (parse_arg(args, 0)?, 1),
+                };
+                let time: f64 = parse_arg(args, time)?;
+                let time = Duration::from_secs_f64(time.max(0.0));
+                if !player.update(|s| s.seek(pos, time)) {
+                    return Err(ack(ACK_ERROR_ARG, "bad song index"));
+                }
+            }
+            "setvol" => {
+                let vol: u8 = parse_arg(args, 0)?;
+                player.update(|s| s.set_volume(vol));
+            }
+            "volume" => {
+                let change: i32 = parse_arg(args, 0)?;
+                player.update(|s| s.set_volume((s.volume as i32 + change).clamp(0, 100) as u8));
+            }
+            "getvol" => {
+                let _ = writeln!(out, "volume: {}", player.get().volume);
+            }
+            "repeat" => {
+                let v = parse_bool(args, 0)?;
+                player.update(|s| s.repeat = v);
+            }
+            "random" => {
+                let v = parse_bool(args, 0)?;
+                player.update(|s| s.random = v);
+            }
+            "single" => {
+                let v = parse_bool(args, 0)?;
+                player.update(|s| s.single = v);
+            }
+            "consume" => {
+                let v = parse_bool(args, 0)?;
+                player.update(|s| s.consume = v);
+            }
+            "add" | "addid" => {
+                let uri = args
+                    .first()
+                    .ok_or_else(|| ack(ACK_ERROR_ARG, "missing uri"))?;
+                let pos = match (cmd, args.get(1)) {
+                    ("addid", Some(_)) => Some(parse_arg(args, 1)?),
+                    _ => None,
+                };
+                let ids =
+                    match uri.trim_matches('/') {
+                        // the whole library
+                        "" => self.library().await?.songs.iter().map(|s| s.id).collect(),
+                        uri => vec![parse_uri(uri)
+                            .ok_or_else(|| ack(ACK_ERROR_NO_EXIST, "no such song"))?],
+                    };
+                let added = self.enqueue(ids, pos).await?;
+                if cmd == "addid" {
+                    out = added;
+                }
+            }
+            "delete" => {
+                let len = player.get().queue.len();
+                let (start, end) =
+                    parse_range(args.first().map(|x| x.as_str()).unwrap_or(""), len)?;
+                if !player.update(|s| s.delete(start, end)) {
+                    return Err(ack(ACK_ERROR_ARG, "bad song index"));
+                }
+            }
+            "deleteid" => {
+                let id = parse_arg(args, 0)?;
+                let pos = player.get().pos_of(id);
+                let pos = pos.ok_or_else(|| ack(ACK_ERROR_NO_EXIST, "no such song"))?;
+                player.update(|s| s.delete(pos, pos + 1));
+            }
+            "clear" => player.update(|s| s.clear()),
+            "move" | "moveid" => {
+                let from = match cmd {
+                    "moveid" => {
+                        let id = parse_arg(args, 0)?;
+                        let pos = player.get().pos_of(id);
+                        pos.ok_or_else(|| ack(ACK_ERROR_NO_EXIST, "no such song"))?
+                    }
+                    _ => parse_arg(args, 0)?,
+                };
+                let to = parse_arg(args, 1)?;
+                if !player.update(|s| s.move_item(from, to)) {
+                    return Err(ack(ACK_ERROR_ARG, "bad song index"));
+                }
+            }
+            "playlistinfo" | "plchanges" => {
+                let len = player.get().queue.len();
+                let range = match (cmd, args.first()) {
+                    ("playlistinfo", Some(r)) => parse_range(r, len)?,
+                    _ => (0, len),
+                };
+                self.write_queue(&mut out, range).await?;
+            }
+            "playlistid" => {
+                let range = match args.first() {
+                    Some(_) => {
+                        let id = parse_arg(args, 0)?;
+                        let pos = player.get().pos_of(id);
+                        let pos = pos.ok_or_else(|| ack(ACK_ERROR_NO_EXIST, "no such song"))?;
+                        (pos, pos + 1)
+                    }
+                    None => (0, player.get().queue.len()),
+                };
+                self.write_queue(&mut out, range).await?;
+            }
+            "playlist" | "plchangesposid" => {
+                let queue = player.get().queue.clone();
+                for (pos, item) in queue.into_iter().enumerate() {
+                    if cmd == "playlist" {
+                        let song = self.song(item.music_id).await?;
+                        let _ = writeln!(out, "{}:file: {}", pos, uri(&song));
+                    } else {
+                        let _ = writeln!(out, "cpos: {}\nId: {}", pos, item.id);
+                    }
+                }
+            }
+            "lsinfo" | "listall" | "listallinfo" => {
+                let library = self.library().await?;
+                let path = args.first().map(|x| x.trim_matches('/')).unwrap_or("");
+                if !path.is_empty() {
+                    let id = parse_uri(path).ok_or_else(|| ack(ACK_ERROR_NO_EXIST, "not found"))?;
+                    let song = library
+                        .song(id)
+                        .ok_or_else(|| ack(ACK_ERROR_NO_EXIST, "not found"))?;
+                    write_song(&mut out, song);
+                    return Ok(out);
+                }
+                for song in &library.songs {
+                    match cmd {
+                        "listall" => {
+                            let _ = writeln!(out, "file: {}", uri(song));
+                        }
+                        _ => write_song(&mut out, song),
+                    }
+                }
+                if cmd == "lsinfo" {
+                    for name in library.playlists().keys() {
+                        let _ = writeln!(out, "playlist: {}", name);
+                    }
+                }
+            }
+            "find" | "search" | "findadd" | "searchadd" => {
+                let filters = parse_filters(args, cmd.starts_with("find"))?;
+                let library = self.library().await?;
+                let found: Vec<&Song> = library
+                    .songs
+                    .iter()
+                    .filter(|s| filters.iter().all(|f| f.matches(s)))
+                    .collect();
+                if cmd.ends_with("add") {
+                    self.enqueue(found.iter().map(|s| s.id).collect(), None)
+                        .await?;
+                } else {
+                    for song in found {
+                        write_song(&mut out, song);
+                    }
+                }
+            }
+            "list" => {
+                let tag = args
+                    .first()
+                    .ok_or_else(|| ack(ACK_ERROR_ARG, "missing tag type"))?
+                    .to_lowercase();
+                let mut filter_args: Vec<String> = args[1..]
+                    .iter()
+                    .take_while(|x| x.as_str() != "group")
+                    .cloned()
+                    .collect();
+                // list album ARTIST
+                if tag == "album" && filter_args.len() == 1 && !filter_args[0].starts_with('(') {
+                    filter_args.insert(0, s!("artist"));
+                }
+                let filters = parse_filters(&filter_args, true)?;
+                let library = self.library().await?;
+                let (name, values): (&str, BTreeSet<&str>) = match tag.as_str() {
+                    "artist" | "albumartist" | "album" => (
+                        if tag == "album" { "Album" } else { "Artist" },
+                        library
+                            .songs
+                            .iter()
+                            .filter(|s| filters.iter().all(|f| f.matches(s)))
+                            .map(|s| s.artist.as_str())
+                            .collect(),
+                    ),
+                    "title" => (
+                        "Title",
+                        library
+                            .songs
+                            .iter()
+                            .filter(|s| filters.iter().all(|f| f.matches(s)))
+                            .map(|s| s.title.as_str())
+                            .collect(),
+                    ),
+                    _ => ("", BTreeSet::new()),
+                };
+                for v in values {
+                    let _ = writeln!(out, "{}: {}", name, v);
+                }
+            }
+            "listplaylists" => {
+                for name in self.library().await?.playlists().keys() {
+                    let _ = writeln!(out, "playlist: {}", name);
+                }
+            }
+            "listplaylist" | "listplaylistinfo" | "load" => {
+                let name = args
+                    .first()
+                    .ok_or_else(|| ack(ACK_ERROR_ARG, "missing playlist name"))?;
+                let library = self.library().await?;
+                let playlists = library.playlists();
+                let songs = playlists
+                    .get(name.as_str())
+                    .ok_or_else(|| ack(ACK_ERROR_NO_EXIST, "no such playlist"))?;
+                match cmd {
+                    "load" => {
+                        self.enqueue(songs.iter().map(|s| s.id).collect(), None)
+                            .await?;
+                    }
+                    "listplaylist" => {
+                        for song in songs {
+                            let _ = writeln!(out, "file: {}", uri(song));
+                        }
+                    }
+                    _ => {
+                        for song in songs {
+                            write_song(&mut out, song);
+                        }
+                    }
+                }
+            }
+            _ => {
+                return Err(ack(
+                    ACK_ERROR_UNKNOWN,
+                    format!("unknown command \"{}\"", cmd),
+                ))
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Changes not reported yet to a client
+struct Events {
+    rx: tokio::sync::broadcast::Receiver<Subsystem>,
+    pending: Vec<Subsystem>,
+}
+
+impl Events {
+    fn push(&mut self, s: Subsystem) {
+        if !self.pending.contains(&s) {
+            self.pending.push(s);
+        }
+    }
+
+    /// Removes and returns the pending changes of the wanted subsystems (all if empty)
+    fn take(&mut self, wanted: &[Subsystem]) -> Vec<Subsystem> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(s) => self.push(s),
+                Err(TryRecvError::Lagged(_)) => {}
+                Err(_) => break,
+            }
+        }
+        let (taken, rest) = self
+            .pending
+            .iter()
+            .partition(|s| wanted.is_empty() || wanted.contains(s));
+        self.pending = rest;
+        taken
+    }
+}
+
+/// Waits for a change of the subsystems (all if empty), or for noidle
+async fn idle<R: AsyncBufReadExt + Unpin>(
+    lines: &mut tokio::io::Lines<R>,
+    events: &mut Events,
+    wanted: &[Subsystem],
+) -> Result<Option<String>> {
+    let mut changed = events.take(wanted);
+    while changed.is_empty() {
+        tokio::select! {
+            ev = events.rx.recv() => match ev {
+                Ok(s) => {
+                    events.push(s);
+                    changed = events.take(wanted);
+                }
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Ok(None),
+            },
+            line = lines.next_line() => match line?.as_deref() {
+                Some("noidle") => break,
+                Some(_) | None => return Ok(None),
+            }
+        }
+    }
+    let mut out = String::new();
+    for s in changed {
+        let _ = writeln!(out, "changed: {}", s.as_str());
+    }
+    out.push_str("OK\n");
+    Ok(Some(out))
+}
+
+async fn handle(stream: TcpStream, session: Session) -> Result<()> {
+    let (r, mut w) = stream.into_split();
+    let mut lines = BufReader::new(r).lines();
+    // events caused since the last idle are reported on the next one
+    let mut events = Events {
+        rx: session.player.subscribe(),
+        pending: vec![],
+    };
+    w.write_all(GREETING.as_bytes()).await?;
+
+    while let Some(line) = lines.next_line().await? {
+        let mut list = vec![line];
+        let list_ok = match list[0].as_str() {
+            "command_list_begin" => Some(false),
+            "command_list_ok_begin" => Some(true),
+            _ => None,
+        };
+        if list_ok.is_some() {
+            list.clear();
+            loop {
+                let line = unwrap_ret!(lines.next_line().await?, Ok(()));
+                if line == "command_list_end" {
+                    break;
+                }
+                list.push(line);
+            }
+        }
+
+        let mut out = String::new();
+        let mut failed = false;
+        for (i, line) in list.iter().enumerate() {
+            let args = match tokenize(line) {
+                Some(x) if !x.is_empty() => x,
+                _ => {
+                    let _ = writeln!(out, "ACK [{}@{}] {{}} bad command", ACK_ERROR_ARG, i);
+                    failed = true;
+                    break;
+                }
+            };
+            match args[0].as_str() {
+                "close" => return Ok(()),
+                "noidle" => continue,
+                "idle" if list_ok.is_none() => {
+                    let wanted: Vec<Subsystem> = args[1..]
+                        .iter()
+                        .filter_map(|x| Subsystem::parse(x))
+                        .collect();
+                    w.write_all(out.as_bytes()).await?;
+                    out.clear();
+                    match idle(&mut lines, &mut events, &wanted).await? {
+                        Some(resp) => out.push_str(&resp),
+                        None => return Ok(()),
+                    }
+                    failed = true; // already has its OK
+                    break;
+                }
+                _ => {}
+            }
+            match session.run(&args).await {
+                Ok(resp) => {
+                    out.push_str(&resp);
+                    if list_ok == Some(true) {
+                        out.push_str("list_OK\n");
+                    }
+                }
+                Err(e) => {
+                    let _ = writeln!(out, "ACK [{}@{}] {{{}}} {}", e.code, i, args[0], e.msg);
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        if !failed {
+            out.push_str("OK\n");
+        }
+        w.write_all(out.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Serves the MPD protocol (https://mpd.readthedocs.io/en/latest/protocol.html) on the listener,
+/// the library shown is the one of the given user
+pub async fn serve(listener: TcpListener, db: Db, player: Player, user: UserID) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("error accepting mpd connection: {:?}", e);
+                continue;
+            }
+        };
+        log::info!("mpd client connected from {}", addr);
+        let session = Session {
+            db: db.clone(),
+            player: player.clone(),
+            user,
+        };
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, session).await {
+                log::warn!("mpd connection closed: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Streams what the MPD player plays, for the http output
+pub async fn stream(req: Request<Body>) -> Result<Response<Body>> {
+    let player = req.state::<Player>();
+    let playing = player.get().current_item().map(|item| item.music_id);
+    let path = match playing {
+        Some(id) => source_path(&*req.state::<Db>().get().await, id)?,
+        None => None,
+    };
+    let content_type = match path.as_deref().map(content_type) {
+        Some(t) if !t.is_empty() => t,
+        _ => "audio/mpeg",
+    };
+    let mut audio = player.listen();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            match audio.recv().await {
+                Ok(chunk) => {
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+    let mut r = Response::new(body);
+    r.headers_mut().insert(CONTENT_TYPE, content_type.parse()?);
+    r.headers_mut().insert(CACHE_CONTROL, "no-cache".parse()?);
+    Ok(r)
+}
@@ -0,0 +1,93 @@
+use crate::domain::entity::{User, UserID};
+use crate::domain::history::Editor;
+use crate::domain::playlist::{self, Format, Locations};
+use crate::infrastructure::db::Db;
+use crate::infrastructure::router::RequestExt;
+use crate::utils::{base_url, res_status};
+use anyhow::{Context, Result};
+use hyper::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use nanoserde::SerJson;
+
+/// The user in the url, None if there is no such user
+fn user(req: &Request<Body>, c: &rusqlite::Connection) -> Result<Option<User>> {
+    let id = req.params().get("id").context("no id in url")?;
+    let id = UserID(id.parse().context("invalid id")?);
+    Ok(User::list(c)?.into_iter().find(|u| u.id == id))
+}
+
+pub async fn export_m3u8(req: Request<Body>) -> Result<Response<Body>> {
+    export(req, Format::M3u8).await
+}
+
+pub async fn export_xspf(req: Request<Body>) -> Result<Response<Body>> {
+    export(req, Format::Xspf).await
+}
+
+/// The library of the user, or one of its playlists with `?playlist=`.
+/// Entries are stream urls, or paths relative to the storage directory with `?paths=local`.
+async fn export(req: Request<Body>, format: Format) -> Result<Response<Body>> {
+    let q = req.query_params();
+    let locations = match q.get("paths").map(|x| x.as_str()) {
+        None | Some("stream") => Locations::Stream(base_url(&req)),
+        Some("local") => Locations::Local,
+        _ => return Ok(res_status(StatusCode::BAD_REQUEST)),
+    };
+    let c = req.state::<Db>().get().await;
+    let user = unwrap_ret!(user(&req, &c)?, Ok(res_status(StatusCode::NOT_FOUND)));
+    let playlist = q.get("playlist").map(|x| x.as_str());
+    let doc = playlist::export(&c, user, playlist, &locations, format)?;
+
+    let (content_type, ext) = match format {
+        Format::M3u8 => ("audio/x-mpegurl; charset=utf-8", "m3u8"),
+        Format::Xspf => ("application/xspf+xml", "xspf"),
+    };
+    let name: String = playlist
+        .unwrap_or("library")
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect();
+    let mut r = Response::new(Body::from(doc));
+    let headers = r.headers_mut();
+    headers.insert(CONTENT_TYPE, content_type.parse()?);
+    headers.insert(
+        CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}.{}\"", name, ext).parse()?,
+    );
+    Ok(r)
+}
+
+/// Imports the M3U, M3U8 or XSPF document in the body into the library of the user,
+/// tagging its musics with `?playlist=` if given
+pub async fn import(mut req: Request<Body>) -> Result<Response<Body>> {
+    let body = hyper::body::to_bytes(req.body_mut()).await?;
+    let entries = playlist::parse(&String::from_utf8_lossy(&body));
+    if entries.is_empty() {
+        return Ok(res_status(StatusCode::BAD_REQUEST));
+    }
+    let playlist = req.query_params().get("playlist").cloned();
+
+    let db = req.state::<Db>();
+    let mut c = db.get().await;
+    let user = unwrap_ret!(user(&req, &c)?, Ok(res_status(StatusCode::NOT_FOUND)));
+    let editor = Editor {
+        user_id: user.id,
+        ..Editor::from_req(&req)
+    };
+    let report = playlist::import(
+        &mut c,
+        &editor,
+        &base_url(&req),
+        playlist.as_deref(),
+        &entries,
+    )?;
+    drop(c);
+    playlist::queue_uploads(db.clone(), editor, playlist, report.queued.clone());
+
+    Ok(Response::new(Body::from(report.serialize_json())))
+}
@@ -0,0 +1,94 @@
+use crate::application::handlers::parse_body;
+use crate::domain::entity::{MusicID, Tag, TagKey, User, UserID};
+use crate::domain::radio::{IcyWriter, Radio, Radios, ICY_METAINT};
+use crate::infrastructure::db::Db;
+use crate::infrastructure::router::RequestExt;
+use crate::utils::res_status;
+use anyhow::{Context, Result};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use nanoserde::{DeJson, SerJson};
+use tokio::sync::broadcast::error::RecvError;
+
+/// The radio of the user in the url, None if there is no such user
+async fn radio(req: &Request<Body>) -> Result<Option<(User, Radio)>> {
+    let id = req.params().get("user").context("no user in url")?;
+    let id = UserID(id.parse().context("invalid user")?);
+    let c = req.state::<Db>().get().await;
+    let user = unwrap_ret!(User::list(&c)?.into_iter().find(|u| u.id == id), Ok(None));
+    Ok(Some((user, req.state::<Radios>().get(id))))
+}
+
+/// Continuous mp3 stream, with ICY metadata if the client asks for it
+pub async fn stream(req: Request<Body>) -> Result<Response<Body>> {
+    let (user, radio) = unwrap_ret!(radio(&req).await?, Ok(res_status(StatusCode::NOT_FOUND)));
+    let icy = req
+        .headers()
+        .get("icy-metadata")
+        .is_some_and(|x| x.as_bytes() == b"1");
+
+    let mut audio = radio.listen();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut writer = IcyWriter::new(ICY_METAINT);
+        loop {
+            match audio.recv().await {
+                Ok(chunk) => {
+                    let data = match icy {
+                        true => writer.write(&chunk).into(),
+                        false => chunk.data,
+                    };
+                    if sender.send_data(data).await.is_err() {
+                        return;
+                    }
+                }
+                // skipping audio would break the metadata interval
+                Err(RecvError::Lagged(_)) if icy => return,
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+
+    let mut r = Response::new(body);
+    let headers = r.headers_mut();
+    headers.insert(CONTENT_TYPE, "audio/mpeg".parse()?);
+    headers.insert(CACHE_CONTROL, "no-cache".parse()?);
+    headers.insert("icy-name", format!("musidex - {}", user.name).parse()?);
+    if icy {
+        headers.insert("icy-metaint", ICY_METAINT.into());
+    }
+    Ok(r)
+}
+
+pub async fn status(req: Request<Body>) -> Result<Response<Body>> {
+    let (_, radio) = unwrap_ret!(radio(&req).await?, Ok(res_status(StatusCode::NOT_FOUND)));
+    Ok(Response::new(Body::from(radio.status().serialize_json())))
+}
+
+#[derive(DeJson)]
+pub struct RadioControlPOST {
+    /// "skip" or "enqueue"
+    pub action: String,
+    pub music_id: Option<i32>,
+}
+
+pub async fn control(mut req: Request<Body>) -> Result<Response<Body>> {
+    let data: RadioControlPOST = parse_body(&mut req).await.context("can't decode body")?;
+    let (_, radio) = unwrap_ret!(radio(&req).await?, Ok(res_status(StatusCode::NOT_FOUND)));
+
+    match (data.action.as_str(), data.music_id) {
+        ("skip", _) => radio.skip(),
+        ("enqueue", Some(id)) => {
+            let c = req.state::<Db>().get().await;
+            // only mp3s can be streamed
+            if Tag::by_id_key(&c, MusicID(id), &TagKey::LocalMP3)?.is_none() {
+                return Ok(res_status(StatusCode::BAD_REQUEST));
+            }
+            radio.enqueue(MusicID(id));
+        }
+        _ => return Ok(res_status(StatusCode::BAD_REQUEST)),
+    }
+
+    Ok(Response::new(Body::from(radio.status().serialize_json())))
+}
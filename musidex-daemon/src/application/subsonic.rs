@@ -0,0 +1,581 @@
+use crate::application::handlers::stream_response;
+use crate::domain::entity::{MusicID, Tag, TagKey, User};
+use crate::domain::history::Editor;
+use crate::domain::library::{decode_id, encode_id, Library, Song};
+use crate::domain::subsonic::{authenticate, SubsonicError};
+use crate::infrastructure::db::{db_log, Db, DbLog, LogAction, LogType};
+use crate::infrastructure::router::RequestExt;
+use crate::infrastructure::xml;
+use anyhow::{Context, Result};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Request, Response};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+const API_VERSION: &str = "1.16.1";
+
+/// Elements that are always lists in the JSON responses
+const LIST_ELEMENTS: &[&str] = &[
+    "musicFolder",
+    "index",
+    "artist",
+    "album",
+    "song",
+    "playlist",
+    "entry",
+];
+
+enum Attr {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+/// Subsonic responses are XML by default and JSON with f=json, so they are built as a tree
+/// that can be rendered to both
+struct Elem {
+    name: &'static str,
+    attrs: Vec<(&'static str, Attr)>,
+    children: Vec<Elem>,
+}
+
+impl Elem {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            attrs: vec![],
+            children: vec![],
+        }
+    }
+
+    fn str(mut self, k: &'static str, v: impl Into<String>) -> Self {
+        self.attrs.push((k, Attr::Str(v.into())));
+        self
+    }
+
+    fn int(mut self, k: &'static str, v: i64) -> Self {
+        self.attrs.push((k, Attr::Int(v)));
+        self
+    }
+
+    fn bool(mut self, k: &'static str, v: bool) -> Self {
+        self.attrs.push((k, Attr::Bool(v)));
+        self
+    }
+
+    fn child(mut self, c: Elem) -> Self {
+        self.children.push(c);
+        self
+    }
+
+    fn children(mut self, c: impl IntoIterator<Item = Elem>) -> Self {
+        self.children.extend(c);
+        self
+    }
+
+    fn xml(&self, out: &mut String) {
+        let _ = write!(out, "<{}", self.name);
+        for (k, v) in &self.attrs {
+            let v = match v {
+                Attr::Str(s) => xml::escape(s),
+                Attr::Int(i) => i.to_string(),
+                Attr::Bool(b) => b.to_string(),
+            };
+            let _ = write!(out, " {}=\"{}\"", k, v);
+        }
+        if self.children.is_empty() {
+            out.push_str("/>");
+            return;
+        }
+        out.push('>');
+        for c in &self.children {
+            c.xml(out);
+        }
+        let _ = write!(out, "</{}>", self.name);
+    }
+
+    fn json(&self, out: &mut String) {
+        out.push('{');
+        let mut first = true;
+        let mut sep = |out: &mut String| {
+            if !first {
+                out.push(',');
+            }
+            first = false;
+        };
+        for (k, v) in &self.attrs {
+            sep(out);
+            let _ = write!(out, "\"{}\":", k);
+            match v {
+                Attr::Str(s) => escape_json(s, out),
+                Attr::Int(i) => {
+                    let _ = write!(out, "{}", i);
+                }
+                Attr::Bool(b) => {
+                    let _ = write!(out, "{}", b);
+                }
+            }
+        }
+        let mut names: Vec<&str> = self.children.iter().map(|c| c.name).collect();
+        names.dedup();
+        for name in names {
+            sep(out);
+            let _ = write!(out, "\"{}\":", name);
+            let mut of_name = self.children.iter().filter(|c| c.name == name);
+            if LIST_ELEMENTS.contains(&name) {
+                out.push('[');
+                for (i, c) in of_name.enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    c.json(out);
+                }
+                out.push(']');
+            } else if let Some(c) = of_name.next() {
+                c.json(out);
+            }
+        }
+        out.push('}');
+    }
+}
+
+fn escape_json(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn respond(json: bool, status: &str, content: Option<Elem>) -> Result<Response<Body>> {
+    let root = Elem::new("subsonic-response")
+        .str("status", status)
+        .str("version", API_VERSION)
+        .str("type", "musidex")
+        .str("serverVersion", env!("CARGO_PKG_VERSION"))
+        .bool("openSubsonic", true)
+        .children(content);
+
+    let mut out = String::new();
+    let content_type = if json {
+        out.push_str("{\"subsonic-response\":");
+        root.json(&mut out);
+        out.push('}');
+        "application/json"
+    } else {
+        out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+        let mut root = root;
+        root.attrs
+            .insert(0, ("xmlns", Attr::Str(s!("http://subsonic.org/restapi"))));
+        root.xml(&mut out);
+        "text/xml; charset=utf-8"
+    };
+    let mut r = Response::new(Body::from(out));
+    r.headers_mut().insert(CONTENT_TYPE, content_type.parse()?);
+    Ok(r)
+}
+
+fn ok(json: bool, content: Option<Elem>) -> Result<Response<Body>> {
+    respond(json, "ok", content)
+}
+
+fn error(json: bool, e: SubsonicError) -> Result<Response<Body>> {
+    respond(
+        json,
+        "failed",
+        Some(
+            Elem::new("error")
+                .int("code", e as i64)
+                .str("message", e.message()),
+        ),
+    )
+}
+
+fn song_elem(name: &'static str, s: &Song) -> Elem {
+    let album_id = encode_id("al", &s.artist);
+    let mut e = Elem::new(name)
+        .str("id", s.id.0.to_string())
+        .str("parent", &album_id)
+        .bool("isDir", false)
+        .str("title", &s.title)
+        .str("album", &s.artist)
+        .str("artist", &s.artist)
+        .str("albumId", album_id)
+        .str("artistId", encode_id("ar", &s.artist))
+        .str("coverArt", s.id.0.to_string())
+        .str("type", "music");
+    if let Some(d) = s.duration {
+        e = e.int("duration", d);
+    }
+    if let Some(ref path) = s.path {
+        let suffix = path.rsplit('.').next().unwrap_or_default();
+        let content_type = match suffix {
+            "mp3" => "audio/mpeg",
+            "ogg" => "audio/ogg",
+            "m4a" => "audio/mp4",
+            "webm" => "audio/webm",
+            _ => "application/octet-stream",
+        };
+        e = e
+            .str("path", path)
+            .str("suffix", suffix)
+            .str("contentType", content_type);
+    }
+    e
+}
+
+fn album_elem(artist: &str, songs: &[&Song]) -> Elem {
+    Elem::new("album")
+        .str("id", encode_id("al", artist))
+        .str("name", artist)
+        .str("title", artist)
+        .str("artist", artist)
+        .str("artistId", encode_id("ar", artist))
+        .bool("isDir", true)
+        .int("songCount", songs.len() as i64)
+        .int(
+            "duration",
+            songs.iter().filter_map(|s| s.duration).sum::<i64>(),
+        )
+        .str("coverArt", encode_id("al", artist))
+}
+
+fn artist_elem(artist: &str) -> Elem {
+    Elem::new("artist")
+        .str("id", encode_id("ar", artist))
+        .str("name", artist)
+        .int("albumCount", 1)
+        .str("coverArt", encode_id("ar", artist))
+}
+
+fn playlist_elem(user: &User, name: &str, songs: &[&Song]) -> Elem {
+    Elem::new("playlist")
+        .str("id", encode_id("pl", name))
+        .str("name", name)
+        .str("owner", &user.name)
+        .bool("public", false)
+        .int("songCount", songs.len() as i64)
+        .int(
+            "duration",
+            songs.iter().filter_map(|s| s.duration).sum::<i64>(),
+        )
+        .str("coverArt", encode_id("pl", name))
+}
+
+/// Query and form parameters, ids can be repeated
+fn params(req: &Request<Body>, body: &[u8]) -> (HashMap<String, String>, Vec<String>) {
+    let query = req.uri().query().unwrap_or("").as_bytes();
+    let mut params = HashMap::new();
+    let mut ids = vec![];
+    for (k, v) in form_urlencoded::parse(query).chain(form_urlencoded::parse(body)) {
+        if k == "id" {
+            ids.push(v.to_string());
+        }
+        params.insert(k.into_owned(), v.into_owned());
+    }
+    (params, ids)
+}
+
+fn paginate<T>(
+    v: Vec<T>,
+    params: &HashMap<String, String>,
+    count: &str,
+    offset: &str,
+    default: usize,
+) -> Vec<T> {
+    let count = params
+        .get(count)
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(default);
+    let offset = params.get(offset).and_then(|x| x.parse().ok()).unwrap_or(0);
+    v.into_iter().skip(offset).take(count).collect()
+}
+
+/// Serves the Subsonic API for /rest/:method, see http://www.subsonic.org/pages/api.jsp
+pub async fn rest(mut req: Request<Body>) -> Result<Response<Body>> {
+    let method = req
+        .params()
+        .get("method")
+        .context("no method in url")?
+        .trim_end_matches(".view")
+        .to_string();
+    let body = match *req.method() {
+        Method::POST => hyper::body::to_bytes(req.body_mut()).await?.to_vec(),
+        _ => vec![],
+    };
+    let (params, ids) = params(&req, &body);
+    let json = params.get("f").map(|x| x.as_str()) == Some("json");
+
+    let db = req.state::<Db>();
+    let user = {
+        let c = db.get().await;
+        match authenticate(&c, &params)? {
+            Ok(u) => u,
+            Err(e) => return error(json, e),
+        }
+    };
+    let library_key = TagKey::UserLibrary(user.id.0.to_string());
+
+    // loading the library reads all of its tags, only the browsing methods need it
+    macro_rules! library {
+        () => {{
+            let c = db.get().await;
+            Library::load(&c, user.clone())?
+        }};
+    }
+
+    let id = ids.first().map(|x| x.as_str()).unwrap_or_default();
+    let song_id = id.parse().ok().map(MusicID);
+
+    macro_rules! found {
+        ($e: expr) => {
+            match $e {
+                Some(x) => x,
+                None => return error(json, SubsonicError::NotFound),
+            }
+        };
+    }
+
+    match method.as_str() {
+        "ping" => ok(json, None),
+        "getLicense" => ok(json, Some(Elem::new("license").bool("valid", true))),
+        "getMusicFolders" => ok(
+            json,
+            Some(
+                Elem::new("musicFolders").child(
+                    Elem::new("musicFolder")
+                        .int("id", user.id.0 as i64)
+                        .str("name", &user.name),
+                ),
+            ),
+        ),
+        "getArtists" => {
+            let library = library!();
+            let mut indexes: Vec<Elem> = vec![];
+            for artist in library.artists().keys() {
+                let letter = artist
+                    .chars()
+                    .next()
+                    .map(|c| c.to_uppercase().to_string())
+                    .unwrap_or_default();
+                let same_index = indexes.last().is_some_and(
+                    |idx| matches!(idx.attrs.first(), Some((_, Attr::Str(l))) if *l == letter),
+                );
+                if !same_index {
+                    indexes.push(Elem::new("index").str("name", letter));
+                }
+                let idx = indexes.pop().unwrap();
+                indexes.push(idx.child(artist_elem(artist)));
+            }
+            ok(
+                json,
+                Some(
+                    Elem::new("artists")
+                        .str("ignoredArticles", "")
+                        .children(indexes),
+                ),
+            )
+        }
+        "getArtist" => {
+            let library = library!();
+            let name = found!(decode_id("ar", id));
+            let artists = library.artists();
+            let songs = found!(artists.get(name.as_str()));
+            ok(
+                json,
+                Some(artist_elem(&name).child(album_elem(&name, songs))),
+            )
+        }
+        "getAlbum" => {
+            let library = library!();
+            let name = found!(decode_id("al", id));
+            let artists = library.artists();
+            let songs = found!(artists.get(name.as_str()));
+            ok(
+                json,
+                Some(album_elem(&name, songs).children(songs.iter().map(|s| song_elem("song", s)))),
+            )
+        }
+        "getAlbumList" | "getAlbumList2" => {
+            let library = library!();
+            let artists = library.artists();
+            let mut albums: Vec<(&str, &Vec<&Song>)> =
+                artists.iter().map(|(k, v)| (*k, v)).collect();
+            match params.get("type").map(|x| x.as_str()) {
+                Some("alphabeticalByName") | Some("alphabeticalByArtist") => {}
+                Some("random") => {
+                    use tinyrand::{RandRange, Seeded, StdRand};
+                    let mut rng = StdRand::seed(
+                        std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .map(|d| d.as_nanos() as u64)
+                            .unwrap_or(0),
+                    );
+                    for i in (1..albums.len()).rev() {
+                        albums.swap(i, rng.next_range(0..i + 1));
+                    }
+                }
+                // newest first, by library order of the newest song of the artist
+                _ => albums.sort_by_key(|(_, songs)| {
+                    library
+                        .songs
+                        .iter()
+                        .position(|s| s.id == songs[0].id)
+                        .unwrap_or(0)
+                }),
+            }
+            let albums = paginate(albums, &params, "size", "offset", 10);
+            let name = if method == "getAlbumList" {
+                "albumList"
+            } else {
+                "albumList2"
+            };
+            ok(
+                json,
+                Some(
+                    Elem::new(name)
+                        .children(albums.into_iter().map(|(a, songs)| album_elem(a, songs))),
+                ),
+            )
+        }
+        "getSong" => {
+            let library = library!();
+            let song = found!(song_id.and_then(|id| library.song(id)));
+            ok(json, Some(song_elem("song", song)))
+        }
+        "search2" | "search3" => {
+            let library = library!();
+            let query = params.get("query").map(|x| x.as_str()).unwrap_or("");
+            let songs = library.search(query);
+            let mut artists: Vec<&str> = songs.iter().map(|s| s.artist.as_str()).collect();
+            artists.sort_unstable();
+            artists.dedup();
+            let all_artists = library.artists();
+
+            let artist_elems =
+                paginate(artists.clone(), &params, "artistCount", "artistOffset", 20)
+                    .into_iter()
+                    .map(artist_elem);
+            let album_elems = paginate(artists, &params, "albumCount", "albumOffset", 20)
+                .into_iter()
+                .map(|a| album_elem(a, &all_artists[a]));
+            let song_elems = paginate(songs, &params, "songCount", "songOffset", 20)
+                .into_iter()
+                .map(|s| song_elem("song", s));
+
+            let name = if method == "search2" {
+                "searchResult2"
+            } else {
+                "searchResult3"
+            };
+            ok(
+                json,
+                Some(
+                    Elem::new(name)
+                        .children(artist_elems)
+                        .children(album_elems)
+                        .children(song_elems),
+                ),
+            )
+        }
+        "getPlaylists" => {
+            let library = library!();
+            let playlists = library.playlists();
+            ok(
+                json,
+                Some(
+                    Elem::new("playlists").children(
+                        playlists
+                            .iter()
+                            .map(|(name, songs)| playlist_elem(&library.user, name, songs)),
+                    ),
+                ),
+            )
+        }
+        "getPlaylist" => {
+            let library = library!();
+            let name = found!(decode_id("pl", id));
+            let playlists = library.playlists();
+            let songs = found!(playlists.get(name.as_str()));
+            ok(
+                json,
+                Some(
+                    playlist_elem(&library.user, &name, songs)
+                        .children(songs.iter().map(|s| song_elem("entry", s))),
+                ),
+            )
+        }
+        "stream" | "download" => {
+            let id = found!(song_id);
+            if !Tag::has_visible(&*db.get().await, id, &library_key)? {
+                return error(json, SubsonicError::NotFound);
+            }
+            stream_response(db, id, req.headers().get(hyper::header::RANGE)).await
+        }
+        "getCoverArt" => {
+            let library = library!();
+            let cover = if let Some(id) = song_id {
+                library.song(id).and_then(|s| s.cover.clone())
+            } else {
+                let artists = library.artists();
+                let playlists = library.playlists();
+                let songs = decode_id("al", id)
+                    .or_else(|| decode_id("ar", id))
+                    .and_then(|a| artists.get(a.as_str()))
+                    .or_else(|| decode_id("pl", id).and_then(|p| playlists.get(p.as_str())));
+                songs.and_then(|songs| songs.iter().find_map(|s| s.cover.clone()))
+            };
+            let cover = found!(cover);
+            let buf = tokio::fs::read(format!("./storage/{}", cover))
+                .await
+                .context("failed reading cover")?;
+            let content_type = match cover.rsplit('.').next() {
+                Some("png") => "image/png",
+                Some("webp") => "image/webp",
+                _ => "image/jpeg",
+            };
+            let mut r = Response::new(Body::from(buf));
+            r.headers_mut().insert(CONTENT_TYPE, content_type.parse()?);
+            Ok(r)
+        }
+        "scrobble" => {
+            if params.get("submission").map(|x| x.as_str()) == Some("false") {
+                return ok(json, None);
+            }
+            let editor = Editor {
+                user_id: user.id,
+                ..Editor::from_req(&req)
+            };
+            let c = db.get().await;
+            for id in &ids {
+                let id = found!(id.parse().ok().map(MusicID));
+                if !Tag::has_visible(&c, id, &library_key)? {
+                    return error(json, SubsonicError::NotFound);
+                }
+                db_log(
+                    &c,
+                    DbLog {
+                        user_id: editor.user_id,
+                        ip: editor.ip.clone(),
+                        type_: LogType::Listen,
+                        action: LogAction::Create,
+                        music_id: Some(id),
+                        target_key: None,
+                        target_value: None,
+                        before: None,
+                        after: None,
+                    },
+                );
+            }
+            ok(json, None)
+        }
+        _ => error(json, SubsonicError::Generic),
+    }
+}
@@ -0,0 +1,97 @@
+use crate::domain::upnp::{self, UpnpDevice, UpnpError, CONNECTION_MANAGER, CONTENT_DIRECTORY};
+use crate::infrastructure::db::Db;
+use crate::infrastructure::router::RequestExt;
+use crate::infrastructure::xml;
+use crate::utils::res_status;
+use anyhow::Result;
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Body, Request, Response, StatusCode};
+use std::fmt::Write;
+
+fn xml_response(body: String) -> Result<Response<Body>> {
+    let mut r = Response::new(Body::from(body));
+    r.headers_mut()
+        .insert(CONTENT_TYPE, "text/xml; charset=\"utf-8\"".parse()?);
+    Ok(r)
+}
+
+pub async fn description(req: Request<Body>) -> Result<Response<Body>> {
+    let device = req.state::<UpnpDevice>();
+    xml_response(upnp::description(&device.uuid, &device.name))
+}
+
+pub async fn scpd(req: Request<Body>) -> Result<Response<Body>> {
+    match req.params().get("service") {
+        Some("content_directory.xml") => xml_response(upnp::content_directory_scpd()),
+        Some("connection_manager.xml") => xml_response(upnp::connection_manager_scpd()),
+        _ => Ok(res_status(StatusCode::NOT_FOUND)),
+    }
+}
+
+fn envelope(body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+        s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+        <s:Body>{}</s:Body></s:Envelope>",
+        body
+    )
+}
+
+fn fault(e: UpnpError) -> Result<Response<Body>> {
+    let body = envelope(&format!(
+        "<s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring>\
+        <detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+        <errorCode>{}</errorCode><errorDescription>{}</errorDescription>\
+        </UPnPError></detail></s:Fault>",
+        e as u16,
+        e.description()
+    ));
+    let mut r = xml_response(body)?;
+    *r.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    Ok(r)
+}
+
+/// SOAP actions of the ContentDirectory and ConnectionManager services
+pub async fn control(mut req: Request<Body>) -> Result<Response<Body>> {
+    let service = match req.params().get("service") {
+        Some("content_directory") => CONTENT_DIRECTORY,
+        Some("connection_manager") => CONNECTION_MANAGER,
+        _ => return Ok(res_status(StatusCode::NOT_FOUND)),
+    };
+    // "urn:schemas-upnp-org:service:ContentDirectory:1#Browse"
+    let action = req
+        .headers()
+        .get("soapaction")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim_matches('"').rsplit_once('#'))
+        .map(|x| x.1.to_string())
+        .unwrap_or_default();
+    let base = format!(
+        "http://{}",
+        req.headers()
+            .get(HOST)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or("localhost")
+    );
+    let body = hyper::body::to_bytes(req.body_mut()).await?;
+    let body = String::from_utf8_lossy(&body);
+
+    let outputs = if service == CONTENT_DIRECTORY {
+        let c = req.state::<Db>().get().await;
+        upnp::content_directory(&c, &base, &action, &body)?
+    } else {
+        upnp::connection_manager(&action)
+    };
+    let outputs = match outputs {
+        Ok(x) => x,
+        Err(e) => return fault(e),
+    };
+
+    let mut resp = format!("<u:{}Response xmlns:u=\"{}\">", action, service);
+    for (name, value) in outputs {
+        let _ = write!(resp, "<{}>{}</{}>", name, xml::escape(&value), name);
+    }
+    let _ = write!(resp, "</u:{}Response>", action);
+    xml_response(envelope(&resp))
+}
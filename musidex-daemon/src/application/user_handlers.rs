@@ -1,10 +1,16 @@
 use crate::application::handlers::parse_body;
+use crate::domain::devices::Devices;
 use crate::domain::entity::{User, UserID};
+use crate::domain::offline::{self, ManifestOptions, ManifestOrder};
+use crate::domain::subsonic;
+use crate::domain::trash;
 use crate::infrastructure::db::{db_log, Db, DbLog, LogAction, LogType};
 use crate::infrastructure::router::RequestExt;
+use crate::utils::res_status;
 use anyhow::{Context, Result};
 use hyper::{Body, Request, Response, StatusCode};
-use nanoserde::DeJson;
+use nanoserde::{DeJson, SerJson};
+use std::path::PathBuf;
 
 #[derive(DeJson)]
 pub struct UserCreatePOST {
@@ -65,6 +71,8 @@ pub async fn delete(req: Request<Body>) -> Result<Response<Body>> {
             music_id: None,
             target_key: None,
             target_value: None,
+            before: None,
+            after: None,
         },
     );
     User::delete(&tx, UserID(id))?;
@@ -72,3 +80,79 @@ pub async fn delete(req: Request<Body>) -> Result<Response<Body>> {
 
     Ok(Response::new(Body::empty()))
 }
+
+pub async fn offline_manifest(req: Request<Body>) -> Result<Response<Body>> {
+    let id = req.params().get("id").context("no id in url")?;
+    let id: i32 = id.parse().context("invalid id")?;
+
+    let q = req.query_params();
+    let order = unwrap_ret!(
+        ManifestOrder::parse(q.get("order").map(|x| x.as_str())),
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
+    let budget = match q.get("budget").map(|x| x.parse()).transpose() {
+        Ok(x) => x,
+        Err(_) => return Ok(res_status(StatusCode::BAD_REQUEST)),
+    };
+    let opts = ManifestOptions {
+        playlist: q.get("playlist").cloned(),
+        budget,
+        order,
+    };
+
+    let db = req.state::<Db>();
+    let manifest = offline::manifest(db, PathBuf::from("./storage/"), UserID(id), opts).await?;
+
+    Ok(Response::new(Body::from(manifest.serialize_json())))
+}
+
+#[derive(DeJson)]
+pub struct SubsonicPasswordPOST {
+    pub password: String,
+}
+
+/// Subsonic clients need the password in clear to check salted tokens, so it is separate
+/// from anything else and only meant for Subsonic
+pub async fn subsonic_password(mut req: Request<Body>) -> Result<Response<Body>> {
+    let data: SubsonicPasswordPOST = parse_body(&mut req).await.context("can't decode body")?;
+    let id = req.params().get("id").context("no id in url")?;
+    let id: i32 = id.parse().context("invalid id")?;
+
+    let db = req.state::<Db>();
+    let c = db.get().await;
+
+    if !User::list(&c)?.iter().any(|u| u.id == UserID(id)) {
+        return Ok(res_status(StatusCode::NOT_FOUND));
+    }
+    subsonic::set_password(&c, UserID(id), &data.password)?;
+
+    Ok(Response::new(Body::empty()))
+}
+
+/// Devices of the user connected to the sync websocket, see domain::devices
+pub async fn devices(req: Request<Body>) -> Result<Response<Body>> {
+    let id = req.params().get("id").context("no id in url")?;
+    let id: i32 = id.parse().context("invalid id")?;
+
+    let c = req.state::<Db>().get().await;
+    if !User::list(&c)?.iter().any(|u| u.id == UserID(id)) {
+        return Ok(res_status(StatusCode::NOT_FOUND));
+    }
+    let devices = req.state::<Devices>().list(UserID(id));
+
+    Ok(Response::new(Body::from(devices.serialize_json())))
+}
+
+/// Musics the user deleted that can still be restored
+pub async fn trash(req: Request<Body>) -> Result<Response<Body>> {
+    let id = req.params().get("id").context("no id in url")?;
+    let id = UserID(id.parse().context("invalid id")?);
+
+    let c = req.state::<Db>().get().await;
+    if !User::list(&c)?.iter().any(|u| u.id == id) {
+        return Ok(res_status(StatusCode::NOT_FOUND));
+    }
+    let entries = trash::list(&c, id)?;
+
+    Ok(Response::new(Body::from(entries.serialize_json())))
+}
@@ -0,0 +1,354 @@
+//! Portable archive of an instance, to migrate servers without copying the database.
+//! It is a tar with a `musidex.json` dump first, then the files of `storage/` if asked for.
+//! Importing merges it into the current instance: users are matched by name, musics by
+//! YouTube video id, and other musics keep their id unless it is taken.
+
+use crate::domain::config;
+use crate::domain::entity::{Music, MusicID, Tag, TagKey, User};
+use crate::domain::upload;
+use crate::infrastructure::db::Db;
+use crate::infrastructure::tar;
+use crate::utils::collect_rows;
+use anyhow::{bail, Context, Result};
+use hyper::body::{Bytes, HttpBody, Sender};
+use hyper::Body;
+use nanoserde::{DeJson, SerJson};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+pub const DUMP_NAME: &str = "musidex.json";
+const DUMP_VERSION: u32 = 1;
+/// tags pointing to files in the storage directory
+const FILE_KEYS: [TagKey; 6] = [
+    TagKey::LocalMP3,
+    TagKey::LocalOGG,
+    TagKey::LocalM4A,
+    TagKey::LocalWEBM,
+    TagKey::Thumbnail,
+    TagKey::CompressedThumbnail,
+];
+
+#[derive(SerJson, DeJson, Debug, Clone, PartialEq)]
+pub struct ConfigEntry {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(SerJson, DeJson, Debug)]
+pub struct Dump {
+    pub version: u32,
+    pub users: Vec<User>,
+    pub musics: Vec<MusicID>,
+    pub tags: Vec<Tag>,
+    /// secret keys stay on the server
+    pub config: Vec<ConfigEntry>,
+}
+
+#[derive(SerJson, Default, Debug, PartialEq)]
+pub struct ImportReport {
+    pub users_created: usize,
+    pub musics_created: usize,
+    /// musics that were already there, only their missing tags were added
+    pub musics_merged: usize,
+    /// musics that got a new id since theirs was taken
+    pub musics_remapped: usize,
+    pub files: usize,
+}
+
+pub fn dump(c: &Connection) -> Result<Dump> {
+    let mut stmt = c.prepare_cached("SELECT id FROM musics ORDER BY id;")?;
+    let musics = collect_rows(stmt.query_map([], |row| Ok(MusicID(row.get(0)?)))?)?;
+    let mut stmt = c.prepare_cached("SELECT * FROM tags ORDER BY music_id, key;")?;
+    let tags = collect_rows(stmt.query_map([], |row| Ok(Tag::from(row)))?)?;
+    Ok(Dump {
+        version: DUMP_VERSION,
+        users: User::list(c)?,
+        musics,
+        tags,
+        config: config::get_public(c)?
+            .into_iter()
+            .map(|(key, value)| ConfigEntry { key, value })
+            .collect(),
+    })
+}
+
+/// Files of the storage directory the musics point to
+pub fn files(dump: &Dump) -> Vec<String> {
+    let mut files: Vec<String> = dump
+        .tags
+        .iter()
+        .filter(|t| FILE_KEYS.contains(&t.key))
+        .filter_map(|t| t.text.clone())
+        .collect();
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// Relative path that stays inside the storage directory, None if it would escape it
+fn safe_path(path: &str) -> Option<PathBuf> {
+    let p = Path::new(path);
+    let safe = !path.is_empty() && p.components().all(|c| matches!(c, Component::Normal(_)));
+    safe.then(|| p.to_path_buf())
+}
+
+/// Writes the archive to the sender, the files are read as they go
+pub async fn export(db: &Db, storage: &Path, with_files: bool, mut out: Sender) -> Result<()> {
+    let dump = dump(&*db.get().await)?;
+    let json = dump.serialize_json();
+    let len = json.len() as u64;
+    let now = UNIX_EPOCH.elapsed().map(|d| d.as_secs()).unwrap_or(0);
+    out.send_data(Bytes::copy_from_slice(&tar::header(DUMP_NAME, len, now)?))
+        .await?;
+    out.send_data(json.into()).await?;
+    out.send_data(vec![0; tar::padding(len)].into()).await?;
+
+    if with_files {
+        for path in files(&dump) {
+            let full = storage.join(unwrap_cont!(safe_path(&path)));
+            let mut f = match tokio::fs::File::open(&full).await {
+                Ok(f) => f,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("can't open {}", full.display())),
+            };
+            let meta = f.metadata().await?;
+            let mtime = meta
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let header = match tar::header(&format!("storage/{}", path), meta.len(), mtime) {
+                Ok(h) => h,
+                Err(e) => {
+                    log::warn!("not exporting {}: {}", path, e);
+                    continue;
+                }
+            };
+            out.send_data(Bytes::copy_from_slice(&header)).await?;
+
+            // the header has the size, so stick to it even if the file changes meanwhile
+            let mut left = meta.len();
+            let mut buf = vec![0; 1 << 16];
+            while left > 0 {
+                let n = f.read(&mut buf[..left.min(1 << 16) as usize]).await?;
+                let chunk = match n {
+                    0 => vec![0; left.min(1 << 16) as usize],
+                    n => buf[..n].to_vec(),
+                };
+                left -= chunk.len() as u64;
+                out.send_data(chunk.into()).await?;
+            }
+            out.send_data(vec![0; tar::padding(meta.len())].into())
+                .await?;
+        }
+    }
+    out.send_data(Bytes::from_static(&tar::END)).await?;
+    Ok(())
+}
+
+/// Reads exact amounts out of a request body
+struct BodyReader {
+    body: Body,
+    buf: Bytes,
+}
+
+impl BodyReader {
+    /// Up to `max` bytes, empty at the end of the body
+    async fn read(&mut self, max: usize) -> Result<Bytes> {
+        while self.buf.is_empty() {
+            match self.body.data().await {
+                Some(data) => self.buf = data?,
+                None => return Ok(Bytes::new()),
+            }
+        }
+        Ok(self.buf.split_to(max.min(self.buf.len())))
+    }
+
+    async fn read_exact(&mut self, n: usize) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(n);
+        while out.len() < n {
+            let data = self.read(n - out.len()).await?;
+            if data.is_empty() {
+                bail!("unexpected end of archive");
+            }
+            out.extend_from_slice(&data);
+        }
+        Ok(out)
+    }
+
+    /// Copies the next n bytes to the writer, or drops them without one
+    async fn copy(&mut self, mut n: u64, mut to: Option<&mut tokio::fs::File>) -> Result<()> {
+        while n > 0 {
+            let data = self.read(n.min(1 << 16) as usize).await?;
+            if data.is_empty() {
+                bail!("unexpected end of archive");
+            }
+            n -= data.len() as u64;
+            if let Some(ref mut f) = to {
+                f.write_all(&data).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Restores an archive made by export. Files already in storage are kept as they are.
+/// None if the archive doesn't start with a dump.
+pub async fn import(db: &Db, storage: &Path, body: Body) -> Result<Option<ImportReport>> {
+    let mut r = BodyReader {
+        body,
+        buf: Bytes::new(),
+    };
+    let mut block = [0; tar::BLOCK];
+    let mut report = None;
+    let mut files = 0;
+    loop {
+        match r.read_exact(tar::BLOCK).await {
+            Ok(data) => block.copy_from_slice(&data),
+            Err(_) if report.is_none() => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let header = match tar::parse_header(&block) {
+            Ok(Some(h)) => h,
+            Ok(None) => break,
+            Err(_) if report.is_none() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let padding = tar::padding(header.size) as u64;
+
+        if report.is_none() {
+            if !header.regular || header.path != DUMP_NAME {
+                return Ok(None);
+            }
+            let json = r.read_exact(header.size as usize).await?;
+            let dump = match Dump::deserialize_json(&String::from_utf8_lossy(&json)) {
+                Ok(x) => x,
+                Err(_) => return Ok(None),
+            };
+            if dump.version > DUMP_VERSION {
+                bail!("archive made by a newer version of musidex");
+            }
+            report = Some(restore(&mut *db.get().await, &dump)?);
+            r.copy(padding, None).await?;
+            continue;
+        }
+
+        let dest = header
+            .path
+            .strip_prefix("storage/")
+            .and_then(safe_path)
+            .filter(|_| header.regular)
+            .map(|p| storage.join(p))
+            .filter(|p| !p.exists());
+        match dest {
+            Some(dest) => {
+                if let Some(parent) = dest.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                // only complete files get their name, so that a failed import can be retried
+                let mut part = dest.clone().into_os_string();
+                part.push(".part");
+                let mut f = tokio::fs::File::create(&part).await?;
+                r.copy(header.size, Some(&mut f)).await?;
+                f.flush().await?;
+                tokio::fs::rename(&part, &dest).await?;
+                files += 1;
+            }
+            None => r.copy(header.size, None).await?,
+        }
+        r.copy(padding, None).await?;
+    }
+
+    Ok(report.map(|r| ImportReport { files, ..r }))
+}
+
+fn insert_music(c: &Connection, id: MusicID) -> Result<()> {
+    c.prepare_cached("INSERT INTO musics (id) VALUES (?1);")?
+        .execute([id.0])?;
+    Ok(())
+}
+
+/// Merges the dump into the database, see the module documentation
+pub fn restore(c: &mut Connection, dump: &Dump) -> Result<ImportReport> {
+    let tx = c.transaction()?;
+    let mut report = ImportReport::default();
+
+    let existing = User::list(&tx)?;
+    let mut users = HashMap::new();
+    for u in &dump.users {
+        let id = match existing.iter().find(|x| x.name == u.name) {
+            Some(x) => x.id,
+            None => {
+                report.users_created += 1;
+                User::create(&tx, u.name.clone())?
+            }
+        };
+        users.insert(u.id.0.to_string(), id);
+    }
+
+    let mut tags: HashMap<MusicID, Vec<&Tag>> = HashMap::new();
+    for tag in &dump.tags {
+        tags.entry(tag.music_id).or_default().push(tag);
+    }
+    for old in &dump.musics {
+        let tags = tags.remove(old).unwrap_or_default();
+        let video_id = tags
+            .iter()
+            .find(|t| t.key == TagKey::YoutubeDLVideoID)
+            .and_then(|t| t.text.as_deref());
+        let merge_into = match video_id {
+            Some(v) => upload::id_exists(&tx, v)?,
+            None => None,
+        };
+        let id = match merge_into {
+            Some(id) => {
+                report.musics_merged += 1;
+                id
+            }
+            None if !Music::exists(&tx, *old)? => {
+                insert_music(&tx, *old)?;
+                report.musics_created += 1;
+                *old
+            }
+            None => {
+                report.musics_created += 1;
+                report.musics_remapped += 1;
+                Music::mk(&tx)?
+            }
+        };
+
+        for tag in tags {
+            let key = match tag.key {
+                TagKey::UserLibrary(ref user) => {
+                    TagKey::UserLibrary(unwrap_cont!(users.get(user)).0.to_string())
+                }
+                ref key => key.clone(),
+            };
+            if merge_into.is_some() && Tag::has(&tx, id, &key)? {
+                continue;
+            }
+            Tag::insert_silent(
+                &tx,
+                Tag {
+                    music_id: id,
+                    key,
+                    ..tag.clone()
+                },
+            )?;
+        }
+    }
+
+    for e in &dump.config {
+        if config::is_secret(&e.key) {
+            continue;
+        }
+        config::insert_if_not_exist(&tx, &e.key, &e.value)?;
+        config::update(&tx, &e.key, &e.value)?;
+    }
+
+    tx.commit()?;
+    Ok(report)
+}
@@ -0,0 +1,234 @@
+//! Online snapshots of the database through SQLite's backup API, so writes can go on meanwhile.
+//! Each snapshot is gzipped into the backup directory next to a `.sha1` file in sha1sum
+//! format, and only the most recent ones are kept.
+//! A hook command can then copy them elsewhere, see backup.sh at the root of the repository.
+
+use crate::domain::player::shell_quote;
+use crate::infrastructure::db::Db;
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use nanoserde::SerJson;
+use rusqlite::backup::Backup;
+use rusqlite::Connection;
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+const PREFIX: &str = "musidex-";
+const EXT: &str = ".db.gz";
+
+#[derive(SerJson, Debug, Clone, PartialEq)]
+pub struct BackupInfo {
+    pub name: String,
+    pub size: u64,
+    /// unix timestamp in seconds
+    pub created: u64,
+    /// of the compressed file, None if the checksum file is missing
+    pub sha1: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Backups {
+    db: Db,
+    dir: PathBuf,
+    keep: usize,
+    /// shell command run after each backup, `{file}` is replaced by the path of the backup
+    hook: Option<String>,
+    /// a scheduled backup and one asked for shouldn't write at the same time
+    running: Arc<Mutex<()>>,
+}
+
+/// Counts and hashes what goes through it
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha1,
+    len: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Gzips the file a buffer at a time, returns the size and sha1 of the compressed file
+fn compress_file(from: &Path, to: &Path) -> Result<(u64, String)> {
+    let mut out = HashingWriter {
+        inner: BufWriter::new(File::create(to)?),
+        hasher: Sha1::new(),
+        len: 0,
+    };
+    let mut gz = GzEncoder::new(&mut out, Compression::new(6));
+    std::io::copy(&mut File::open(from)?, &mut gz)?;
+    gz.finish()?;
+    out.flush()?;
+    let sha1 = out
+        .hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Ok((out.len, sha1))
+}
+
+fn checksum_path(path: &Path) -> PathBuf {
+    let mut p = path.to_path_buf().into_os_string();
+    p.push(".sha1");
+    PathBuf::from(p)
+}
+
+impl Backups {
+    /// Keeps at least one backup whatever `keep` says
+    pub fn new(db: Db, dir: PathBuf, keep: usize, hook: Option<String>) -> Self {
+        Self {
+            db,
+            dir,
+            keep: keep.max(1),
+            hook,
+            running: Default::default(),
+        }
+    }
+
+    /// Takes a snapshot now then removes the ones beyond retention
+    pub async fn run(&self) -> Result<BackupInfo> {
+        let _running = self.running.lock().await;
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .context("could not create backup directory")?;
+        let name = format!(
+            "{}{}{}",
+            PREFIX,
+            chrono::Utc::now().format("%Y%m%d-%H%M%S-%3f"),
+            EXT
+        );
+
+        let snapshot = self.dir.join(".snapshot.db");
+        let _ = tokio::fs::remove_file(&snapshot).await;
+        {
+            let src = self.db.get().await;
+            let mut dst = Connection::open(&snapshot)?;
+            Backup::new(&src, &mut dst)?
+                .run_to_completion(1024, Duration::ZERO, None)
+                .context("error during sqlite backup")?;
+        }
+
+        let path = self.dir.join(&name);
+        let mut part = path.clone().into_os_string();
+        part.push(".part");
+        let part = PathBuf::from(part);
+        let compressed = tokio::task::spawn_blocking({
+            let (snapshot, part) = (snapshot.clone(), part.clone());
+            move || compress_file(&snapshot, &part)
+        })
+        .await?;
+        tokio::fs::remove_file(&snapshot).await?;
+        let (size, sha1) = compressed?;
+        tokio::fs::rename(&part, &path).await?;
+        tokio::fs::write(checksum_path(&path), format!("{}  {}\n", sha1, name)).await?;
+
+        self.prune()?;
+        self.run_hook(&path).await?;
+        Ok(BackupInfo {
+            name,
+            size,
+            created: UNIX_EPOCH.elapsed().map(|d| d.as_secs()).unwrap_or(0),
+            sha1: Some(sha1),
+        })
+    }
+
+    /// Most recent first, empty if the directory doesn't exist yet
+    pub fn list(&self) -> Result<Vec<BackupInfo>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut backups = vec![];
+        for entry in entries {
+            let entry = entry?;
+            let name = unwrap_cont!(entry.file_name().to_str().map(ToString::to_string));
+            if !name.starts_with(PREFIX) || !name.ends_with(EXT) {
+                continue;
+            }
+            let meta = entry.metadata()?;
+            let sha1 = std::fs::read_to_string(checksum_path(&entry.path()))
+                .ok()
+                .and_then(|x| x.split_whitespace().next().map(ToString::to_string));
+            backups.push(BackupInfo {
+                name,
+                size: meta.len(),
+                created: meta
+                    .modified()?
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+                sha1,
+            });
+        }
+        // names start with the date
+        backups.sort_by(|a, b| b.name.cmp(&a.name));
+        Ok(backups)
+    }
+
+    async fn run_hook(&self, path: &Path) -> Result<()> {
+        let hook = unwrap_ret!(self.hook.as_ref(), Ok(()));
+        let cmd = hook.replace("{file}", &shell_quote(&path.to_string_lossy()));
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&cmd)
+            .stdin(Stdio::null())
+            .spawn()
+            .with_context(|| format!("error starting backup hook {}", cmd))?;
+        let status = tokio::task::spawn_blocking(move || child.wait()).await??;
+        if !status.success() {
+            bail!("backup hook {} failed with {}", cmd, status);
+        }
+        Ok(())
+    }
+
+    fn prune(&self) -> Result<()> {
+        for old in self.list()?.into_iter().skip(self.keep) {
+            let path = self.dir.join(&old.name);
+            std::fs::remove_file(&path)?;
+            let _ = std::fs::remove_file(checksum_path(&path));
+            log::info!("removed old backup {}", old.name);
+        }
+        Ok(())
+    }
+
+    /// Backs up every interval, counting from the last backup so restarts don't delay it
+    pub fn start(self, interval: Duration) {
+        tokio::spawn(async move {
+            loop {
+                let since_last = self
+                    .list()
+                    .ok()
+                    .and_then(|l| l.first().map(|b| b.created))
+                    .and_then(|created| UNIX_EPOCH.elapsed().ok()?.as_secs().checked_sub(created))
+                    .map(Duration::from_secs);
+                let wait = interval.saturating_sub(since_last.unwrap_or(interval));
+                tokio::time::sleep(wait).await;
+                match self.run().await {
+                    Ok(b) => log::info!("backed up the database to {}", b.name),
+                    Err(e) => {
+                        log::error!("error backing up the database: {:?}", e);
+                        tokio::time::sleep(interval.min(Duration::from_secs(3600))).await;
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,108 @@
+use crate::domain::entity::{Music, MusicID, Tag, TagKey, TagSchema};
+use crate::domain::history::Editor;
+use crate::domain::query::Query;
+use crate::domain::sync::fetch_metadata;
+use anyhow::Result;
+use hyper::StatusCode;
+use nanoserde::{DeJson, SerJson};
+use rusqlite::Connection;
+use std::collections::HashSet;
+
+#[derive(DeJson)]
+pub struct BulkEdit {
+    /// musics to edit, can be combined with filter
+    pub music_ids: Option<Vec<MusicID>>,
+    /// tag query selecting the musics to edit, see domain::query
+    pub filter: Option<String>,
+    pub ops: Vec<BulkOp>,
+}
+
+#[derive(DeJson)]
+pub struct BulkOp {
+    /// "set" or "remove"
+    pub op: String,
+    pub key: TagKey,
+    pub text: Option<String>,
+    pub integer: Option<i64>,
+    pub date: Option<String>,
+}
+
+#[derive(SerJson, Debug, PartialEq)]
+pub struct BulkResult {
+    pub music_id: MusicID,
+    pub key: TagKey,
+    pub status: u16,
+    pub error: Option<String>,
+}
+
+/// Applies every op to every selected music.
+/// Invalid ops are reported in the results and skipped, the others are applied.
+pub fn apply(
+    c: &Connection,
+    editor: &Editor,
+    music_ids: Option<Vec<MusicID>>,
+    filter: Option<&Query>,
+    ops: Vec<BulkOp>,
+) -> Result<Vec<BulkResult>> {
+    let mut targets = music_ids.unwrap_or_default();
+    if let Some(filter) = filter {
+        let meta = fetch_metadata(c)?;
+        let tags = meta.tags.unwrap_or_default();
+        targets.extend(filter.filter(&meta.musics, &tags));
+    }
+    let mut seen = HashSet::with_capacity(targets.len());
+    targets.retain(|id| seen.insert(*id));
+
+    let schemas = TagSchema::list(c)?;
+    let mut results = Vec::with_capacity(targets.len() * ops.len());
+
+    for id in targets {
+        let exists = Music::exists(c, id)?;
+        for op in &ops {
+            let mut res = BulkResult {
+                music_id: id,
+                key: op.key.clone(),
+                status: StatusCode::OK.as_u16(),
+                error: None,
+            };
+            if !exists {
+                res.status = StatusCode::NOT_FOUND.as_u16();
+                results.push(res);
+                continue;
+            }
+            match op.op.as_str() {
+                "set" => {
+                    let tag = Tag {
+                        music_id: id,
+                        key: op.key.clone(),
+                        text: op.text.clone(),
+                        integer: op.integer,
+                        date: op.date.clone(),
+                        vector: None,
+                    };
+                    if let Err(violation) = editor.insert_user_tag(c, &schemas, tag)? {
+                        res.status = violation.status().as_u16();
+                        res.error = Some(violation.to_string());
+                    }
+                }
+                "remove" => {
+                    if !Tag::has(c, id, &op.key)? {
+                        res.status = StatusCode::NOT_FOUND.as_u16();
+                    } else if !TagSchema::find(&schemas, &op.key).is_some_and(|s| s.user_editable) {
+                        res.status = StatusCode::FORBIDDEN.as_u16();
+                        res.error = Some(format!("tag key is not user editable: {}", op.key));
+                    } else {
+                        editor.remove_tag(c, id, &op.key)?;
+                    }
+                }
+                x => {
+                    res.status = StatusCode::BAD_REQUEST.as_u16();
+                    res.error = Some(format!("unknown op: {}", x));
+                }
+            }
+            results.push(res);
+        }
+    }
+
+    Ok(results)
+}
@@ -1,45 +1,84 @@
+//! Deletes the musics that are in no library, then the files of the storage directory no tag
+//! points to. Musics go to the trash of who cleaned and keep their files until they are purged
+//! from it, files are moved to `.trash/<date>/` in the storage directory rather than deleted,
+//! and purged from there after a while.
+
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use chrono::{NaiveDateTime, Utc};
+use nanoserde::SerJson;
 use rusqlite::TransactionBehavior;
 
-use crate::domain::entity::{Music, MusicID};
+use crate::domain::entity::{MusicID, UserID};
+use crate::domain::trash;
 use crate::infrastructure::db::Db;
+use crate::utils::collect_rows;
 use std::collections::HashSet;
 
-pub async fn clean(db: &Db) -> Result<()> {
-    let mut c = db.get().await;
+pub const TRASH_DIR: &str = ".trash";
+const TRASH_DATE: &str = "%Y%m%d-%H%M%S";
+
+/// A new dated directory of the trash, relative to the storage directory
+pub fn new_trash_dir() -> PathBuf {
+    Path::new(TRASH_DIR).join(Utc::now().format(TRASH_DATE).to_string())
+}
 
-    {
-        let tx = c.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+#[derive(SerJson, Debug, Default)]
+pub struct CleanReport {
+    pub dry_run: bool,
+    pub musics: Vec<MusicID>,
+    pub files: Vec<String>,
+    /// size of the files
+    pub bytes: u64,
+    /// where the files went relative to the storage directory, None on dry run or if there were none
+    pub trash: Option<String>,
+    /// files that couldn't be moved to the trash and stayed where they were
+    pub errors: Vec<String>,
+}
 
-        for lone_music in tx
-            .prepare(
-                "
+/// With `dry_run` nothing is changed, the report says what would be
+pub async fn clean(db: &Db, storage: &Path, user: UserID, dry_run: bool) -> Result<CleanReport> {
+    let mut report = CleanReport {
+        dry_run,
+        ..Default::default()
+    };
+    let mut c = db.get().await;
+    let tx = c.transaction_with_behavior(match dry_run {
+        true => TransactionBehavior::Deferred,
+        false => TransactionBehavior::Exclusive,
+    })?;
+
+    let mut stmt = tx.prepare(
+        "
         SELECT id FROM musics
-        WHERE id NOT IN (
+        WHERE id NOT IN (SELECT music_id FROM trash)
+        AND id NOT IN (
         SELECT music_id FROM tags
         LEFT JOIN musics
         WHERE tags.music_id = musics.id
           AND tags.key LIKE 'user_library%'
           GROUP BY musics.id)",
-            )?
-            .query_map([], |x| x.get::<&str, i32>("id"))?
-        {
-            Music::delete(&tx, MusicID(lone_music?))?;
-        }
+    )?;
+    report.musics = collect_rows(stmt.query_map([], |x| x.get("id").map(MusicID))?)?;
+    drop(stmt);
 
-        tx.commit()?;
-    }
-    tokio::time::sleep(Duration::from_secs_f32(0.01)).await;
+    // trashed and merged musics keep their files in case they are restored or unmerged
+    let mut stmt = tx.prepare(
+        "SELECT text FROM tags WHERE text IS NOT NULL
+        UNION
+        SELECT merge_tags.text FROM merge_tags
+        INNER JOIN merges ON merges.id = merge_tags.merge_id
+        WHERE merge_tags.text IS NOT NULL AND merges.unmerged=0",
+    )?;
+    let texts = collect_rows(stmt.query_map([], |x| x.get::<_, String>("text"))?)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    drop(stmt);
 
-    let tx = c.transaction_with_behavior(TransactionBehavior::Exclusive)?;
-    let texts = tx
-        .prepare("SELECT text FROM tags WHERE text IS NOT NULL")?
-        .query_map([], |x| x.get::<&str, String>("text"))?
-        .collect::<std::result::Result<HashSet<_>, _>>()?;
-
-    for file in std::fs::read_dir("storage")? {
+    let mut files = vec![];
+    for file in std::fs::read_dir(storage)? {
         let file = unwrap_cont!(file.ok());
         let ftype = unwrap_cont!(file.file_type().ok());
         if !ftype.is_file() {
@@ -53,14 +92,70 @@ pub async fn clean(db: &Db) -> Result<()> {
         if texts.contains(&*name) {
             continue;
         }
-        log::info!(
-            "cleaning {:?}: {:?}",
-            name,
-            std::fs::remove_file(file.path())
-        );
+        report.bytes += file.metadata().map(|m| m.len()).unwrap_or(0);
+        report.files.push(name.to_string());
+        files.push(file.path());
     }
+    report.files.sort();
 
+    if dry_run {
+        return Ok(report);
+    }
+    for &id in &report.musics {
+        trash::trash(&tx, user, id)?;
+    }
     tx.commit()?;
+    drop(c);
+
+    // the files aren't referenced by anything, the database doesn't need to wait for them
+    if !files.is_empty() {
+        let trash = new_trash_dir();
+        std::fs::create_dir_all(storage.join(&trash)).context("could not create trash")?;
+        for path in files {
+            let to = storage.join(&trash).join(unwrap_cont!(path.file_name()));
+            log::info!("cleaning {:?}", path);
+            if let Err(e) = std::fs::rename(&path, to) {
+                report.errors.push(format!("{}: {}", path.display(), e));
+            }
+        }
+        report.trash = Some(trash.to_string_lossy().to_string());
+    }
+
+    Ok(report)
+}
 
-    Ok(())
+/// Deletes the cleans older than `max_age` from the trash, returns how many were
+pub fn purge_trash(storage: &Path, max_age: Duration) -> Result<usize> {
+    let entries = match std::fs::read_dir(storage.join(TRASH_DIR)) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let oldest = Utc::now().naive_utc() - chrono::Duration::from_std(max_age)?;
+    let mut purged = 0;
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let date =
+            unwrap_cont!(NaiveDateTime::parse_from_str(&name.to_string_lossy(), TRASH_DATE).ok());
+        if date < oldest {
+            std::fs::remove_dir_all(entry.path())?;
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+/// Purges the trash every hour
+pub fn start_trash_purge(storage: PathBuf, max_age: Duration) {
+    tokio::spawn(async move {
+        loop {
+            match purge_trash(&storage, max_age) {
+                Ok(0) => {}
+                Ok(n) => log::info!("purged {} cleans from the trash", n),
+                Err(e) => log::error!("error purging the trash: {:?}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+        }
+    });
 }
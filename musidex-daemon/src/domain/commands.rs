@@ -0,0 +1,174 @@
+use crate::domain::entity::{Music, MusicID, Tag, TagKey, TagSchema, UserID};
+use crate::domain::history::Editor;
+use crate::domain::music::MoveDirection;
+use crate::domain::upload;
+use crate::infrastructure::db::{db_log, Db, DbLog, LogAction, LogType};
+use anyhow::{Context, Result};
+use hyper::StatusCode;
+use nanoserde::{DeJson, SerJson};
+
+/// Command sent by a client as a text message over the sync websocket, e.g.
+/// `{"id": 3, "cmd": "tag_delete", "music_id": 12, "key": "title"}`.
+/// The other fields of the message are the body of the command.
+#[derive(DeJson)]
+pub struct Command {
+    /// chosen by the client, echoed back in the ack
+    pub id: u64,
+    pub cmd: String,
+}
+
+/// Reply to a command, sent as a text message. Sync updates are always binary messages.
+#[derive(SerJson, Debug, PartialEq)]
+pub struct Ack {
+    pub id: u64,
+    pub status: u16,
+    pub error: Option<String>,
+}
+
+#[derive(DeJson)]
+struct TagDelete {
+    music_id: MusicID,
+    key: TagKey,
+}
+
+#[derive(DeJson)]
+struct Move {
+    library: String,
+    id_base: MusicID,
+    id_to_move: MusicID,
+    /// "above" or "below"
+    direction: String,
+}
+
+#[derive(DeJson)]
+struct Listen {
+    music_id: MusicID,
+}
+
+#[derive(DeJson)]
+struct YoutubeUpload {
+    url: String,
+}
+
+impl Ack {
+    pub fn new(id: u64, status: StatusCode) -> Ack {
+        Ack {
+            id,
+            status: status.as_u16(),
+            error: None,
+        }
+    }
+
+    pub fn error(id: u64, status: StatusCode, error: impl ToString) -> Ack {
+        Ack {
+            id,
+            status: status.as_u16(),
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+/// Parses the command header, None if the message isn't a command at all
+pub fn parse(msg: &str) -> Option<Command> {
+    Command::deserialize_json(msg).ok()
+}
+
+/// Runs a command received from a client, errors are reported in the ack
+pub async fn run(db: &Db, editor: &Editor, cmd: &Command, msg: &str) -> Ack {
+    match run_inner(db, editor, cmd, msg).await {
+        Ok(ack) => ack,
+        Err(e) => {
+            log::error!("error running command {}: {:?}", cmd.cmd, e);
+            Ack::error(cmd.id, StatusCode::INTERNAL_SERVER_ERROR, e)
+        }
+    }
+}
+
+async fn run_inner(db: &Db, editor: &Editor, cmd: &Command, msg: &str) -> Result<Ack> {
+    let id = cmd.id;
+    let ok = Ack::new(id, StatusCode::OK);
+    macro_rules! body {
+        ($t: ty) => {
+            match <$t>::deserialize_json(msg) {
+                Ok(x) => x,
+                Err(e) => return Ok(Ack::error(id, StatusCode::BAD_REQUEST, e)),
+            }
+        };
+    }
+
+    match cmd.cmd.as_str() {
+        "tag_create" => {
+            let tag = body!(Tag);
+            let mut c = db.get().await;
+            let tx = c.transaction().context("transaction begin failed")?;
+            if let Err(violation) = editor.insert_user_tag(&tx, &TagSchema::list(&tx)?, tag)? {
+                return Ok(Ack::error(id, violation.status(), violation));
+            }
+            tx.commit()?;
+            Ok(ok)
+        }
+        "tag_delete" => {
+            let b = body!(TagDelete);
+            if editor.user_id == UserID(-1) {
+                return Ok(Ack::error(id, StatusCode::BAD_REQUEST, "no user id"));
+            }
+            let mut c = db.get().await;
+            let tx = c.transaction().context("transaction begin failed")?;
+            editor.remove_tag(&tx, b.music_id, &b.key)?;
+            tx.commit()?;
+            Ok(ok)
+        }
+        "move" => {
+            let b = body!(Move);
+            let direction = match MoveDirection::parse(&b.direction) {
+                Some(x) => x,
+                None => return Ok(Ack::error(id, StatusCode::BAD_REQUEST, "invalid direction")),
+            };
+            let key = TagKey::UserLibrary(b.library);
+            let mut c = db.get().await;
+            if !Music::move_(&mut c, editor, &key, b.id_base, b.id_to_move, direction)? {
+                return Ok(Ack::new(id, StatusCode::NOT_FOUND));
+            }
+            Ok(ok)
+        }
+        "listen" => {
+            let b = body!(Listen);
+            let c = db.get().await;
+            if !Music::exists(&c, b.music_id)? {
+                return Ok(Ack::new(id, StatusCode::NOT_FOUND));
+            }
+            db_log(
+                &c,
+                DbLog {
+                    user_id: editor.user_id,
+                    ip: editor.ip.clone(),
+                    type_: LogType::Listen,
+                    action: LogAction::Create,
+                    music_id: Some(b.music_id),
+                    target_key: None,
+                    target_value: None,
+                    before: None,
+                    after: None,
+                },
+            );
+            Ok(ok)
+        }
+        "youtube_upload" => {
+            let b = body!(YoutubeUpload);
+            if b.url.len() < 3 {
+                return Ok(Ack::new(id, StatusCode::BAD_REQUEST));
+            }
+            if editor.user_id == UserID(-1) {
+                return Ok(Ack::error(id, StatusCode::BAD_REQUEST, "no user id"));
+            }
+            let mut c = db.get().await;
+            let status = upload::youtube_upload(&mut c, editor, b.url).await?;
+            Ok(Ack::new(id, status))
+        }
+        x => Ok(Ack::error(
+            id,
+            StatusCode::BAD_REQUEST,
+            format!("unknown command: {}", x),
+        )),
+    }
+}
@@ -29,7 +29,7 @@ pub fn update(c: &Connection, key: &str, value: &str) -> Result<()> {
     Ok(())
 }
 
-fn insert_if_not_exist(c: &Connection, key: &str, value: &str) -> Result<()> {
+pub fn insert_if_not_exist(c: &Connection, key: &str, value: &str) -> Result<()> {
     c.prepare_cached(
         "INSERT INTO config (key, value)
                     VALUES (?1, ?2)
@@ -46,7 +46,18 @@ pub fn get_all(c: &Connection) -> Result<Vec<(String, String)>> {
     collect_rows(v)
 }
 
-#[allow(dead_code)]
+/// Keys that are only used by the daemon and never sent to clients
+pub fn is_secret(key: &str) -> bool {
+    key.starts_with("secret_")
+}
+
+/// All the config that can be sent to clients
+pub fn get_public(c: &Connection) -> Result<Vec<(String, String)>> {
+    let mut all = get_all(c)?;
+    all.retain(|(k, _)| !is_secret(k));
+    Ok(all)
+}
+
 pub fn get(c: &Connection, key: &str) -> Result<Option<String>> {
     let v = c
         .prepare_cached("SELECT value FROM config WHERE key= ?1")?
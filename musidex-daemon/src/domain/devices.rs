@@ -0,0 +1,306 @@
+//! Connect mode: the devices of a user register over the sync websocket, share what they play,
+//! and control each other through the server.
+
+use crate::domain::commands::{Ack, Command};
+use crate::domain::entity::{Music, MusicID, User, UserID};
+use crate::domain::history::Editor;
+use crate::domain::party::server_time;
+use crate::infrastructure::db::Db;
+use hyper::StatusCode;
+use nanoserde::{DeJson, SerJson};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+struct Device {
+    user: UserID,
+    name: String,
+    track: Option<MusicID>,
+    playing: bool,
+    /// seconds into the track at server time `at`
+    position: f64,
+    at: f64,
+    volume: u8,
+    /// messages relayed to the websocket of the device
+    tx: mpsc::UnboundedSender<String>,
+}
+
+impl Device {
+    fn position_at(&self, now: f64) -> f64 {
+        match self.playing {
+            true => self.position + (now - self.at) / 1000.0,
+            false => self.position,
+        }
+    }
+}
+
+#[derive(SerJson, Debug, PartialEq)]
+pub struct DeviceInfo {
+    pub id: u64,
+    pub name: String,
+    pub track: Option<i32>,
+    pub playing: bool,
+    pub position: f64,
+    pub volume: u8,
+}
+
+/// Sent to the devices of a user when one of them changes
+#[derive(SerJson)]
+struct DeviceList {
+    #[nserde(rename = "type")]
+    kind: String,
+    devices: Vec<DeviceInfo>,
+}
+
+/// A command sent by another device, relayed as `{"type": "device_control", ...}`
+#[derive(SerJson, DeJson, Debug, PartialEq)]
+pub struct RelayedControl {
+    #[nserde(rename = "type")]
+    pub kind: String,
+    /// device that sent it
+    pub from: u64,
+    pub action: String,
+    pub music_id: Option<i32>,
+    pub position: Option<f64>,
+    pub volume: Option<u8>,
+}
+
+#[derive(DeJson)]
+struct Register {
+    name: String,
+}
+
+#[derive(DeJson)]
+struct State {
+    track: Option<MusicID>,
+    playing: bool,
+    position: f64,
+    volume: u8,
+}
+
+#[derive(DeJson)]
+struct Control {
+    target: u64,
+    /// play, pause, next, volume or transfer
+    action: String,
+    music_id: Option<MusicID>,
+    position: Option<f64>,
+    volume: Option<u8>,
+    /// transfer: the device whose playback moves to the target, the sender by default
+    source: Option<u64>,
+}
+
+/// Devices connected right now, they are forgotten when disconnecting
+#[derive(Clone, Default)]
+pub struct Devices {
+    devices: Arc<Mutex<BTreeMap<u64, Device>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+/// The device a websocket client registered as, unregistered when dropped
+pub struct Registration {
+    pub id: u64,
+    pub user: UserID,
+    pub rx: mpsc::UnboundedReceiver<String>,
+    devices: Devices,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut devices = self.devices.devices.lock().unwrap();
+        devices.remove(&self.id);
+        notify_list(&devices, self.user);
+    }
+}
+
+fn list(devices: &BTreeMap<u64, Device>, user: UserID) -> Vec<DeviceInfo> {
+    let now = server_time();
+    devices
+        .iter()
+        .filter(|(_, d)| d.user == user)
+        .map(|(&id, d)| DeviceInfo {
+            id,
+            name: d.name.clone(),
+            track: d.track.map(|x| x.0),
+            playing: d.playing,
+            position: d.position_at(now),
+            volume: d.volume,
+        })
+        .collect()
+}
+
+fn notify_list(devices: &BTreeMap<u64, Device>, user: UserID) {
+    let msg = DeviceList {
+        kind: s!("devices"),
+        devices: list(devices, user),
+    }
+    .serialize_json();
+    for d in devices.values().filter(|d| d.user == user) {
+        let _ = d.tx.send(msg.clone());
+    }
+}
+
+impl Devices {
+    pub fn register(&self, user: UserID, name: String) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut devices = self.devices.lock().unwrap();
+        devices.insert(
+            id,
+            Device {
+                user,
+                name,
+                track: None,
+                playing: false,
+                position: 0.0,
+                at: server_time(),
+                volume: 100,
+                tx,
+            },
+        );
+        notify_list(&devices, user);
+        Registration {
+            id,
+            user,
+            rx,
+            devices: self.clone(),
+        }
+    }
+
+    pub fn list(&self, user: UserID) -> Vec<DeviceInfo> {
+        list(&self.devices.lock().unwrap(), user)
+    }
+
+    fn set_state(&self, id: u64, state: State) {
+        let mut devices = self.devices.lock().unwrap();
+        let d = unwrap_ret!(devices.get_mut(&id));
+        d.track = state.track;
+        d.playing = state.playing;
+        d.position = state.position.max(0.0);
+        d.at = server_time();
+        d.volume = state.volume.min(100);
+        let user = d.user;
+        notify_list(&devices, user);
+    }
+
+    fn relay(&self, from: &Registration, c: &Control) -> StatusCode {
+        let devices = self.devices.lock().unwrap();
+        let mine = |id: u64| devices.get(&id).filter(|d| d.user == from.user);
+        let target = unwrap_ret!(mine(c.target), StatusCode::NOT_FOUND);
+        let msg = |action: &str| RelayedControl {
+            kind: s!("device_control"),
+            from: from.id,
+            action: action.to_string(),
+            music_id: None,
+            position: None,
+            volume: None,
+        };
+
+        let relayed = match c.action.as_str() {
+            "pause" | "next" => msg(&c.action),
+            "play" => RelayedControl {
+                music_id: c.music_id.map(|x| x.0),
+                position: c.position,
+                ..msg("play")
+            },
+            "volume" => RelayedControl {
+                volume: Some(unwrap_ret!(c.volume, StatusCode::BAD_REQUEST).min(100)),
+                ..msg("volume")
+            },
+            "transfer" => {
+                let source = unwrap_ret!(mine(c.source.unwrap_or(from.id)), StatusCode::NOT_FOUND);
+                let track = unwrap_ret!(source.track, StatusCode::BAD_REQUEST);
+                let _ = source.tx.send(msg("pause").serialize_json());
+                RelayedControl {
+                    music_id: Some(track.0),
+                    position: Some(source.position_at(server_time())),
+                    volume: Some(source.volume),
+                    ..msg("play")
+                }
+            }
+            _ => return StatusCode::BAD_REQUEST,
+        };
+        let _ = target.tx.send(relayed.serialize_json());
+        StatusCode::OK
+    }
+}
+
+pub fn is_device_command(cmd: &Command) -> bool {
+    cmd.cmd.starts_with("device_")
+}
+
+/// Runs a device command of a websocket client.
+/// `device_register {name}` makes the connection a device of its user, which then reports
+/// what it plays with `device_state {track, playing, position, volume}` and controls the other
+/// devices of the user with `device_control {target, action, ...}`.
+/// Registered devices receive the device list whenever it changes and the relayed controls.
+pub async fn run(
+    db: &Db,
+    devices: &Devices,
+    registration: &mut Option<Registration>,
+    editor: &Editor,
+    cmd: &Command,
+    msg: &str,
+) -> Ack {
+    let id = cmd.id;
+    macro_rules! body {
+        ($t: ty) => {
+            match <$t>::deserialize_json(msg) {
+                Ok(x) => x,
+                Err(e) => return Ack::error(id, StatusCode::BAD_REQUEST, e),
+            }
+        };
+    }
+    macro_rules! registered {
+        () => {
+            match registration {
+                Some(r) => r,
+                None => return Ack::error(id, StatusCode::BAD_REQUEST, "not a registered device"),
+            }
+        };
+    }
+
+    match cmd.cmd.as_str() {
+        "device_register" => {
+            let b = body!(Register);
+            // the device belongs to the user of the connection, never to one it claims to be
+            let user = editor.user_id;
+            if user == UserID(-1) {
+                return Ack::error(id, StatusCode::BAD_REQUEST, "no user id");
+            }
+            let exists = User::list(&*db.get().await).map(|l| l.iter().any(|u| u.id == user));
+            match exists {
+                Ok(true) => {}
+                Ok(false) => return Ack::error(id, StatusCode::NOT_FOUND, "no such user"),
+                Err(e) => return Ack::error(id, StatusCode::INTERNAL_SERVER_ERROR, e),
+            }
+            *registration = None;
+            *registration = Some(devices.register(user, b.name));
+            Ack::new(id, StatusCode::OK)
+        }
+        "device_state" => {
+            let b = body!(State);
+            let r = registered!();
+            devices.set_state(r.id, b);
+            Ack::new(id, StatusCode::OK)
+        }
+        "device_control" => {
+            let b = body!(Control);
+            let r = registered!();
+            if let Some(music_id) = b.music_id {
+                match Music::exists(&*db.get().await, music_id) {
+                    Ok(true) => {}
+                    Ok(false) => return Ack::new(id, StatusCode::NOT_FOUND),
+                    Err(e) => return Ack::error(id, StatusCode::INTERNAL_SERVER_ERROR, e),
+                }
+            }
+            Ack::new(id, devices.relay(r, &b))
+        }
+        x => Ack::error(
+            id,
+            StatusCode::BAD_REQUEST,
+            format!("unknown command: {}", x),
+        ),
+    }
+}
@@ -0,0 +1,144 @@
+use crate::domain::entity::{MusidexMetadata, Tag, TagKey};
+use crate::domain::sync::compress_meta;
+use crate::infrastructure::cbor::CborWriter;
+use std::collections::HashMap;
+
+/// How the metadata is sent to clients, negotiated with ?encoding=
+/// Clients that don't ask for anything get deflated JSON.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyncEncoding {
+    Json,
+    /// deflated CBOR, see encode_cbor_v1
+    CborV1,
+}
+
+impl SyncEncoding {
+    /// None if the encoding is unknown, for example a newer version than ours
+    pub fn parse(v: Option<&str>) -> Option<SyncEncoding> {
+        match v {
+            None | Some("json") => Some(SyncEncoding::Json),
+            Some("cbor1") => Some(SyncEncoding::CborV1),
+            _ => None,
+        }
+    }
+
+    pub fn encode(self, m: &MusidexMetadata) -> Vec<u8> {
+        match self {
+            SyncEncoding::Json => compress_meta(m),
+            SyncEncoding::CborV1 => miniz_oxide::deflate::compress_to_vec(&encode_cbor_v1(m), 5),
+        }
+    }
+}
+
+/// Encodes the metadata as a CBOR map:
+///
+/// - `v`: 1
+/// - `seq`, `musics`: same as JSON
+/// - `keys`: every tag key used below, tags refer to them by index
+/// - `tags`, `patches`: null or arrays of `[music_id, key index, text, integer, date, vector]`,
+///   patches are prefixed by their kind. Vectors are RFC 8746 float32 little endian typed arrays.
+/// - `users`: `[id, name]` arrays, `settings`: `[key, value]` arrays
+/// - `schema`: `[key, value_type, user_editable, synced, display_name]` arrays
+pub fn encode_cbor_v1(m: &MusidexMetadata) -> Vec<u8> {
+    let mut keys: HashMap<&TagKey, usize> = HashMap::new();
+    let mut key_list: Vec<&TagKey> = vec![];
+    let all_tags = m
+        .tags
+        .iter()
+        .flatten()
+        .chain(m.patches.iter().flatten().map(|p| &p.tag));
+    for tag in all_tags {
+        keys.entry(&tag.key).or_insert_with(|| {
+            key_list.push(&tag.key);
+            key_list.len() - 1
+        });
+    }
+
+    let write_tag = |w: &mut CborWriter, tag: &Tag| {
+        w.int(tag.music_id.0 as i64);
+        w.int(keys[&tag.key] as i64);
+        w.opt_text(tag.text.as_deref());
+        w.opt_int(tag.integer);
+        w.opt_text(tag.date.as_deref());
+        match tag.vector {
+            Some(ref v) => w.f32_array(&v.0),
+            None => w.null(),
+        }
+    };
+
+    let mut w = CborWriter::default();
+    w.map(9);
+
+    w.text("v");
+    w.int(1);
+
+    w.text("seq");
+    w.int(m.seq);
+
+    w.text("musics");
+    w.array(m.musics.len());
+    for id in &m.musics {
+        w.int(id.0 as i64);
+    }
+
+    w.text("keys");
+    w.array(key_list.len());
+    for key in key_list {
+        let key: String = key.into();
+        w.text(&key);
+    }
+
+    w.text("tags");
+    match m.tags {
+        Some(ref tags) => {
+            w.array(tags.len());
+            for tag in tags {
+                w.array(6);
+                write_tag(&mut w, tag);
+            }
+        }
+        None => w.null(),
+    }
+
+    w.text("patches");
+    match m.patches {
+        Some(ref patches) => {
+            w.array(patches.len());
+            for patch in patches {
+                w.array(7);
+                w.text(&patch.kind);
+                write_tag(&mut w, &patch.tag);
+            }
+        }
+        None => w.null(),
+    }
+
+    w.text("users");
+    w.array(m.users.len());
+    for user in &m.users {
+        w.array(2);
+        w.int(user.id.0 as i64);
+        w.text(&user.name);
+    }
+
+    w.text("settings");
+    w.array(m.settings.len());
+    for (k, v) in &m.settings {
+        w.array(2);
+        w.text(k);
+        w.text(v);
+    }
+
+    w.text("schema");
+    w.array(m.schema.len());
+    for s in &m.schema {
+        w.array(5);
+        w.text(&s.key);
+        w.text(s.value_type.as_str());
+        w.bool(s.user_editable);
+        w.bool(s.synced);
+        w.text(&s.display_name);
+    }
+
+    w.buf
+}
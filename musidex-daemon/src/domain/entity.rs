@@ -8,7 +8,7 @@ use rusqlite::types::ToSqlOutput;
 use rusqlite::{Row, ToSql};
 use std::str::Chars;
 
-#[derive(Copy, Clone, Hash, PartialEq, Eq, SerJson, DeJson, Debug)]
+#[derive(Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, SerJson, DeJson, Debug)]
 #[nserde(transparent)]
 pub struct MusicID(pub i32);
 
@@ -27,6 +27,20 @@ pub struct Music {
     pub id: MusicID,
 }
 
+/// Record of merged_id being merged into into_id, keeping the tags merged_id had
+#[derive(Clone, Debug, PartialEq, SerJson)]
+pub struct MusicMerge {
+    pub id: i64,
+    pub timestamp: String,
+    pub into_id: MusicID,
+    pub merged_id: MusicID,
+    pub unmerged: bool,
+    /// tags that were moved to into_id
+    pub moved: Vec<Tag>,
+    /// tags that were dropped since into_id already had the key
+    pub discarded: Vec<Tag>,
+}
+
 #[derive(Clone, Debug, Hash, Eq, PartialEq, SerJson, DeJson)]
 pub struct Tag {
     pub music_id: MusicID,
@@ -52,13 +66,35 @@ pub struct Patch {
     pub tag: Tag,
 }
 
-#[derive(SerJson, Hash, Default)]
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum TagValueType {
+    None,
+    Text,
+    Integer,
+    Date,
+    Vector,
+}
+
+/// Declares how the tags with a given key (or nested prefix ending with ':') look like
+#[derive(Clone, Debug, Hash, PartialEq, Eq, SerJson)]
+pub struct TagSchema {
+    pub key: String,
+    pub value_type: TagValueType,
+    pub user_editable: bool,
+    pub synced: bool,
+    pub display_name: String,
+}
+
+#[derive(Clone, SerJson, Hash, Default)]
 pub struct MusidexMetadata {
     pub musics: Vec<MusicID>,
     pub tags: Option<Vec<Tag>>,
     pub users: Vec<User>,
     pub settings: Vec<(String, String)>,
+    pub schema: Vec<TagSchema>,
     pub patches: Option<Vec<Patch>>,
+    /// changelog position of this state, to ask for what changed since
+    pub seq: i64,
 }
 
 impl Eq for Vector {}
@@ -207,6 +243,48 @@ impl<'a, 'b> From<&'a Row<'b>> for Tag {
     }
 }
 
+impl<'a, 'b> From<&'a Row<'b>> for TagSchema {
+    fn from(row: &'a Row<'b>) -> Self {
+        Self {
+            key: row.get_unwrap("key"),
+            value_type: row.get_unwrap::<_, String>("value_type").deref().into(),
+            user_editable: row.get_unwrap("user_editable"),
+            synced: row.get_unwrap("synced"),
+            display_name: row.get_unwrap("display_name"),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for TagValueType {
+    fn from(v: &'a str) -> TagValueType {
+        match v {
+            "text" => TagValueType::Text,
+            "integer" => TagValueType::Integer,
+            "date" => TagValueType::Date,
+            "vector" => TagValueType::Vector,
+            _ => TagValueType::None,
+        }
+    }
+}
+
+impl TagValueType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TagValueType::None => "none",
+            TagValueType::Text => "text",
+            TagValueType::Integer => "integer",
+            TagValueType::Date => "date",
+            TagValueType::Vector => "vector",
+        }
+    }
+}
+
+impl SerJson for TagValueType {
+    fn ser_json(&self, d: usize, st: &mut SerJsonState) {
+        s!(self.as_str()).ser_json(d, st);
+    }
+}
+
 impl SerJson for TagKey {
     fn ser_json(&self, d: usize, st: &mut SerJsonState) {
         let s: String = self.into();
@@ -0,0 +1,158 @@
+//! RSS 2.0 podcast feeds of a library or playlist, for listening through podcast apps.
+//! Apps can't send headers, so feeds are authenticated by a per user token in their url.
+
+use crate::domain::entity::{Tag, TagKey, UserID};
+use crate::domain::library::Song;
+use crate::domain::stream::content_type;
+use crate::infrastructure::xml;
+use crate::utils::{constant_time_eq, row_missing_opt};
+use anyhow::Result;
+use chrono::{TimeZone, Utc};
+use rusqlite::Connection;
+use std::fmt::Write;
+use std::path::Path;
+
+/// Podcast apps sort episodes by date, musics have none so the library order is used
+/// as seconds after this date (2000-01-01), keeping the top of the library as the latest episode
+const FEED_EPOCH: i64 = 946_684_800;
+
+pub struct FeedEntry {
+    pub song: Song,
+    /// user_library integer
+    pub order: i64,
+    /// size of the audio file in bytes, enclosures need one
+    pub size: u64,
+    pub thumbnail: Option<String>,
+}
+
+pub fn token(c: &Connection, user: UserID) -> Result<Option<String>> {
+    let mut stmt = c.prepare_cached("SELECT token FROM feed_tokens WHERE user_id=?1;")?;
+    Ok(row_missing_opt(stmt.query_row([user.0], |row| row.get(0)))?)
+}
+
+/// Replaces the token of the user, feed urls with the old one stop working
+pub fn new_token(c: &Connection, user: UserID) -> Result<String> {
+    // 128 bits from the OS random source, the token is all that protects the feed
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("no random source: {}", e))?;
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    c.prepare_cached(
+        "INSERT INTO feed_tokens (user_id, token) VALUES (?1, ?2)
+        ON CONFLICT (user_id) DO UPDATE SET token=?2;",
+    )?
+    .execute(rusqlite::params![user.0, token])?;
+    Ok(token)
+}
+
+pub fn check_token(c: &Connection, user: UserID, given: Option<&str>) -> Result<bool> {
+    Ok(match (token(c, user)?, given) {
+        (Some(expected), Some(given)) => constant_time_eq(expected.as_bytes(), given.as_bytes()),
+        _ => false,
+    })
+}
+
+/// Playable musics of the user library, top first, only the ones with this user_tag if any
+pub fn entries(
+    c: &Connection,
+    storage: &Path,
+    user: UserID,
+    playlist: Option<&str>,
+) -> Result<Vec<FeedEntry>> {
+    let mut library = Tag::by_key_visible(c, &TagKey::UserLibrary(user.0.to_string()))?;
+    library.sort_by_key(|t| (-t.integer.unwrap_or(0), t.music_id.0));
+
+    let mut entries = vec![];
+    for tag in library {
+        let song = Song::load(c, tag.music_id)?;
+        let path = unwrap_cont!(song.path.as_ref());
+        if playlist.is_some_and(|p| !song.playlists.iter().any(|x| x == p)) {
+            continue;
+        }
+        let size = std::fs::metadata(storage.join(path))
+            .map(|m| m.len())
+            .unwrap_or(0);
+        let thumbnail = Tag::by_id_key(c, tag.music_id, &TagKey::Thumbnail)?.and_then(|t| t.text);
+        entries.push(FeedEntry {
+            song,
+            order: tag.integer.unwrap_or(0),
+            size,
+            thumbnail,
+        });
+    }
+    Ok(entries)
+}
+
+/// HH:MM:SS
+fn itunes_duration(secs: i64) -> String {
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn pub_date(order: i64) -> String {
+    Utc.timestamp_opt(FEED_EPOCH.saturating_add(order), 0)
+        .single()
+        .unwrap_or_default()
+        .to_rfc2822()
+}
+
+/// The feed document, `base` is the url of the server without trailing slash
+pub fn render(title: &str, base: &str, entries: &[FeedEntry]) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+        <rss version=\"2.0\" xmlns:itunes=\"http://www.itunes.com/dtds/podcast-1.0.dtd\">\
+        <channel>",
+    );
+    let title = xml::escape(title);
+    let _ = write!(
+        out,
+        "<title>{}</title><link>{}/</link><description>{}</description>\
+        <language>en</language><itunes:author>musidex</itunes:author>\
+        <itunes:explicit>false</itunes:explicit>",
+        title, base, title
+    );
+    if let Some(cover) = entries.iter().find_map(|e| e.thumbnail.as_ref()) {
+        let _ = write!(
+            out,
+            "<itunes:image href=\"{}/storage/{}\"/>",
+            base,
+            xml::escape(cover)
+        );
+    }
+
+    for e in entries {
+        let song = &e.song;
+        let path = song.path.as_deref().unwrap_or_default();
+        let _ = write!(
+            out,
+            "<item><title>{}</title><itunes:author>{}</itunes:author>\
+            <guid isPermaLink=\"false\">musidex-{}</guid><pubDate>{}</pubDate>\
+            <enclosure url=\"{}/api/stream/{}\" length=\"{}\" type=\"{}\"/>",
+            xml::escape(&song.title),
+            xml::escape(&song.artist),
+            song.id.0,
+            pub_date(e.order),
+            base,
+            song.id.0,
+            e.size,
+            content_type(path),
+        );
+        if let Some(ref thumbnail) = e.thumbnail {
+            let _ = write!(
+                out,
+                "<itunes:image href=\"{}/storage/{}\"/>",
+                base,
+                xml::escape(thumbnail)
+            );
+        }
+        if let Some(d) = song.duration {
+            let _ = write!(
+                out,
+                "<itunes:duration>{}</itunes:duration>",
+                itunes_duration(d)
+            );
+        }
+        out.push_str("</item>");
+    }
+
+    out.push_str("</channel></rss>");
+    out
+}
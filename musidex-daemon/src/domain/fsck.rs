@@ -0,0 +1,319 @@
+//! Checks that the tags agree with the storage directory, the reverse of what clean does:
+//! tags pointing at missing files, treated downloads without audio, and audio files that
+//! don't decode or are shorter than their duration tag, like truncated downloads.
+//! Repairing drops the dangling tags and queues the musics coming from YouTube again, their
+//! broken files go to the trash like the ones clean removes.
+//! Probing every file takes a while, so the API runs it in the background, see FsckRuns.
+
+use crate::domain::clean::new_trash_dir;
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::domain::jobs::{self, JobKind};
+use crate::infrastructure::db::Db;
+use crate::utils::collect_rows;
+use anyhow::{Context, Result};
+use nanoserde::SerJson;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+const AUDIO_KEYS: [TagKey; 4] = [
+    TagKey::LocalMP3,
+    TagKey::LocalOGG,
+    TagKey::LocalM4A,
+    TagKey::LocalWEBM,
+];
+const THUMBNAIL_KEYS: [TagKey; 2] = [TagKey::Thumbnail, TagKey::CompressedThumbnail];
+
+pub const MISSING_FILE: &str = "missing_file";
+pub const NO_AUDIO: &str = "no_audio";
+pub const UNDECODABLE: &str = "undecodable";
+pub const DURATION_MISMATCH: &str = "duration_mismatch";
+
+pub struct FsckOptions {
+    pub repair: bool,
+    /// probes the audio files with this ffprobe compatible command, None to skip it
+    pub probe: Option<String>,
+}
+
+#[derive(SerJson, Debug, Clone, PartialEq)]
+pub struct Issue {
+    pub music_id: MusicID,
+    /// one of the constants of this module
+    pub kind: String,
+    pub key: Option<TagKey>,
+    pub path: Option<String>,
+    pub detail: Option<String>,
+    /// what was done about it, None if nothing was
+    pub repair: Option<String>,
+}
+
+#[derive(SerJson, Debug, Clone, Default)]
+pub struct FsckReport {
+    pub musics: usize,
+    pub files: usize,
+    /// false if the probe was skipped or isn't installed, then only the presence of files is checked
+    pub probed: bool,
+    pub issues: Vec<Issue>,
+    /// where the broken files went relative to the storage directory, None if there were none
+    pub trash: Option<String>,
+    /// files that couldn't be moved to the trash, their tags are dropped anyway
+    pub errors: Vec<String>,
+}
+
+fn issue(music_id: MusicID, kind: &str, tag: Option<&Tag>) -> Issue {
+    Issue {
+        music_id,
+        kind: kind.to_string(),
+        key: tag.map(|t| t.key.clone()),
+        path: tag.and_then(|t| t.text.clone()),
+        detail: None,
+        repair: None,
+    }
+}
+
+/// Duration in seconds, None if the file doesn't decode. Err if the probe can't be run.
+fn probe(cmd: &str, file: &Path) -> std::io::Result<Option<f64>> {
+    let out = Command::new(cmd)
+        .args(["-v", "error", "-show_entries", "format=duration"])
+        .args(["-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(file)
+        .output()?;
+    if !out.status.success() {
+        return Ok(None);
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().parse().ok())
+}
+
+/// Durations can be off by a bit depending on the container, truncated files are off by more
+fn duration_matches(probed: f64, tagged: f64) -> bool {
+    (probed - tagged).abs() <= (tagged * 0.05).max(3.0)
+}
+
+fn is_safe(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+}
+
+pub async fn fsck(db: &Db, storage: &Path, opts: FsckOptions) -> Result<FsckReport> {
+    let mut musics: BTreeMap<MusicID, Vec<Tag>> = BTreeMap::new();
+    // musics whose download is queued aren't missing their audio
+    let downloading: HashSet<MusicID>;
+    {
+        let c = db.get().await;
+        let mut stmt = c.prepare_cached("SELECT id FROM musics;")?;
+        for id in collect_rows(stmt.query_map([], |row| Ok(MusicID(row.get(0)?)))?)? {
+            musics.insert(id, vec![]);
+        }
+        let mut stmt = c.prepare_cached("SELECT * FROM tags;")?;
+        for tag in collect_rows(stmt.query_map([], |row| Ok(Tag::from(row)))?)? {
+            musics.entry(tag.music_id).or_default().push(tag);
+        }
+        let mut stmt = c.prepare_cached(
+            "SELECT music_id FROM jobs WHERE kind=?1 AND status IN ('queued', 'running');",
+        )?;
+        let v = stmt.query_map([JobKind::YoutubeDL.as_str()], |row| row.get(0).map(MusicID))?;
+        downloading = collect_rows(v)?.into_iter().collect();
+    }
+
+    let mut report = FsckReport {
+        musics: musics.len(),
+        probed: opts.probe.is_some(),
+        ..Default::default()
+    };
+    // tags to drop, files to move to the trash and musics to download again
+    let mut drop_tags = vec![];
+    let mut trash_files = vec![];
+    let mut requeue = vec![];
+
+    for (&id, tags) in &musics {
+        let find = |key: &TagKey| tags.iter().find(|t| &t.key == key);
+        let url = find(&TagKey::YoutubeDLURL).is_some();
+        let mut issues = vec![];
+        let mut has_audio = false;
+
+        for tag in tags {
+            let is_audio = AUDIO_KEYS.contains(&tag.key);
+            if !is_audio && !THUMBNAIL_KEYS.contains(&tag.key) {
+                continue;
+            }
+            let path = unwrap_cont!(tag.text.as_deref());
+            let full = storage.join(path);
+            if !is_safe(path) || !full.is_file() {
+                issues.push(issue(id, MISSING_FILE, Some(tag)));
+                drop_tags.push((id, tag.key.clone()));
+                continue;
+            }
+            report.files += 1;
+            if !is_audio {
+                continue;
+            }
+
+            let probe_cmd = match opts.probe {
+                Some(ref cmd) if report.probed => cmd.clone(),
+                _ => {
+                    has_audio = true;
+                    continue;
+                }
+            };
+            let probed = {
+                let full = full.clone();
+                tokio::task::spawn_blocking(move || probe(&probe_cmd, &full)).await?
+            };
+            let tagged = find(&TagKey::Duration).and_then(|t| t.integer);
+            let bad = match (probed, tagged) {
+                (Err(e), _) => {
+                    log::warn!("not probing audio files, can't run the probe: {}", e);
+                    report.probed = false;
+                    None
+                }
+                (Ok(None), _) => Some(issue(id, UNDECODABLE, Some(tag))),
+                (Ok(Some(d)), Some(t)) if !duration_matches(d, t as f64) => {
+                    let mut i = issue(id, DURATION_MISMATCH, Some(tag));
+                    i.detail = Some(format!("file lasts {:.1}s but the tag says {}s", d, t));
+                    Some(i)
+                }
+                _ => None,
+            };
+            match bad {
+                // a broken file can only be replaced by downloading it again
+                Some(i) if url => {
+                    issues.push(i);
+                    drop_tags.push((id, tag.key.clone()));
+                    trash_files.push(full);
+                }
+                Some(i) => {
+                    issues.push(i);
+                    has_audio = true;
+                }
+                None => has_audio = true,
+            }
+        }
+
+        // musics that lost their audio above were already reported
+        let treated = find(&TagKey::YoutubeDLWorkerTreated).and_then(|t| t.text.as_deref());
+        let downloaded = treated == Some("true") && !downloading.contains(&id);
+        let lost_audio = !has_audio && tags.iter().any(|t| AUDIO_KEYS.contains(&t.key));
+        if downloaded && !has_audio && !lost_audio {
+            issues.push(issue(id, NO_AUDIO, None));
+        }
+        if url && !has_audio && (lost_audio || downloaded) {
+            requeue.push(id);
+        }
+        report.issues.extend(issues);
+    }
+
+    if !opts.repair {
+        return Ok(report);
+    }
+    {
+        let mut c = db.get().await;
+        let tx = c.transaction()?;
+        for (id, key) in &drop_tags {
+            Tag::remove(&tx, *id, key)?;
+        }
+        for &id in &requeue {
+            jobs::enqueue(&tx, JobKind::YoutubeDL, Some(id))?;
+        }
+        tx.commit()?;
+    }
+    // nothing points at the files anymore, if moving them fails they are only left behind
+    if !trash_files.is_empty() {
+        let trash = new_trash_dir();
+        tokio::fs::create_dir_all(storage.join(&trash))
+            .await
+            .context("could not create trash")?;
+        for file in &trash_files {
+            let to = storage.join(&trash).join(unwrap_cont!(file.file_name()));
+            if let Err(e) = tokio::fs::rename(file, &to).await {
+                report.errors.push(format!("{}: {}", file.display(), e));
+            }
+        }
+        report.trash = Some(trash.to_string_lossy().to_string());
+    }
+
+    for i in &mut report.issues {
+        let about_audio =
+            i.kind == NO_AUDIO || i.key.as_ref().is_some_and(|k| AUDIO_KEYS.contains(k));
+        let dropped = i
+            .key
+            .as_ref()
+            .is_some_and(|k| drop_tags.contains(&(i.music_id, k.clone())));
+        i.repair = if about_audio && requeue.contains(&i.music_id) {
+            Some(s!("downloading again"))
+        } else if dropped {
+            Some(s!("tag dropped"))
+        } else {
+            None
+        };
+    }
+    Ok(report)
+}
+
+#[derive(SerJson, Debug, Clone)]
+pub struct FsckRun {
+    pub id: u64,
+    /// "running", "done" or "failed"
+    pub status: String,
+    pub error: Option<String>,
+    pub report: Option<FsckReport>,
+}
+
+/// The fsck runs started from the API, the last few are kept until the daemon restarts
+#[derive(Clone, Default)]
+pub struct FsckRuns {
+    runs: Arc<Mutex<BTreeMap<u64, FsckRun>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+const KEPT_RUNS: usize = 10;
+
+impl FsckRuns {
+    /// Runs fsck in the background, returns the id to follow it with `get`
+    pub fn start(&self, db: Db, storage: PathBuf, opts: FsckOptions) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        {
+            let mut runs = self.runs.lock().unwrap();
+            runs.insert(
+                id,
+                FsckRun {
+                    id,
+                    status: s!("running"),
+                    error: None,
+                    report: None,
+                },
+            );
+            while runs.len() > KEPT_RUNS {
+                runs.pop_first();
+            }
+        }
+        let runs = self.clone();
+        tokio::spawn(async move {
+            let res = fsck(&db, &storage, opts).await;
+            if let Err(ref e) = res {
+                log::error!("error running fsck: {:?}", e);
+            }
+            let mut runs = runs.runs.lock().unwrap();
+            // forgotten if more runs were started meanwhile
+            let run = unwrap_ret!(runs.get_mut(&id));
+            match res {
+                Ok(report) => {
+                    run.status = s!("done");
+                    run.report = Some(report);
+                }
+                Err(e) => {
+                    run.status = s!("failed");
+                    run.error = Some(format!("{:#}", e));
+                }
+            }
+        });
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<FsckRun> {
+        self.runs.lock().unwrap().get(&id).cloned()
+    }
+}
@@ -0,0 +1,121 @@
+use crate::domain::entity::{MusicID, Tag, TagKey, TagSchema, User, UserID};
+use crate::domain::schema::SchemaViolation;
+use crate::infrastructure::db::{db_log, DbLog, LogAction, LogEntry, LogType};
+use anyhow::Result;
+use hyper::{Body, Request, StatusCode};
+use rusqlite::Connection;
+
+/// Who is doing an edit through the API, used to log it
+#[derive(Clone, Debug)]
+pub struct Editor {
+    pub user_id: UserID,
+    pub ip: String,
+}
+
+impl Editor {
+    pub fn from_req(req: &Request<Body>) -> Editor {
+        Editor {
+            // -1 when the client didn't tell us who it is
+            user_id: User::from_req(req).unwrap_or(UserID(-1)),
+            ip: req
+                .headers()
+                .get("x-real-ip")
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn log(
+        &self,
+        c: &Connection,
+        music_id: MusicID,
+        key: &TagKey,
+        before: Option<Tag>,
+        after: Option<Tag>,
+    ) {
+        let action = match (&before, &after) {
+            (None, _) => LogAction::Create,
+            (Some(_), Some(_)) => LogAction::Update,
+            (Some(_), None) => LogAction::Delete,
+        };
+        db_log(
+            c,
+            DbLog {
+                user_id: self.user_id,
+                ip: self.ip.clone(),
+                type_: LogType::Tag,
+                action,
+                music_id: Some(music_id),
+                target_key: Some(key.clone()),
+                target_value: after
+                    .as_ref()
+                    .or(before.as_ref())
+                    .and_then(|t| t.text.clone()),
+                before,
+                after,
+            },
+        );
+    }
+
+    /// Inserts a tag, logging a snapshot of the tag before and after
+    pub fn insert_tag(&self, c: &Connection, tag: Tag) -> Result<()> {
+        let before = Tag::by_id_key(c, tag.music_id, &tag.key)?;
+        Tag::insert(c, tag.clone())?;
+        let (id, key) = (tag.music_id, tag.key.clone());
+        self.log(c, id, &key, before, Some(tag));
+        Ok(())
+    }
+
+    /// Inserts a tag sent by a user: validates it against the schema and puts new
+    /// library entries at the end of the library when no order is given.
+    pub fn insert_user_tag(
+        &self,
+        c: &Connection,
+        schemas: &[TagSchema],
+        mut tag: Tag,
+    ) -> Result<Result<(), SchemaViolation>> {
+        if let TagKey::UserLibrary(_) = tag.key {
+            if tag.integer.is_none() {
+                tag.integer = Some(Tag::max_integer_by_key(c, &tag.key)?.unwrap_or(0) + 100);
+            }
+        }
+        if let Err(violation) = TagSchema::validate(schemas, &tag) {
+            return Ok(Err(violation));
+        }
+        self.insert_tag(c, tag)?;
+        Ok(Ok(()))
+    }
+
+    /// Removes a tag, logging a snapshot of the tag before
+    pub fn remove_tag(&self, c: &Connection, id: MusicID, key: &TagKey) -> Result<()> {
+        let before = Tag::by_id_key(c, id, key)?;
+        Tag::remove(c, id, key)?;
+        if before.is_some() {
+            self.log(c, id, key, before, None);
+        }
+        Ok(())
+    }
+}
+
+/// Reverts a tag edit, if the tag wasn't modified since
+pub fn undo(c: &Connection, editor: &Editor, log_id: i64) -> Result<StatusCode> {
+    let entry = unwrap_ret!(LogEntry::by_id(c, log_id)?, Ok(StatusCode::NOT_FOUND));
+    if entry.type_ != "tag" || (entry.before.is_none() && entry.after.is_none()) {
+        return Ok(StatusCode::BAD_REQUEST);
+    }
+    let (id, key) = match (entry.music_id, entry.target_key) {
+        (Some(id), Some(key)) => (id, key),
+        _ => return Ok(StatusCode::BAD_REQUEST),
+    };
+
+    if Tag::by_id_key(c, id, &key)? != entry.after {
+        return Ok(StatusCode::CONFLICT);
+    }
+
+    match entry.before {
+        Some(before) => editor.insert_tag(c, before)?,
+        None => editor.remove_tag(c, id, &key)?,
+    }
+    Ok(StatusCode::OK)
+}
@@ -0,0 +1,204 @@
+//! Persistent queue of the background work, each worker consumes the jobs of its kind.
+//! Work derived from tags (thumbnails, embeddings) is queued by triggers when the tags
+//! change, see the jobs migration, and downloads are queued by the uploads.
+//! Failed jobs are tried again a few times before staying failed until retried.
+
+use crate::domain::entity::MusicID;
+use crate::infrastructure::db::Db;
+use crate::utils::{collect_rows, row_missing_opt};
+use anyhow::{Context, Result};
+use hyper::StatusCode;
+use nanoserde::SerJson;
+use rusqlite::{params, Connection, Row};
+use std::future::Future;
+use std::time::Duration;
+
+pub const MAX_ATTEMPTS: i64 = 3;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum JobKind {
+    YoutubeDL,
+    NeuralEmbed,
+    Thumbnail,
+    EmbeddingReduce,
+}
+
+impl JobKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::YoutubeDL => "youtube_dl",
+            JobKind::NeuralEmbed => "neural_embed",
+            JobKind::Thumbnail => "thumbnail",
+            JobKind::EmbeddingReduce => "embedding_reduce",
+        }
+    }
+}
+
+#[derive(SerJson, Debug, Clone, PartialEq)]
+pub struct Job {
+    pub id: i64,
+    pub kind: String,
+    pub music_id: Option<MusicID>,
+    /// "queued", "running" or "failed"
+    pub status: String,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl<'a, 'b> From<&'a Row<'b>> for Job {
+    fn from(row: &'a Row<'b>) -> Self {
+        Self {
+            id: row.get_unwrap("id"),
+            kind: row.get_unwrap("kind"),
+            music_id: row.get_unwrap::<_, Option<i32>>("music_id").map(MusicID),
+            status: row.get_unwrap("status"),
+            attempts: row.get_unwrap("attempts"),
+            last_error: row.get_unwrap("last_error"),
+            created_at: row.get_unwrap("created_at"),
+            updated_at: row.get_unwrap("updated_at"),
+        }
+    }
+}
+
+/// Does nothing if the same job is already queued
+pub fn enqueue(c: &Connection, kind: JobKind, music: Option<MusicID>) -> Result<()> {
+    c.prepare_cached(
+        "INSERT OR IGNORE INTO jobs (kind, music_id, status, created_at, updated_at)
+        VALUES (?1, ?2, 'queued', DATETIME('now'), DATETIME('now'));",
+    )?
+    .execute(params![kind.as_str(), music.map(|x| x.0)])
+    .context("error queuing job")?;
+    Ok(())
+}
+
+/// Marks the next queued job of that kind as running, new jobs before the ones that failed
+pub fn claim(c: &Connection, kind: JobKind) -> Result<Option<Job>> {
+    let mut stmt = c.prepare_cached(
+        "UPDATE jobs SET status='running', attempts=attempts+1, updated_at=DATETIME('now')
+        WHERE id = (SELECT id FROM jobs WHERE kind=?1 AND status='queued'
+                    ORDER BY attempts, id LIMIT 1)
+        RETURNING *;",
+    )?;
+    row_missing_opt(stmt.query_row([kind.as_str()], |row| Ok(Some(Job::from(row)))))
+        .context("error claiming job")
+}
+
+/// The job is removed, along with the earlier failures of the same work
+pub fn done(c: &Connection, job: &Job) -> Result<()> {
+    c.prepare_cached(
+        "DELETE FROM jobs WHERE id=?1
+        OR (kind=?2 AND music_id IS ?3 AND status='failed');",
+    )?
+    .execute(params![job.id, job.kind, job.music_id.map(|x| x.0)])?;
+    Ok(())
+}
+
+/// Queued again unless it was tried too many times. Another queued job for the same work replaces it.
+pub fn fail(c: &Connection, job: &Job, error: &str) -> Result<()> {
+    let status = match job.attempts >= MAX_ATTEMPTS {
+        true => "failed",
+        false => "queued",
+    };
+    c.prepare_cached(
+        "UPDATE OR REPLACE jobs SET status=?2, last_error=?3, updated_at=DATETIME('now')
+        WHERE id=?1;",
+    )?
+    .execute(params![job.id, status, error])?;
+    Ok(())
+}
+
+/// Jobs that were running when the daemon stopped are queued again
+pub fn recover(c: &Connection) -> Result<()> {
+    c.prepare_cached("UPDATE OR REPLACE jobs SET status='queued' WHERE status='running';")?
+        .execute([])?;
+    Ok(())
+}
+
+/// Oldest first, optionally only those with that status
+pub fn list(c: &Connection, status: Option<&str>) -> Result<Vec<Job>> {
+    let mut stmt =
+        c.prepare_cached("SELECT * FROM jobs WHERE ?1 IS NULL OR status=?1 ORDER BY id;")?;
+    let v = stmt.query_map([status], |row| Ok(Job::from(row)))?;
+    collect_rows(v)
+}
+
+pub fn by_id(c: &Connection, id: i64) -> Result<Option<Job>> {
+    let mut stmt = c.prepare_cached("SELECT * FROM jobs WHERE id=?1;")?;
+    row_missing_opt(stmt.query_row([id], |row| Ok(Some(Job::from(row)))))
+        .context("error getting job by id")
+}
+
+/// Queues a failed job again with its attempts reset
+pub fn retry(c: &Connection, id: i64) -> Result<StatusCode> {
+    let job = unwrap_ret!(by_id(c, id)?, Ok(StatusCode::NOT_FOUND));
+    if job.status != "failed" {
+        return Ok(StatusCode::CONFLICT);
+    }
+    c.prepare_cached(
+        "UPDATE OR REPLACE jobs SET status='queued', attempts=0, updated_at=DATETIME('now')
+        WHERE id=?1;",
+    )?
+    .execute([id])?;
+    Ok(StatusCode::OK)
+}
+
+/// Queues all the failed jobs again, returns how many there were
+pub fn retry_failed(c: &Connection) -> Result<usize> {
+    let n = c
+        .prepare_cached(
+            "UPDATE OR REPLACE jobs SET status='queued', attempts=0, updated_at=DATETIME('now')
+            WHERE status='failed';",
+        )?
+        .execute([])?;
+    Ok(n)
+}
+
+/// Removes a job that isn't running
+pub fn cancel(c: &Connection, id: i64) -> Result<StatusCode> {
+    let job = unwrap_ret!(by_id(c, id)?, Ok(StatusCode::NOT_FOUND));
+    if job.status == "running" {
+        return Ok(StatusCode::CONFLICT);
+    }
+    c.prepare_cached("DELETE FROM jobs WHERE id=?1;")?
+        .execute([id])?;
+    Ok(StatusCode::OK)
+}
+
+/// Runs the next job of that kind if there is one, returns whether there was
+pub async fn run_next<F, Fut>(db: &Db, kind: JobKind, work: F) -> Result<bool>
+where
+    F: FnOnce(Job) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let job = unwrap_ret!(claim(&*db.get().await, kind)?, Ok(false));
+    let res = work(job.clone()).await;
+    let c = db.get().await;
+    match res {
+        Ok(()) => done(&c, &job)?,
+        Err(e) => {
+            log::error!("{} job {} failed: {:?}", kind.as_str(), job.id, e);
+            fail(&c, &job, &format!("{:?}", e))?;
+        }
+    }
+    Ok(true)
+}
+
+/// Runs the jobs of that kind one after the other, looking for new ones every 5 seconds
+pub fn consume<F, Fut>(db: Db, kind: JobKind, work: F)
+where
+    F: Fn(Db, Job) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send,
+{
+    tokio::spawn(async move {
+        loop {
+            match run_next(&db, kind, |job| work(db.clone(), job)).await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => log::error!("error running {} jobs: {:?}", kind.as_str(), e),
+            }
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    });
+}
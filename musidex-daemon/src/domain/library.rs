@@ -0,0 +1,154 @@
+use crate::domain::entity::{MusicID, Tag, TagKey, User};
+use crate::domain::stream::source_of;
+use anyhow::Result;
+use rusqlite::Connection;
+use std::collections::{BTreeMap, HashMap};
+
+/// The library of a user as seen by music players that expect artists and albums.
+/// There are no albums in musidex, so each artist has a single album with all its songs.
+/// Playlists are the user_tag tags of the library.
+pub struct Library {
+    pub user: User,
+    /// by library order, top first
+    pub songs: Vec<Song>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Song {
+    pub id: MusicID,
+    pub title: String,
+    pub artist: String,
+    /// seconds
+    pub duration: Option<i64>,
+    /// relative to the storage directory
+    pub path: Option<String>,
+    pub cover: Option<String>,
+    pub playlists: Vec<String>,
+}
+
+pub const UNKNOWN_ARTIST: &str = "Unknown Artist";
+
+/// Ids of things that aren't musics are the kind followed by the hex encoded name,
+/// so that they stay the same between requests.
+pub fn encode_id(kind: &str, name: &str) -> String {
+    format!("{}-{}", kind, encode_hex(name))
+}
+
+pub fn decode_id(kind: &str, id: &str) -> Option<String> {
+    decode_hex(id.strip_prefix(kind)?.strip_prefix('-')?)
+}
+
+pub fn encode_hex(v: &str) -> String {
+    v.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_hex(hex: &str) -> Option<String> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+impl Song {
+    pub fn load(c: &Connection, id: MusicID) -> Result<Song> {
+        Ok(Song::from_tags(id, Tag::by_id(c, id)?))
+    }
+
+    fn from_tags(id: MusicID, tags: Vec<Tag>) -> Song {
+        let mut song = Song {
+            id,
+            title: String::new(),
+            artist: s!(UNKNOWN_ARTIST),
+            duration: None,
+            path: source_of(&tags),
+            cover: None,
+            playlists: vec![],
+        };
+        let mut thumbnail = None;
+        for tag in tags {
+            match tag.key {
+                TagKey::Title => song.title = tag.text.unwrap_or_default(),
+                TagKey::Artist => song.artist = tag.text.unwrap_or_else(|| s!(UNKNOWN_ARTIST)),
+                TagKey::Duration => song.duration = tag.integer,
+                TagKey::CompressedThumbnail => song.cover = tag.text,
+                TagKey::Thumbnail => thumbnail = tag.text,
+                TagKey::UserTag(name) => song.playlists.push(name),
+                _ => {}
+            }
+        }
+        if song.cover.is_none() {
+            song.cover = thumbnail;
+        }
+        song
+    }
+}
+
+impl Library {
+    pub fn load(c: &Connection, user: User) -> Result<Library> {
+        let key = TagKey::UserLibrary(user.id.0.to_string());
+        let mut tags: HashMap<MusicID, Vec<Tag>> = HashMap::new();
+        for tag in Tag::of_musics_with_key(c, &key)? {
+            tags.entry(tag.music_id).or_default().push(tag);
+        }
+
+        let mut order: Vec<(i64, MusicID)> = tags
+            .iter()
+            .map(|(id, tags)| {
+                let entry = tags.iter().find(|t| t.key == key);
+                (entry.and_then(|t| t.integer).unwrap_or(0), *id)
+            })
+            .collect();
+        order.sort_by_key(|&(order, id)| (-order, id.0));
+
+        let songs = order
+            .into_iter()
+            .map(|(_, id)| Song::from_tags(id, tags.remove(&id).unwrap_or_default()))
+            .collect();
+
+        Ok(Library { user, songs })
+    }
+
+    pub fn song(&self, id: MusicID) -> Option<&Song> {
+        self.songs.iter().find(|s| s.id == id)
+    }
+
+    /// Songs by artist name, sorted by name
+    pub fn artists(&self) -> BTreeMap<&str, Vec<&Song>> {
+        let mut artists: BTreeMap<&str, Vec<&Song>> = BTreeMap::new();
+        for song in &self.songs {
+            artists.entry(&song.artist).or_default().push(song);
+        }
+        artists
+    }
+
+    /// Songs by playlist name, sorted by name
+    pub fn playlists(&self) -> BTreeMap<&str, Vec<&Song>> {
+        let mut playlists: BTreeMap<&str, Vec<&Song>> = BTreeMap::new();
+        for song in &self.songs {
+            for p in &song.playlists {
+                playlists.entry(p).or_default().push(song);
+            }
+        }
+        playlists
+    }
+
+    /// Songs whose title or artist contains every word of the query, case insensitive
+    pub fn search(&self, query: &str) -> Vec<&Song> {
+        let words: Vec<String> = query
+            .split_whitespace()
+            .map(|w| w.trim_matches('"').to_lowercase())
+            .filter(|w| !w.is_empty())
+            .collect();
+        self.songs
+            .iter()
+            .filter(|s| {
+                let hay = format!("{} {}", s.title, s.artist).to_lowercase();
+                words.iter().all(|w| hay.contains(w))
+            })
+            .collect()
+    }
+}
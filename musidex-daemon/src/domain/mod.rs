@@ -1,11 +1,34 @@
+pub mod archive;
+pub mod backup;
+pub mod bulk;
 pub mod clean;
+pub mod commands;
 pub mod config;
+pub mod devices;
+pub mod encoding;
 pub mod entity;
+pub mod feed;
+pub mod fsck;
+pub mod history;
+pub mod jobs;
+pub mod library;
 pub mod music;
+pub mod mutations;
+pub mod offline;
+pub mod party;
+pub mod player;
+pub mod playlist;
+pub mod query;
+pub mod radio;
+pub mod schema;
+pub mod scope;
 pub mod stream;
+pub mod subsonic;
 pub mod sync;
 pub mod tags;
+pub mod trash;
 pub mod upload;
+pub mod upnp;
 pub mod user;
 pub mod worker_embedding_dimreduce;
 pub mod worker_neural_embed;
@@ -1,4 +1,7 @@
-use crate::domain::entity::{Music, MusicID, Tag, TagKey, UserID};
+use crate::domain::entity::{Music, MusicID, MusicMerge, Tag, TagKey};
+use crate::domain::history::Editor;
+use crate::domain::trash;
+use crate::utils::{collect_rows, row_missing_opt};
 use anyhow::{Context, Result};
 use hyper::StatusCode;
 use rusqlite::Connection;
@@ -10,6 +13,14 @@ pub enum MoveDirection {
 }
 
 impl MoveDirection {
+    pub fn parse(v: &str) -> Option<MoveDirection> {
+        match v {
+            "above" => Some(MoveDirection::Above),
+            "below" => Some(MoveDirection::Below),
+            _ => None,
+        }
+    }
+
     pub fn offset(&self) -> i64 {
         match self {
             MoveDirection::Above => 1,
@@ -42,9 +53,42 @@ impl Music {
             .map(|x| x == 1)
     }
 
-    pub fn merge(c: &mut Connection, id1: MusicID, id2: MusicID) -> Result<()> {
+    pub fn exists(c: &Connection, id: MusicID) -> Result<bool> {
+        let mut stmt = c.prepare_cached("SELECT count(1) FROM musics WHERE id=?1;")?;
+        let v: i32 = stmt.query_row([id.0], |row| row.get(0))?;
+        Ok(v == 1)
+    }
+
+    /// Merges id2 into id1, tags of id1 taking precedence.
+    /// The tags of id2 are kept in the merge record so that it can be undone with unmerge.
+    pub fn merge(c: &mut Connection, id1: MusicID, id2: MusicID) -> Result<i64> {
         let t = c.transaction().context("transaction begin failed")?;
 
+        t.prepare_cached(
+            "INSERT INTO merges (timestamp, into_id, merged_id) VALUES (DATETIME('now'), ?1, ?2);",
+        )?
+        .execute([&id1.0, &id2.0])
+        .context("error creating merge record")?;
+        let merge_id = t.last_insert_rowid();
+
+        for tag in Tag::by_id(&t, id2)? {
+            let moved = !Tag::has(&t, id1, &tag.key)?;
+            t.prepare_cached(
+                "INSERT INTO merge_tags (merge_id, key, moved, text, integer, date, vector)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);",
+            )?
+            .execute(rusqlite::params![
+                merge_id,
+                tag.key,
+                moved,
+                tag.text,
+                tag.integer,
+                tag.date,
+                tag.vector
+            ])
+            .context("error saving merged tag")?;
+        }
+
         t.prepare_cached("UPDATE OR IGNORE tags SET music_id = ?1 WHERE music_id = ?2;")
             .context("error preparing merge music")?
             .execute([&id1.0, &id2.0])
@@ -54,19 +98,97 @@ impl Music {
 
         t.commit().context("transaction commit failed")?;
 
-        Ok(())
+        Ok(merge_id)
+    }
+
+    /// Restores the merged music with the tags it had at the time of the merge.
+    /// Moved tags are taken back from the music it was merged into,
+    /// unless they were edited since then, in which case nothing is done.
+    pub fn unmerge(c: &mut Connection, merge_id: i64) -> Result<StatusCode> {
+        let t = c.transaction().context("transaction begin failed")?;
+
+        let merge = unwrap_ret!(MusicMerge::by_id(&t, merge_id)?, Ok(StatusCode::NOT_FOUND));
+        if merge.unmerged
+            || !Music::exists(&t, merge.into_id)?
+            || Music::exists(&t, merge.merged_id)?
+        {
+            return Ok(StatusCode::CONFLICT);
+        }
+        for tag in &merge.moved {
+            let cur = Tag::by_id_key(&t, merge.into_id, &tag.key)?;
+            if cur.is_some_and(|cur| {
+                Tag {
+                    music_id: merge.merged_id,
+                    ..cur
+                } != *tag
+            }) {
+                return Ok(StatusCode::CONFLICT);
+            }
+        }
+
+        t.prepare_cached("INSERT INTO musics (id) VALUES (?1);")?
+            .execute([merge.merged_id.0])
+            .context("error recreating merged music")?;
+
+        for tag in merge.moved {
+            Tag::remove(&t, merge.into_id, &tag.key)?;
+            Tag::insert_silent(&t, tag)?;
+        }
+        for tag in merge.discarded {
+            Tag::insert_silent(&t, tag)?;
+        }
+
+        t.prepare_cached("UPDATE merges SET unmerged=1 WHERE id=?1;")?
+            .execute([merge_id])?;
+
+        t.commit().context("transaction commit failed")?;
+        Ok(StatusCode::OK)
+    }
+
+    /// Follows the merges of a deleted music to find the music it ended up in
+    pub fn resolve_merged(c: &Connection, mut id: MusicID) -> Result<Option<MusicID>> {
+        let mut stmt = c.prepare_cached(
+            "SELECT into_id FROM merges WHERE merged_id=?1 AND unmerged=0 ORDER BY id DESC LIMIT 1;",
+        )?;
+        // bounded in case of a merge cycle through unmerges and re-merges
+        for _ in 0..64 {
+            if Music::exists(c, id)? {
+                return Ok(Some(id));
+            }
+            id = match row_missing_opt(stmt.query_row([id.0], |row| row.get(0).map(Some)))? {
+                Some(into) => MusicID(into),
+                None => return Ok(None),
+            };
+        }
+        Ok(None)
     }
 
     pub fn move_(
         c: &mut Connection,
+        editor: &Editor,
         library: &TagKey,
         id_base: MusicID,
         id_to_move: MusicID,
         direction: MoveDirection,
     ) -> Result<bool> {
         let t = c.transaction().context("transaction begin failed")?;
+        if !Self::move_in_tx(&t, editor, library, id_base, id_to_move, direction)? {
+            return Ok(false);
+        }
+        t.commit().context("transaction commit failed")?;
+        Ok(true)
+    }
 
-        let order_taken = Tag::by_key(&t, library)?;
+    /// Same as move_ but for callers that already are in a transaction
+    pub fn move_in_tx(
+        t: &Connection,
+        editor: &Editor,
+        library: &TagKey,
+        id_base: MusicID,
+        id_to_move: MusicID,
+        direction: MoveDirection,
+    ) -> Result<bool> {
+        let order_taken = Tag::by_key(t, library)?;
 
         let order_taken: HashMap<_, _> = order_taken
             .into_iter()
@@ -76,7 +198,7 @@ impl Music {
         let Some(Tag {
             integer: Some(order_base),
             ..
-        }) = Tag::by_id_key(&t, id_base, library)?
+        }) = Tag::by_id_key(t, id_base, library)?
         else {
             log::error!("couldn't find order for id_bottom");
             return Ok(false);
@@ -85,25 +207,27 @@ impl Music {
         let mut to_move = order_base + direction.offset();
 
         while let Some(mid_to_move) = order_taken.get(&to_move) {
-            Tag::insert_silent(
-                &t,
-                Tag::new_integer(*mid_to_move, library.clone(), to_move + direction.offset()),
-            )
-            .context("error inserting tag for min insertion")?;
+            editor
+                .insert_tag(
+                    t,
+                    Tag::new_integer(*mid_to_move, library.clone(), to_move + direction.offset()),
+                )
+                .context("error inserting tag for min insertion")?;
             to_move += direction.offset();
         }
 
-        Tag::insert_silent(
-            &t,
+        editor.insert_tag(
+            t,
             Tag::new_integer(id_to_move, library.clone(), order_base + direction.offset()),
         )?;
 
-        t.commit().context("transaction commit failed")?;
         Ok(true)
     }
 }
 
-pub fn delete_music(c: &Connection, uid: UserID, id: MusicID) -> Result<StatusCode> {
+/// Moves the music to the trash if the editor is its only owner, otherwise removes it from their library
+pub fn delete_music(c: &Connection, editor: &Editor, id: MusicID) -> Result<StatusCode> {
+    let uid = editor.user_id;
     let tags = Tag::by_id(&c, id)?;
     let owners: Vec<_> = tags
         .iter()
@@ -115,12 +239,75 @@ pub fn delete_music(c: &Connection, uid: UserID, id: MusicID) -> Result<StatusCo
     }
     match owners.len() {
         1 | 0 => {
-            Music::delete(&c, id).context("couldn't delete music from db")?;
+            trash::trash(c, uid, id).context("couldn't move music to the trash")?;
         }
         _ => {
-            Tag::remove(&c, id, &TagKey::UserLibrary(uid.to_string()))?;
+            editor.remove_tag(c, id, &TagKey::UserLibrary(uid.to_string()))?;
         }
     };
 
     Ok(StatusCode::OK)
 }
+
+impl MusicMerge {
+    pub fn by_id(c: &Connection, id: i64) -> Result<Option<MusicMerge>> {
+        let mut stmt = c.prepare_cached("SELECT * FROM merges WHERE id=?1;")?;
+        let merge = row_missing_opt(stmt.query_row([id], |row| {
+            Ok(Some((
+                row.get::<_, String>("timestamp")?,
+                MusicID(row.get("into_id")?),
+                MusicID(row.get("merged_id")?),
+                row.get::<_, bool>("unmerged")?,
+            )))
+        }))?;
+        let (timestamp, into_id, merged_id, unmerged) = unwrap_ret!(merge, Ok(None));
+
+        let mut stmt =
+            c.prepare_cached("SELECT ?2 AS music_id, * FROM merge_tags WHERE merge_id=?1;")?;
+        let tags = stmt.query_map(rusqlite::params![id, merged_id.0], |row| {
+            Ok((Tag::from(row), row.get::<_, bool>("moved")?))
+        })?;
+        let (moved, discarded): (Vec<_>, Vec<_>) = collect_rows(tags)?
+            .into_iter()
+            .partition(|(_, moved)| *moved);
+
+        Ok(Some(MusicMerge {
+            id,
+            timestamp,
+            into_id,
+            merged_id,
+            unmerged,
+            moved: moved.into_iter().map(|(t, _)| t).collect(),
+            discarded: discarded.into_iter().map(|(t, _)| t).collect(),
+        }))
+    }
+
+    /// Returns the merges that were done into a music, most recent first
+    pub fn by_into_id(c: &Connection, id: MusicID) -> Result<Vec<MusicMerge>> {
+        let mut stmt =
+            c.prepare_cached("SELECT id FROM merges WHERE into_id=?1 ORDER BY id DESC;")?;
+        let ids = collect_rows(stmt.query_map([id.0], |row| row.get::<_, i64>(0))?)?;
+        let mut merges = Vec::with_capacity(ids.len());
+        for id in ids {
+            merges.extend(MusicMerge::by_id(c, id)?);
+        }
+        Ok(merges)
+    }
+
+    /// Finds the music that now holds a tag of a music that was merged, such as an old youtube id
+    pub fn find_merged_tag(c: &Connection, key: &TagKey, text: &str) -> Result<Option<MusicID>> {
+        let mut stmt = c.prepare_cached(
+            "SELECT merges.into_id FROM merge_tags
+            INNER JOIN merges ON merges.id = merge_tags.merge_id
+            WHERE merge_tags.key=?1 AND merge_tags.text=?2 AND merges.unmerged=0
+            ORDER BY merges.id DESC LIMIT 1;",
+        )?;
+        let into = row_missing_opt(
+            stmt.query_row(rusqlite::params![key, text], |row| row.get(0).map(Some)),
+        )?;
+        match into {
+            Some(into) => Music::resolve_merged(c, MusicID(into)),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use crate::domain::entity::{Music, MusicID, Tag, TagKey, TagSchema};
+use crate::domain::history::Editor;
+use crate::domain::music::{delete_music, MoveDirection};
+use crate::domain::sync::head_seq;
+use crate::infrastructure::db::{db_log, DbLog, LogAction, LogType};
+use crate::utils::row_missing_opt;
+use anyhow::Result;
+use hyper::StatusCode;
+use nanoserde::{DeJson, SerJson};
+use rusqlite::Connection;
+
+/// Edits made by a client while offline, applied in order once it is back online
+#[derive(DeJson)]
+pub struct MutationBatch {
+    pub mutations: Vec<Mutation>,
+}
+
+#[derive(DeJson)]
+pub struct Mutation {
+    /// when the client made the edit, in unix milliseconds
+    pub ts: i64,
+    /// "set", "remove", "move" or "delete"
+    pub op: String,
+    pub music_id: MusicID,
+
+    /// set and remove
+    pub key: Option<TagKey>,
+    pub text: Option<String>,
+    pub integer: Option<i64>,
+    pub date: Option<String>,
+
+    /// move: music_id is moved next to id_base in the library
+    pub library: Option<String>,
+    pub id_base: Option<MusicID>,
+    /// "above" or "below"
+    pub direction: Option<String>,
+}
+
+#[derive(SerJson, Debug, PartialEq)]
+pub struct MutationResult {
+    pub status: u16,
+    pub error: Option<String>,
+}
+
+#[derive(SerJson)]
+pub struct MutationResults {
+    /// one per mutation, in the same order
+    pub results: Vec<MutationResult>,
+    /// changelog position after the batch, to resume syncing from
+    pub seq: i64,
+}
+
+impl MutationResult {
+    fn new(status: StatusCode) -> Self {
+        Self {
+            status: status.as_u16(),
+            error: None,
+        }
+    }
+
+    fn error(status: StatusCode, error: impl ToString) -> Self {
+        Self {
+            status: status.as_u16(),
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+/// Last time the tag (or anything about the music if key is None) changed on the server
+/// up to the given seq, None if it didn't change since the oldest changelog entry we kept
+fn last_change(
+    c: &Connection,
+    until: i64,
+    id: MusicID,
+    key: Option<&TagKey>,
+) -> Result<Option<i64>> {
+    let mut stmt = c.prepare_cached(
+        "SELECT max(ts) FROM changelog
+        WHERE seq <= ?1 AND music_id = ?2 AND (?3 IS NULL OR key = ?3);",
+    )?;
+    let ts =
+        row_missing_opt(stmt.query_row(rusqlite::params![until, id.0, key], |row| row.get(0)))?;
+    Ok(ts.flatten())
+}
+
+/// Applies the mutations in order with the following conflict policy:
+///
+/// - set/remove: last writer wins per tag. If the tag changed on the server after the
+///   client edit, the edit is dropped with 409 Conflict.
+/// - delete: dropped with 409 if anything about the music changed after the client edit.
+/// - user_library: adding a music to a library always succeeds, the music is put at the end
+///   when the position is already taken. Reordering is sent as moves, replayed relatively to
+///   the base music so that reorders made on several devices merge instead of overwriting.
+///   Moves are dropped with 404 if one of the musics isn't in the library anymore.
+///
+/// Must be run in a transaction.
+pub fn apply(c: &Connection, editor: &Editor, mutations: Vec<Mutation>) -> Result<MutationResults> {
+    let schemas = TagSchema::list(c)?;
+    // mutations of the batch shouldn't conflict with each other
+    let before = head_seq(c)?;
+    let mut results = Vec::with_capacity(mutations.len());
+    for m in mutations {
+        results.push(apply_one(c, editor, &schemas, before, m)?);
+    }
+    Ok(MutationResults {
+        results,
+        seq: head_seq(c)?,
+    })
+}
+
+fn apply_one(
+    c: &Connection,
+    editor: &Editor,
+    schemas: &[TagSchema],
+    before: i64,
+    m: Mutation,
+) -> Result<MutationResult> {
+    if !Music::exists(c, m.music_id)? {
+        return Ok(MutationResult::new(StatusCode::NOT_FOUND));
+    }
+    let is_newer = |key: Option<&TagKey>| -> Result<bool> {
+        Ok(last_change(c, before, m.music_id, key)?.is_none_or(|ts| ts <= m.ts))
+    };
+
+    match m.op.as_str() {
+        "set" => {
+            let key = unwrap_ret!(
+                m.key,
+                Ok(MutationResult::error(
+                    StatusCode::BAD_REQUEST,
+                    "missing key"
+                ))
+            );
+            let mut integer = m.integer;
+            if let TagKey::UserLibrary(_) = key {
+                if Tag::has(c, m.music_id, &key)? {
+                    // the order is only changed through moves
+                    return Ok(MutationResult::new(StatusCode::OK));
+                }
+                if let Some(i) = integer {
+                    let taken = Tag::by_key(c, &key)?.iter().any(|t| t.integer == Some(i));
+                    if taken {
+                        integer = None;
+                    }
+                }
+            } else if !is_newer(Some(&key))? {
+                return Ok(MutationResult::new(StatusCode::CONFLICT));
+            }
+            let tag = Tag {
+                music_id: m.music_id,
+                key,
+                text: m.text,
+                integer,
+                date: m.date,
+                vector: None,
+            };
+            Ok(match editor.insert_user_tag(c, schemas, tag)? {
+                Ok(()) => MutationResult::new(StatusCode::OK),
+                Err(violation) => MutationResult::error(violation.status(), violation),
+            })
+        }
+        "remove" => {
+            let key = unwrap_ret!(
+                m.key,
+                Ok(MutationResult::error(
+                    StatusCode::BAD_REQUEST,
+                    "missing key"
+                ))
+            );
+            if !TagSchema::find(schemas, &key).is_some_and(|s| s.user_editable) {
+                return Ok(MutationResult::error(
+                    StatusCode::FORBIDDEN,
+                    format!("tag key is not user editable: {}", key),
+                ));
+            }
+            if !is_newer(Some(&key))? {
+                return Ok(MutationResult::new(StatusCode::CONFLICT));
+            }
+            editor.remove_tag(c, m.music_id, &key)?;
+            Ok(MutationResult::new(StatusCode::OK))
+        }
+        "move" => {
+            let (library, id_base, direction) = match (
+                m.library,
+                m.id_base,
+                m.direction.as_deref().and_then(MoveDirection::parse),
+            ) {
+                (Some(l), Some(b), Some(d)) => (TagKey::UserLibrary(l), b, d),
+                _ => {
+                    return Ok(MutationResult::error(
+                        StatusCode::BAD_REQUEST,
+                        "missing library, id_base or direction",
+                    ))
+                }
+            };
+            if !Tag::has(c, m.music_id, &library)? {
+                return Ok(MutationResult::new(StatusCode::NOT_FOUND));
+            }
+            if !Music::move_in_tx(c, editor, &library, id_base, m.music_id, direction)? {
+                return Ok(MutationResult::new(StatusCode::NOT_FOUND));
+            }
+            Ok(MutationResult::new(StatusCode::OK))
+        }
+        "delete" => {
+            if !is_newer(None)? {
+                return Ok(MutationResult::new(StatusCode::CONFLICT));
+            }
+            db_log(
+                c,
+                DbLog {
+                    user_id: editor.user_id,
+                    ip: editor.ip.clone(),
+                    type_: LogType::Music,
+                    action: LogAction::Delete,
+                    music_id: Some(m.music_id),
+                    target_key: None,
+                    target_value: None,
+                    before: None,
+                    after: None,
+                },
+            );
+            Ok(MutationResult::new(delete_music(c, editor, m.music_id)?))
+        }
+        x => Ok(MutationResult::error(
+            StatusCode::BAD_REQUEST,
+            format!("unknown op: {}", x),
+        )),
+    }
+}
@@ -0,0 +1,242 @@
+use crate::domain::entity::{MusicID, Tag, TagKey, UserID};
+use crate::domain::stream::source_path;
+use crate::infrastructure::db::Db;
+use crate::utils::collect_rows;
+use anyhow::{Context, Result};
+use nanoserde::SerJson;
+use rusqlite::Connection;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ManifestOrder {
+    /// top of the library first
+    Library,
+    /// most listened first, then library order
+    Listens,
+}
+
+pub struct ManifestOptions {
+    /// only the musics of the user library with this user_tag
+    pub playlist: Option<String>,
+    /// maximum total size in bytes
+    pub budget: Option<u64>,
+    pub order: ManifestOrder,
+}
+
+/// What a client should have downloaded for offline use, by priority
+#[derive(SerJson, Debug)]
+pub struct OfflineManifest {
+    pub musics: Vec<OfflineEntry>,
+    pub total_size: u64,
+    /// number of musics that would have been included without the budget
+    pub candidates: usize,
+}
+
+#[derive(SerJson, Debug, Clone, PartialEq)]
+pub struct OfflineEntry {
+    pub music_id: MusicID,
+    pub size: u64,
+    /// sha1 of the file, hex encoded
+    pub hash: String,
+    /// file extension: mp3, m4a...
+    pub format: String,
+    /// relative to the storage directory
+    pub path: String,
+    pub thumbnail: Option<String>,
+}
+
+struct Candidate {
+    music_id: MusicID,
+    order: i64,
+    listens: i64,
+    path: String,
+    thumbnail: Option<String>,
+}
+
+#[derive(Clone)]
+struct FileHash {
+    size: u64,
+    mtime: i64,
+    hash: String,
+}
+
+impl ManifestOrder {
+    pub fn parse(v: Option<&str>) -> Option<ManifestOrder> {
+        match v {
+            None | Some("library") => Some(ManifestOrder::Library),
+            Some("listens") => Some(ManifestOrder::Listens),
+            _ => None,
+        }
+    }
+}
+
+fn candidates(c: &Connection, user: UserID, opts: &ManifestOptions) -> Result<Vec<Candidate>> {
+    let mut stmt = c.prepare_cached(
+        "SELECT music_id, count(1) FROM logs WHERE type = 'listen' AND user_id = ?1 GROUP BY music_id;",
+    )?;
+    let listens: HashMap<i32, i64> =
+        collect_rows(stmt.query_map([user.0], |row| Ok((row.get(0)?, row.get(1)?)))?)?
+            .into_iter()
+            .collect();
+
+    let playlist = opts.playlist.clone().map(TagKey::UserTag);
+    let mut candidates = vec![];
+    for tag in Tag::by_key_visible(c, &TagKey::UserLibrary(user.0.to_string()))? {
+        let id = tag.music_id;
+        if let Some(ref playlist) = playlist {
+            if !Tag::has(c, id, playlist)? {
+                continue;
+            }
+        }
+        let path = unwrap_cont!(source_path(c, id)?);
+        let thumbnail = match Tag::by_id_key(c, id, &TagKey::CompressedThumbnail)? {
+            Some(t) => t.text,
+            None => Tag::by_id_key(c, id, &TagKey::Thumbnail)?.and_then(|t| t.text),
+        };
+        candidates.push(Candidate {
+            music_id: id,
+            order: tag.integer.unwrap_or(0),
+            listens: listens.get(&id.0).copied().unwrap_or(0),
+            path,
+            thumbnail,
+        });
+    }
+
+    candidates.sort_by_key(|x| {
+        let listens = match opts.order {
+            ManifestOrder::Library => 0,
+            ManifestOrder::Listens => x.listens,
+        };
+        (-listens, -x.order, x.music_id.0)
+    });
+    Ok(candidates)
+}
+
+fn cached_hashes(c: &Connection) -> Result<HashMap<String, FileHash>> {
+    let mut stmt = c.prepare_cached("SELECT path, size, mtime, hash FROM file_hashes;")?;
+    let v = stmt.query_map([], |row| {
+        Ok((
+            row.get(0)?,
+            FileHash {
+                size: row.get(1)?,
+                mtime: row.get(2)?,
+                hash: row.get(3)?,
+            },
+        ))
+    })?;
+    Ok(collect_rows(v)?.into_iter().collect())
+}
+
+fn store_hashes(c: &Connection, hashes: &[(String, FileHash)]) -> Result<()> {
+    let mut stmt = c.prepare_cached(
+        "INSERT INTO file_hashes (path, size, mtime, hash) VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT (path) DO UPDATE SET size=?2, mtime=?3, hash=?4;",
+    )?;
+    for (path, h) in hashes {
+        stmt.execute(rusqlite::params![path, h.size, h.mtime, h.hash])?;
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut f = std::fs::File::open(path)?;
+    let mut hasher = Sha1::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Size and hash of the file, None if it doesn't exist. Only hashes the file if it changed.
+fn file_hash(storage: &Path, path: &str, cached: Option<&FileHash>) -> Result<Option<FileHash>> {
+    let full = storage.join(path);
+    let meta = match std::fs::metadata(&full) {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let size = meta.len();
+    let mtime = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0);
+    if let Some(cached) = cached {
+        if cached.size == size && cached.mtime == mtime {
+            return Ok(Some(cached.clone()));
+        }
+    }
+    let hash = hash_file(&full).with_context(|| format!("error hashing {}", full.display()))?;
+    Ok(Some(FileHash { size, mtime, hash }))
+}
+
+/// Lists what the user should download to use the app offline, by priority,
+/// stopping at the first music that doesn't fit in the budget.
+/// Musics that aren't downloaded on the server yet are left out.
+pub async fn manifest(
+    db: &Db,
+    storage: PathBuf,
+    user: UserID,
+    opts: ManifestOptions,
+) -> Result<OfflineManifest> {
+    let (candidates, cached) = {
+        let c = db.get().await;
+        (candidates(&c, user, &opts)?, cached_hashes(&c)?)
+    };
+
+    let (entries, updated) = tokio::task::spawn_blocking(move || -> Result<_> {
+        let mut entries = Vec::with_capacity(candidates.len());
+        let mut updated = vec![];
+        for cand in candidates {
+            let cached = cached.get(&cand.path);
+            let h = unwrap_cont!(file_hash(&storage, &cand.path, cached)?);
+            if cached.map(|x| (x.size, x.mtime, &x.hash)) != Some((h.size, h.mtime, &h.hash)) {
+                updated.push((cand.path.clone(), h.clone()));
+            }
+            entries.push(OfflineEntry {
+                music_id: cand.music_id,
+                size: h.size,
+                hash: h.hash,
+                format: cand.path.rsplit('.').next().unwrap_or_default().to_string(),
+                path: cand.path,
+                thumbnail: cand.thumbnail,
+            });
+        }
+        Ok((entries, updated))
+    })
+    .await??;
+
+    if !updated.is_empty() {
+        store_hashes(&*db.get().await, &updated)?;
+    }
+
+    let n_candidates = entries.len();
+    let mut total_size = 0;
+    let mut musics = vec![];
+    for entry in entries {
+        if opts.budget.is_some_and(|b| total_size + entry.size > b) {
+            break;
+        }
+        total_size += entry.size;
+        musics.push(entry);
+    }
+
+    Ok(OfflineManifest {
+        musics,
+        total_size,
+        candidates: n_candidates,
+    })
+}
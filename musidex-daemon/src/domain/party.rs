@@ -0,0 +1,406 @@
+//! Listening parties: clients sharing a queue and playing it at the same position.
+//! Everything goes through the sync websocket as text messages, see `run`.
+//! The server holds the timeline: the position of the current track at a server time,
+//! and clients estimate their clock offset with `party_clock` to follow it.
+
+use crate::domain::commands::{Ack, Command};
+use crate::domain::entity::{Music, MusicID};
+use crate::infrastructure::db::Db;
+use hyper::StatusCode;
+use nanoserde::{DeJson, SerJson};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use tinyrand::{RandRange, Seeded, StdRand};
+use tokio::sync::watch;
+
+const CODE_LEN: usize = 6;
+/// no 0/O or 1/I to read them out loud
+const CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// Milliseconds since the epoch, as seen by the server
+pub fn server_time() -> f64 {
+    UNIX_EPOCH
+        .elapsed()
+        .map(|d| d.as_secs_f64() * 1000.0)
+        .unwrap_or(0.0)
+}
+
+struct PartyState {
+    hosts: BTreeSet<u64>,
+    members: BTreeSet<u64>,
+    queue: Vec<MusicID>,
+    current: Option<usize>,
+    playing: bool,
+    /// seconds into the current track at server time `at`
+    position: f64,
+    at: f64,
+}
+
+impl PartyState {
+    fn position_at(&self, now: f64) -> f64 {
+        match self.playing {
+            true => self.position + (now - self.at) / 1000.0,
+            false => self.position,
+        }
+    }
+
+    fn set_position(&mut self, position: f64, now: f64) {
+        self.position = position.max(0.0);
+        self.at = now;
+    }
+}
+
+#[derive(Clone)]
+pub struct Party {
+    pub code: String,
+    state: Arc<Mutex<PartyState>>,
+    /// bumped on every change, members send the new state to their client
+    version: watch::Sender<u64>,
+}
+
+/// State of the party sent to a member as `{"type": "party", ...}`
+#[derive(SerJson, Debug)]
+pub struct PartyView {
+    #[nserde(rename = "type")]
+    pub kind: String,
+    pub code: String,
+    /// member id of the client
+    pub you: u64,
+    pub host: bool,
+    pub hosts: Vec<u64>,
+    pub members: Vec<u64>,
+    pub queue: Vec<i32>,
+    pub current: Option<usize>,
+    pub playing: bool,
+    pub position: f64,
+    pub at: f64,
+}
+
+/// Reply to `party_clock`, the client computes its offset as
+/// `server_time - (client_time + now) / 2` when receiving it
+#[derive(SerJson, DeJson, Debug)]
+pub struct ClockReply {
+    #[nserde(rename = "type")]
+    pub kind: String,
+    pub id: u64,
+    pub client_time: f64,
+    pub server_time: f64,
+}
+
+#[derive(DeJson)]
+struct Clock {
+    client_time: f64,
+}
+
+#[derive(DeJson)]
+struct Join {
+    code: String,
+}
+
+#[derive(DeJson)]
+struct Control {
+    /// play, pause, seek, next, enqueue or promote
+    action: String,
+    /// play: index in the queue to start, seek: seconds
+    index: Option<usize>,
+    position: Option<f64>,
+    /// next: only goes to the next track if this one is playing, so that hosts
+    /// noticing the end of a track at the same time don't skip several
+    from: Option<usize>,
+    music_id: Option<MusicID>,
+    member: Option<u64>,
+}
+
+/// The parties going on, created in memory when a client asks for one
+#[derive(Clone)]
+pub struct Parties {
+    parties: Arc<Mutex<HashMap<String, Party>>>,
+    next_member: Arc<AtomicU64>,
+    rng: Arc<Mutex<StdRand>>,
+}
+
+/// The party a websocket client is in, leaving it when dropped
+pub struct Membership {
+    pub party: Party,
+    pub member: u64,
+    pub rx: watch::Receiver<u64>,
+    parties: Parties,
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        self.parties.leave(&self.party, self.member);
+    }
+}
+
+impl Default for Parties {
+    fn default() -> Self {
+        let seed = UNIX_EPOCH.elapsed().map(|d| d.as_nanos()).unwrap_or(0) as u64;
+        Self {
+            parties: Default::default(),
+            next_member: Arc::new(AtomicU64::new(1)),
+            rng: Arc::new(Mutex::new(StdRand::seed(seed))),
+        }
+    }
+}
+
+impl Parties {
+    fn new_code(&self, taken: &HashMap<String, Party>) -> String {
+        let mut rng = self.rng.lock().unwrap();
+        loop {
+            let code: String = (0..CODE_LEN)
+                .map(|_| CODE_CHARS[rng.next_range(0..CODE_CHARS.len())] as char)
+                .collect();
+            if !taken.contains_key(&code) {
+                return code;
+            }
+        }
+    }
+
+    /// Starts a party with the caller as its host
+    pub fn create(&self) -> Membership {
+        let mut parties = self.parties.lock().unwrap();
+        let code = self.new_code(&parties);
+        let party = Party {
+            code: code.clone(),
+            state: Arc::new(Mutex::new(PartyState {
+                hosts: BTreeSet::new(),
+                members: BTreeSet::new(),
+                queue: vec![],
+                current: None,
+                playing: false,
+                position: 0.0,
+                at: server_time(),
+            })),
+            version: watch::channel(0).0,
+        };
+        parties.insert(code, party.clone());
+        self.enter(party, true)
+    }
+
+    /// Joins the party with this code, codes aren't case sensitive
+    pub fn join(&self, code: &str) -> Option<Membership> {
+        let party = self.get(&code.trim().to_ascii_uppercase())?;
+        Some(self.enter(party, false))
+    }
+
+    pub fn get(&self, code: &str) -> Option<Party> {
+        self.parties.lock().unwrap().get(code).cloned()
+    }
+
+    fn enter(&self, party: Party, host: bool) -> Membership {
+        let member = self.next_member.fetch_add(1, Ordering::Relaxed);
+        let rx = party.version.subscribe();
+        party.update(|s| {
+            s.members.insert(member);
+            if host {
+                s.hosts.insert(member);
+            }
+        });
+        Membership {
+            party,
+            member,
+            rx,
+            parties: self.clone(),
+        }
+    }
+
+    /// The party ends with its last member, and someone takes over when the last host leaves
+    fn leave(&self, party: &Party, member: u64) {
+        let mut parties = self.parties.lock().unwrap();
+        let empty = party.update(|s| {
+            s.members.remove(&member);
+            s.hosts.remove(&member);
+            if s.hosts.is_empty() {
+                if let Some(&oldest) = s.members.iter().next() {
+                    s.hosts.insert(oldest);
+                }
+            }
+            s.members.is_empty()
+        });
+        if empty {
+            parties.remove(&party.code);
+        }
+    }
+}
+
+impl Party {
+    fn update<R>(&self, f: impl FnOnce(&mut PartyState) -> R) -> R {
+        let r = f(&mut self.state.lock().unwrap());
+        self.version.send_modify(|v| *v += 1);
+        r
+    }
+
+    pub fn view(&self, member: u64) -> PartyView {
+        let s = self.state.lock().unwrap();
+        let now = server_time();
+        PartyView {
+            kind: s!("party"),
+            code: self.code.clone(),
+            you: member,
+            host: s.hosts.contains(&member),
+            hosts: s.hosts.iter().copied().collect(),
+            members: s.members.iter().copied().collect(),
+            queue: s.queue.iter().map(|x| x.0).collect(),
+            current: s.current,
+            playing: s.playing,
+            position: s.position_at(now),
+            at: now,
+        }
+    }
+
+    fn control(&self, member: u64, c: &Control) -> StatusCode {
+        let mut s = self.state.lock().unwrap();
+        if !s.hosts.contains(&member) {
+            return StatusCode::FORBIDDEN;
+        }
+        let now = server_time();
+        match c.action.as_str() {
+            "play" => {
+                if let Some(index) = c.index {
+                    if index >= s.queue.len() {
+                        return StatusCode::BAD_REQUEST;
+                    }
+                    s.current = Some(index);
+                    s.set_position(0.0, now);
+                }
+                if s.current.is_none() {
+                    return StatusCode::BAD_REQUEST;
+                }
+                let position = s.position_at(now);
+                s.set_position(position, now);
+                s.playing = true;
+            }
+            "pause" => {
+                let position = s.position_at(now);
+                s.set_position(position, now);
+                s.playing = false;
+            }
+            "seek" => {
+                let position = unwrap_ret!(c.position, StatusCode::BAD_REQUEST);
+                s.set_position(position, now);
+            }
+            "next" => {
+                let current = unwrap_ret!(s.current, StatusCode::BAD_REQUEST);
+                if c.from.is_some_and(|from| from != current) {
+                    return StatusCode::OK;
+                }
+                if current + 1 < s.queue.len() {
+                    s.current = Some(current + 1);
+                } else {
+                    s.playing = false;
+                }
+                s.set_position(0.0, now);
+            }
+            "enqueue" => {
+                let id = unwrap_ret!(c.music_id, StatusCode::BAD_REQUEST);
+                s.queue.push(id);
+                if s.current.is_none() {
+                    s.current = Some(s.queue.len() - 1);
+                    s.set_position(0.0, now);
+                }
+            }
+            "promote" => {
+                let other = unwrap_ret!(c.member, StatusCode::BAD_REQUEST);
+                if !s.members.contains(&other) {
+                    return StatusCode::NOT_FOUND;
+                }
+                s.hosts.insert(other);
+            }
+            _ => return StatusCode::BAD_REQUEST,
+        }
+        drop(s);
+        self.version.send_modify(|v| *v += 1);
+        StatusCode::OK
+    }
+}
+
+pub fn is_party_command(cmd: &Command) -> bool {
+    cmd.cmd.starts_with("party_")
+}
+
+/// Runs a party command of a websocket client, and returns the reply to send.
+/// `party_create`, `party_join {code}`, `party_leave` and `party_control {action, ...}` are acked,
+/// the state of the party is then sent to its members whenever it changes.
+/// `party_clock {client_time}` is answered with a ClockReply.
+pub async fn run(
+    db: &Db,
+    parties: &Parties,
+    membership: &mut Option<Membership>,
+    cmd: &Command,
+    msg: &str,
+) -> String {
+    let id = cmd.id;
+    macro_rules! body {
+        ($t: ty) => {
+            match <$t>::deserialize_json(msg) {
+                Ok(x) => x,
+                Err(e) => return Ack::error(id, StatusCode::BAD_REQUEST, e).serialize_json(),
+            }
+        };
+    }
+
+    let status = match cmd.cmd.as_str() {
+        "party_clock" => {
+            let b = body!(Clock);
+            return ClockReply {
+                kind: s!("clock"),
+                id,
+                client_time: b.client_time,
+                server_time: server_time(),
+            }
+            .serialize_json();
+        }
+        "party_create" => {
+            *membership = None;
+            *membership = Some(parties.create());
+            StatusCode::OK
+        }
+        "party_join" => {
+            let b = body!(Join);
+            *membership = None;
+            *membership = parties.join(&b.code);
+            match membership {
+                Some(_) => StatusCode::OK,
+                None => StatusCode::NOT_FOUND,
+            }
+        }
+        "party_leave" => {
+            *membership = None;
+            StatusCode::OK
+        }
+        "party_control" => {
+            let b = body!(Control);
+            let m = match membership {
+                Some(m) => m,
+                None => {
+                    return Ack::error(id, StatusCode::BAD_REQUEST, "not in a party")
+                        .serialize_json()
+                }
+            };
+            if let Some(music_id) = b.music_id {
+                let exists = Music::exists(&*db.get().await, music_id);
+                match exists {
+                    Ok(true) => {}
+                    Ok(false) => return Ack::new(id, StatusCode::NOT_FOUND).serialize_json(),
+                    Err(e) => {
+                        return Ack::error(id, StatusCode::INTERNAL_SERVER_ERROR, e)
+                            .serialize_json()
+                    }
+                }
+            }
+            m.party.control(m.member, &b)
+        }
+        x => {
+            return Ack::error(
+                id,
+                StatusCode::BAD_REQUEST,
+                format!("unknown command: {}", x),
+            )
+            .serialize_json()
+        }
+    };
+    Ack::new(id, status).serialize_json()
+}
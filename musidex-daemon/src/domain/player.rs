@@ -0,0 +1,606 @@
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::domain::stream::source_path;
+use crate::infrastructure::db::Db;
+use anyhow::{Context, Result};
+use hyper::body::Bytes;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tinyrand::{RandRange, Seeded, StdRand};
+use tokio::sync::{broadcast, watch};
+
+/// bitrate assumed when a music has no duration tag, 128kbps
+const DEFAULT_BYTES_PER_SEC: u64 = 16000;
+const CHUNKS_PER_SEC: u64 = 10;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PlayState {
+    Stop,
+    Play,
+    Pause,
+}
+
+/// What changed, as in the MPD idle command
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Subsystem {
+    Database,
+    Playlist,
+    Player,
+    Mixer,
+    Options,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueueItem {
+    /// stays the same when the queue is reordered
+    pub id: u32,
+    pub music_id: MusicID,
+}
+
+/// Where the audio goes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Output {
+    /// sent to whoever listens to /api/mpd/stream, at the playback speed
+    Http,
+    /// a local player, {file}, {start} (seconds) and {volume} (0-100) are replaced.
+    /// For example: mpv --no-video --start={start} --volume={volume} {file}
+    Command(String),
+}
+
+/// Server-side play queue, controlled by the MPD server
+pub struct PlayerState {
+    pub queue: Vec<QueueItem>,
+    /// incremented on every queue change
+    pub version: u32,
+    next_id: u32,
+    pub state: PlayState,
+    /// position in the queue
+    pub current: Option<usize>,
+    elapsed: Duration,
+    resumed_at: Option<Instant>,
+    pub volume: u8,
+    pub repeat: bool,
+    pub random: bool,
+    pub single: bool,
+    pub consume: bool,
+    /// incremented every time the output has to start over, so that stale song ends are ignored
+    pub generation: u64,
+    rng: StdRand,
+}
+
+#[derive(Clone)]
+pub struct Player {
+    state: Arc<Mutex<PlayerState>>,
+    events: broadcast::Sender<Subsystem>,
+    generation: watch::Sender<u64>,
+    audio: broadcast::Sender<Bytes>,
+    pub output: Output,
+    storage: PathBuf,
+    pub started: Instant,
+}
+
+impl PlayState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PlayState::Stop => "stop",
+            PlayState::Play => "play",
+            PlayState::Pause => "pause",
+        }
+    }
+}
+
+impl Subsystem {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Subsystem::Database => "database",
+            Subsystem::Playlist => "playlist",
+            Subsystem::Player => "player",
+            Subsystem::Mixer => "mixer",
+            Subsystem::Options => "options",
+        }
+    }
+
+    pub fn parse(v: &str) -> Option<Subsystem> {
+        Some(match v {
+            "database" => Subsystem::Database,
+            "playlist" => Subsystem::Playlist,
+            "player" => Subsystem::Player,
+            "mixer" => Subsystem::Mixer,
+            "options" => Subsystem::Options,
+            _ => return None,
+        })
+    }
+}
+
+impl Output {
+    pub fn parse(v: &str) -> Output {
+        match v {
+            "" | "http" => Output::Http,
+            cmd => Output::Command(cmd.to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Output::Http => "http",
+            Output::Command(_) => "command",
+        }
+    }
+}
+
+impl Default for PlayerState {
+    fn default() -> Self {
+        let seed = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Self {
+            queue: vec![],
+            version: 1,
+            next_id: 0,
+            state: PlayState::Stop,
+            current: None,
+            elapsed: Duration::ZERO,
+            resumed_at: None,
+            volume: 100,
+            repeat: false,
+            random: false,
+            single: false,
+            consume: false,
+            generation: 0,
+            rng: StdRand::seed(seed),
+        }
+    }
+}
+
+impl PlayerState {
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed + self.resumed_at.map(|x| x.elapsed()).unwrap_or_default()
+    }
+
+    pub fn current_item(&self) -> Option<&QueueItem> {
+        self.queue.get(self.current?)
+    }
+
+    pub fn pos_of(&self, id: u32) -> Option<usize> {
+        self.queue.iter().position(|x| x.id == id)
+    }
+
+    /// Adds the music at the given position or at the end, returns its queue id
+    pub fn add(&mut self, music_id: MusicID, pos: Option<usize>) -> Option<u32> {
+        let pos = pos.unwrap_or(self.queue.len());
+        if pos > self.queue.len() {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queue.insert(pos, QueueItem { id, music_id });
+        if let Some(ref mut cur) = self.current {
+            if pos <= *cur {
+                *cur += 1;
+            }
+        }
+        self.version += 1;
+        Some(id)
+    }
+
+    /// Removes the songs at the positions, playback continues with the next song
+    /// if the current one is removed
+    pub fn delete(&mut self, start: usize, end: usize) -> bool {
+        if start >= end || end > self.queue.len() {
+            return false;
+        }
+        self.queue.drain(start..end);
+        self.version += 1;
+        match self.current {
+            Some(cur) if cur >= end => self.current = Some(cur - (end - start)),
+            Some(cur) if cur >= start => {
+                if start < self.queue.len() && self.state != PlayState::Stop {
+                    let state = self.state;
+                    self.start(start, Duration::ZERO);
+                    if state == PlayState::Pause {
+                        self.pause(Some(true));
+                    }
+                } else {
+                    self.stop();
+                    self.current = None;
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.version += 1;
+        self.stop();
+        self.current = None;
+    }
+
+    pub fn move_item(&mut self, from: usize, to: usize) -> bool {
+        if from >= self.queue.len() || to >= self.queue.len() {
+            return false;
+        }
+        let item = self.queue.remove(from);
+        self.queue.insert(to, item);
+        if let Some(cur) = self.current {
+            self.current = Some(if cur == from {
+                to
+            } else if from < cur && cur <= to {
+                cur - 1
+            } else if to <= cur && cur < from {
+                cur + 1
+            } else {
+                cur
+            });
+        }
+        self.version += 1;
+        true
+    }
+
+    fn start(&mut self, pos: usize, elapsed: Duration) {
+        self.current = Some(pos);
+        self.state = PlayState::Play;
+        self.elapsed = elapsed;
+        self.resumed_at = Some(Instant::now());
+        self.generation += 1;
+    }
+
+    /// Plays the song at pos, or resumes/starts the current song if None
+    pub fn play(&mut self, pos: Option<usize>) -> bool {
+        match pos {
+            Some(pos) if pos >= self.queue.len() => false,
+            Some(pos) => {
+                self.start(pos, Duration::ZERO);
+                true
+            }
+            None => {
+                match self.state {
+                    PlayState::Play => {}
+                    PlayState::Pause => self.pause(Some(false)),
+                    PlayState::Stop => {
+                        let pos = self.current.unwrap_or(0);
+                        if pos >= self.queue.len() {
+                            return false;
+                        }
+                        self.start(pos, Duration::ZERO);
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    /// Toggles pause if None
+    pub fn pause(&mut self, pause: Option<bool>) {
+        let pause = pause.unwrap_or(self.state == PlayState::Play);
+        match (self.state, pause) {
+            (PlayState::Play, true) => {
+                self.elapsed = self.elapsed();
+                self.resumed_at = None;
+                self.state = PlayState::Pause;
+            }
+            (PlayState::Pause, false) => {
+                self.resumed_at = Some(Instant::now());
+                self.state = PlayState::Play;
+            }
+            _ => return,
+        }
+        self.generation += 1;
+    }
+
+    pub fn stop(&mut self) {
+        if self.state == PlayState::Stop {
+            return;
+        }
+        self.state = PlayState::Stop;
+        self.elapsed = Duration::ZERO;
+        self.resumed_at = None;
+        self.generation += 1;
+    }
+
+    /// Seeks to the given position in the song at pos, starts playing if stopped
+    pub fn seek(&mut self, pos: usize, to: Duration) -> bool {
+        if pos >= self.queue.len() {
+            return false;
+        }
+        let paused = self.state == PlayState::Pause;
+        self.start(pos, to);
+        if paused {
+            self.pause(Some(true));
+        }
+        true
+    }
+
+    /// Position of the song after the current one when it is skipped (or ended)
+    fn next_pos(&mut self, ended: bool) -> Option<usize> {
+        let cur = self.current?;
+        let len = self.queue.len();
+        if ended && self.single {
+            return self.repeat.then_some(cur);
+        }
+        if self.random && len > 1 {
+            let next = self.rng.next_range(0..len - 1);
+            return Some(if next >= cur { next + 1 } else { next });
+        }
+        if cur + 1 < len {
+            return Some(cur + 1);
+        }
+        (self.repeat && len > 0).then_some(0)
+    }
+
+    /// The next song when it is known in advance, for status
+    pub fn peek_next(&self) -> Option<usize> {
+        if self.random {
+            return None;
+        }
+        let cur = self.current?;
+        if cur + 1 < self.queue.len() {
+            return Some(cur + 1);
+        }
+        self.repeat.then_some(0)
+    }
+
+    pub fn next(&mut self) {
+        match self.next_pos(false) {
+            Some(pos) => self.start(pos, Duration::ZERO),
+            None => {
+                self.stop();
+                self.current = None;
+            }
+        }
+    }
+
+    pub fn previous(&mut self) {
+        let Some(cur) = self.current else { return };
+        let pos = match cur {
+            0 if self.repeat => self.queue.len() - 1,
+            0 => 0,
+            _ => cur - 1,
+        };
+        self.start(pos, Duration::ZERO);
+    }
+
+    /// Called by the output when the song was played until the end
+    pub fn song_ended(&mut self, generation: u64) {
+        if generation != self.generation || self.state != PlayState::Play {
+            return;
+        }
+        let Some(cur) = self.current else { return };
+        if self.consume {
+            self.queue.remove(cur);
+            self.version += 1;
+            // the song after the consumed one is now at cur
+            let next = if self.single {
+                None
+            } else if cur < self.queue.len() {
+                Some(cur)
+            } else {
+                (self.repeat && !self.queue.is_empty()).then_some(0)
+            };
+            match next {
+                Some(pos) => self.start(pos, Duration::ZERO),
+                None => {
+                    self.stop();
+                    self.current = None;
+                }
+            }
+            return;
+        }
+        match self.next_pos(true) {
+            Some(pos) => self.start(pos, Duration::ZERO),
+            None => {
+                self.stop();
+                self.current = None;
+            }
+        }
+    }
+
+    pub fn set_volume(&mut self, volume: u8) {
+        let volume = volume.min(100);
+        if volume != self.volume {
+            self.volume = volume;
+            if matches!(self.state, PlayState::Play) {
+                // local players only take the volume when starting
+                self.elapsed = self.elapsed();
+                self.resumed_at = Some(Instant::now());
+                self.generation += 1;
+            }
+        }
+    }
+}
+
+/// Kills the local player when playback moves on
+struct KillOnDrop(Child);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+pub fn shell_quote(v: &str) -> String {
+    format!("'{}'", v.replace('\'', "'\\''"))
+}
+
+impl Player {
+    pub fn new(output: Output, storage: PathBuf) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(PlayerState::default())),
+            events: broadcast::channel(128).0,
+            generation: watch::channel(0).0,
+            audio: broadcast::channel(64).0,
+            output,
+            storage,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn get(&self) -> MutexGuard<'_, PlayerState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Changes the state, notifying idle clients and the output of what changed
+    pub fn update<R>(&self, f: impl FnOnce(&mut PlayerState) -> R) -> R {
+        let mut s = self.get();
+        let before = (
+            s.version,
+            (s.state, s.current, s.generation),
+            s.volume,
+            (s.repeat, s.random, s.single, s.consume),
+        );
+        let r = f(&mut s);
+        let generation = s.generation;
+        let changed = [
+            (before.0 != s.version, Subsystem::Playlist),
+            (
+                before.1 != (s.state, s.current, s.generation),
+                Subsystem::Player,
+            ),
+            (before.2 != s.volume, Subsystem::Mixer),
+            (
+                before.3 != (s.repeat, s.random, s.single, s.consume),
+                Subsystem::Options,
+            ),
+        ];
+        drop(s);
+        for (did_change, subsystem) in changed {
+            if did_change {
+                let _ = self.events.send(subsystem);
+            }
+        }
+        self.generation.send_if_modified(|g| {
+            let modified = *g != generation;
+            *g = generation;
+            modified
+        });
+        r
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Subsystem> {
+        self.events.subscribe()
+    }
+
+    pub fn notify(&self, subsystem: Subsystem) {
+        let _ = self.events.send(subsystem);
+    }
+
+    /// The audio sent to the http output
+    pub fn listen(&self) -> broadcast::Receiver<Bytes> {
+        self.audio.subscribe()
+    }
+
+    /// Starts the task sending the current song to the output
+    pub fn start(&self, db: Db) {
+        let player = self.clone();
+        tokio::spawn(async move {
+            let mut generation = player.generation.subscribe();
+            loop {
+                generation.borrow_and_update();
+                let job = {
+                    let s = player.get();
+                    match (s.state, s.current_item()) {
+                        (PlayState::Play, Some(item)) => {
+                            Some((s.generation, item.music_id, s.elapsed(), s.volume))
+                        }
+                        _ => None,
+                    }
+                };
+                let (gen, music_id, start, volume) = match job {
+                    Some(x) => x,
+                    None => {
+                        if generation.changed().await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+
+                let res = tokio::select! {
+                    r = player.play_song(&db, music_id, start, volume) => Some(r),
+                    _ = generation.changed() => None,
+                };
+                match res {
+                    Some(Ok(())) => player.update(|s| s.song_ended(gen)),
+                    Some(Err(e)) => {
+                        log::error!("error playing {:?}: {:?}", music_id, e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        player.update(|s| s.song_ended(gen))
+                    }
+                    None => {}
+                }
+            }
+        });
+    }
+
+    async fn play_song(&self, db: &Db, id: MusicID, start: Duration, volume: u8) -> Result<()> {
+        let (path, duration) = {
+            let c = db.get().await;
+            let path = source_path(&c, id)?.context("no playable source")?;
+            let duration = Tag::by_id_key(&c, id, &TagKey::Duration)?.and_then(|t| t.integer);
+            (path, duration)
+        };
+        let file = self.storage.join(path);
+        match self.output {
+            Output::Http => {
+                pump(&file, duration, start, |chunk| {
+                    // nobody listening is fine
+                    let _ = self.audio.send(chunk);
+                })
+                .await
+            }
+            Output::Command(ref cmd) => run_command(cmd, &file, start, volume).await,
+        }
+    }
+}
+
+/// Sends the file in chunks at the speed it plays, starting at the elapsed time
+pub async fn pump(
+    file: &Path,
+    duration: Option<i64>,
+    start: Duration,
+    mut send: impl FnMut(Bytes),
+) -> Result<()> {
+    let buf = Bytes::from(tokio::fs::read(file).await?);
+    let len = buf.len() as u64;
+    let bytes_per_sec = match duration {
+        Some(d) if d > 0 => (len / d as u64).max(1),
+        _ => DEFAULT_BYTES_PER_SEC,
+    };
+    let chunk = (bytes_per_sec / CHUNKS_PER_SEC).max(1) as usize;
+    let mut offset = ((start.as_millis() as u64 * bytes_per_sec / 1000) as usize).min(buf.len());
+
+    let mut interval = tokio::time::interval(Duration::from_millis(1000 / CHUNKS_PER_SEC));
+    while offset < buf.len() {
+        interval.tick().await;
+        let end = (offset + chunk).min(buf.len());
+        send(buf.slice(offset..end));
+        offset = end;
+    }
+    Ok(())
+}
+
+async fn run_command(cmd: &str, file: &Path, start: Duration, volume: u8) -> Result<()> {
+    let cmd = cmd
+        .replace("{file}", &shell_quote(&file.to_string_lossy()))
+        .replace("{start}", &start.as_secs().to_string())
+        .replace("{volume}", &volume.to_string());
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(&cmd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("error starting output command {}", cmd))?;
+    let mut child = KillOnDrop(child);
+    loop {
+        if child.0.try_wait()?.is_some() {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
@@ -0,0 +1,395 @@
+//! M3U8 and XSPF playlists, to move a library or playlist in and out of other players.
+//! Imported entries are matched to existing musics, unknown YouTube urls are downloaded.
+
+use crate::domain::entity::{Music, MusicID, Tag, TagKey, User};
+use crate::domain::history::Editor;
+use crate::domain::library::{Library, Song};
+use crate::domain::trash;
+use crate::domain::upload;
+use crate::infrastructure::db::Db;
+use crate::infrastructure::xml;
+use crate::utils::row_missing_opt;
+use anyhow::Result;
+use nanoserde::SerJson;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+const SOURCE_KEYS: [TagKey; 4] = [
+    TagKey::LocalMP3,
+    TagKey::LocalOGG,
+    TagKey::LocalM4A,
+    TagKey::LocalWEBM,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    M3u8,
+    Xspf,
+}
+
+/// Where exported entries point to
+pub enum Locations {
+    /// `/api/stream/:id` of the server at this base url
+    Stream(String),
+    /// files relative to the storage directory
+    Local,
+}
+
+/// An entry of an imported playlist, anything could be missing
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct PlaylistEntry {
+    pub location: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    /// sha1 of the file, hex encoded
+    pub hash: Option<String>,
+}
+
+#[derive(SerJson, Default, Debug)]
+pub struct ImportReport {
+    /// musics of the playlist, in its order
+    pub matched: Vec<i32>,
+    /// YouTube urls sent to youtube-dl
+    pub queued: Vec<String>,
+    /// entries that matched nothing
+    pub unmatched: Vec<String>,
+}
+
+fn file_hash(c: &Connection, path: &str) -> Result<Option<String>> {
+    let mut stmt = c.prepare_cached("SELECT hash FROM file_hashes WHERE path=?1;")?;
+    Ok(row_missing_opt(stmt.query_row([path], |row| row.get(0)))?)
+}
+
+fn youtube_url(c: &Connection, id: MusicID) -> Result<Option<String>> {
+    Ok(Tag::by_id_key(c, id, &TagKey::YoutubeDLURL)?.and_then(|t| t.text))
+}
+
+/// The library of the user, or its musics with this user_tag, as a playlist document.
+/// Musics that aren't downloaded are exported as their YouTube url if they have one.
+pub fn export(
+    c: &Connection,
+    user: User,
+    playlist: Option<&str>,
+    locations: &Locations,
+    format: Format,
+) -> Result<String> {
+    let title = match playlist {
+        Some(p) => format!("{} - {}", user.name, p),
+        None => format!("{} - musidex", user.name),
+    };
+    let library = Library::load(c, user)?;
+    let songs = library
+        .songs
+        .iter()
+        .filter(|s| playlist.is_none_or(|p| s.playlists.iter().any(|x| x == p)));
+
+    let mut out = match format {
+        Format::M3u8 => format!("#EXTM3U\n#PLAYLIST:{}\n", title.replace('\n', " ")),
+        Format::Xspf => format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\
+            <title>{}</title><trackList>",
+            xml::escape(&title)
+        ),
+    };
+    for song in songs {
+        let location = match (&song.path, locations) {
+            (Some(_), Locations::Stream(base)) => format!("{}/api/stream/{}", base, song.id.0),
+            (Some(path), Locations::Local) => path.clone(),
+            (None, _) => unwrap_cont!(youtube_url(c, song.id)?),
+        };
+        let hash = match song.path {
+            Some(ref path) => file_hash(c, path)?,
+            None => None,
+        };
+        match format {
+            Format::M3u8 => write_m3u8(&mut out, song, &location),
+            Format::Xspf => write_xspf(&mut out, song, &location, hash),
+        }
+    }
+    if format == Format::Xspf {
+        out.push_str("</trackList></playlist>\n");
+    }
+    Ok(out)
+}
+
+fn write_m3u8(out: &mut String, song: &Song, location: &str) {
+    let _ = writeln!(
+        out,
+        "#EXTINF:{},{} - {}\n{}",
+        song.duration.unwrap_or(-1),
+        song.artist.replace('\n', " "),
+        song.title.replace('\n', " "),
+        location
+    );
+}
+
+fn write_xspf(out: &mut String, song: &Song, location: &str, hash: Option<String>) {
+    let _ = write!(
+        out,
+        "<track><location>{}</location><title>{}</title><creator>{}</creator>",
+        xml::escape(location),
+        xml::escape(&song.title),
+        xml::escape(&song.artist)
+    );
+    if let Some(d) = song.duration {
+        let _ = write!(out, "<duration>{}</duration>", d * 1000);
+    }
+    if let Some(h) = hash {
+        let _ = write!(out, "<identifier>sha1:{}</identifier>", h);
+    }
+    out.push_str("</track>");
+}
+
+/// Entries of an M3U, M3U8 or XSPF document
+pub fn parse(doc: &str) -> Vec<PlaylistEntry> {
+    match doc.trim_start().starts_with('<') {
+        true => parse_xspf(doc),
+        false => parse_m3u(doc),
+    }
+}
+
+fn parse_m3u(doc: &str) -> Vec<PlaylistEntry> {
+    let mut entries = vec![];
+    let mut current = PlaylistEntry::default();
+    for line in doc.lines().map(str::trim) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:123,Artist - Title
+            let name = info.split_once(',').map(|x| x.1.trim()).unwrap_or_default();
+            match name.split_once(" - ") {
+                Some((artist, title)) => {
+                    current.artist = Some(artist.trim().to_string());
+                    current.title = Some(title.trim().to_string());
+                }
+                None if !name.is_empty() => current.title = Some(name.to_string()),
+                None => {}
+            }
+        } else if !line.is_empty() && !line.starts_with('#') {
+            current.location = Some(line.to_string());
+            entries.push(std::mem::take(&mut current));
+        }
+    }
+    entries
+}
+
+fn parse_xspf(doc: &str) -> Vec<PlaylistEntry> {
+    let mut entries = vec![];
+    let mut rest = doc;
+    while let Some(start) = rest.find("<track>") {
+        rest = &rest[start..];
+        let end = rest.find("</track>").unwrap_or(rest.len());
+        let track = &rest[..end];
+        rest = &rest[end..];
+        let text = |name| xml::element_text(track, name).filter(|x| !x.is_empty());
+        entries.push(PlaylistEntry {
+            location: text("location"),
+            title: text("title"),
+            artist: text("creator"),
+            hash: text("identifier").and_then(|x| x.strip_prefix("sha1:").map(str::to_string)),
+        });
+    }
+    entries
+}
+
+/// Video id of a YouTube url, None if it isn't one
+pub fn youtube_id(url: &str) -> Option<&str> {
+    let rest = url.split_once("://").map(|x| x.1).unwrap_or(url);
+    let rest = rest.strip_prefix("www.").unwrap_or(rest);
+    let rest = rest.strip_prefix("m.").unwrap_or(rest);
+    let id = if let Some(path) = rest.strip_prefix("youtu.be/") {
+        path.split(['?', '&', '#']).next()?
+    } else if rest.starts_with("youtube.com/") || rest.starts_with("music.youtube.com/") {
+        let query = rest.split_once('?')?.1;
+        query.split('&').find_map(|kv| kv.strip_prefix("v="))?
+    } else {
+        return None;
+    };
+    (!id.is_empty()).then_some(id)
+}
+
+/// Finds the musics imported entries are about, built once per import
+struct Matcher {
+    /// base url of this server, only its stream urls point to our musics
+    base: String,
+    /// file name of the audio files
+    files: HashMap<String, MusicID>,
+    /// lowercase title, then lowercase artist
+    titles: HashMap<String, Vec<(Option<String>, MusicID)>>,
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+impl Matcher {
+    fn new(c: &Connection, base: &str) -> Result<Matcher> {
+        let mut files = HashMap::new();
+        for key in &SOURCE_KEYS {
+            for tag in Tag::by_key_visible(c, key)? {
+                let path = unwrap_cont!(tag.text);
+                files.insert(file_name(&path).to_string(), tag.music_id);
+            }
+        }
+        let artists: HashMap<MusicID, String> = Tag::by_key(c, &TagKey::Artist)?
+            .into_iter()
+            .filter_map(|t| Some((t.music_id, t.text?.to_lowercase())))
+            .collect();
+        let mut titles: HashMap<String, Vec<_>> = HashMap::new();
+        for tag in Tag::by_key_visible(c, &TagKey::Title)? {
+            let title = unwrap_cont!(tag.text).to_lowercase();
+            let artist = artists.get(&tag.music_id).cloned();
+            titles
+                .entry(title)
+                .or_default()
+                .push((artist, tag.music_id));
+        }
+        Ok(Matcher {
+            base: base.trim_end_matches('/').to_string(),
+            files,
+            titles,
+        })
+    }
+
+    /// Tries by YouTube url, by our own stream url, by file hash, by file name,
+    /// then by title and artist
+    fn find(&self, c: &Connection, e: &PlaylistEntry) -> Result<Option<MusicID>> {
+        if let Some(ref location) = e.location {
+            if let Some(id) = find_youtube(c, location)? {
+                return Ok(Some(id));
+            }
+            let stream_id = location
+                .strip_prefix(self.base.as_str())
+                .and_then(|x| x.strip_prefix("/api/stream/"))
+                .and_then(|x| x.parse().ok())
+                .map(MusicID);
+            if let Some(id) = stream_id {
+                if Music::exists(c, id)? {
+                    return Ok(Some(id));
+                }
+            }
+        }
+        if let Some(ref hash) = e.hash {
+            let mut stmt = c.prepare_cached("SELECT path FROM file_hashes WHERE hash=?1;")?;
+            let path: Option<String> = row_missing_opt(stmt.query_row([hash], |row| row.get(0)))?;
+            if let Some(id) = path.and_then(|p| self.files.get(file_name(&p))) {
+                return Ok(Some(*id));
+            }
+        }
+        if let Some(ref location) = e.location {
+            let location = location.strip_prefix("file://").unwrap_or(location);
+            if let Some(id) = self.files.get(file_name(location)) {
+                return Ok(Some(*id));
+            }
+        }
+        if let Some(ref title) = e.title {
+            let artist = e.artist.as_ref().map(|x| x.to_lowercase());
+            let found = self
+                .titles
+                .get(&title.to_lowercase())
+                .and_then(|candidates| {
+                    candidates
+                        .iter()
+                        .find(|(a, _)| artist.is_none() || *a == artist)
+                        .map(|x| x.1)
+                });
+            return Ok(found);
+        }
+        Ok(None)
+    }
+}
+
+/// The music downloaded from this YouTube url, if any
+pub fn find_youtube(c: &Connection, url: &str) -> Result<Option<MusicID>> {
+    let id = unwrap_ret!(youtube_id(url), Ok(None));
+    for t in Tag::by_key_text(c, &TagKey::YoutubeDLURL, url)? {
+        if !trash::contains(c, t.music_id)? {
+            return Ok(Some(t.music_id));
+        }
+    }
+    upload::id_exists(c, id)
+}
+
+fn add_to_playlist(
+    c: &Connection,
+    editor: &Editor,
+    id: MusicID,
+    playlist: Option<&str>,
+) -> Result<()> {
+    let library = TagKey::UserLibrary(editor.user_id.0.to_string());
+    if !Tag::has(c, id, &library)? {
+        let max = Tag::max_integer_by_key(c, &library)?.unwrap_or(0);
+        editor.insert_tag(c, Tag::new_integer(id, library, max + 100))?;
+    }
+    if let Some(p) = playlist {
+        let key = TagKey::UserTag(p.to_string());
+        if !Tag::has(c, id, &key)? {
+            editor.insert_tag(c, Tag::new_key(id, key))?;
+        }
+    }
+    Ok(())
+}
+
+/// Adds the musics matching the entries to the library of the editor, and tags them with
+/// the playlist name if any. `base` is the url of this server, for entries exported with
+/// `Locations::Stream`. Unmatched YouTube urls are returned in `queued` for queue_uploads.
+pub fn import(
+    c: &mut Connection,
+    editor: &Editor,
+    base: &str,
+    playlist: Option<&str>,
+    entries: &[PlaylistEntry],
+) -> Result<ImportReport> {
+    let tx = c.transaction()?;
+    let matcher = Matcher::new(&tx, base)?;
+    let mut report = ImportReport::default();
+    let mut matched = vec![];
+    for e in entries {
+        match matcher.find(&tx, e)? {
+            Some(id) => matched.push(id),
+            None => match e.location {
+                Some(ref url) if youtube_id(url).is_some() => report.queued.push(url.clone()),
+                _ => report.unmatched.push(describe(e)),
+            },
+        }
+    }
+    // the first entry ends up on top of the library
+    for &id in matched.iter().rev() {
+        add_to_playlist(&tx, editor, id, playlist)?;
+    }
+    tx.commit()?;
+    report.matched = matched.into_iter().map(|x| x.0).collect();
+    Ok(report)
+}
+
+fn describe(e: &PlaylistEntry) -> String {
+    match (&e.artist, &e.title, &e.location) {
+        (Some(artist), Some(title), _) => format!("{} - {}", artist, title),
+        (_, _, Some(location)) => location.clone(),
+        (_, Some(title), None) => title.clone(),
+        _ => s!("?"),
+    }
+}
+
+/// Downloads the YouTube urls one after the other, adding them to the playlist once known
+pub fn queue_uploads(db: Db, editor: Editor, playlist: Option<String>, urls: Vec<String>) {
+    if urls.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        for url in urls {
+            let mut c = db.get().await;
+            if let Err(e) = upload::youtube_upload(&mut c, &editor, url.clone()).await {
+                log::error!("error importing {}: {:?}", url, e);
+                continue;
+            }
+            let added = find_youtube(&c, &url).and_then(|id| match id {
+                Some(id) => add_to_playlist(&c, &editor, id, playlist.as_deref()),
+                None => Ok(()),
+            });
+            if let Err(e) = added {
+                log::error!("error adding {} to the playlist: {:?}", url, e);
+            }
+        }
+    });
+}
@@ -0,0 +1,425 @@
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use anyhow::{Context, Result};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A small query language over tags, for example:
+///
+/// `artist="Daft Punk" and duration>=180 and has:user_library:*`
+/// `(title~love or title~amour) and not has:user_tag:skip`
+///
+/// - `key=v`, `key!=v`, `key<v`, `key<=v`, `key>v`, `key>=v` compare the tag value.
+///   Integers are compared against `integer`, `YYYY-MM(-DD)` literals against `date`
+///   and everything else against `text`.
+/// - `key~v` is a case insensitive substring match on `text`.
+/// - `has:key` matches musics having the tag.
+/// - A key ending with `*` is a prefix match, `user_library:*` matches every library.
+/// - A bare word is a case insensitive substring match on any text tag.
+/// - Terms are combined with `and`, `or`, `not` and parenthesis, `and` being implied.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+    Has(KeyPattern),
+    Cmp(KeyPattern, CmpOp, Value),
+    Text(String),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyPattern {
+    Exact(TagKey),
+    Prefix(String),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    /// only matches musics that have the key, use `not k=v` to include the others
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Integer(i64),
+    Date(String),
+    Text(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Op(CmpOp),
+    Word(String, bool),
+}
+
+impl KeyPattern {
+    pub fn parse(s: &str) -> KeyPattern {
+        match s.strip_suffix('*') {
+            Some(prefix) => KeyPattern::Prefix(prefix.to_string()),
+            None => KeyPattern::Exact(s.into()),
+        }
+    }
+
+    /// Parses a comma separated list of key patterns such as `title,artist,user_library:*`
+    pub fn parse_list(s: &str) -> Vec<KeyPattern> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(KeyPattern::parse)
+            .collect()
+    }
+
+    pub fn matches(&self, key: &TagKey) -> bool {
+        match self {
+            KeyPattern::Exact(k) => k == key,
+            KeyPattern::Prefix(prefix) => {
+                let s: String = key.into();
+                s.starts_with(prefix.as_str())
+            }
+        }
+    }
+}
+
+impl Value {
+    fn parse(s: String, quoted: bool) -> Value {
+        if quoted {
+            return Value::Text(s);
+        }
+        if let Ok(v) = s.parse() {
+            return Value::Integer(v);
+        }
+        if DATE_LITERAL.is_match(&s) {
+            return Value::Date(s);
+        }
+        Value::Text(s)
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref DATE_LITERAL: regex::Regex = regex::Regex::new(r"^\d{4}-\d{2}(-\d{2})?$").unwrap();
+}
+
+impl CmpOp {
+    fn test(self, tag: &Tag, v: &Value) -> bool {
+        let ord = match (self, v) {
+            (CmpOp::Contains, v) => {
+                let needle = match v {
+                    Value::Integer(i) => i.to_string(),
+                    Value::Date(s) | Value::Text(s) => s.clone(),
+                };
+                return tag
+                    .text
+                    .as_ref()
+                    .map(|t| t.to_lowercase().contains(&needle.to_lowercase()))
+                    .unwrap_or(false);
+            }
+            (_, Value::Integer(i)) => unwrap_ret!(tag.integer, false).cmp(i),
+            (CmpOp::Eq | CmpOp::Ne, Value::Date(d)) => {
+                let eq = unwrap_ret!(tag.date.as_ref(), false).starts_with(d.as_str());
+                return eq == (self == CmpOp::Eq);
+            }
+            (_, Value::Date(d)) => {
+                let date = unwrap_ret!(tag.date.as_ref(), false);
+                let date = date.get(..d.len()).unwrap_or(date);
+                date.cmp(d.as_str())
+            }
+            (_, Value::Text(s)) => unwrap_ret!(tag.text.as_ref(), false).cmp(s),
+        };
+        match self {
+            CmpOp::Eq => ord == Ordering::Equal,
+            CmpOp::Ne => ord != Ordering::Equal,
+            CmpOp::Lt => ord == Ordering::Less,
+            CmpOp::Le => ord != Ordering::Greater,
+            CmpOp::Gt => ord == Ordering::Greater,
+            CmpOp::Ge => ord != Ordering::Less,
+            CmpOp::Contains => unreachable!(),
+        }
+    }
+}
+
+impl Query {
+    pub fn parse(s: &str) -> Result<Query> {
+        let tokens = tokenize(s)?;
+        let mut p = Parser { tokens, pos: 0 };
+        let q = p.parse_or()?;
+        if let Some(t) = p.tokens.get(p.pos) {
+            bail!("unexpected token {:?} at position {}", t, p.pos);
+        }
+        Ok(q)
+    }
+
+    /// Returns whether the music described by the given tags matches the query
+    pub fn matches(&self, tags: &[&Tag]) -> bool {
+        match self {
+            Query::Has(k) => tags.iter().any(|t| k.matches(&t.key)),
+            Query::Cmp(k, CmpOp::Ne, v) => {
+                tags.iter().any(|t| k.matches(&t.key))
+                    && !Query::Cmp(k.clone(), CmpOp::Eq, v.clone()).matches(tags)
+            }
+            Query::Cmp(k, op, v) => tags.iter().any(|t| k.matches(&t.key) && op.test(t, v)),
+            Query::Text(s) => {
+                let s = s.to_lowercase();
+                tags.iter().any(|t| {
+                    t.text
+                        .as_ref()
+                        .map(|x| x.to_lowercase().contains(&s))
+                        .unwrap_or(false)
+                })
+            }
+            Query::And(a, b) => a.matches(tags) && b.matches(tags),
+            Query::Or(a, b) => a.matches(tags) || b.matches(tags),
+            Query::Not(a) => !a.matches(tags),
+        }
+    }
+
+    /// Filters the given musics, returning the ones that match the query
+    pub fn filter(&self, musics: &[MusicID], tags: &[Tag]) -> Vec<MusicID> {
+        let mut by_music: HashMap<MusicID, Vec<&Tag>> = HashMap::with_capacity(musics.len());
+        for t in tags {
+            by_music.entry(t.music_id).or_default().push(t);
+        }
+        musics
+            .iter()
+            .filter(|id| self.matches(by_music.get(id).map(|x| x.as_slice()).unwrap_or_default()))
+            .copied()
+            .collect()
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::LParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::RParen);
+            }
+            '=' | '!' | '<' | '>' | '~' => tokens.push(Token::Op(parse_op(&mut chars)?)),
+            '"' => {
+                chars.next();
+                let mut w = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => w.push(chars.next().context("unfinished escape")?),
+                        Some(c) => w.push(c),
+                        None => bail!("unterminated string"),
+                    }
+                }
+                tokens.push(Token::Word(w, true));
+            }
+            _ => {
+                let mut w = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()=!<>~\"".contains(c) {
+                        break;
+                    }
+                    w.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(w, false));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_op(chars: &mut Peekable<Chars>) -> Result<CmpOp> {
+    let c = chars.next().context("expected operator")?;
+    let eq_follows = chars.peek() == Some(&'=');
+    if eq_follows {
+        chars.next();
+    }
+    Ok(match (c, eq_follows) {
+        ('=', _) => CmpOp::Eq,
+        ('!', true) => CmpOp::Ne,
+        ('<', false) => CmpOp::Lt,
+        ('<', true) => CmpOp::Le,
+        ('>', false) => CmpOp::Gt,
+        ('>', true) => CmpOp::Ge,
+        ('~', false) => CmpOp::Contains,
+        _ => bail!("invalid operator starting with {}", c),
+    })
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn peek_keyword(&self, kw: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w, false)) if w.eq_ignore_ascii_case(kw))
+    }
+
+    fn parse_or(&mut self) -> Result<Query> {
+        let mut q = self.parse_and()?;
+        while self.peek_keyword("or") {
+            self.pos += 1;
+            q = Query::Or(Box::new(q), Box::new(self.parse_and()?));
+        }
+        Ok(q)
+    }
+
+    fn parse_and(&mut self) -> Result<Query> {
+        let mut q = self.parse_unary()?;
+        loop {
+            if self.peek_keyword("and") {
+                self.pos += 1;
+            } else if self.peek().is_none()
+                || self.peek() == Some(&Token::RParen)
+                || self.peek_keyword("or")
+            {
+                return Ok(q);
+            }
+            q = Query::And(Box::new(q), Box::new(self.parse_unary()?));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Query> {
+        if self.peek_keyword("not") {
+            self.pos += 1;
+            return Ok(Query::Not(Box::new(self.parse_unary()?)));
+        }
+        match self.next() {
+            Some(Token::LParen) => {
+                let q = self.parse_or()?;
+                if self.next() != Some(Token::RParen) {
+                    bail!("expected closing parenthesis");
+                }
+                Ok(q)
+            }
+            Some(Token::Word(w, quoted)) => {
+                if let Some(Token::Op(op)) = self.peek().cloned() {
+                    self.pos += 1;
+                    let (v, vquoted) = match self.next() {
+                        Some(Token::Word(v, vquoted)) => (v, vquoted),
+                        t => bail!("expected value after {:?}, got {:?}", op, t),
+                    };
+                    return Ok(Query::Cmp(
+                        KeyPattern::parse(&w),
+                        op,
+                        Value::parse(v, vquoted),
+                    ));
+                }
+                if !quoted {
+                    if let Some(key) = w.strip_prefix("has:") {
+                        return Ok(Query::Has(KeyPattern::parse(key)));
+                    }
+                }
+                Ok(Query::Text(w))
+            }
+            t => bail!("unexpected token {:?}", t),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Query::parse("artist=\"Daft Punk\"").unwrap(),
+            Query::Cmp(
+                KeyPattern::Exact(TagKey::Artist),
+                CmpOp::Eq,
+                Value::Text(s!("Daft Punk"))
+            )
+        );
+        assert_eq!(
+            Query::parse("has:user_library:*").unwrap(),
+            Query::Has(KeyPattern::Prefix(s!("user_library:")))
+        );
+        assert_eq!(
+            Query::parse("a or b c").unwrap(),
+            Query::Or(
+                Box::new(Query::Text(s!("a"))),
+                Box::new(Query::And(
+                    Box::new(Query::Text(s!("b"))),
+                    Box::new(Query::Text(s!("c")))
+                ))
+            )
+        );
+        assert_eq!(
+            Query::parse("not (duration>=3)").unwrap(),
+            Query::Not(Box::new(Query::Cmp(
+                KeyPattern::Exact(TagKey::Duration),
+                CmpOp::Ge,
+                Value::Integer(3)
+            )))
+        );
+        assert!(Query::parse("(a").is_err());
+        assert!(Query::parse("a=").is_err());
+        assert!(Query::parse("\"a").is_err());
+        assert!(Query::parse("a !b").is_err());
+
+        let date = |s: &str| match Query::parse(s) {
+            Ok(Query::Cmp(_, _, v)) => v,
+            x => panic!("unexpected {:?}", x),
+        };
+        assert_eq!(date("date>2021-03"), Value::Date(s!("2021-03")));
+        assert_eq!(date("date>2021-03-04"), Value::Date(s!("2021-03-04")));
+        assert_eq!(date("date>2021-03-04x"), Value::Text(s!("2021-03-04x")));
+    }
+
+    #[test]
+    fn test_matches() {
+        let id = MusicID(1);
+        let mut date = Tag::new_key(id, TagKey::Other(s!("release")));
+        date.date = Some(s!("2021-03-04T00:00:00+00:00"));
+        let t = [
+            Tag::new_text(id, TagKey::Artist, s!("Daft Punk")),
+            Tag::new_integer(id, TagKey::Duration, 200),
+            Tag::new_integer(id, TagKey::UserLibrary(s!("1")), 100),
+            date,
+        ];
+        let t: Vec<&Tag> = t.iter().collect();
+
+        let m = |q: &str| Query::parse(q).unwrap().matches(&t);
+        assert!(m("artist=\"Daft Punk\""));
+        assert!(m("artist~daft"));
+        assert!(m("punk"));
+        assert!(!m("artist=daft"));
+        assert!(m("artist!=daft"));
+        assert!(m("duration>100 and duration<=200"));
+        assert!(!m("duration>200"));
+        assert!(m("has:user_library:* and not has:user_library:2"));
+        assert!(m("user_library:*>=100"));
+        assert!(m("release=2021-03"));
+        assert!(m("release>2020-12-31 and release<2022-01"));
+        assert!(!m("release<2021-03-04"));
+        assert!(m("title=a or artist~punk"));
+        assert!(!m("has:title"));
+        assert!(!m("title!=a"));
+        assert!(m("not title=a"));
+    }
+}
@@ -0,0 +1,323 @@
+//! Internet radio: one shared stream per user, everyone tuned in hears the same thing.
+//! It plays the queue, and when the queue is empty, a song of the library similar to the last one.
+
+use crate::domain::entity::{MusicID, Tag, TagKey, UserID, Vector};
+use crate::domain::library::Song;
+use crate::domain::player::pump;
+use crate::infrastructure::db::Db;
+use anyhow::{Context, Result};
+use hyper::body::Bytes;
+use nanoserde::SerJson;
+use rusqlite::Connection;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tinyrand::{RandRange, Seeded, StdRand};
+use tokio::sync::{broadcast, Notify};
+
+/// Bytes of audio between two ICY metadata blocks
+pub const ICY_METAINT: usize = 16000;
+/// Songs that were played recently aren't picked again
+const RECENT: usize = 20;
+/// The next song is picked among the most similar ones
+const SIMILAR_PICK: usize = 5;
+
+#[derive(Clone, Debug)]
+pub struct RadioChunk {
+    pub data: Bytes,
+    /// what is playing, as in the ICY StreamTitle
+    pub title: Arc<str>,
+}
+
+struct RadioState {
+    current: Option<(MusicID, Arc<str>)>,
+    queue: VecDeque<MusicID>,
+    recent: VecDeque<MusicID>,
+    /// whether the task sending the audio is running, it stops when nobody listens
+    running: bool,
+    rng: StdRand,
+}
+
+#[derive(SerJson)]
+pub struct RadioStatus {
+    pub playing: Option<i32>,
+    pub title: String,
+    pub queue: Vec<i32>,
+    pub listeners: usize,
+}
+
+#[derive(Clone)]
+pub struct Radio {
+    pub user: UserID,
+    db: Db,
+    storage: PathBuf,
+    state: Arc<Mutex<RadioState>>,
+    audio: broadcast::Sender<RadioChunk>,
+    skip: Arc<Notify>,
+}
+
+/// The radios of every user, created when first tuned into
+#[derive(Clone)]
+pub struct Radios {
+    db: Db,
+    storage: PathBuf,
+    radios: Arc<Mutex<HashMap<UserID, Radio>>>,
+}
+
+impl Radios {
+    pub fn new(db: Db, storage: PathBuf) -> Self {
+        Self {
+            db,
+            storage,
+            radios: Default::default(),
+        }
+    }
+
+    pub fn get(&self, user: UserID) -> Radio {
+        let mut radios = self.radios.lock().unwrap();
+        radios
+            .entry(user)
+            .or_insert_with(|| Radio::new(user, self.db.clone(), self.storage.clone()))
+            .clone()
+    }
+}
+
+/// StreamTitle of a song
+pub fn stream_title(song: &Song) -> String {
+    format!("{} - {}", song.artist, song.title)
+}
+
+fn cosine(a: &Vector, b: &Vector) -> f32 {
+    let dot: f32 = a.0.iter().zip(b.0.iter()).map(|(x, y)| x * y).sum();
+    let norm = |v: &Vector| v.0.iter().map(|x| x * x).sum::<f32>().sqrt();
+    dot / (norm(a) * norm(b)).max(f32::EPSILON)
+}
+
+/// Picks the song to play after `current` among the mp3s of the library of the user.
+/// Songs similar to the current one are preferred, otherwise it's random.
+pub fn next_song(
+    c: &Connection,
+    user: UserID,
+    current: Option<MusicID>,
+    recent: &[MusicID],
+    rng: &mut StdRand,
+) -> Result<Option<MusicID>> {
+    let mp3s: HashSet<MusicID> = Tag::by_key(c, &TagKey::LocalMP3)?
+        .into_iter()
+        .map(|t| t.music_id)
+        .collect();
+    let library: Vec<MusicID> = Tag::by_key_visible(c, &TagKey::UserLibrary(user.0.to_string()))?
+        .into_iter()
+        .map(|t| t.music_id)
+        .filter(|id| mp3s.contains(id) && Some(*id) != current)
+        .collect();
+    let mut candidates: Vec<MusicID> = library
+        .iter()
+        .copied()
+        .filter(|id| !recent.contains(id))
+        .collect();
+    if candidates.is_empty() {
+        candidates = library;
+    }
+    if candidates.is_empty() {
+        return Ok(current.filter(|id| mp3s.contains(id)));
+    }
+
+    let embeddings: HashMap<MusicID, Vector> = Tag::by_key(c, &TagKey::Embedding)?
+        .into_iter()
+        .filter_map(|t| Some((t.music_id, t.vector?)))
+        .collect();
+    if let Some(current) = current.and_then(|id| embeddings.get(&id)) {
+        let mut similar: Vec<(f32, MusicID)> = candidates
+            .iter()
+            .filter_map(|id| Some((cosine(current, embeddings.get(id)?), *id)))
+            .collect();
+        if !similar.is_empty() {
+            similar.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1 .0.cmp(&b.1 .0)));
+            similar.truncate(SIMILAR_PICK);
+            return Ok(Some(similar[rng.next_range(0..similar.len())].1));
+        }
+    }
+    Ok(Some(candidates[rng.next_range(0..candidates.len())]))
+}
+
+/// ICY metadata block, the length in 16 bytes units followed by the padded metadata
+pub fn icy_metadata(title: &str) -> Vec<u8> {
+    // a quote would end the value for most clients
+    let meta = format!("StreamTitle='{}';", title.replace('\'', "\u{2019}"));
+    let mut meta = meta.into_bytes();
+    meta.truncate(255 * 16);
+    let blocks = meta.len().div_ceil(16);
+    meta.resize(blocks * 16, 0);
+    meta.insert(0, blocks as u8);
+    meta
+}
+
+/// Interleaves the audio with ICY metadata every `metaint` bytes.
+/// The title is only sent when it changes, otherwise the block is empty.
+pub struct IcyWriter {
+    metaint: usize,
+    until_meta: usize,
+    sent_title: Option<Arc<str>>,
+}
+
+impl IcyWriter {
+    pub fn new(metaint: usize) -> Self {
+        Self {
+            metaint,
+            until_meta: metaint,
+            sent_title: None,
+        }
+    }
+
+    pub fn write(&mut self, chunk: &RadioChunk) -> Vec<u8> {
+        let mut out = Vec::with_capacity(chunk.data.len() + 1);
+        let mut data = &chunk.data[..];
+        while !data.is_empty() {
+            let n = self.until_meta.min(data.len());
+            out.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            self.until_meta -= n;
+            if self.until_meta == 0 {
+                if self.sent_title.as_ref() == Some(&chunk.title) {
+                    out.push(0);
+                } else {
+                    out.extend(icy_metadata(&chunk.title));
+                    self.sent_title = Some(chunk.title.clone());
+                }
+                self.until_meta = self.metaint;
+            }
+        }
+        out
+    }
+}
+
+impl Radio {
+    fn new(user: UserID, db: Db, storage: PathBuf) -> Self {
+        let seed = UNIX_EPOCH.elapsed().map(|d| d.as_nanos()).unwrap_or(0) as u64;
+        Self {
+            user,
+            db,
+            storage,
+            state: Arc::new(Mutex::new(RadioState {
+                current: None,
+                queue: VecDeque::new(),
+                recent: VecDeque::new(),
+                running: false,
+                rng: StdRand::seed(seed),
+            })),
+            audio: broadcast::channel(64).0,
+            skip: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Tunes into the radio, starting it if nobody was listening
+    pub fn listen(&self) -> broadcast::Receiver<RadioChunk> {
+        let mut state = self.state.lock().unwrap();
+        let rx = self.audio.subscribe();
+        if !state.running {
+            state.running = true;
+            self.start();
+        }
+        rx
+    }
+
+    /// Goes to the next song right away
+    pub fn skip(&self) {
+        self.skip.notify_waiters();
+    }
+
+    pub fn enqueue(&self, id: MusicID) {
+        self.state.lock().unwrap().queue.push_back(id);
+    }
+
+    pub fn status(&self) -> RadioStatus {
+        let state = self.state.lock().unwrap();
+        RadioStatus {
+            playing: state.current.as_ref().map(|x| x.0 .0),
+            title: state
+                .current
+                .as_ref()
+                .map(|x| x.1.to_string())
+                .unwrap_or_default(),
+            queue: state.queue.iter().map(|x| x.0).collect(),
+            listeners: self.audio.receiver_count(),
+        }
+    }
+
+    /// Pops the queue, or finds something to play
+    async fn next(&self) -> Result<Option<MusicID>> {
+        let c = self.db.get().await;
+        let mut state = self.state.lock().unwrap();
+        if let Some(id) = state.queue.pop_front() {
+            return Ok(Some(id));
+        }
+        let current = state.current.as_ref().map(|x| x.0);
+        let recent: Vec<MusicID> = state.recent.iter().copied().collect();
+        next_song(&c, self.user, current, &recent, &mut state.rng)
+    }
+
+    async fn play(&self, id: MusicID) -> Result<()> {
+        let (song, path) = {
+            let c = self.db.get().await;
+            let song = Song::load(&c, id)?;
+            let path = Tag::by_id_key(&c, id, &TagKey::LocalMP3)?
+                .and_then(|t| t.text)
+                .context("no mp3 to play")?;
+            (song, path)
+        };
+        let title: Arc<str> = stream_title(&song).into();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.current = Some((id, title.clone()));
+            state.recent.push_back(id);
+            if state.recent.len() > RECENT {
+                state.recent.pop_front();
+            }
+        }
+        let file = self.storage.join(path);
+        pump(&file, song.duration, Duration::ZERO, |data| {
+            let _ = self.audio.send(RadioChunk {
+                data,
+                title: title.clone(),
+            });
+        })
+        .await
+    }
+
+    fn start(&self) {
+        let radio = self.clone();
+        tokio::spawn(async move {
+            loop {
+                {
+                    let mut state = radio.state.lock().unwrap();
+                    if radio.audio.receiver_count() == 0 {
+                        state.running = false;
+                        state.current = None;
+                        return;
+                    }
+                }
+                let id = match radio.next().await {
+                    Ok(Some(id)) => id,
+                    Ok(None) => {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        continue;
+                    }
+                    Err(e) => {
+                        log::error!("error finding the next song of the radio: {:?}", e);
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        continue;
+                    }
+                };
+                tokio::select! {
+                    r = radio.play(id) => if let Err(e) = r {
+                        log::error!("error playing {:?} on the radio: {:?}", id, e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    },
+                    _ = radio.skip.notified() => {}
+                }
+            }
+        });
+    }
+}
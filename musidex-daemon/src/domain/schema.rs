@@ -0,0 +1,86 @@
+use crate::domain::entity::{Tag, TagKey, TagSchema, TagValueType};
+use crate::utils::collect_rows;
+use anyhow::Result;
+use hyper::StatusCode;
+use rusqlite::Connection;
+use std::fmt::{Display, Formatter};
+
+pub enum SchemaViolation {
+    UnknownKey(TagKey),
+    NotEditable(TagKey),
+    WrongType(TagKey, TagValueType),
+}
+
+impl SchemaViolation {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            SchemaViolation::NotEditable(_) => StatusCode::FORBIDDEN,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl Display for SchemaViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaViolation::UnknownKey(k) => write!(f, "unknown tag key: {}", k),
+            SchemaViolation::NotEditable(k) => write!(f, "tag key is not user editable: {}", k),
+            SchemaViolation::WrongType(k, t) => {
+                write!(f, "tag {} must have a {} value", k, t.as_str())
+            }
+        }
+    }
+}
+
+impl TagSchema {
+    pub fn list(c: &Connection) -> Result<Vec<TagSchema>> {
+        let mut stmt = c.prepare_cached("SELECT * FROM tag_schema ORDER BY key;")?;
+        let v = stmt.query_map([], |row| Ok(TagSchema::from(row)))?;
+        collect_rows(v)
+    }
+
+    /// Finds the schema of a key, an exact match taking precedence over the longest nested prefix
+    pub fn find<'a>(schemas: &'a [TagSchema], key: &TagKey) -> Option<&'a TagSchema> {
+        let key: String = key.into();
+        let mut best: Option<&TagSchema> = None;
+        for s in schemas {
+            if s.key == key {
+                return Some(s);
+            }
+            if s.key.ends_with(':')
+                && key.starts_with(&s.key)
+                && best.is_none_or(|b| b.key.len() < s.key.len())
+            {
+                best = Some(s);
+            }
+        }
+        best
+    }
+
+    pub fn is_synced(schemas: &[TagSchema], key: &TagKey) -> bool {
+        TagSchema::find(schemas, key).is_none_or(|s| s.synced)
+    }
+
+    /// Checks that a tag coming from a user respects the schema
+    pub fn validate(schemas: &[TagSchema], tag: &Tag) -> Result<(), SchemaViolation> {
+        let schema = TagSchema::find(schemas, &tag.key)
+            .ok_or_else(|| SchemaViolation::UnknownKey(tag.key.clone()))?;
+        if !schema.user_editable {
+            return Err(SchemaViolation::NotEditable(tag.key.clone()));
+        }
+        let has_value = match schema.value_type {
+            TagValueType::None => true,
+            TagValueType::Text => tag.text.is_some(),
+            TagValueType::Integer => tag.integer.is_some(),
+            TagValueType::Date => tag.date.is_some(),
+            TagValueType::Vector => tag.vector.is_some(),
+        };
+        if !has_value || (tag.vector.is_some() && schema.value_type != TagValueType::Vector) {
+            return Err(SchemaViolation::WrongType(
+                tag.key.clone(),
+                schema.value_type,
+            ));
+        }
+        Ok(())
+    }
+}
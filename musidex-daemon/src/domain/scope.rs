@@ -0,0 +1,142 @@
+use crate::domain::entity::{MusicID, MusidexMetadata, Patch, Tag, TagKey, TagSchema, UserID};
+use crate::domain::sync::fetch_metadata;
+use crate::utils::collect_rows;
+use anyhow::Result;
+use rusqlite::Connection;
+use std::collections::{HashMap, HashSet};
+
+/// What a sync subscriber identified as a user gets to see:
+/// the musics in their library and the ones in nobody's library (shared),
+/// and only their own library tags.
+#[derive(Clone, Debug)]
+pub struct SyncScope {
+    pub user: UserID,
+    /// musics the subscriber currently knows about, None until it was sent a snapshot
+    /// or while it resumes from a cursor we know nothing about
+    visible: Option<HashSet<MusicID>>,
+}
+
+/// The users having each music in their library, read once per update and shared by the scopes
+#[derive(Debug, Default)]
+pub struct LibraryOwners(HashMap<MusicID, Vec<String>>);
+
+impl LibraryOwners {
+    pub fn load(c: &Connection) -> Result<Self> {
+        let mut stmt = c.prepare_cached(
+            "SELECT music_id, key FROM tags WHERE key LIKE 'user_library:%'
+            AND music_id NOT IN (SELECT music_id FROM trash);",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                MusicID(row.get(0)?),
+                TagKey::from(&*row.get::<_, String>(1)?),
+            ))
+        })?;
+        let mut owners: HashMap<MusicID, Vec<String>> = HashMap::new();
+        for (id, key) in collect_rows(rows)? {
+            if let Some(user) = key.as_user_library() {
+                owners.entry(id).or_default().push(user.to_string());
+            }
+        }
+        Ok(Self(owners))
+    }
+
+    fn visible(&self, user: UserID, musics: &[MusicID]) -> HashSet<MusicID> {
+        musics
+            .iter()
+            .filter(|id| {
+                self.0
+                    .get(id)
+                    .is_none_or(|o| o.iter().any(|u| u.parse() == Ok(user.0)))
+            })
+            .copied()
+            .collect()
+    }
+}
+
+impl SyncScope {
+    pub fn new(user: UserID) -> Self {
+        Self {
+            user,
+            visible: None,
+        }
+    }
+
+    /// The subscriber resumes from a cursor of its own, what it saw there is unknown
+    pub fn forget(&mut self) {
+        self.visible = None;
+    }
+
+    fn keeps(&self, tag: &Tag) -> bool {
+        match tag.key {
+            TagKey::UserLibrary(ref user) => user.parse() == Ok(self.user.0),
+            _ => true,
+        }
+    }
+
+    /// Restricts the metadata to the scope. `owners` must be read along with `meta`.
+    /// Full snapshots are filtered, patches also get the tags of the musics
+    /// entering the scope and removals for the ones leaving it.
+    pub fn apply(
+        &mut self,
+        c: &Connection,
+        owners: &LibraryOwners,
+        mut meta: MusidexMetadata,
+    ) -> Result<MusidexMetadata> {
+        if let (None, Some(patches)) = (&self.visible, &meta.patches) {
+            // we can't tell which musics entered or left the scope since the cursor of the client
+            if patches
+                .iter()
+                .any(|p| matches!(p.tag.key, TagKey::UserLibrary(_)))
+            {
+                meta = fetch_metadata(c)?;
+            }
+        }
+
+        let visible = owners.visible(self.user, &meta.musics);
+        meta.musics.retain(|id| visible.contains(id));
+
+        if let Some(ref mut tags) = meta.tags {
+            tags.retain(|t| visible.contains(&t.music_id) && self.keeps(t));
+        }
+
+        if let (None, Some(patches)) = (&meta.tags, &mut meta.patches) {
+            let known = self.visible.as_ref().unwrap_or(&visible);
+            patches.retain(|p| {
+                let id = p.tag.music_id;
+                self.keeps(&p.tag)
+                    && (visible.contains(&id) || (p.kind == "remove" && known.contains(&id)))
+            });
+            let patched: HashSet<(MusicID, TagKey)> = patches
+                .iter()
+                .map(|p| (p.tag.music_id, p.tag.key.clone()))
+                .collect();
+
+            let schema = TagSchema::list(c)?;
+            for &id in known.symmetric_difference(&visible) {
+                let entering = visible.contains(&id);
+                for tag in Tag::by_id(c, id)? {
+                    if !self.keeps(&tag)
+                        || !TagSchema::is_synced(&schema, &tag.key)
+                        || patched.contains(&(id, tag.key.clone()))
+                    {
+                        continue;
+                    }
+                    patches.push(match entering {
+                        true => Patch {
+                            kind: s!("add"),
+                            tag,
+                        },
+                        false => Patch {
+                            kind: s!("remove"),
+                            tag: Tag::new_key(id, tag.key),
+                        },
+                    });
+                }
+            }
+        }
+
+        self.visible = Some(visible);
+        Ok(meta)
+    }
+}
@@ -4,6 +4,7 @@ use crate::infrastructure::db::Client;
 use crate::utils::get_file_range;
 use anyhow::{Context, Result};
 use hyper::http::HeaderValue;
+use rusqlite::Connection;
 
 pub struct MusicMetadata {
     pub buf: Vec<u8>,
@@ -11,40 +12,47 @@ pub struct MusicMetadata {
     pub content_type: &'static str,
 }
 
-pub async fn stream_music(
-    c: Client<'_>,
-    id: MusicID,
-    range: Option<&HeaderValue>,
-) -> Result<MusicMetadata> {
-    let mut source_path = None;
-    {
-        let tags = Tag::by_id(&c, id)?;
+/// Path of the audio file of the music, relative to the storage directory
+pub fn source_path(c: &Connection, id: MusicID) -> Result<Option<String>> {
+    Ok(source_of(&Tag::by_id(c, id)?))
+}
 
-        for tag in tags {
-            if (tag.key == LocalMP3
+/// Same as source_path, for tags that are already loaded
+pub fn source_of(tags: &[Tag]) -> Option<String> {
+    tags.iter()
+        .find(|tag| {
+            (tag.key == LocalMP3
                 || tag.key == LocalOGG
                 || tag.key == LocalM4A
                 || tag.key == LocalWEBM)
                 && tag.text.is_some()
-            {
-                source_path = tag.text;
-                break;
-            }
-        }
-    }
-    let source_path = source_path.context("no streamable source found")?;
+        })
+        .and_then(|tag| tag.text.clone())
+}
 
-    let content_type = if source_path.ends_with("mp3") {
+/// Mime type of an audio file, empty if unknown
+pub fn content_type(path: &str) -> &'static str {
+    if path.ends_with("mp3") {
         "audio/mpeg"
-    } else if source_path.ends_with("ogg") {
+    } else if path.ends_with("ogg") {
         "audio/ogg"
-    } else if source_path.ends_with("m4a") {
+    } else if path.ends_with("m4a") {
         "audio/mp4"
-    } else if source_path.ends_with("webm") {
+    } else if path.ends_with("webm") {
         "audio/webm"
     } else {
         ""
-    };
+    }
+}
+
+pub async fn stream_music(
+    c: Client<'_>,
+    id: MusicID,
+    range: Option<&HeaderValue>,
+) -> Result<MusicMetadata> {
+    let source_path = source_path(&c, id)?.context("no streamable source found")?;
+
+    let content_type = content_type(&source_path);
 
     let file_path = format!("./storage/{}", source_path);
 
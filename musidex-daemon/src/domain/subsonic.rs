@@ -0,0 +1,74 @@
+use crate::domain::entity::{User, UserID};
+use crate::domain::library::decode_hex;
+use crate::utils::{constant_time_eq, row_missing_opt};
+use anyhow::Result;
+use md5::{Digest, Md5};
+use rusqlite::Connection;
+use std::collections::HashMap;
+
+/// Subsonic error codes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SubsonicError {
+    Generic = 0,
+    MissingParameter = 10,
+    WrongCredentials = 40,
+    NotFound = 70,
+}
+
+impl SubsonicError {
+    pub fn message(self) -> &'static str {
+        match self {
+            SubsonicError::Generic => "A generic error.",
+            SubsonicError::MissingParameter => "Required parameter is missing.",
+            SubsonicError::WrongCredentials => "Wrong username or password.",
+            SubsonicError::NotFound => "The requested data was not found.",
+        }
+    }
+}
+
+pub fn set_password(c: &Connection, user: UserID, password: &str) -> Result<()> {
+    c.prepare_cached(
+        "INSERT INTO subsonic_credentials (user_id, password) VALUES (?1, ?2)
+        ON CONFLICT (user_id) DO UPDATE SET password=?2;",
+    )?
+    .execute(rusqlite::params![user.0, password])?;
+    Ok(())
+}
+
+fn password(c: &Connection, user: UserID) -> Result<Option<String>> {
+    let mut stmt =
+        c.prepare_cached("SELECT password FROM subsonic_credentials WHERE user_id=?1;")?;
+    Ok(row_missing_opt(stmt.query_row([user.0], |row| row.get(0)))?)
+}
+
+/// Checks the u, p or t and s parameters against the subsonic password of the user
+pub fn authenticate(
+    c: &Connection,
+    params: &HashMap<String, String>,
+) -> Result<Result<User, SubsonicError>> {
+    let name = unwrap_ret!(params.get("u"), Ok(Err(SubsonicError::MissingParameter)));
+    let user = User::list(c)?.into_iter().find(|u| &u.name == name);
+    let user = unwrap_ret!(user, Ok(Err(SubsonicError::WrongCredentials)));
+    let expected = unwrap_ret!(
+        password(c, user.id)?,
+        Ok(Err(SubsonicError::WrongCredentials))
+    );
+
+    let ok = match (params.get("t"), params.get("s"), params.get("p")) {
+        (Some(token), Some(salt), _) => {
+            let digest = format!("{:x}", Md5::digest(format!("{}{}", expected, salt)));
+            constant_time_eq(digest.as_bytes(), token.to_lowercase().as_bytes())
+        }
+        (_, _, Some(p)) => match p.strip_prefix("enc:") {
+            Some(hex) => {
+                decode_hex(hex).is_some_and(|p| constant_time_eq(p.as_bytes(), expected.as_bytes()))
+            }
+            None => constant_time_eq(p.as_bytes(), expected.as_bytes()),
+        },
+        _ => return Ok(Err(SubsonicError::MissingParameter)),
+    };
+    if !ok {
+        return Ok(Err(SubsonicError::WrongCredentials));
+    }
+    Ok(Ok(user))
+}
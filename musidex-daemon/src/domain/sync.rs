@@ -1,5 +1,3 @@
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -8,26 +6,55 @@ use futures::{sink::SinkExt, stream::StreamExt};
 use hyper_tungstenite::{tungstenite, HyperWebsocket};
 use nanoserde::SerJson;
 use rusqlite::Connection;
-use tokio::sync::mpsc;
 use tokio::sync::watch;
+use tokio::sync::{mpsc, Notify};
 use tungstenite::Message;
 
+use crate::domain::commands::{self, Ack, Command};
 use crate::domain::config;
-use crate::domain::entity::{Music, MusicID, MusidexMetadata, Patch, Tag, TagKey, User};
+use crate::domain::devices::{self, Devices, Registration};
+use crate::domain::encoding::SyncEncoding;
+use crate::domain::entity::{
+    Music, MusicID, MusidexMetadata, Patch, Tag, TagKey, TagSchema, User, UserID,
+};
+use crate::domain::history::Editor;
+use crate::domain::party::{self, Membership, Parties};
+use crate::domain::query::{KeyPattern, Query};
+use crate::domain::scope::{LibraryOwners, SyncScope};
+use crate::domain::trash;
 use crate::infrastructure::db::Db;
-use crate::utils::collect_rows;
-use std::collections::HashMap;
+use crate::utils::{collect_rows, row_missing_opt};
+use std::collections::{HashMap, HashSet};
+
+/// How many changelog entries are kept, clients further behind get a full snapshot
+const CHANGELOG_RETENTION: i64 = 100_000;
+/// Changes are looked for at least that often, even without a commit notification
+const FALLBACK_POLL: Duration = Duration::from_secs(2);
 
 #[derive(Clone)]
 pub struct SyncBroadcastSubscriber {
-    rx: watch::Receiver<Arc<(MusidexMetadata, Option<MusidexMetadata>)>>,
+    db: Db,
+    rx: watch::Receiver<Arc<SyncUpdate>>,
     refresh_tx: mpsc::Sender<()>,
+    /// listening parties of the websocket clients
+    parties: Parties,
+    /// devices of the websocket clients, for connect mode
+    devices: Devices,
+}
+
+/// Changes going from seq `from` to `patch.seq`
+#[derive(Default)]
+pub struct SyncUpdate {
+    pub from: i64,
+    pub patch: MusidexMetadata,
+    /// read along with the patch, for the scoped subscribers
+    pub owners: LibraryOwners,
 }
 
 pub struct SyncBroadcast {
     c: Connection,
-    tx: watch::Sender<Arc<(MusidexMetadata, Option<MusidexMetadata>)>>,
-    refresh_tx: mpsc::Sender<()>,
+    commits: Arc<Notify>,
+    tx: watch::Sender<Arc<SyncUpdate>>,
     refresh_rx: mpsc::Receiver<()>,
 }
 
@@ -36,128 +63,126 @@ pub fn compress_meta(x: &MusidexMetadata) -> Vec<u8> {
     miniz_oxide::deflate::compress_to_vec(json.as_bytes(), 5)
 }
 
+impl SyncBroadcastSubscriber {
+    pub fn devices(&self) -> Devices {
+        self.devices.clone()
+    }
+}
+
 impl SyncBroadcast {
-    pub fn new() -> Result<(Self, SyncBroadcastSubscriber)> {
-        let (tx, rx) = watch::channel(Arc::new((MusidexMetadata::default(), None)));
+    pub fn new(db: &Db) -> Result<(Self, SyncBroadcastSubscriber)> {
+        let (tx, rx) = watch::channel(Arc::new(SyncUpdate::default()));
         let (refresh_tx, refresh_rx) = mpsc::channel(16);
         Ok((
             Self {
                 c: Db::mk_conn()?,
+                commits: db.commits(),
                 tx,
-                refresh_tx: refresh_tx.clone(),
                 refresh_rx,
             },
-            SyncBroadcastSubscriber { rx, refresh_tx },
+            SyncBroadcastSubscriber {
+                db: db.clone(),
+                rx,
+                refresh_tx,
+                parties: Parties::default(),
+                devices: Devices::default(),
+            },
         ))
     }
 
     pub fn start_workers(self) {
-        let refresh_tx = self.refresh_tx;
-        tokio::spawn(async move {
-            loop {
-                tokio::time::sleep(Duration::from_secs(2)).await;
-                let _ = refresh_tx.send(()).await;
-            }
-        });
         let mut r = self.refresh_rx;
         let db = self.c;
         let tx = self.tx;
+        let commits = self.commits;
         tokio::spawn(async move {
-            let mut last_hash = 1234;
-            let mut last_map: Option<TagMap> = None;
-            while let Some(()) = r.recv().await {
-                let m = fetch_metadata(&db);
-                let m = match m {
+            let mut last_seq = head_seq(&db).unwrap_or(0);
+            loop {
+                tokio::select! {
+                    _ = commits.notified() => {},
+                    Some(()) = r.recv() => {},
+                    // catches the commits that landed after the 50ms below
+                    _ = tokio::time::sleep(FALLBACK_POLL) => {},
+                }
+                // the commit hook runs right before the commit lands, and this batches bursts of writes
+                tokio::time::sleep(Duration::from_millis(50)).await;
+
+                let fetched = read_snapshot(&db, |c| {
+                    Ok((fetch_since(c, Some(last_seq))?, LibraryOwners::load(c)?))
+                });
+                let (m, owners) = match fetched {
                     Ok(x) => x,
                     Err(e) => {
-                        log::error!("error fetching metadata to send to subscribers: {}", e);
+                        log::error!("error fetching changes to send to subscribers: {}", e);
                         continue;
                     }
                 };
-                let mut s = DefaultHasher::new();
-                m.hash(&mut s);
-                let hash = s.finish();
-
-                if hash == last_hash {
+                if m.seq == last_seq {
                     continue;
                 }
-                last_hash = hash;
+                let from = last_seq;
+                last_seq = m.seq;
 
-                let (last_mapp, musipatch) = mk_patches(&last_map, &m);
-                last_map = Some(last_mapp);
+                if let Err(e) = prune_changelog(&db) {
+                    log::error!("error pruning changelog: {}", e);
+                }
 
-                let _ = tx.send(Arc::new((m, musipatch)));
+                let _ = tx.send(Arc::new(SyncUpdate {
+                    from,
+                    patch: m,
+                    owners,
+                }));
             }
         });
     }
 }
 
-type TagMap = HashMap<(i32, TagKey), Tag>;
-
-pub fn mk_patches(
-    last_map: &Option<TagMap>,
-    new: &MusidexMetadata,
-) -> (TagMap, Option<MusidexMetadata>) {
-    let newmap = mk_tag_map(new);
-
-    if let Some(last_map) = last_map {
-        let mut patches = Vec::with_capacity(5);
-
-        for k in last_map.keys() {
-            if newmap.contains_key(k) {
-                continue;
-            }
-            patches.push(Patch {
-                kind: s!("remove"),
-                tag: Tag::new_key(MusicID(k.0), k.1.clone()),
-            })
-        }
-
-        for (k, t) in &newmap {
-            let last_get = last_map.get(k);
-            if last_get == Some(t) {
-                continue;
-            }
-            if last_get.is_none() {
-                patches.push(Patch {
-                    kind: s!("add"),
-                    tag: t.clone(),
-                });
-                continue;
-            }
-            patches.push(Patch {
-                kind: s!("update"),
-                tag: t.clone(),
-            })
-        }
-
-        let newpatch = MusidexMetadata {
-            musics: new.musics.clone(),
-            tags: None,
-            users: new.users.clone(),
-            settings: new.settings.clone(),
-            patches: Some(patches),
-        };
-        return (newmap, Some(newpatch));
-    }
-    (newmap, None)
-}
-
-pub fn mk_tag_map(x: &MusidexMetadata) -> TagMap {
-    let tags = unwrap_ret!(x.tags.as_ref(), HashMap::default());
-    let mut v = HashMap::with_capacity(tags.len());
-    for t in tags {
-        v.insert((t.music_id.0, t.key.clone()), t.clone());
-    }
-    v
+/// What a websocket subscriber asked for when connecting
+pub struct SyncOptions {
+    /// seq the client already has, to only send what changed
+    pub since: Option<i64>,
+    /// only send what this user can see, see SyncScope
+    pub user: Option<UserID>,
+    pub encoding: SyncEncoding,
+    /// who runs the commands sent over the socket
+    pub editor: Editor,
 }
 
 pub async fn serve_sync_websocket(
     websocket: HyperWebsocket,
     mut b: SyncBroadcastSubscriber,
+    opts: SyncOptions,
 ) -> Result<()> {
     let mut websocket = websocket.await?;
-    let mut first_msg = true;
+    let encoding = opts.encoding;
+
+    // commands run one after the other in their own task so that syncing isn't blocked by them
+    let (cmd_tx, mut cmd_rx) = mpsc::channel::<(Command, String)>(64);
+    let (ack_tx, mut ack_rx) = mpsc::channel::<Ack>(64);
+    let db = b.db.clone();
+    let editor = opts.editor.clone();
+    tokio::spawn(async move {
+        while let Some((cmd, msg)) = cmd_rx.recv().await {
+            let ack = commands::run(&db, &editor, &cmd, &msg).await;
+            if ack_tx.send(ack).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let (first, mut scope) = {
+        let c = b.db.get().await;
+        let mut scope = opts.user.map(SyncScope::new);
+        let first = fetch_scoped(&c, &mut scope, opts.since)?;
+        (first, scope)
+    };
+    let mut cursor = first.seq;
+    let mut membership: Option<Membership> = None;
+    let mut registration: Option<Registration> = None;
+    websocket
+        .send(Message::Binary(encoding.encode(&first)))
+        .await?;
+    drop(first);
 
     loop {
         tokio::select! {
@@ -175,58 +200,277 @@ pub async fn serve_sync_websocket(
                     Message::Text(v) => {
                         if &v == "refresh" {
                             let _ = b.refresh_tx.send(()).await;
+                        } else if let Some(since) = v.strip_prefix("since=").and_then(|x| x.parse().ok()) {
+                            let c = b.db.get().await;
+                            if let Some(ref mut scope) = scope {
+                                scope.forget();
+                            }
+                            let m = fetch_scoped(&c, &mut scope, Some(since))?;
+                            drop(c);
+                            cursor = m.seq;
+                            websocket.send(Message::Binary(encoding.encode(&m))).await?;
+                        } else if let Some(cmd) = commands::parse(&v) {
+                            if party::is_party_command(&cmd) {
+                                let reply = party::run(&b.db, &b.parties, &mut membership, &cmd, &v).await;
+                                websocket.send(Message::Text(reply)).await?;
+                            } else if devices::is_device_command(&cmd) {
+                                let ack = devices::run(&b.db, &b.devices, &mut registration, &opts.editor, &cmd, &v).await;
+                                websocket.send(Message::Text(ack.serialize_json())).await?;
+                            } else {
+                                let _ = cmd_tx.send((cmd, v)).await;
+                            }
                         }
                     }
                 }
             }
+            Ok(_) = party_changed(&mut membership) => {
+                if let Some(ref m) = membership {
+                    let view = m.party.view(m.member);
+                    websocket.send(Message::Text(view.serialize_json())).await?;
+                }
+            }
+            Some(relayed) = device_message(&mut registration) => {
+                websocket.send(Message::Text(relayed)).await?;
+            }
+            Some(ack) = ack_rx.recv() => {
+                websocket.send(Message::Text(ack.serialize_json())).await?;
+            }
             Ok(_) = b.rx.changed() => {
-                let meta = b.rx.borrow().clone();
+                let update = b.rx.borrow().clone();
 
-                let (musi, musipatch) = &*meta;
-
-                let chosen = if first_msg {
-                    first_msg = false;
-                    &musi
+                if update.patch.seq <= cursor {
+                    continue;
+                }
+                let msg = if update.from == cursor && scope.is_none() {
+                    encoding.encode(&update.patch)
                 } else {
-                    musipatch.as_ref().unwrap_or(&musi)
+                    let c = b.db.get().await;
+                    let m = match (update.from == cursor, &mut scope) {
+                        (true, Some(scope)) => scope.apply(&c, &update.owners, update.patch.clone())?,
+                        // we missed an update or the subscriber resynced, catch up on our own
+                        _ => fetch_scoped(&c, &mut scope, Some(cursor))?,
+                    };
+                    encoding.encode(&m)
                 };
+                cursor = update.patch.seq.max(cursor);
 
-                websocket.send(Message::Binary(compress_meta(chosen))).await?;
+                websocket.send(Message::Binary(msg)).await?;
             }
         }
     }
 }
 
-pub fn fetch_metadata(c: &Connection) -> Result<MusidexMetadata> {
-    let mut musics = c.prepare_cached("SELECT * FROM musics")?;
-    let mut tags = c.prepare_cached("SELECT * FROM tags")?;
+async fn party_changed(membership: &mut Option<Membership>) -> Result<(), watch::error::RecvError> {
+    match membership {
+        Some(m) => m.rx.changed().await,
+        None => std::future::pending().await,
+    }
+}
 
-    let musics = musics.query_map([], |r| Ok(Into::into(r)))?;
-    let tags = tags.query_map([], |r| Ok(Into::into(r)))?;
+async fn device_message(registration: &mut Option<Registration>) -> Option<String> {
+    match registration {
+        Some(r) => r.rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
 
-    let musics = collect_rows(musics.map(|x| x.map(|v: Music| v.id)))?;
+/// What changed since the cursor, restricted to the scope if there is one
+fn fetch_scoped(
+    c: &Connection,
+    scope: &mut Option<SyncScope>,
+    since: Option<i64>,
+) -> Result<MusidexMetadata> {
+    read_snapshot(c, |c| {
+        let m = fetch_since(c, since)?;
+        match scope {
+            Some(scope) => scope.apply(c, &LibraryOwners::load(c)?, m),
+            None => Ok(m),
+        }
+    })
+}
+
+/// Runs f in a read transaction if not already in a transaction, so that
+/// the seq and the data read by f are consistent
+fn read_snapshot<T>(c: &Connection, f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+    if !c.is_autocommit() {
+        return f(c);
+    }
+    let tx = c.unchecked_transaction()?;
+    let v = f(&tx)?;
+    tx.commit()?;
+    Ok(v)
+}
+
+/// Last seq of the changelog, 0 if nothing ever changed
+pub fn head_seq(c: &Connection) -> Result<i64> {
+    let mut stmt = c.prepare_cached("SELECT max(seq) FROM changelog;")?;
+    let v: Option<i64> = stmt.query_row([], |row| row.get(0))?;
+    Ok(v.unwrap_or(0))
+}
+
+fn prune_changelog(c: &Connection) -> Result<()> {
+    c.prepare_cached("DELETE FROM changelog WHERE seq <= (SELECT max(seq) FROM changelog) - ?1;")?
+        .execute([CHANGELOG_RETENTION])?;
+    Ok(())
+}
 
-    let tags = tags.filter(|tag: &Result<Tag, _>| {
-        if let Ok(tag) = tag {
-            if tag.key == TagKey::FullEmbedding {
-                return false;
+/// Changes since the given seq if available, otherwise the full metadata
+pub fn fetch_since(c: &Connection, since: Option<i64>) -> Result<MusidexMetadata> {
+    if let Some(since) = since {
+        if let Some(m) = fetch_changes(c, since)? {
+            return Ok(m);
+        }
+    }
+    fetch_metadata(c)
+}
+
+/// Returns the tag patches to go from seq `since` to the current state,
+/// or None if the changelog doesn't go back that far.
+pub fn fetch_changes(c: &Connection, since: i64) -> Result<Option<MusidexMetadata>> {
+    read_snapshot(c, |c| {
+        let head = head_seq(c)?;
+        if since > head || since < 0 {
+            return Ok(None);
+        }
+        if since < head {
+            let oldest: Option<i64> = row_missing_opt(
+                c.prepare_cached("SELECT seq FROM changelog WHERE seq <= ?1 + 1 LIMIT 1;")?
+                    .query_row([since], |row| row.get(0).map(Some)),
+            )?;
+            if oldest.is_none() {
+                return Ok(None);
             }
         }
-        true
-    });
 
-    let tags: Vec<Tag> = collect_rows(tags)?;
+        let mut stmt = c.prepare_cached(
+            "SELECT music_id, key, kind FROM changelog
+            WHERE seq > ?1 AND key IS NOT NULL
+            ORDER BY seq;",
+        )?;
+        let changes = stmt.query_map([since], |row| {
+            Ok((
+                MusicID(row.get("music_id")?),
+                TagKey::from(&*row.get::<_, String>("key")?),
+                row.get::<_, String>("kind")?,
+            ))
+        })?;
+
+        // only the first change of each tag matters to know if the client had it
+        let mut first_change: HashMap<(MusicID, TagKey), bool> = HashMap::new();
+        let mut order = vec![];
+        for change in changes {
+            let (id, key, kind) = change?;
+            let k = (id, key);
+            if first_change.contains_key(&k) {
+                continue;
+            }
+            first_change.insert(k.clone(), kind != "add");
+            order.push(k);
+        }
+
+        let schema = TagSchema::list(c)?;
+        let trashed = trash::ids(c)?;
+        let mut patches = Vec::with_capacity(order.len());
+        for (id, key) in order {
+            if !TagSchema::is_synced(&schema, &key) {
+                continue;
+            }
+            let had = first_change[&(id, key.clone())];
+            let tag = match trashed.contains(&id) {
+                true => None,
+                false => Tag::by_id_key(c, id, &key)?,
+            };
+            let kind = match (had, tag) {
+                (false, Some(tag)) => ("add", tag),
+                (true, Some(tag)) => ("update", tag),
+                (true, None) => ("remove", Tag::new_key(id, key)),
+                (false, None) => continue,
+            };
+            patches.push(Patch {
+                kind: s!(kind.0),
+                tag: kind.1,
+            });
+        }
+
+        let mut meta = fetch_metadata_base(c, schema)?;
+        meta.seq = head;
+        meta.patches = Some(patches);
+        Ok(Some(meta))
+    })
+}
+
+/// Same as fetch_metadata but only returns the musics matching the filter,
+/// and only the tags whose key matches one of the fields if provided.
+pub fn fetch_metadata_filtered(
+    c: &Connection,
+    filter: Option<&Query>,
+    fields: Option<&[KeyPattern]>,
+) -> Result<MusidexMetadata> {
+    let mut meta = fetch_metadata(c)?;
+    let tags = meta.tags.take().unwrap_or_default();
+
+    if let Some(filter) = filter {
+        meta.musics = filter.filter(&meta.musics, &tags);
+    }
+
+    let kept: HashSet<MusicID> = meta.musics.iter().copied().collect();
+    meta.tags = Some(
+        tags.into_iter()
+            .filter(|t| kept.contains(&t.music_id))
+            .filter(|t| fields.is_none_or(|f| f.iter().any(|k| k.matches(&t.key))))
+            .collect(),
+    );
+
+    Ok(meta)
+}
+
+/// Everything but the tags
+fn fetch_metadata_base(c: &Connection, schema: Vec<TagSchema>) -> Result<MusidexMetadata> {
+    let mut musics =
+        c.prepare_cached("SELECT * FROM musics WHERE id NOT IN (SELECT music_id FROM trash)")?;
+    let musics = musics.query_map([], |r| Ok(Into::into(r)))?;
+    let musics = collect_rows(musics.map(|x| x.map(|v: Music| v.id)))?;
 
     let mut users = User::list(c)?;
-    let config = config::get_all(c)?;
+    let config = config::get_public(c)?;
 
     users.sort_by(|a, b| a.name.cmp(&b.name));
 
     Ok(MusidexMetadata {
         musics,
-        tags: Some(tags),
+        tags: None,
         users,
         settings: config,
+        schema,
         patches: None,
+        seq: 0,
+    })
+}
+
+pub fn fetch_metadata(c: &Connection) -> Result<MusidexMetadata> {
+    read_snapshot(c, |c| {
+        let seq = head_seq(c)?;
+        let mut tags = c.prepare_cached(
+            "SELECT * FROM tags WHERE music_id NOT IN (SELECT music_id FROM trash)",
+        )?;
+        let tags = tags.query_map([], |r| Ok(Into::into(r)))?;
+
+        let schema = TagSchema::list(c)?;
+
+        let tags = tags.filter(|tag: &Result<Tag, _>| {
+            if let Ok(tag) = tag {
+                if !TagSchema::is_synced(&schema, &tag.key) {
+                    return false;
+                }
+            }
+            true
+        });
+
+        let tags: Vec<Tag> = collect_rows(tags)?;
+
+        let mut meta = fetch_metadata_base(c, schema)?;
+        meta.tags = Some(tags);
+        meta.seq = seq;
+        Ok(meta)
     })
 }
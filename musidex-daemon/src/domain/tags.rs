@@ -197,6 +197,30 @@ impl Tag {
         collect_rows(v)
     }
 
+    /// Every tag of the musics that have the key and aren't in the trash, in one query
+    pub fn of_musics_with_key(c: &Connection, key: &TagKey) -> Result<Vec<Tag>> {
+        let mut stmt = c.prepare_cached(
+            "
+            SELECT * FROM tags
+            WHERE music_id IN (SELECT music_id FROM tags WHERE key=?1)
+            AND music_id NOT IN (SELECT music_id FROM trash);",
+        )?;
+        let v = stmt.query_map([key], |row| Ok(Tag::from(row)))?;
+        collect_rows(v)
+    }
+
+    /// Like by_key without the musics in the trash, for what users browse or play
+    pub fn by_key_visible(c: &Connection, key: &TagKey) -> Result<Vec<Tag>> {
+        let mut stmt = c.prepare_cached(
+            "
+            SELECT * FROM tags
+            WHERE key=?1 AND music_id NOT IN (SELECT music_id FROM trash)
+            ORDER BY music_id DESC;",
+        )?;
+        let v = stmt.query_map([key], |row| Ok(Tag::from(row)))?;
+        collect_rows(v)
+    }
+
     pub fn by_id_key(c: &Connection, id: MusicID, key: &TagKey) -> Result<Option<Tag>> {
         let mut stmt = c.prepare_cached(
             "
@@ -215,4 +239,15 @@ impl Tag {
         let v: i32 = stmt.query_row(rusqlite::params![id.0, key], |row| row.get(0))?;
         Ok(v == 1)
     }
+
+    /// Like has but false for the musics in the trash
+    pub fn has_visible(c: &Connection, id: MusicID, key: &TagKey) -> Result<bool> {
+        let mut stmt = c.prepare_cached(
+            "
+            SELECT count(1) FROM tags
+            WHERE music_id=?1 AND key=?2 AND music_id NOT IN (SELECT music_id FROM trash);",
+        )?;
+        let v: i32 = stmt.query_row(rusqlite::params![id.0, key], |row| row.get(0))?;
+        Ok(v == 1)
+    }
 }
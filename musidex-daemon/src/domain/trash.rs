@@ -0,0 +1,127 @@
+//! Deleted musics go to the trash of whoever deleted them, keeping all their tags, embeddings
+//! and history so they can be restored. They are hidden from the metadata sent to clients
+//! and purged for good after a number of days.
+
+use crate::domain::entity::{Music, MusicID, Tag, TagKey, UserID};
+use crate::infrastructure::db::Db;
+use crate::utils::{collect_rows, row_missing_opt};
+use anyhow::{Context, Result};
+use hyper::StatusCode;
+use nanoserde::SerJson;
+use rusqlite::Connection;
+use std::collections::HashSet;
+use std::time::Duration;
+
+#[derive(SerJson, Debug, Clone, PartialEq)]
+pub struct TrashEntry {
+    pub music_id: MusicID,
+    pub deleted_at: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+/// Does nothing if the music already is in the trash
+pub fn trash(c: &Connection, user: UserID, id: MusicID) -> Result<()> {
+    log::info!("moving music {:?} to the trash of {:?}", id, user);
+    c.prepare_cached(
+        "INSERT INTO trash (music_id, user_id, deleted_at) VALUES (?1, ?2, DATETIME('now'))
+        ON CONFLICT (music_id) DO NOTHING;",
+    )?
+    .execute([id.0, user.0])
+    .context("error trashing music")?;
+    Ok(())
+}
+
+/// Only the user who deleted the music can restore it
+pub fn restore(c: &Connection, user: UserID, id: MusicID) -> Result<StatusCode> {
+    let mut stmt = c.prepare_cached("SELECT user_id FROM trash WHERE music_id=?1;")?;
+    let owner = row_missing_opt(stmt.query_row([id.0], |row| row.get(0).map(Some)))?;
+    match owner {
+        None => return Ok(StatusCode::NOT_FOUND),
+        Some(owner) if UserID(owner) != user => return Ok(StatusCode::FORBIDDEN),
+        Some(_) => {}
+    }
+    c.prepare_cached("DELETE FROM trash WHERE music_id=?1;")?
+        .execute([id.0])?;
+    Ok(StatusCode::OK)
+}
+
+pub fn contains(c: &Connection, id: MusicID) -> Result<bool> {
+    let mut stmt = c.prepare_cached("SELECT count(1) FROM trash WHERE music_id=?1;")?;
+    Ok(stmt.query_row([id.0], |row| row.get::<_, i64>(0))? > 0)
+}
+
+pub fn ids(c: &Connection) -> Result<HashSet<MusicID>> {
+    let mut stmt = c.prepare_cached("SELECT music_id FROM trash;")?;
+    let v = stmt.query_map([], |row| row.get(0).map(MusicID))?;
+    Ok(collect_rows(v)?.into_iter().collect())
+}
+
+/// What the user deleted, most recent first
+pub fn list(c: &Connection, user: UserID) -> Result<Vec<TrashEntry>> {
+    let mut stmt = c.prepare_cached(
+        "SELECT music_id, deleted_at FROM trash WHERE user_id=?1
+        ORDER BY deleted_at DESC, music_id DESC;",
+    )?;
+    let rows = stmt.query_map([user.0], |row| {
+        Ok((MusicID(row.get(0)?), row.get::<_, String>(1)?))
+    })?;
+    let mut entries = vec![];
+    for (music_id, deleted_at) in collect_rows(rows)? {
+        let text = |key| -> Result<Option<String>> {
+            Ok(Tag::by_id_key(c, music_id, &key)?.and_then(|t| t.text))
+        };
+        entries.push(TrashEntry {
+            music_id,
+            deleted_at,
+            title: text(TagKey::Title)?,
+            artist: text(TagKey::Artist)?,
+        });
+    }
+    Ok(entries)
+}
+
+/// Deletes the musics trashed more than `days` ago, returns how many were
+pub fn purge(c: &Connection, days: u32) -> Result<usize> {
+    let mut stmt =
+        c.prepare_cached("SELECT music_id FROM trash WHERE deleted_at <= DATETIME('now', ?1);")?;
+    let old =
+        collect_rows(stmt.query_map([format!("-{} days", days)], |row| row.get(0).map(MusicID))?)?;
+    for &id in &old {
+        Music::delete(c, id)?;
+    }
+    Ok(old.len())
+}
+
+pub struct TrashPurgeWorker {
+    db: Db,
+    days: u32,
+}
+
+impl TrashPurgeWorker {
+    pub fn new(db: Db, days: u32) -> Self {
+        TrashPurgeWorker { db, days }
+    }
+
+    pub fn start(self) {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.step().await {
+                    log::error!("error while purging the trash: {:?}", e);
+                }
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+            }
+        });
+    }
+
+    pub async fn step(&self) -> Result<usize> {
+        let mut c = self.db.get().await;
+        let tx = c.transaction()?;
+        let n = purge(&tx, self.days)?;
+        tx.commit()?;
+        if n > 0 {
+            log::info!("purged {} musics from the trash", n);
+        }
+        Ok(n)
+    }
+}
@@ -1,10 +1,17 @@
-use crate::domain::entity::{Music, MusicID, Tag, TagKey, UserID};
+use crate::domain::entity::{Music, MusicID, MusicMerge, Tag, TagKey};
+use crate::domain::history::Editor;
+use crate::domain::jobs::{self, JobKind};
+use crate::domain::trash;
 use crate::infrastructure::youtube_dl::{ytdl_run_with_args, SingleVideo, YoutubeDlOutput};
 use anyhow::{Context, Result};
 use hyper::StatusCode;
 use rusqlite::Connection;
 
-pub async fn youtube_upload(c: &mut Connection, url: String, uid: UserID) -> Result<StatusCode> {
+pub async fn youtube_upload(
+    c: &mut Connection,
+    editor: &Editor,
+    url: String,
+) -> Result<StatusCode> {
     let metadata = ytdl_run_with_args(vec!["--no-playlist", "-J", "--", &url])
         .await
         .context("error downloading metadata")?;
@@ -13,46 +20,54 @@ pub async fn youtube_upload(c: &mut Connection, url: String, uid: UserID) -> Res
         YoutubeDlOutput::SingleVideo(v) => v,
     };
     if let Some(mid) = id_exists(c, &v.id)? {
-        let k = TagKey::UserLibrary(s!(uid));
+        let k = TagKey::UserLibrary(s!(editor.user_id));
         if Tag::has(&c, mid, &k)? {
             return Ok(StatusCode::CONFLICT);
         }
         let max_id = Tag::max_integer_by_key(c, &k)?.unwrap_or(0);
-        Tag::insert(&c, Tag::new_integer(mid, k, max_id + 100))?;
+        editor.insert_tag(c, Tag::new_integer(mid, k, max_id + 100))?;
         return Ok(StatusCode::OK);
     }
     let wp = v.webpage_url.take().context("no webpage url")?;
     let tx = c.transaction()?;
-    push_for_treatment(&tx, v, wp, uid).context("error pushing for treatment")?;
+    push_for_treatment(&tx, editor, v, wp).context("error pushing for treatment")?;
     tx.commit()?;
     Ok(StatusCode::OK)
 }
 
-fn id_exists(c: &Connection, id: &str) -> Result<Option<MusicID>> {
-    Ok(Tag::by_key_text(c, &TagKey::YoutubeDLVideoID, id)
-        .context("error getting ids")?
-        .into_iter()
-        .find_map(|t| {
-            if t.text.as_deref() == Some(id) {
-                return Some(t.music_id);
-            }
-            None
-        }))
+/// The music downloaded from this YouTube video, merged into another one or not.
+/// Musics in the trash don't count, uploading them again downloads a new one.
+pub fn id_exists(c: &Connection, id: &str) -> Result<Option<MusicID>> {
+    for t in Tag::by_key_text(c, &TagKey::YoutubeDLVideoID, id).context("error getting ids")? {
+        if t.text.as_deref() == Some(id) && !trash::contains(c, t.music_id)? {
+            return Ok(Some(t.music_id));
+        }
+    }
+    let merged = MusicMerge::find_merged_tag(c, &TagKey::YoutubeDLVideoID, id)
+        .context("error getting merged ids")?;
+    match merged {
+        Some(mid) if trash::contains(c, mid)? => Ok(None),
+        x => Ok(x),
+    }
 }
 
-fn push_for_treatment(c: &Connection, v: Box<SingleVideo>, url: String, uid: UserID) -> Result<()> {
+fn push_for_treatment(
+    c: &Connection,
+    editor: &Editor,
+    v: Box<SingleVideo>,
+    url: String,
+) -> Result<()> {
     let id = Music::mk(&c)?;
 
-    let mk_tag = |key, v| Tag::insert(&c, Tag::new_text(id, key, v));
+    let mk_tag = |key, v| editor.insert_tag(c, Tag::new_text(id, key, v));
 
     let (title, artist) = parse_title(&v.title, &v);
     mk_tag(TagKey::YoutubeDLURL, url)?;
     mk_tag(TagKey::YoutubeDLVideoID, v.id)?;
-    mk_tag(TagKey::YoutubeDLWorkerTreated, s!("false"))?;
     mk_tag(TagKey::Title, title)?;
     if let Some(v) = v.duration {
-        Tag::insert(
-            &c,
+        editor.insert_tag(
+            c,
             Tag {
                 music_id: id,
                 key: TagKey::Duration,
@@ -71,10 +86,11 @@ fn push_for_treatment(c: &Connection, v: Box<SingleVideo>, url: String, uid: Use
     }
     mk_tag(TagKey::YoutubeDLOriginalTitle, v.title)?;
 
-    let ul_key = TagKey::UserLibrary(s!(uid));
+    let ul_key = TagKey::UserLibrary(s!(editor.user_id));
     let max_id = Tag::max_integer_by_key(c, &ul_key)?.unwrap_or(0);
     let tag = Tag::new_integer(id, ul_key, max_id + 100);
-    Tag::insert(&c, tag)?;
+    editor.insert_tag(c, tag)?;
+    jobs::enqueue(c, JobKind::YoutubeDL, Some(id))?;
     Ok(())
 }
 
@@ -127,7 +143,7 @@ pub async fn youtube_upload_playlist(
     url: String,
     index_start: Option<usize>,
     index_stop: Option<usize>,
-    uid: UserID,
+    editor: &Editor,
 ) -> Result<(StatusCode, usize)> {
     let start = index_start.unwrap_or(0);
     let stop = index_stop.unwrap_or(50);
@@ -181,19 +197,19 @@ pub async fn youtube_upload_playlist(
                     bail!("only yt playlist are supported at the moment");
                 }
                 if let Some(mid) = id_exists(c, &entry.id)? {
-                    let k = TagKey::UserLibrary(s!(uid));
+                    let k = TagKey::UserLibrary(s!(editor.user_id));
                     if Tag::has(&c, mid, &k)? {
                         log::info!("music from playlist was already in library: {}", &entry.id);
                         continue;
                     }
-                    Tag::insert(&c, Tag::new_key(mid, k.clone()))?;
+                    editor.insert_tag(c, Tag::new_key(mid, k.clone()))?;
                     continue;
                 }
                 let tx = c.transaction()?;
                 entry.playlist_title = p.title.clone();
                 let url = entry.url.take().context("no url?")?;
 
-                push_for_treatment(&tx, entry, url, uid)?;
+                push_for_treatment(&tx, editor, entry, url)?;
                 tx.commit()?;
             }
             return Ok((StatusCode::OK, count));
@@ -0,0 +1,712 @@
+use crate::domain::config;
+use crate::domain::entity::{MusicID, User, UserID};
+use crate::domain::library::{decode_hex, encode_hex, Library, Song};
+use crate::domain::stream::content_type;
+use crate::domain::sync::head_seq;
+use crate::infrastructure::xml;
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use std::collections::HashSet;
+use std::fmt::Write;
+use tinyrand::{Rand, Seeded, StdRand};
+
+pub const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+pub const CONTENT_DIRECTORY: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+pub const CONNECTION_MANAGER: &str = "urn:schemas-upnp-org:service:ConnectionManager:1";
+
+const PROTOCOL_INFO: &str =
+    "http-get:*:audio/mpeg:*,http-get:*:audio/mp4:*,http-get:*:audio/ogg:*,http-get:*:audio/webm:*";
+
+pub struct UpnpDevice {
+    pub uuid: String,
+    pub name: String,
+}
+
+impl UpnpDevice {
+    /// The uuid is generated once and kept in the config so that control points recognize us
+    pub fn load(c: &Connection, name: String) -> Result<UpnpDevice> {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let mut rng = StdRand::seed(seed);
+        let (a, b) = (rng.next_u64(), rng.next_u64());
+        let uuid = format!(
+            "{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+            a >> 32,
+            (a >> 16) & 0xffff,
+            a & 0xfff,
+            0x8000 | ((b >> 48) & 0x3fff),
+            b & 0xffff_ffff_ffff
+        );
+        config::insert_if_not_exist(c, "upnp_uuid", &uuid)?;
+        let uuid = config::get(c, "upnp_uuid")?.context("upnp uuid missing")?;
+        Ok(UpnpDevice { uuid, name })
+    }
+}
+
+/// UPnP errors returned in SOAP faults
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UpnpError {
+    InvalidAction = 401,
+    InvalidArgs = 402,
+    NoSuchObject = 701,
+}
+
+impl UpnpError {
+    pub fn description(self) -> &'static str {
+        match self {
+            UpnpError::InvalidAction => "Invalid Action",
+            UpnpError::InvalidArgs => "Invalid Args",
+            UpnpError::NoSuchObject => "No such object",
+        }
+    }
+}
+
+/// The tree browsed by control points:
+///
+/// - 0: one container per user
+/// - u1: All music, Artists, Playlists
+/// - u1/artists/<hex name>, u1/playlists/<hex name>: the songs
+///
+/// Songs are items under their container, like u1/all/12
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Object {
+    Root,
+    User(UserID),
+    All(UserID),
+    Artists(UserID),
+    Playlists(UserID),
+    Artist(UserID, String),
+    Playlist(UserID, String),
+    Item(Box<Object>, MusicID),
+}
+
+impl Object {
+    pub fn parse(id: &str) -> Option<Object> {
+        if id == "0" {
+            return Some(Object::Root);
+        }
+        let parts: Vec<&str> = id.split('/').collect();
+        let user = UserID(parts[0].strip_prefix('u')?.parse().ok()?);
+        let (container, rest) = match &parts[1..] {
+            [] => (Object::User(user), &[][..]),
+            ["all", rest @ ..] => (Object::All(user), rest),
+            ["artists"] => (Object::Artists(user), &[][..]),
+            ["playlists"] => (Object::Playlists(user), &[][..]),
+            ["artists", name, rest @ ..] => (Object::Artist(user, decode_hex(name)?), rest),
+            ["playlists", name, rest @ ..] => (Object::Playlist(user, decode_hex(name)?), rest),
+            _ => return None,
+        };
+        match (rest, &container) {
+            ([], _) => Some(container),
+            ([id], Object::All(_) | Object::Artist(..) | Object::Playlist(..)) => {
+                let id = MusicID(id.parse().ok()?);
+                Some(Object::Item(Box::new(container), id))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> String {
+        match self {
+            Object::Root => s!("0"),
+            Object::User(u) => format!("u{}", u.0),
+            Object::All(u) => format!("u{}/all", u.0),
+            Object::Artists(u) => format!("u{}/artists", u.0),
+            Object::Playlists(u) => format!("u{}/playlists", u.0),
+            Object::Artist(u, name) => format!("u{}/artists/{}", u.0, encode_hex(name)),
+            Object::Playlist(u, name) => format!("u{}/playlists/{}", u.0, encode_hex(name)),
+            Object::Item(parent, id) => format!("{}/{}", parent.id(), id.0),
+        }
+    }
+
+    pub fn parent(&self) -> Option<Object> {
+        Some(match self {
+            Object::Root => return None,
+            Object::User(_) => Object::Root,
+            Object::All(u) | Object::Artists(u) | Object::Playlists(u) => Object::User(*u),
+            Object::Artist(u, _) => Object::Artists(*u),
+            Object::Playlist(u, _) => Object::Playlists(*u),
+            Object::Item(parent, _) => (**parent).clone(),
+        })
+    }
+
+    fn user(&self) -> Option<UserID> {
+        match self {
+            Object::Root => None,
+            Object::User(u)
+            | Object::All(u)
+            | Object::Artists(u)
+            | Object::Playlists(u)
+            | Object::Artist(u, _)
+            | Object::Playlist(u, _) => Some(*u),
+            Object::Item(parent, _) => parent.user(),
+        }
+    }
+}
+
+enum Entry<'a> {
+    Container {
+        obj: Object,
+        title: String,
+        class: &'static str,
+        children: usize,
+    },
+    Item {
+        obj: Object,
+        song: &'a Song,
+    },
+}
+
+/// The libraries needed to browse an object, loaded once per request.
+/// Only the library of the user of the object is loaded, the root lists users by name and
+/// only searching it needs every library.
+struct Libraries {
+    users: Vec<User>,
+    libraries: Vec<Library>,
+}
+
+impl Libraries {
+    fn load(c: &Connection, obj: &Object, search: bool) -> Result<Libraries> {
+        let users = User::list(c)?;
+        let libraries = users
+            .iter()
+            .filter(|u| obj.user().map_or(search, |id| u.id == id))
+            .map(|u| Library::load(c, u.clone()))
+            .collect::<Result<_>>()?;
+        Ok(Libraries { users, libraries })
+    }
+
+    fn get(&self, user: UserID) -> Option<&Library> {
+        self.libraries.iter().find(|l| l.user.id == user)
+    }
+
+    /// The songs of a container
+    fn songs(&self, obj: &Object) -> Option<Vec<&Song>> {
+        let lib = self.get(obj.user()?)?;
+        Some(match obj {
+            Object::All(_) => lib.songs.iter().collect(),
+            Object::Artist(_, name) => lib.artists().remove(name.as_str())?,
+            Object::Playlist(_, name) => lib.playlists().remove(name.as_str())?,
+            _ => return None,
+        })
+    }
+
+    fn entry(&self, obj: Object) -> Option<Entry<'_>> {
+        let container = |obj: Object, title: String, class, children| {
+            Some(Entry::Container {
+                obj,
+                title,
+                class,
+                children,
+            })
+        };
+        match obj {
+            Object::Root => container(
+                obj,
+                s!("Musidex"),
+                "object.container.storageFolder",
+                self.users.len(),
+            ),
+            Object::User(u) => {
+                let name = self.users.iter().find(|x| x.id == u)?.name.clone();
+                container(obj, name, "object.container.storageFolder", 3)
+            }
+            Object::All(u) => {
+                let n = self.get(u)?.songs.len();
+                container(obj, s!("All music"), "object.container.storageFolder", n)
+            }
+            Object::Artists(u) => {
+                let n = self.get(u)?.artists().len();
+                container(obj, s!("Artists"), "object.container.storageFolder", n)
+            }
+            Object::Playlists(u) => {
+                let n = self.get(u)?.playlists().len();
+                container(obj, s!("Playlists"), "object.container.storageFolder", n)
+            }
+            Object::Artist(_, ref name) | Object::Playlist(_, ref name) => {
+                let n = self.songs(&obj)?.len();
+                let class = match obj {
+                    Object::Artist(..) => "object.container.person.musicArtist",
+                    _ => "object.container.playlistContainer",
+                };
+                container(obj.clone(), name.clone(), class, n)
+            }
+            Object::Item(ref parent, id) => {
+                let song = *self.songs(parent)?.iter().find(|s| s.id == id)?;
+                Some(Entry::Item { obj, song })
+            }
+        }
+    }
+
+    fn children(&self, obj: &Object) -> Option<Vec<Entry<'_>>> {
+        let containers =
+            |objs: Vec<Object>| objs.into_iter().filter_map(|o| self.entry(o)).collect();
+        Some(match obj {
+            Object::Root => containers(self.users.iter().map(|u| Object::User(u.id)).collect()),
+            Object::User(u) => containers(vec![
+                Object::All(*u),
+                Object::Artists(*u),
+                Object::Playlists(*u),
+            ]),
+            Object::Artists(u) => containers(
+                self.get(*u)?
+                    .artists()
+                    .keys()
+                    .map(|a| Object::Artist(*u, a.to_string()))
+                    .collect(),
+            ),
+            Object::Playlists(u) => containers(
+                self.get(*u)?
+                    .playlists()
+                    .keys()
+                    .map(|p| Object::Playlist(*u, p.to_string()))
+                    .collect(),
+            ),
+            Object::Item(..) => vec![],
+            _ => self
+                .songs(obj)?
+                .into_iter()
+                .map(|song| Entry::Item {
+                    obj: Object::Item(Box::new(obj.clone()), song.id),
+                    song,
+                })
+                .collect(),
+        })
+    }
+}
+
+fn duration(secs: i64) -> String {
+    format!(
+        "{}:{:02}:{:02}.000",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60
+    )
+}
+
+fn write_didl(out: &mut String, base: &str, entries: &[Entry]) {
+    out.push_str(
+        "<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" \
+        xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+        xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">",
+    );
+    for entry in entries {
+        match entry {
+            Entry::Container {
+                obj,
+                title,
+                class,
+                children,
+            } => {
+                let parent = obj.parent().map(|p| p.id()).unwrap_or_else(|| s!("-1"));
+                let _ = write!(
+                    out,
+                    "<container id=\"{}\" parentID=\"{}\" restricted=\"1\" searchable=\"1\" childCount=\"{}\">\
+                    <dc:title>{}</dc:title><upnp:class>{}</upnp:class></container>",
+                    xml::escape(&obj.id()),
+                    xml::escape(&parent),
+                    children,
+                    xml::escape(title),
+                    class
+                );
+            }
+            Entry::Item { obj, song } => {
+                let parent = obj.parent().map(|p| p.id()).unwrap_or_default();
+                let artist = xml::escape(&song.artist);
+                let _ = write!(
+                    out,
+                    "<item id=\"{}\" parentID=\"{}\" restricted=\"1\">\
+                    <dc:title>{}</dc:title><dc:creator>{}</dc:creator>\
+                    <upnp:artist>{}</upnp:artist><upnp:album>{}</upnp:album>\
+                    <upnp:class>object.item.audioItem.musicTrack</upnp:class>",
+                    xml::escape(&obj.id()),
+                    xml::escape(&parent),
+                    xml::escape(&song.title),
+                    artist,
+                    artist,
+                    artist,
+                );
+                if let Some(ref cover) = song.cover {
+                    let _ = write!(
+                        out,
+                        "<upnp:albumArtURI>{}/storage/{}</upnp:albumArtURI>",
+                        base,
+                        xml::escape(cover)
+                    );
+                }
+                if let Some(ref path) = song.path {
+                    let _ = write!(
+                        out,
+                        "<res protocolInfo=\"http-get:*:{}:*\"",
+                        content_type(path)
+                    );
+                    if let Some(d) = song.duration {
+                        let _ = write!(out, " duration=\"{}\"", duration(d));
+                    }
+                    let _ = write!(out, ">{}/api/stream/{}</res>", base, song.id.0);
+                }
+                out.push_str("</item>");
+            }
+        }
+    }
+    out.push_str("</DIDL-Lite>");
+}
+
+/// Criteria like `upnp:class derivedfrom "object.item.audioItem" and dc:title contains "x"`.
+/// Only the "contains" and "=" conditions on text properties are used, all of them must match.
+fn search_matches(criteria: &str, song: &Song) -> bool {
+    let mut rest = criteria;
+    while let Some(i) = rest.find('"') {
+        let before = &rest[..i];
+        let after = &rest[i + 1..];
+        let end = after.find('"').unwrap_or(after.len());
+        let value = after[..end].to_lowercase();
+        rest = after.get(end + 1..).unwrap_or_default();
+
+        let mut words = before.split_whitespace().rev();
+        let (op, prop) = (
+            words.next().unwrap_or_default(),
+            words.next().unwrap_or_default(),
+        );
+        let target = match prop {
+            "dc:title" => song.title.to_lowercase(),
+            "dc:creator" | "upnp:artist" | "upnp:album" => song.artist.to_lowercase(),
+            _ => continue,
+        };
+        let ok = match op {
+            "contains" => target.contains(&value),
+            "=" => target == value,
+            "!=" => target != value,
+            "doesNotContain" => !target.contains(&value),
+            _ => true,
+        };
+        if !ok {
+            return false;
+        }
+    }
+    true
+}
+
+fn arg(body: &str, name: &str) -> Result<String, UpnpError> {
+    xml::element_text(body, name).ok_or(UpnpError::InvalidArgs)
+}
+
+fn arg_num(body: &str, name: &str) -> Result<usize, UpnpError> {
+    arg(body, name)?
+        .trim()
+        .parse()
+        .map_err(|_| UpnpError::InvalidArgs)
+}
+
+fn page<T>(v: Vec<T>, start: usize, count: usize) -> Vec<T> {
+    let count = if count == 0 { usize::MAX } else { count };
+    v.into_iter().skip(start).take(count).collect()
+}
+
+type Outputs = Vec<(&'static str, String)>;
+
+/// Runs a ContentDirectory action, returns its output arguments.
+/// base is the url the daemon is reached at by the control point, like http://192.168.1.2:3200
+pub fn content_directory(
+    c: &Connection,
+    base: &str,
+    action: &str,
+    body: &str,
+) -> Result<Result<Outputs, UpnpError>> {
+    let update_id = (head_seq(c)? as u32).to_string();
+    let res = match action {
+        "GetSearchCapabilities" => Ok(vec![(
+            "SearchCaps",
+            s!("dc:title,dc:creator,upnp:artist,upnp:album,upnp:class"),
+        )]),
+        "GetSortCapabilities" => Ok(vec![("SortCaps", s!(""))]),
+        "GetSystemUpdateID" => Ok(vec![("Id", update_id)]),
+        "Browse" | "Search" => {
+            let id_arg = if action == "Browse" {
+                "ObjectID"
+            } else {
+                "ContainerID"
+            };
+            let args = arg(body, id_arg).and_then(|id| {
+                Ok((
+                    id,
+                    arg_num(body, "StartingIndex")?,
+                    arg_num(body, "RequestedCount")?,
+                ))
+            });
+            let (id, start, count) = match args {
+                Ok(x) => x,
+                Err(e) => return Ok(Err(e)),
+            };
+            let obj = unwrap_ret!(Object::parse(&id), Ok(Err(UpnpError::NoSuchObject)));
+            let libraries = Libraries::load(c, &obj, action == "Search")?;
+
+            let entries = match action {
+                "Search" => {
+                    let criteria = arg(body, "SearchCriteria").unwrap_or_default();
+                    let containers = match obj {
+                        Object::Root | Object::User(_) => libraries
+                            .libraries
+                            .iter()
+                            .filter(|l| obj.user().is_none_or(|u| u == l.user.id))
+                            .map(|l| Object::All(l.user.id))
+                            .collect(),
+                        Object::Artists(_) | Object::Playlists(_) => {
+                            let children = libraries.children(&obj).unwrap_or_default();
+                            children
+                                .into_iter()
+                                .filter_map(|e| match e {
+                                    Entry::Container { obj, .. } => Some(obj),
+                                    Entry::Item { .. } => None,
+                                })
+                                .collect()
+                        }
+                        _ => vec![obj.clone()],
+                    };
+                    let mut entries = vec![];
+                    let mut seen = HashSet::new();
+                    for container in containers {
+                        for song in libraries.songs(&container).unwrap_or_default() {
+                            if !seen.insert(song.id) {
+                                continue;
+                            }
+                            if criteria.trim() == "*" || search_matches(&criteria, song) {
+                                entries.push(Entry::Item {
+                                    obj: Object::Item(Box::new(container.clone()), song.id),
+                                    song,
+                                });
+                            }
+                        }
+                    }
+                    Some(entries)
+                }
+                _ => match arg(body, "BrowseFlag").as_deref() {
+                    Ok("BrowseMetadata") => libraries.entry(obj).map(|e| vec![e]),
+                    Ok("BrowseDirectChildren") => libraries.children(&obj),
+                    _ => return Ok(Err(UpnpError::InvalidArgs)),
+                },
+            };
+            let entries = unwrap_ret!(entries, Ok(Err(UpnpError::NoSuchObject)));
+            let total = entries.len();
+            let entries = page(entries, start, count);
+            let mut didl = String::new();
+            write_didl(&mut didl, base, &entries);
+            Ok(vec![
+                ("Result", didl),
+                ("NumberReturned", entries.len().to_string()),
+                ("TotalMatches", total.to_string()),
+                ("UpdateID", update_id),
+            ])
+        }
+        _ => Err(UpnpError::InvalidAction),
+    };
+    Ok(res)
+}
+
+pub fn connection_manager(action: &str) -> Result<Outputs, UpnpError> {
+    match action {
+        "GetProtocolInfo" => Ok(vec![("Source", s!(PROTOCOL_INFO)), ("Sink", s!(""))]),
+        "GetCurrentConnectionIDs" => Ok(vec![("ConnectionIDs", s!("0"))]),
+        "GetCurrentConnectionInfo" => Ok(vec![
+            ("RcsID", s!("-1")),
+            ("AVTransportID", s!("-1")),
+            ("ProtocolInfo", s!("")),
+            ("PeerConnectionManager", s!("")),
+            ("PeerConnectionID", s!("-1")),
+            ("Direction", s!("Output")),
+            ("Status", s!("OK")),
+        ]),
+        _ => Err(UpnpError::InvalidAction),
+    }
+}
+
+pub fn description(uuid: &str, name: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0" xmlns:dlna="urn:schemas-dlna-org:device-1-0">
+<specVersion><major>1</major><minor>0</minor></specVersion>
+<device>
+<deviceType>{}</deviceType>
+<friendlyName>{}</friendlyName>
+<manufacturer>Musidex</manufacturer>
+<modelName>Musidex</modelName>
+<modelNumber>{}</modelNumber>
+<UDN>uuid:{}</UDN>
+<dlna:X_DLNADOC>DMS-1.50</dlna:X_DLNADOC>
+<serviceList>
+<service>
+<serviceType>{}</serviceType>
+<serviceId>urn:upnp-org:serviceId:ContentDirectory</serviceId>
+<SCPDURL>/upnp/content_directory.xml</SCPDURL>
+<controlURL>/upnp/control/content_directory</controlURL>
+<eventSubURL>/upnp/event/content_directory</eventSubURL>
+</service>
+<service>
+<serviceType>{}</serviceType>
+<serviceId>urn:upnp-org:serviceId:ConnectionManager</serviceId>
+<SCPDURL>/upnp/connection_manager.xml</SCPDURL>
+<controlURL>/upnp/control/connection_manager</controlURL>
+<eventSubURL>/upnp/event/connection_manager</eventSubURL>
+</service>
+</serviceList>
+</device>
+</root>"#,
+        DEVICE_TYPE,
+        xml::escape(name),
+        env!("CARGO_PKG_VERSION"),
+        uuid,
+        CONTENT_DIRECTORY,
+        CONNECTION_MANAGER
+    )
+}
+
+/// (name, is an input, related state variable)
+type Argument<'a> = (&'a str, bool, &'a str);
+
+fn scpd(actions: &[(&str, &[Argument])], vars: &[(&str, &str)]) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <scpd xmlns=\"urn:schemas-upnp-org:service-1-0\">\
+        <specVersion><major>1</major><minor>0</minor></specVersion><actionList>",
+    );
+    for (name, args) in actions {
+        let _ = write!(out, "<action><name>{}</name><argumentList>", name);
+        for (arg, is_in, var) in args.iter() {
+            let _ = write!(
+                out,
+                "<argument><name>{}</name><direction>{}</direction>\
+                <relatedStateVariable>{}</relatedStateVariable></argument>",
+                arg,
+                if *is_in { "in" } else { "out" },
+                var
+            );
+        }
+        out.push_str("</argumentList></action>");
+    }
+    out.push_str("</actionList><serviceStateTable>");
+    for (name, type_) in vars {
+        let events = if *name == "SystemUpdateID" {
+            "yes"
+        } else {
+            "no"
+        };
+        let _ = write!(
+            out,
+            "<stateVariable sendEvents=\"{}\"><name>{}</name><dataType>{}</dataType></stateVariable>",
+            events, name, type_
+        );
+    }
+    out.push_str("</serviceStateTable></scpd>");
+    out
+}
+
+pub fn content_directory_scpd() -> String {
+    let browse_out: &[(&str, bool, &str)] = &[
+        ("Result", false, "A_ARG_TYPE_Result"),
+        ("NumberReturned", false, "A_ARG_TYPE_Count"),
+        ("TotalMatches", false, "A_ARG_TYPE_Count"),
+        ("UpdateID", false, "A_ARG_TYPE_UpdateID"),
+    ];
+    let browse: Vec<_> = [
+        ("ObjectID", true, "A_ARG_TYPE_ObjectID"),
+        ("BrowseFlag", true, "A_ARG_TYPE_BrowseFlag"),
+        ("Filter", true, "A_ARG_TYPE_Filter"),
+        ("StartingIndex", true, "A_ARG_TYPE_Index"),
+        ("RequestedCount", true, "A_ARG_TYPE_Count"),
+        ("SortCriteria", true, "A_ARG_TYPE_SortCriteria"),
+    ]
+    .iter()
+    .chain(browse_out)
+    .copied()
+    .collect();
+    let search: Vec<_> = [
+        ("ContainerID", true, "A_ARG_TYPE_ObjectID"),
+        ("SearchCriteria", true, "A_ARG_TYPE_SearchCriteria"),
+        ("Filter", true, "A_ARG_TYPE_Filter"),
+        ("StartingIndex", true, "A_ARG_TYPE_Index"),
+        ("RequestedCount", true, "A_ARG_TYPE_Count"),
+        ("SortCriteria", true, "A_ARG_TYPE_SortCriteria"),
+    ]
+    .iter()
+    .chain(browse_out)
+    .copied()
+    .collect();
+    scpd(
+        &[
+            ("Browse", &browse),
+            ("Search", &search),
+            (
+                "GetSearchCapabilities",
+                &[("SearchCaps", false, "SearchCapabilities")],
+            ),
+            (
+                "GetSortCapabilities",
+                &[("SortCaps", false, "SortCapabilities")],
+            ),
+            ("GetSystemUpdateID", &[("Id", false, "SystemUpdateID")]),
+        ],
+        &[
+            ("A_ARG_TYPE_ObjectID", "string"),
+            ("A_ARG_TYPE_Result", "string"),
+            ("A_ARG_TYPE_BrowseFlag", "string"),
+            ("A_ARG_TYPE_Filter", "string"),
+            ("A_ARG_TYPE_SortCriteria", "string"),
+            ("A_ARG_TYPE_SearchCriteria", "string"),
+            ("A_ARG_TYPE_Index", "ui4"),
+            ("A_ARG_TYPE_Count", "ui4"),
+            ("A_ARG_TYPE_UpdateID", "ui4"),
+            ("SearchCapabilities", "string"),
+            ("SortCapabilities", "string"),
+            ("SystemUpdateID", "ui4"),
+        ],
+    )
+}
+
+pub fn connection_manager_scpd() -> String {
+    scpd(
+        &[
+            (
+                "GetProtocolInfo",
+                &[
+                    ("Source", false, "SourceProtocolInfo"),
+                    ("Sink", false, "SinkProtocolInfo"),
+                ],
+            ),
+            (
+                "GetCurrentConnectionIDs",
+                &[("ConnectionIDs", false, "CurrentConnectionIDs")],
+            ),
+            (
+                "GetCurrentConnectionInfo",
+                &[
+                    ("ConnectionID", true, "A_ARG_TYPE_ConnectionID"),
+                    ("RcsID", false, "A_ARG_TYPE_RcsID"),
+                    ("AVTransportID", false, "A_ARG_TYPE_AVTransportID"),
+                    ("ProtocolInfo", false, "A_ARG_TYPE_ProtocolInfo"),
+                    (
+                        "PeerConnectionManager",
+                        false,
+                        "A_ARG_TYPE_ConnectionManager",
+                    ),
+                    ("PeerConnectionID", false, "A_ARG_TYPE_ConnectionID"),
+                    ("Direction", false, "A_ARG_TYPE_Direction"),
+                    ("Status", false, "A_ARG_TYPE_ConnectionStatus"),
+                ],
+            ),
+        ],
+        &[
+            ("SourceProtocolInfo", "string"),
+            ("SinkProtocolInfo", "string"),
+            ("CurrentConnectionIDs", "string"),
+            ("A_ARG_TYPE_ConnectionID", "i4"),
+            ("A_ARG_TYPE_RcsID", "i4"),
+            ("A_ARG_TYPE_AVTransportID", "i4"),
+            ("A_ARG_TYPE_ProtocolInfo", "string"),
+            ("A_ARG_TYPE_ConnectionManager", "string"),
+            ("A_ARG_TYPE_Direction", "string"),
+            ("A_ARG_TYPE_ConnectionStatus", "string"),
+        ],
+    )
+}
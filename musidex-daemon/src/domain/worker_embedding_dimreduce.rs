@@ -1,9 +1,8 @@
-use std::time::Duration;
-
 use anyhow::{Context, Result};
 use rusqlite::Connection;
 
 use crate::domain::entity::{Tag, TagKey, Vector};
+use crate::domain::jobs::{self, JobKind};
 use crate::infrastructure::db::Db;
 use nalgebra::SVD;
 
@@ -16,16 +15,10 @@ impl EmbeddingReduceWorker {
         EmbeddingReduceWorker { db }
     }
 
+    /// A single reduce job is queued at a time since the whole library is reduced at once
     pub fn start(self) {
-        let _ = tokio::spawn(async move {
-            loop {
-                let mut c = self.db.get().await;
-                let v = Self::step(&mut c).context("error while running dimreduce worker");
-                if let Err(e) = v {
-                    log::error!("{:?}", e);
-                }
-                tokio::time::sleep(Duration::from_secs(5)).await;
-            }
+        jobs::consume(self.db, JobKind::EmbeddingReduce, |db, _| async move {
+            Self::step(&mut *db.get().await)
         });
     }
 
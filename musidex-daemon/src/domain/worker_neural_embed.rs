@@ -1,3 +1,5 @@
+use crate::domain::entity::MusicID;
+use crate::domain::jobs::{self, Job, JobKind};
 use crate::infrastructure::db::Db;
 use crate::utils::collect_rows;
 use anyhow::{Context, Result};
@@ -15,29 +17,29 @@ impl NeuralEmbedWorker {
         NeuralEmbedWorker { db }
     }
 
-    pub fn start(mut self) {
-        tokio::spawn(async move {
-            loop {
-                tokio::time::sleep(Duration::from_secs(5)).await;
-                let v = self
-                    .step()
-                    .await
-                    .context("error while running neural worker");
-                if let Err(e) = v {
-                    log::error!("{:?}", e);
-                }
-            }
+    pub fn start(self) {
+        jobs::consume(self.db, JobKind::NeuralEmbed, |db, job| async move {
+            Self::work(&db, job).await
         });
     }
 
-    pub async fn step(&mut self) -> Result<()> {
-        let g = self.db.get().await;
-        if !needs_embedding(&g)? {
+    /// The script embeds every music missing one, so the jobs queued meanwhile are often already done
+    pub async fn work(db: &Db, job: Job) -> Result<()> {
+        let id = job.music_id.context("embedding job without music")?;
+        if !needs_embedding(&*db.get().await, id)? {
             return Ok(());
         }
 
         log::info!("some musics need embeddings");
+        Self::run_script().await?;
+        if needs_embedding(&*db.get().await, id)? {
+            bail!("the script didn't embed music {}", id.0);
+        }
+        log::info!("done!");
+        Ok(())
+    }
 
+    async fn run_script() -> Result<()> {
         tokio::task::spawn_blocking(move || {
             let args = vec!["musidex-neuralembed/neuralembedv2.py"];
 
@@ -58,9 +60,8 @@ impl NeuralEmbedWorker {
             }
 
             if exit_code.is_none() {
-                log::error!("timeouted vectorizing... killing python");
                 child.kill().context("failed killing python")?;
-                return Ok(());
+                bail!("timeouted vectorizing, killed python");
             }
 
             if exit_code.unwrap().success() {
@@ -78,30 +79,29 @@ impl NeuralEmbedWorker {
                 ))
             }
         })
-        .await??;
-        log::info!("done!");
-        Ok(())
+        .await?
     }
 }
 
-pub fn needs_embedding(c: &Connection) -> Result<bool> {
+/// Musics longer than 30 minutes are too long to embed
+pub fn needs_embedding(c: &Connection, id: MusicID) -> Result<bool> {
     let mut v = c.prepare_cached(
         "
     SELECT * FROM musics
-    WHERE 
-        (SELECT COUNT(1) FROM tags 
+    WHERE id = ?1
+    AND
+        (SELECT COUNT(1) FROM tags
          WHERE music_id = id AND key='full_embedding') = 0
     AND
-        (SELECT COUNT(1) FROM tags 
+        (SELECT COUNT(1) FROM tags
          WHERE music_id = id AND key='local_mp3') = 1
     AND
-        (SELECT COUNT(1) FROM tags 
-         WHERE music_id = id AND key='duration' AND integer>30*60) = 0
-    LIMIT 1;
+        (SELECT COUNT(1) FROM tags
+         WHERE music_id = id AND key='duration' AND integer>30*60) = 0;
     ",
     )?;
-    let v = v.query([])?.mapped(|row| row.get(0));
+    let v = v.query([id.0])?.mapped(|row| row.get(0));
     let v: Vec<i32> = collect_rows(v)?;
 
-    Ok(v.len() > 0)
+    Ok(!v.is_empty())
 }
@@ -1,9 +1,8 @@
-use std::time::Duration;
-
 use anyhow::{Context, Result};
 use rusqlite::Connection;
 
 use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::domain::jobs::{self, Job, JobKind};
 use crate::infrastructure::db::Db;
 use crate::utils::row_missing_opt;
 use image::imageops::FilterType;
@@ -18,27 +17,16 @@ impl SmallThumbnailWorker {
         SmallThumbnailWorker { db }
     }
 
-    pub fn start(mut self) {
-        let _ = tokio::spawn(async move {
-            loop {
-                let v = self
-                    .step()
-                    .await
-                    .context("error while running youtubedl worker");
-                if let Err(e) = v {
-                    log::error!("{:?}", e);
-                }
-                tokio::time::sleep(Duration::from_secs(5)).await;
-            }
+    pub fn start(self) {
+        jobs::consume(self.db, JobKind::Thumbnail, |db, job| async move {
+            Self::work(&db, job).await
         });
     }
 
-    pub async fn step(&mut self) -> Result<()> {
-        let c = self.db.get().await;
-        let candidate = unwrap_ret!(find_candidate(&c)?, Ok(()));
-
-        let thumb = unwrap_ret!(Tag::by_id_key(&c, candidate, &TagKey::Thumbnail)?, Ok(()));
-        let thumb_p = unwrap_ret!(thumb.text, Ok(()));
+    pub async fn work(db: &Db, job: Job) -> Result<()> {
+        let candidate = job.music_id.context("thumbnail job without music")?;
+        let c = db.get().await;
+        let thumb_p = unwrap_ret!(needs_compression(&c, candidate)?, Ok(()));
 
         let buf = tokio::fs::read(format!("storage/{}", thumb_p)).await?;
         let img = image::load_from_memory(&buf)?;
@@ -74,10 +62,12 @@ impl SmallThumbnailWorker {
     }
 }
 
-pub fn find_candidate(c: &Connection) -> Result<Option<MusicID>> {
-    let mut stmt = c.prepare_cached("SELECT music_id as id2 FROM tags WHERE key=?1 AND 0 = (SELECT COUNT(1) FROM tags WHERE key=?2 AND music_id=id2) LIMIT 1;")?;
-    let v = stmt.query_row([TagKey::Thumbnail, TagKey::CompressedThumbnail], |x| {
-        x.get("id2").map(MusicID).map(Some)
-    });
-    row_missing_opt(v).context("failed getting id")
+/// The thumbnail to compress, None if the music has none or it already is
+pub fn needs_compression(c: &Connection, id: MusicID) -> Result<Option<String>> {
+    let mut stmt = c.prepare_cached("SELECT text FROM tags WHERE music_id=?1 AND key=?2 AND 0 = (SELECT COUNT(1) FROM tags WHERE key=?3 AND music_id=?1);")?;
+    let v = stmt.query_row(
+        rusqlite::params![id.0, TagKey::Thumbnail, TagKey::CompressedThumbnail],
+        |x| x.get("text"),
+    );
+    row_missing_opt(v).context("failed getting thumbnail")
 }
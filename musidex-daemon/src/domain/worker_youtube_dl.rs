@@ -1,10 +1,9 @@
 use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::domain::jobs::{self, Job, JobKind};
 use crate::infrastructure::db::Db;
 use crate::infrastructure::youtube_dl::{ytdl_run_with_args, SingleVideo, YoutubeDlOutput};
 use anyhow::{Context, Result};
 use image::ImageFormat;
-use rusqlite::Connection;
-use std::time::Duration;
 
 pub struct YoutubeDLWorker {
     db: Db,
@@ -15,26 +14,18 @@ impl YoutubeDLWorker {
         YoutubeDLWorker { db }
     }
 
-    pub fn start(mut self) {
-        let _ = tokio::spawn(async move {
-            loop {
-                tokio::time::sleep(Duration::from_secs(5)).await;
-                let v = self
-                    .step()
-                    .await
-                    .context("error while running youtubedl worker");
-                if let Err(e) = v {
-                    log::error!("{:?}", e);
-                }
-            }
+    pub fn start(self) {
+        jobs::consume(self.db, JobKind::YoutubeDL, |db, job| async move {
+            Self::work(&db, job).await
         });
     }
 
-    pub async fn step(&mut self) -> Result<()> {
-        let c = self.db.get().await;
-        let candidate = unwrap_ret!(Self::find_candidate(&c)?, Ok(()));
-        Self::youtube_dl_work(&self.db, candidate).await?;
-        Ok(())
+    pub async fn work(db: &Db, job: Job) -> Result<()> {
+        let id = job.music_id.context("download job without music")?;
+        let url = Tag::by_id_key(&*db.get().await, id, &TagKey::YoutubeDLURL)?
+            .and_then(|t| t.text)
+            .context("no youtube url to download")?;
+        Self::youtube_dl_work(db, (id, url)).await
     }
 
     pub async fn youtube_dl_work(db: &Db, (id, vid_url): (MusicID, String)) -> Result<()> {
@@ -54,14 +45,7 @@ impl YoutubeDLWorker {
                     if tries > 0 {
                         continue;
                     }
-                    log::error!("{:?}", e);
-                    let c = db.get().await;
-                    Tag::insert(
-                        &c,
-                        Tag::new_text(id, TagKey::YoutubeDLWorkerTreated, s!("error")),
-                    )?;
-                    drop(c);
-                    return Ok(());
+                    return Err(e);
                 }
             };
             break;
@@ -119,21 +103,6 @@ impl YoutubeDLWorker {
         log::info!("success downloaded {}", vid_url);
         Ok(())
     }
-
-    pub fn find_candidate(c: &Connection) -> Result<Option<(MusicID, String)>> {
-        for yt_treated in Tag::by_key(c, &TagKey::YoutubeDLWorkerTreated)? {
-            if yt_treated.text.as_deref().unwrap_or("") != "false" {
-                continue;
-            }
-            let vid_url = unwrap_cont!(Tag::by_id_key(
-                c,
-                yt_treated.music_id,
-                &TagKey::YoutubeDLURL
-            )?);
-            return Ok(Some((yt_treated.music_id, unwrap_cont!(vid_url.text))));
-        }
-        Ok(None)
-    }
 }
 
 pub async fn download(vid_url: &str) -> Result<Box<SingleVideo>> {
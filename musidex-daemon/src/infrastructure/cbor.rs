@@ -0,0 +1,93 @@
+/// Minimal CBOR (RFC 8949) writer, only what is needed to encode the sync metadata
+#[derive(Default)]
+pub struct CborWriter {
+    pub buf: Vec<u8>,
+}
+
+const MAJOR_UINT: u8 = 0;
+const MAJOR_NINT: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+
+/// RFC 8746 typed array: float32, little endian
+pub const TAG_F32LE_ARRAY: u64 = 85;
+
+impl CborWriter {
+    fn head(&mut self, major: u8, v: u64) {
+        let major = major << 5;
+        match v {
+            0..=23 => self.buf.push(major | v as u8),
+            24..=0xFF => self.buf.extend_from_slice(&[major | 24, v as u8]),
+            0x100..=0xFFFF => {
+                self.buf.push(major | 25);
+                self.buf.extend_from_slice(&(v as u16).to_be_bytes());
+            }
+            0x10000..=0xFFFF_FFFF => {
+                self.buf.push(major | 26);
+                self.buf.extend_from_slice(&(v as u32).to_be_bytes());
+            }
+            _ => {
+                self.buf.push(major | 27);
+                self.buf.extend_from_slice(&v.to_be_bytes());
+            }
+        }
+    }
+
+    pub fn int(&mut self, v: i64) {
+        if v >= 0 {
+            self.head(MAJOR_UINT, v as u64)
+        } else {
+            self.head(MAJOR_NINT, !v as u64)
+        }
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.buf.push(if v { 0xF5 } else { 0xF4 });
+    }
+
+    pub fn null(&mut self) {
+        self.buf.push(0xF6);
+    }
+
+    pub fn text(&mut self, v: &str) {
+        self.head(MAJOR_TEXT, v.len() as u64);
+        self.buf.extend_from_slice(v.as_bytes());
+    }
+
+    pub fn tag(&mut self, tag: u64) {
+        self.head(MAJOR_TAG, tag);
+    }
+
+    pub fn array(&mut self, len: usize) {
+        self.head(MAJOR_ARRAY, len as u64);
+    }
+
+    pub fn map(&mut self, len: usize) {
+        self.head(MAJOR_MAP, len as u64);
+    }
+
+    pub fn opt_text(&mut self, v: Option<&str>) {
+        match v {
+            Some(v) => self.text(v),
+            None => self.null(),
+        }
+    }
+
+    pub fn opt_int(&mut self, v: Option<i64>) {
+        match v {
+            Some(v) => self.int(v),
+            None => self.null(),
+        }
+    }
+
+    pub fn f32_array(&mut self, v: &[f32]) {
+        self.tag(TAG_F32LE_ARRAY);
+        self.head(MAJOR_BYTES, (v.len() * 4) as u64);
+        for x in v {
+            self.buf.extend_from_slice(&x.to_le_bytes());
+        }
+    }
+}
@@ -1,10 +1,12 @@
-use crate::domain::entity::{MusicID, TagKey, UserID};
-use crate::utils::env_or;
+use crate::domain::entity::{MusicID, Tag, TagKey, UserID};
+use crate::utils::{collect_rows, env_or, row_missing_opt};
 use anyhow::{Context, Result};
-use rusqlite::{params, Connection};
+use nanoserde::{DeJson, SerJson};
+use rusqlite::{params, Connection, Row};
+use std::ops::Deref;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::{Mutex, MutexGuard};
+use tokio::sync::{Mutex, MutexGuard, Notify};
 
 #[derive(Default, Clone)]
 pub struct Db(Arc<DbInner>);
@@ -13,6 +15,7 @@ pub struct Db(Arc<DbInner>);
 pub struct DbInner {
     conns: Vec<Mutex<Connection>>,
     round_robin: AtomicUsize,
+    commits: Arc<Notify>,
 }
 
 pub type Client<'a> = MutexGuard<'a, Connection>;
@@ -23,7 +26,9 @@ impl Db {
     pub async fn connect() -> Result<Db> {
         let mut pool = DbInner::default();
         for _ in 0..N_CONN {
-            pool.conns.push(Mutex::new(Self::mk_conn()?));
+            let conn = Self::mk_conn()?;
+            pool.watch_commits(&conn);
+            pool.conns.push(Mutex::new(conn));
         }
         Ok(Db(Arc::new(pool)))
     }
@@ -41,10 +46,16 @@ impl Db {
         let conn = Connection::open_in_memory().unwrap();
         conn.execute_batch("PRAGMA foreign_keys = ON;").unwrap();
         let mut inner = DbInner::default();
+        inner.watch_commits(&conn);
         inner.conns.push(Mutex::new(conn));
         Db(Arc::new(inner))
     }
 
+    /// Notified whenever a connection of the pool commits a transaction
+    pub fn commits(&self) -> Arc<Notify> {
+        self.0.commits.clone()
+    }
+
     pub async fn get(&self) -> Client<'_> {
         for v in &self.0.conns {
             if let Ok(x) = v.try_lock() {
@@ -56,13 +67,23 @@ impl Db {
     }
 }
 
+impl DbInner {
+    fn watch_commits(&self, conn: &Connection) {
+        let commits = self.commits.clone();
+        conn.commit_hook(Some(move || {
+            commits.notify_one();
+            false
+        }));
+    }
+}
+
 pub enum LogType {
     User,
     Tag,
     Music,
+    Listen,
 }
 
-#[allow(dead_code)]
 pub enum LogAction {
     Create,
     Update,
@@ -77,13 +98,15 @@ pub struct DbLog {
     pub music_id: Option<MusicID>,
     pub target_key: Option<TagKey>,
     pub target_value: Option<String>,
+    pub before: Option<Tag>,
+    pub after: Option<Tag>,
 }
 
 pub fn db_log(c: &Connection, log: DbLog) {
     let mut stmt = c
         .prepare_cached(
-            "INSERT INTO logs (timestamp, user_id, ip, type, action, music_id, target_key, target_value)
-            VALUES (DATETIME('now'), ?1, ?2, ?3, ?4, ?5, ?6, ?7);",
+            "INSERT INTO logs (timestamp, user_id, ip, type, action, music_id, target_key, target_value, tag_before, tag_after)
+            VALUES (DATETIME('now'), ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9);",
         )
         .unwrap();
     stmt.execute(params![
@@ -93,6 +116,7 @@ pub fn db_log(c: &Connection, log: DbLog) {
             LogType::User => "user",
             LogType::Tag => "tag",
             LogType::Music => "music",
+            LogType::Listen => "listen",
         },
         match log.action {
             LogAction::Create => "create",
@@ -102,6 +126,62 @@ pub fn db_log(c: &Connection, log: DbLog) {
         log.music_id.map(|x| x.0),
         log.target_key,
         log.target_value,
+        log.before.map(|x| x.serialize_json()),
+        log.after.map(|x| x.serialize_json()),
     ])
     .unwrap();
 }
+
+#[derive(Clone, Debug, SerJson)]
+pub struct LogEntry {
+    pub id: i64,
+    pub timestamp: String,
+    pub user_id: UserID,
+    #[nserde(rename = "type")]
+    pub type_: String,
+    pub action: String,
+    pub music_id: Option<MusicID>,
+    pub target_key: Option<TagKey>,
+    pub target_value: Option<String>,
+    pub before: Option<Tag>,
+    pub after: Option<Tag>,
+}
+
+impl<'a, 'b> From<&'a Row<'b>> for LogEntry {
+    fn from(row: &'a Row<'b>) -> Self {
+        let snapshot = |col: &str| {
+            row.get_unwrap::<_, Option<String>>(col)
+                .and_then(|x| Tag::deserialize_json(&x).ok())
+        };
+        Self {
+            id: row.get_unwrap("id"),
+            timestamp: row.get_unwrap("timestamp"),
+            user_id: UserID(row.get_unwrap("user_id")),
+            type_: row.get_unwrap("type"),
+            action: row.get_unwrap("action"),
+            music_id: row.get_unwrap::<_, Option<i32>>("music_id").map(MusicID),
+            target_key: row
+                .get_unwrap::<_, Option<String>>("target_key")
+                .map(|x| x.deref().into()),
+            target_value: row.get_unwrap("target_value"),
+            before: snapshot("tag_before"),
+            after: snapshot("tag_after"),
+        }
+    }
+}
+
+impl LogEntry {
+    pub fn by_id(c: &Connection, id: i64) -> Result<Option<LogEntry>> {
+        let mut stmt = c.prepare_cached("SELECT * FROM logs WHERE id=?1;")?;
+        row_missing_opt(stmt.query_row([id], |row| Ok(Some(LogEntry::from(row)))))
+            .context("error getting log by id")
+    }
+
+    /// Returns the logs concerning a music, most recent first
+    pub fn by_music(c: &Connection, id: MusicID) -> Result<Vec<LogEntry>> {
+        let mut stmt =
+            c.prepare_cached("SELECT * FROM logs WHERE music_id=?1 ORDER BY id DESC;")?;
+        let v = stmt.query_map([id.0], |row| Ok(LogEntry::from(row)))?;
+        collect_rows(v)
+    }
+}
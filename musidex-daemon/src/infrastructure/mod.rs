@@ -1,4 +1,8 @@
+pub mod cbor;
 pub mod db;
 pub mod migrate;
 pub mod router;
+pub mod ssdp;
+pub mod tar;
+pub mod xml;
 pub mod youtube_dl;
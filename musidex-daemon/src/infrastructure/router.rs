@@ -490,6 +490,7 @@ impl Params {
 pub trait RequestExt {
     fn params(&self) -> &Params;
     fn cookies(&self) -> Option<&Cookies>;
+    fn query_params(&self) -> HashMap<String, String>;
     fn state<T: Send + Sync + 'static>(&self) -> &T;
 }
 
@@ -502,6 +503,12 @@ impl RequestExt for Request<Body> {
         self.extensions().get::<Cookies>()
     }
 
+    fn query_params(&self) -> HashMap<String, String> {
+        form_urlencoded::parse(self.uri().query().unwrap_or("").as_bytes())
+            .into_owned()
+            .collect()
+    }
+
     fn state<T: Send + Sync + 'static>(&self) -> &T {
         self.extensions()
             .get::<Arc<Extensions>>()
@@ -0,0 +1,164 @@
+//! SSDP, the discovery part of UPnP: answers M-SEARCH requests and regularly announces the device
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+pub const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+pub const PORT: u16 = 1900;
+const MAX_AGE: u64 = 1800;
+
+#[derive(Clone)]
+pub struct Ssdp {
+    pub uuid: String,
+    /// port the device description is served on
+    pub http_port: u16,
+    /// device and service types advertised besides upnp:rootdevice and the uuid
+    pub types: Vec<String>,
+}
+
+/// Address of this machine as seen from the peer, so that it can reach the description
+fn local_ip_for(peer: SocketAddr) -> IpAddr {
+    let ip = std::net::UdpSocket::bind(("0.0.0.0", 0))
+        .and_then(|s| s.connect(peer).and_then(|_| s.local_addr()))
+        .map(|x| x.ip());
+    ip.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+/// Socket listening on the SSDP multicast group, shared with the other SSDP services of the host
+pub fn multicast_socket() -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)).into())
+        .with_context(|| format!("error binding ssdp to port {}", PORT))?;
+    socket.join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(2)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+impl Ssdp {
+    fn all_types(&self) -> Vec<String> {
+        let mut v = vec![s!("upnp:rootdevice"), format!("uuid:{}", self.uuid)];
+        v.extend(self.types.iter().cloned());
+        v
+    }
+
+    fn usn(&self, nt: &str) -> String {
+        if nt.starts_with("uuid:") {
+            return nt.to_string();
+        }
+        format!("uuid:{}::{}", self.uuid, nt)
+    }
+
+    fn location(&self, ip: IpAddr) -> String {
+        format!("http://{}:{}/upnp/description.xml", ip, self.http_port)
+    }
+
+    /// Responses to the request if it is an M-SEARCH we should answer
+    pub fn responses(&self, req: &str, local_ip: IpAddr) -> Vec<String> {
+        let mut lines = req.split("\r\n");
+        if !lines
+            .next()
+            .is_some_and(|l| l.starts_with("M-SEARCH * HTTP/1.1"))
+        {
+            return vec![];
+        }
+        let mut man = None;
+        let mut st = None;
+        for line in lines {
+            let (k, v) = unwrap_cont!(line.split_once(':'));
+            match k.trim().to_ascii_uppercase().as_str() {
+                "MAN" => man = Some(v.trim().trim_matches('"')),
+                "ST" => st = Some(v.trim()),
+                _ => {}
+            }
+        }
+        if man != Some("ssdp:discover") {
+            return vec![];
+        }
+        let st = unwrap_ret!(st, vec![]);
+        let matching = match st {
+            "ssdp:all" => self.all_types(),
+            st if self.all_types().iter().any(|t| t == st) => vec![st.to_string()],
+            _ => vec![],
+        };
+        matching
+            .iter()
+            .map(|st| {
+                format!(
+                    "HTTP/1.1 200 OK\r\n\
+                    CACHE-CONTROL: max-age={}\r\n\
+                    EXT:\r\n\
+                    LOCATION: {}\r\n\
+                    SERVER: Linux UPnP/1.0 musidex/{}\r\n\
+                    ST: {}\r\n\
+                    USN: {}\r\n\r\n",
+                    MAX_AGE,
+                    self.location(local_ip),
+                    env!("CARGO_PKG_VERSION"),
+                    st,
+                    self.usn(st)
+                )
+            })
+            .collect()
+    }
+
+    fn notify(&self, nt: &str, local_ip: IpAddr) -> String {
+        format!(
+            "NOTIFY * HTTP/1.1\r\n\
+            HOST: {}:{}\r\n\
+            CACHE-CONTROL: max-age={}\r\n\
+            LOCATION: {}\r\n\
+            NT: {}\r\n\
+            NTS: ssdp:alive\r\n\
+            SERVER: Linux UPnP/1.0 musidex/{}\r\n\
+            USN: {}\r\n\r\n",
+            MULTICAST_ADDR,
+            PORT,
+            MAX_AGE,
+            self.location(local_ip),
+            nt,
+            env!("CARGO_PKG_VERSION"),
+            self.usn(nt)
+        )
+    }
+
+    /// Answers the searches received on the socket
+    pub async fn serve(self, socket: UdpSocket) {
+        let mut buf = vec![0; 4096];
+        loop {
+            let (n, peer) = match socket.recv_from(&mut buf).await {
+                Ok(x) => x,
+                Err(e) => {
+                    log::warn!("error receiving ssdp: {:?}", e);
+                    continue;
+                }
+            };
+            let req = String::from_utf8_lossy(&buf[..n]);
+            let responses = self.responses(&req, local_ip_for(peer));
+            for resp in responses {
+                if let Err(e) = socket.send_to(resp.as_bytes(), peer).await {
+                    log::warn!("error answering ssdp search from {}: {:?}", peer, e);
+                }
+            }
+        }
+    }
+
+    /// Announces the device on the network, before the announcements expire
+    pub async fn announce(self, socket: UdpSocket) {
+        let group = SocketAddr::from((MULTICAST_ADDR, PORT));
+        loop {
+            let ip = local_ip_for(group);
+            for nt in self.all_types() {
+                if let Err(e) = socket.send_to(self.notify(&nt, ip).as_bytes(), group).await {
+                    log::warn!("error sending ssdp notify: {:?}", e);
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(MAX_AGE / 3)).await;
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Just enough tar (ustar) to write archives of regular files and read them back
+
+use anyhow::{bail, Result};
+
+pub const BLOCK: usize = 512;
+
+/// Two zero blocks mark the end of the archive
+pub const END: [u8; 2 * BLOCK] = [0; 2 * BLOCK];
+
+#[derive(Debug, PartialEq)]
+pub struct Header {
+    pub path: String,
+    pub size: u64,
+    /// directories, links and extended headers aren't regular files and are skipped
+    pub regular: bool,
+}
+
+/// Zeros to add after the data of an entry to fill its last block
+pub fn padding(size: u64) -> usize {
+    (BLOCK - (size as usize % BLOCK)) % BLOCK
+}
+
+fn octal(field: &mut [u8], v: u64) {
+    let s = format!("{:0width$o}", v, width = field.len() - 1);
+    field[..s.len()].copy_from_slice(s.as_bytes());
+}
+
+fn checksum(block: &[u8; BLOCK]) -> u64 {
+    block
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| match i {
+            148..=155 => b' ' as u64,
+            _ => b as u64,
+        })
+        .sum()
+}
+
+/// Header of a regular file, paths longer than 100 bytes are split into the ustar prefix
+pub fn header(path: &str, size: u64, mtime: u64) -> Result<[u8; BLOCK]> {
+    let (prefix, name) = match path.len() {
+        0..=100 => ("", path),
+        _ => match path[..path.len().min(156)].rfind('/') {
+            Some(i) if path.len() - i - 1 <= 100 => (&path[..i], &path[i + 1..]),
+            _ => bail!("path too long for tar: {}", path),
+        },
+    };
+    if size >= 1 << 33 {
+        bail!("file too big for tar: {}", path);
+    }
+
+    let mut h = [0; BLOCK];
+    h[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut h[100..108], 0o644);
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    octal(&mut h[124..136], size);
+    octal(&mut h[136..148], mtime);
+    h[156] = b'0';
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    h[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    let sum = checksum(&h);
+    octal(&mut h[148..155], sum);
+    h[155] = b' ';
+    Ok(h)
+}
+
+fn field_str(field: &[u8]) -> Result<&str> {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    Ok(std::str::from_utf8(&field[..end])?)
+}
+
+fn field_octal(field: &[u8]) -> Result<u64> {
+    let s = field_str(field)?.trim_matches(|c: char| c == ' ' || c == '\0');
+    if s.is_empty() {
+        return Ok(0);
+    }
+    Ok(u64::from_str_radix(s, 8)?)
+}
+
+/// None at the end of the archive
+pub fn parse_header(h: &[u8; BLOCK]) -> Result<Option<Header>> {
+    if h.iter().all(|&b| b == 0) {
+        return Ok(None);
+    }
+    if field_octal(&h[148..156])? != checksum(h) {
+        bail!("invalid tar header checksum");
+    }
+    let name = field_str(&h[..100])?;
+    let prefix = match &h[257..262] == b"ustar" {
+        true => field_str(&h[345..500])?,
+        false => "",
+    };
+    let path = match prefix {
+        "" => name.to_string(),
+        _ => format!("{}/{}", prefix, name),
+    };
+    Ok(Some(Header {
+        path,
+        size: field_octal(&h[124..136])?,
+        regular: matches!(h[156], b'0' | 0),
+    }))
+}
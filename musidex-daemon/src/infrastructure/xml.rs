@@ -0,0 +1,44 @@
+//! Just enough XML for the protocols that need it, documents are written by hand
+
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+pub fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Unescaped text of the first element with this local name, whatever its namespace prefix.
+/// Elements are expected to only contain text.
+pub fn element_text(doc: &str, name: &str) -> Option<String> {
+    let mut rest = doc;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = rest.find('>')?;
+        let tag = &rest[..end];
+        let tag_name = tag
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches('/');
+        let local = tag_name.rsplit(':').next().unwrap_or_default();
+        if local != name || tag.starts_with('/') {
+            continue;
+        }
+        if tag.ends_with('/') {
+            return Some(String::new());
+        }
+        let content = &rest[end + 1..];
+        let close = content.find(&format!("</{}>", tag_name))?;
+        return Some(unescape(&content[..close]));
+    }
+    None
+}
@@ -10,22 +10,34 @@ mod infrastructure;
 #[cfg(test)]
 mod tests;
 
-use crate::application::{handlers, user_handlers};
-use crate::domain::clean::clean;
+use crate::application::{
+    archive, backup, feed, fsck, handlers, jobs, mpd, playlist, radio, subsonic, upnp,
+    user_handlers,
+};
+use crate::domain::backup::Backups;
+use crate::domain::clean;
 use crate::domain::config;
+use crate::domain::entity::UserID;
+use crate::domain::fsck::FsckRuns;
+use crate::domain::player::{Output, Player};
+use crate::domain::radio::Radios;
 use crate::domain::sync::SyncBroadcast;
+use crate::domain::trash::TrashPurgeWorker;
+use crate::domain::upnp::{UpnpDevice, CONNECTION_MANAGER, CONTENT_DIRECTORY, DEVICE_TYPE};
 use crate::domain::worker_embedding_dimreduce::EmbeddingReduceWorker;
 use crate::domain::worker_neural_embed::NeuralEmbedWorker;
 use crate::domain::worker_thumbnail_resize::SmallThumbnailWorker;
 use crate::domain::worker_youtube_dl::YoutubeDLWorker;
 use crate::infrastructure::db::Db;
 use crate::infrastructure::migrate::migrate;
-use crate::infrastructure::router::{RequestExt, Router};
+use crate::infrastructure::router::Router;
+use crate::infrastructure::ssdp::{self, Ssdp};
 use crate::utils::env_or;
 use anyhow::Context;
 use hyper::server::conn::AddrIncoming;
-use hyper::{Body, Request, Response, Server};
+use hyper::{Body, Response, Server};
 use include_dir::{include_dir, Dir};
+use std::path::PathBuf;
 
 pub static MIGRATIONS: Dir = include_dir!("migrations");
 
@@ -43,17 +55,39 @@ async fn start() -> anyhow::Result<()> {
         .context("error running migrations")?;
 
     config::init(&db).await?;
+    // jobs interrupted by the last shutdown are started over
+    domain::jobs::recover(&*db.get().await)?;
 
     let ytdl_worker = YoutubeDLWorker::new(db.clone());
     let neuralembed_worker = NeuralEmbedWorker::new(db.clone());
     let small_thumbnail_worker = SmallThumbnailWorker::new(db.clone());
     let embedding_dimreduce_worker = EmbeddingReduceWorker::new(db.clone());
-    let (broadcast, sub) = SyncBroadcast::new()?;
+    let (broadcast, sub) = SyncBroadcast::new(&db)?;
+    let backups = Backups::new(
+        db.clone(),
+        PathBuf::from(env_or("BACKUP_DIR", s!("./backups/"))),
+        env_or("BACKUP_KEEP", 7),
+        // e.g. BACKUP_HOOK="./backup.sh {file}" to upload them
+        std::env::var("BACKUP_HOOK").ok().filter(|x| !x.is_empty()),
+    );
+
+    // the mpd server is only started when a port is given
+    let mpd_port: u16 = env_or("MPD_PORT", 0);
+    let player = (mpd_port != 0).then(|| {
+        Player::new(
+            Output::parse(&env_or("MPD_OUTPUT", s!("http"))),
+            PathBuf::from("./storage/"),
+        )
+    });
 
     let mut router = Router::new();
     router
-        .state(db)
+        .state(db.clone())
+        .state(sub.devices())
         .state(sub)
+        .state(backups.clone())
+        .state(FsckRuns::default())
+        .state(Radios::new(db.clone(), PathBuf::from("./storage/")))
         .get("/api/restart_server", move |_| {
             std::process::exit(77);
             #[allow(unreachable_code)]
@@ -62,14 +96,20 @@ async fn start() -> anyhow::Result<()> {
             }
         })
         .get("/api/metadata", handlers::metadata)
+        .get("/api/export", archive::export)
+        .post("/api/import", archive::import)
+        .post("/api/admin/backup", backup::create)
+        .get("/api/admin/backups", backup::list)
+        .post("/api/admin/fsck", fsck::fsck)
+        .get("/api/admin/fsck/:id", fsck::run)
+        .get("/api/jobs", jobs::list)
+        .post("/api/jobs/:id/retry", jobs::retry)
+        .delete("/api/jobs/:id", jobs::cancel)
         .get("/api/metadata_extension", handlers::metadata_extension)
         .get("/api/metadata/compressed", handlers::metadata_compressed)
         .get("/api/ping", handlers::ping)
         .get("/api/metadata/ws", handlers::subscribe_sync)
-        .post("/api/clean", move |r: Request<Body>| async move {
-            clean(r.state::<Db>()).await?;
-            Ok(Response::new(Body::empty()))
-        })
+        .post("/api/clean", handlers::clean)
         .post("/api/config/update", handlers::update_config)
         .post("/api/youtube_upload", handlers::youtube_upload)
         .post(
@@ -78,10 +118,17 @@ async fn start() -> anyhow::Result<()> {
         )
         .get("/api/stream/:musicid", handlers::stream)
         .delete("/api/music/:id", handlers::delete_music_handler)
+        .post("/api/music/:id/restore", handlers::restore_music)
+        .get("/api/music/:id/history", handlers::music_history)
         .post("/api/music/retry_errors", handlers::retry_on_error)
         .post("/api/music/merge", handlers::merge_music)
+        .post("/api/music/unmerge", handlers::unmerge_music)
+        .get("/api/music/:id/merges", handlers::music_merges)
         .post("/api/tag/create", handlers::create_tag)
+        .post("/api/tags/bulk", handlers::bulk_tags)
+        .post("/api/sync/mutations", handlers::apply_mutations)
         .delete("/api/tag", handlers::delete_tag)
+        .post("/api/undo/:log_id", handlers::undo)
         .put(
             "/api/move/:id_library/:id_base/:id_to_move/:direction",
             handlers::move_,
@@ -89,11 +136,63 @@ async fn start() -> anyhow::Result<()> {
         .post("/api/user/create", user_handlers::create)
         .post("/api/user/update/:id", user_handlers::update)
         .delete("/api/user/:id", user_handlers::delete)
+        .get(
+            "/api/user/:id/offline_manifest",
+            user_handlers::offline_manifest,
+        )
+        .post(
+            "/api/user/:id/subsonic_password",
+            user_handlers::subsonic_password,
+        )
+        .get("/api/user/:id/devices", user_handlers::devices)
+        .get("/api/user/:id/trash", user_handlers::trash)
+        .get("/api/user/:id/feed.xml", feed::feed)
+        .get("/api/user/:id/feed_token", feed::token)
+        .post("/api/user/:id/feed_token", feed::new_token)
+        .get("/api/user/:id/playlist.m3u8", playlist::export_m3u8)
+        .get("/api/user/:id/playlist.xspf", playlist::export_xspf)
+        .post("/api/user/:id/playlist/import", playlist::import)
+        .get("/api/radio/:user", radio::status)
+        .get("/api/radio/:user/stream", radio::stream)
+        .post("/api/radio/:user/control", radio::control)
+        .get("/rest/:method", subsonic::rest)
+        .post("/rest/:method", subsonic::rest)
         .static_files("/storage/", "./storage/")
         .static_files("/", "./web/")
         .index_html(&["/users", "/settings", "/merge", "/music_map", "/explorer"])
         .nocors(env_or("NO_CORS", false));
 
+    if let Some(ref player) = player {
+        router
+            .state(player.clone())
+            .get("/api/mpd/stream", mpd::stream);
+    }
+
+    // the upnp media server is only announced when asked for
+    let upnp_device = match env_or("UPNP", false) {
+        true => Some(UpnpDevice::load(
+            &*db.get().await,
+            env_or("UPNP_NAME", s!("Musidex")),
+        )?),
+        false => None,
+    };
+    let upnp_ssdp = upnp_device.as_ref().map(|d| Ssdp {
+        uuid: d.uuid.clone(),
+        http_port: env_or("PORT", 3200),
+        types: vec![
+            s!(DEVICE_TYPE),
+            s!(CONTENT_DIRECTORY),
+            s!(CONNECTION_MANAGER),
+        ],
+    });
+    if let Some(device) = upnp_device {
+        router
+            .state(device)
+            .get("/upnp/description.xml", upnp::description)
+            .get("/upnp/:service", upnp::scpd)
+            .post("/upnp/control/:service", upnp::control);
+    }
+
     let port = env_or("PORT", 3200);
     let addr = ([0, 0, 0, 0], port).into();
     let incoming =
@@ -105,6 +204,41 @@ async fn start() -> anyhow::Result<()> {
     small_thumbnail_worker.start();
     embedding_dimreduce_worker.start();
     broadcast.start_workers();
+    // deleted musics and cleaned files are kept that long
+    let trash_days: u32 = env_or("TRASH_DAYS", 7);
+    TrashPurgeWorker::new(db.clone(), trash_days).start();
+    clean::start_trash_purge(
+        PathBuf::from("./storage/"),
+        std::time::Duration::from_secs(trash_days as u64 * 24 * 3600),
+    );
+
+    // scheduled backups are opt-in, e.g. BACKUP_INTERVAL_HOURS=24
+    let backup_interval: u64 = env_or("BACKUP_INTERVAL_HOURS", 0);
+    if backup_interval > 0 {
+        backups.start(std::time::Duration::from_secs(backup_interval * 3600));
+    }
+
+    if let Some(player) = player {
+        let addr: std::net::SocketAddr = ([0, 0, 0, 0], mpd_port).into();
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("error binding mpd to {}", addr))?;
+        player.start(db.clone());
+        tokio::spawn(mpd::serve(
+            listener,
+            db,
+            player,
+            UserID(env_or("MPD_USER", 1)),
+        ));
+        log::info!("MPD listening on {}", addr);
+    }
+
+    if let Some(ssdp) = upnp_ssdp {
+        tokio::spawn(ssdp.clone().serve(ssdp::multicast_socket()?));
+        let socket = tokio::net::UdpSocket::bind(("0.0.0.0", 0)).await?;
+        tokio::spawn(ssdp.announce(socket));
+        log::info!("UPnP media server announced");
+    }
 
     let server = Server::builder(incoming).serve(service);
     println!("Listening on http://{}", addr);
@@ -0,0 +1,189 @@
+use super::*;
+use crate::application::archive as handlers;
+use crate::domain::archive::{self, ImportReport};
+use crate::domain::config;
+use crate::domain::entity::{Music, Tag, TagKey, User};
+use crate::infrastructure::router::Router;
+use crate::infrastructure::tar;
+use anyhow::Result;
+use std::path::Path;
+
+#[test]
+fn test_tar_header() -> Result<()> {
+    let h = tar::header("storage/a.mp3", 700, 12)?;
+    assert_eq!(
+        tar::parse_header(&h)?,
+        Some(tar::Header {
+            path: s!("storage/a.mp3"),
+            size: 700,
+            regular: true,
+        })
+    );
+    assert_eq!(tar::padding(700), 324);
+    assert_eq!(tar::padding(1024), 0);
+
+    let long = format!("storage/{}/{}", "d".repeat(120), "f".repeat(90));
+    let h = tar::header(&long, 1, 0)?;
+    assert_eq!(tar::parse_header(&h)?.unwrap().path, long);
+    assert!(tar::header(&"f".repeat(101), 1, 0).is_err());
+
+    let mut corrupted = h;
+    corrupted[0] = b'x';
+    assert!(tar::parse_header(&corrupted).is_err());
+    assert_eq!(tar::parse_header(&[0; tar::BLOCK])?, None);
+    Ok(())
+}
+
+fn tmp_storage(name: &str) -> Result<std::path::PathBuf> {
+    let dir = std::env::temp_dir().join(format!("musidex-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+async fn export(db: &Db, storage: &Path, with_files: bool) -> Result<Vec<u8>> {
+    let (sender, body) = Body::channel();
+    let (db, storage) = (db.clone(), storage.to_path_buf());
+    let export =
+        tokio::spawn(async move { archive::export(&db, &storage, with_files, sender).await });
+    let data = hyper::body::to_bytes(body).await?;
+    export.await??;
+    Ok(data.to_vec())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_archive() -> Result<()> {
+    let from_storage = tmp_storage("archive-from")?;
+    let to_storage = tmp_storage("archive-to")?;
+    std::fs::write(from_storage.join("a.mp3"), [7u8; 700])?;
+    std::fs::write(from_storage.join("t.jpg"), b"new")?;
+    std::fs::write(to_storage.join("t.jpg"), b"old")?;
+
+    let from = mk_db().await?;
+    let other = {
+        let c = from.get().await;
+        let alice = User::create(&c, s!("alice"))?;
+        config::insert_if_not_exist(&c, "ui_color", "red")?;
+        config::insert_if_not_exist(&c, "secret_key", "hunter2")?;
+        let video = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_text(video, TagKey::YoutubeDLVideoID, s!("v1")))?;
+        Tag::insert(&c, Tag::new_text(video, TagKey::Title, s!("Imported")))?;
+        Tag::insert(&c, Tag::new_text(video, TagKey::LocalMP3, s!("a.mp3")))?;
+        let library = TagKey::UserLibrary(alice.0.to_string());
+        Tag::insert(&c, Tag::new_integer(video, library, 100))?;
+        let other = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_text(other, TagKey::Title, s!("Other")))?;
+        Tag::insert(&c, Tag::new_text(other, TagKey::Thumbnail, s!("t.jpg")))?;
+        Tag::insert(
+            &c,
+            Tag::new_text(other, TagKey::LocalM4A, s!("../etc/passwd")),
+        )?;
+        other
+    };
+
+    let data = export(&from, &from_storage, true).await?;
+    assert_eq!(data.len() % tar::BLOCK, 0);
+    let first: &[u8; tar::BLOCK] = data[..tar::BLOCK].try_into()?;
+    assert_eq!(tar::parse_header(first)?.unwrap().path, archive::DUMP_NAME);
+    let dump = String::from_utf8_lossy(&data);
+    assert!(dump.contains("ui_color") && !dump.contains("hunter2"));
+    assert!(dump.contains("storage/a.mp3"));
+    let without_files = export(&from, &from_storage, false).await?;
+    assert!(!String::from_utf8_lossy(&without_files).contains("storage/a.mp3"));
+
+    // the instance already has the video, someone with the id of the other music and a user
+    let to = mk_db().await?;
+    let (existing, taken) = {
+        let c = to.get().await;
+        User::create(&c, s!("bob"))?;
+        let existing = Music::mk(&c)?;
+        Tag::insert(
+            &c,
+            Tag::new_text(existing, TagKey::YoutubeDLVideoID, s!("v1")),
+        )?;
+        Tag::insert(&c, Tag::new_text(existing, TagKey::Title, s!("Kept")))?;
+        let taken = Music::mk(&c)?;
+        assert_eq!(taken, other);
+        (existing, taken)
+    };
+
+    let report = archive::import(&to, &to_storage, Body::from(data.clone())).await?;
+    assert_eq!(
+        report,
+        Some(ImportReport {
+            users_created: 1,
+            musics_created: 1,
+            musics_merged: 1,
+            musics_remapped: 1,
+            files: 1,
+        })
+    );
+
+    let c = to.get().await;
+    let alice = User::list(&c)?
+        .into_iter()
+        .find(|u| u.name == "alice")
+        .unwrap();
+    assert_ne!(alice.id.0, 2);
+    // merged musics keep what they had and get the rest
+    let title = Tag::by_id_key(&c, existing, &TagKey::Title)?.unwrap().text;
+    assert_eq!(title.as_deref(), Some("Kept"));
+    assert!(Tag::has(&c, existing, &TagKey::LocalMP3)?);
+    let library = TagKey::UserLibrary(alice.id.0.to_string());
+    assert_eq!(
+        Tag::by_id_key(&c, existing, &library)?.unwrap().integer,
+        Some(100)
+    );
+
+    let remapped = Tag::by_key_text(&c, &TagKey::Title, "Other")?;
+    assert_eq!(remapped.len(), 1);
+    assert_ne!(remapped[0].music_id, taken);
+    assert!(Tag::by_id(&c, taken)?.is_empty());
+
+    let configs = config::get_all(&c)?;
+    assert!(configs.contains(&(s!("ui_color"), s!("red"))));
+    assert!(!configs.iter().any(|(k, _)| k == "secret_key"));
+    drop(c);
+
+    assert_eq!(std::fs::read(to_storage.join("a.mp3"))?, vec![7u8; 700]);
+    assert_eq!(std::fs::read(to_storage.join("t.jpg"))?, b"old");
+
+    // importing again merges the videos, and the files are already there
+    let report = archive::import(&to, &to_storage, Body::from(data)).await?;
+    let report = report.unwrap();
+    assert_eq!((report.musics_created, report.musics_merged), (1, 1));
+    assert_eq!(report.files, 0);
+
+    assert!(archive::import(&to, &to_storage, Body::from("nope"))
+        .await?
+        .is_none());
+    assert!(archive::import(&to, &to_storage, Body::empty())
+        .await?
+        .is_none());
+
+    let mut router = Router::new();
+    router
+        .state(to.clone())
+        .get("/api/export", handlers::export)
+        .post("/api/import", handlers::import);
+    let req = |method: &str, uri: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let resp = router.serve(req("GET", "/api/export?files=maybe")).await?;
+    assert_eq!(resp.status(), 400);
+    let resp = router.serve(req("GET", "/api/export")).await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "application/x-tar");
+    let data = hyper::body::to_bytes(resp.into_body()).await?;
+    assert!(String::from_utf8_lossy(&data).contains("Kept"));
+    let resp = router.serve(req("POST", "/api/import")).await?;
+    assert_eq!(resp.status(), 400);
+
+    std::fs::remove_dir_all(&from_storage)?;
+    std::fs::remove_dir_all(&to_storage)?;
+    Ok(())
+}
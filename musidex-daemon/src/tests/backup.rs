@@ -0,0 +1,97 @@
+use super::*;
+use crate::application::backup as handlers;
+use crate::domain::backup::{BackupInfo, Backups};
+use crate::domain::entity::User;
+use crate::infrastructure::router::Router;
+use anyhow::Result;
+use flate2::read::GzDecoder;
+use nanoserde::DeJson;
+use sha1::{Digest, Sha1};
+
+#[derive(DeJson)]
+struct Listed {
+    name: String,
+    sha1: Option<String>,
+}
+
+#[test_log::test(tokio::test)]
+async fn test_backup() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("musidex-backup-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let db = mk_db().await?;
+    User::create(&*db.get().await, s!("alice"))?;
+    let uploaded = dir.join("uploaded");
+    let hook = format!("cp {{file}} '{}'", uploaded.display());
+    let backups = Backups::new(db.clone(), dir.clone(), 2, Some(hook));
+    assert_eq!(backups.list()?, vec![]);
+
+    let mut taken: Vec<BackupInfo> = vec![];
+    for _ in 0..3 {
+        taken.push(backups.run().await?);
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+    let listed = backups.list()?;
+    assert_eq!(
+        listed.iter().map(|b| &b.name).collect::<Vec<_>>(),
+        vec![&taken[2].name, &taken[1].name]
+    );
+    assert!(!dir.join(&taken[0].name).exists());
+    assert!(!dir.join(format!("{}.sha1", taken[0].name)).exists());
+
+    let latest = &listed[0];
+    let data = std::fs::read(dir.join(&latest.name))?;
+    assert_eq!(latest.size, data.len() as u64);
+    let hash: String = Sha1::digest(&data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    assert_eq!(latest.sha1.as_ref(), Some(&hash));
+    let sidecar = std::fs::read_to_string(dir.join(format!("{}.sha1", latest.name)))?;
+    assert_eq!(sidecar, format!("{}  {}\n", hash, latest.name));
+    // the hook got the last one
+    assert_eq!(std::fs::read(&uploaded)?, data);
+
+    // the snapshot is a working database
+    let restored = dir.join("restored.db");
+    std::io::copy(
+        &mut GzDecoder::new(&data[..]),
+        &mut std::fs::File::create(&restored)?,
+    )?;
+    let c = rusqlite::Connection::open(&restored)?;
+    assert!(User::list(&c)?.iter().any(|u| u.name == "alice"));
+    drop(c);
+
+    let mut router = Router::new();
+    router
+        .state(backups)
+        .post("/api/admin/backup", handlers::create)
+        .get("/api/admin/backups", handlers::list);
+    let req = |method: &str, uri: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let resp = router.serve(req("POST", "/api/admin/backup")).await?;
+    assert_eq!(resp.status(), 200);
+    let created = Listed::deserialize_json(&String::from_utf8_lossy(
+        &hyper::body::to_bytes(resp.into_body()).await?,
+    ))?;
+    assert!(created.sha1.is_some());
+    let resp = router.serve(req("GET", "/api/admin/backups")).await?;
+    let listed = Vec::<Listed>::deserialize_json(&String::from_utf8_lossy(
+        &hyper::body::to_bytes(resp.into_body()).await?,
+    ))?;
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0].name, created.name);
+
+    // a failing upload is an error, the backup itself stays
+    let failing = Backups::new(db.clone(), dir.clone(), 5, Some(s!("exit 3")));
+    assert!(failing.run().await.is_err());
+    assert_eq!(failing.list()?.len(), 3);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use super::*;
+use crate::domain::bulk::{apply, BulkOp};
+use crate::domain::entity::{Music, MusicID, Tag, TagKey, UserID};
+use crate::domain::history::Editor;
+use crate::domain::query::Query;
+use crate::infrastructure::db::LogEntry;
+use anyhow::Result;
+
+fn op(op: &str, key: TagKey, text: Option<&str>) -> BulkOp {
+    BulkOp {
+        op: s!(op),
+        key,
+        text: text.map(|x| s!(x)),
+        integer: None,
+        date: None,
+    }
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_bulk_edit() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+    let editor = Editor {
+        user_id: UserID(1),
+        ip: s!(""),
+    };
+
+    let m1 = Music::mk(&c)?;
+    let m2 = Music::mk(&c)?;
+    let m3 = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(m1, TagKey::Artist, s!("x")))?;
+    Tag::insert(&c, Tag::new_text(m2, TagKey::Artist, s!("x")))?;
+    Tag::insert(&c, Tag::new_key(m2, TagKey::UserTag(s!("old"))))?;
+    Tag::insert(&c, Tag::new_text(m3, TagKey::Artist, s!("y")))?;
+
+    let filter = Query::parse("artist=x")?;
+    let results = apply(
+        &c,
+        &editor,
+        None,
+        Some(&filter),
+        vec![
+            op("set", TagKey::Artist, Some("z")),
+            op("set", TagKey::UserLibrary(s!("1")), None),
+            op("remove", TagKey::UserTag(s!("old")), None),
+            op("set", TagKey::LocalMP3, Some("a.mp3")),
+        ],
+    )?;
+
+    let statuses: Vec<_> = results.iter().map(|r| (r.music_id, r.status)).collect();
+    assert_eq!(
+        statuses,
+        vec![
+            (m1, 200),
+            (m1, 200),
+            (m1, 404),
+            (m1, 403),
+            (m2, 200),
+            (m2, 200),
+            (m2, 200),
+            (m2, 403),
+        ]
+    );
+
+    let artist = |id| Tag::by_id_key(&c, id, &TagKey::Artist);
+    assert_eq!(artist(m1)?.unwrap().text.as_deref(), Some("z"));
+    assert_eq!(artist(m2)?.unwrap().text.as_deref(), Some("z"));
+    assert_eq!(artist(m3)?.unwrap().text.as_deref(), Some("y"));
+    assert!(!Tag::has(&c, m2, &TagKey::UserTag(s!("old")))?);
+
+    let lib = Tag::by_key(&c, &TagKey::UserLibrary(s!("1")))?;
+    assert_eq!(lib.len(), 2);
+    assert_ne!(lib[0].integer, lib[1].integer);
+
+    assert_eq!(LogEntry::by_music(&c, m2)?.len(), 3);
+
+    let results = apply(
+        &c,
+        &editor,
+        Some(vec![m3, MusicID(1234)]),
+        None,
+        vec![op("rename", TagKey::Artist, None)],
+    )?;
+    assert_eq!(results[0].status, 400);
+    assert_eq!(results[1].status, 404);
+
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use super::*;
+use crate::application::handlers;
+use crate::domain::clean::{self, TRASH_DIR};
+use crate::domain::entity::{Music, Tag, TagKey, UserID};
+use crate::domain::trash;
+use crate::infrastructure::router::Router;
+use anyhow::Result;
+use std::time::Duration;
+
+#[test_log::test(tokio::test)]
+async fn test_clean() -> Result<()> {
+    let storage = std::env::temp_dir().join(format!("musidex-clean-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&storage);
+    std::fs::create_dir_all(&storage)?;
+    std::fs::write(storage.join("kept.mp3"), [0; 10])?;
+    std::fs::write(storage.join("lone.mp3"), [0; 20])?;
+    std::fs::write(storage.join("orphan.jpg"), [0; 5])?;
+    std::fs::write(storage.join("notes.txt"), [0; 5])?;
+    std::fs::write(storage.join("merged.mp3"), [0; 5])?;
+
+    let db = mk_db().await?;
+    let (kept, lone) = {
+        let mut c = db.get().await;
+        let kept = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_key(kept, TagKey::UserLibrary(s!("1"))))?;
+        Tag::insert(&c, Tag::new_text(kept, TagKey::LocalMP3, s!("kept.mp3")))?;
+        // the discarded file of a merged music is still needed by an unmerge
+        let merged = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_key(merged, TagKey::UserLibrary(s!("1"))))?;
+        Tag::insert(
+            &c,
+            Tag::new_text(merged, TagKey::LocalMP3, s!("merged.mp3")),
+        )?;
+        Music::merge(&mut c, kept, merged)?;
+        let lone = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_text(lone, TagKey::LocalMP3, s!("lone.mp3")))?;
+        (kept, lone)
+    };
+
+    let report = clean::clean(&db, &storage, UserID(1), true).await?;
+    assert!(report.dry_run);
+    assert_eq!(report.musics, vec![lone]);
+    assert_eq!(report.files, vec![s!("orphan.jpg")]);
+    assert_eq!(report.bytes, 5);
+    assert_eq!(report.trash, None);
+    assert!(storage.join("lone.mp3").exists());
+    assert!(Music::exists(&*db.get().await, lone)?);
+
+    // the lone music can still be restored from the trash, with its file
+    let report = clean::clean(&db, &storage, UserID(1), false).await?;
+    assert_eq!(report.musics, vec![lone]);
+    assert_eq!(report.files, vec![s!("orphan.jpg")]);
+    assert!(report.errors.is_empty());
+    let trash = storage.join(report.trash.unwrap());
+    assert!(trash.starts_with(storage.join(TRASH_DIR)));
+    assert!(trash.join("orphan.jpg").exists());
+    assert!(storage.join("lone.mp3").exists());
+    assert!(storage.join("kept.mp3").exists() && storage.join("notes.txt").exists());
+    assert!(storage.join("merged.mp3").exists());
+    {
+        let c = db.get().await;
+        assert!(Music::exists(&c, lone)?);
+        assert_eq!(trash::list(&c, UserID(1))?[0].music_id, lone);
+        assert!(Music::exists(&c, kept)?);
+    }
+
+    let report = clean::clean(&db, &storage, UserID(1), false).await?;
+    assert!(report.musics.is_empty() && report.files.is_empty());
+    assert_eq!(report.trash, None);
+
+    // once purged from the trash its file goes too
+    assert_eq!(trash::purge(&*db.get().await, 0)?, 1);
+    let report = clean::clean(&db, &storage, UserID(1), false).await?;
+    assert_eq!(report.files, vec![s!("lone.mp3")]);
+    let trash = storage.join(report.trash.unwrap());
+    assert_eq!(std::fs::read(trash.join("lone.mp3"))?, [0; 20]);
+    assert!(!storage.join("lone.mp3").exists());
+
+    // only the old cleans are purged
+    let old = storage.join(TRASH_DIR).join("20000101-000000");
+    std::fs::create_dir_all(&old)?;
+    std::fs::write(old.join("a.mp3"), [0; 5])?;
+    let week = Duration::from_secs(7 * 24 * 3600);
+    assert_eq!(clean::purge_trash(&storage, week)?, 1);
+    assert!(!old.exists() && trash.exists());
+    assert_eq!(clean::purge_trash(&storage, week)?, 0);
+
+    let mut router = Router::new();
+    router.state(db).post("/api/clean", handlers::clean);
+    let req = Request::builder()
+        .method("POST")
+        .uri("/api/clean?dry_run=maybe")
+        .body(Body::empty())?;
+    assert_eq!(router.serve(req).await?.status(), 400);
+    // the trashed musics need an owner
+    let req = Request::builder()
+        .method("POST")
+        .uri("/api/clean")
+        .body(Body::empty())?;
+    assert_eq!(router.serve(req).await?.status(), 400);
+
+    std::fs::remove_dir_all(&storage)?;
+    Ok(())
+}
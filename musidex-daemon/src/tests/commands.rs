@@ -0,0 +1,74 @@
+use super::*;
+use crate::domain::commands::{self, Ack};
+use crate::domain::entity::{Music, Tag, TagKey, UserID};
+use crate::domain::history::Editor;
+use crate::infrastructure::db::LogEntry;
+use anyhow::Result;
+
+async fn run(db: &Db, msg: &str) -> Ack {
+    run_as(db, UserID(1), msg).await
+}
+
+async fn run_as(db: &Db, user_id: UserID, msg: &str) -> Ack {
+    let editor = Editor {
+        user_id,
+        ip: s!("127.0.0.1"),
+    };
+    let cmd = commands::parse(msg).expect("not a command");
+    commands::run(db, &editor, &cmd, msg).await
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_commands() -> Result<()> {
+    let db = mk_db().await?;
+    let music = Music::mk(&*db.get().await)?;
+
+    let ack = run(
+        &db,
+        &format!(
+            r#"{{"id": 1, "cmd": "tag_create", "music_id": {}, "key": "title", "text": "hello"}}"#,
+            music.0
+        ),
+    )
+    .await;
+    assert_eq!((ack.id, ack.status, ack.error), (1, 200, None));
+    assert_eq!(
+        Tag::by_id_key(&*db.get().await, music, &TagKey::Title)?.and_then(|t| t.text),
+        Some(s!("hello"))
+    );
+
+    // like the REST endpoint, deleting needs to know who does it
+    let delete = format!(
+        r#"{{"id": 2, "cmd": "tag_delete", "music_id": {}, "key": "title"}}"#,
+        music.0
+    );
+    let ack = run_as(&db, UserID(-1), &delete).await;
+    assert_eq!(ack.status, 400);
+    assert!(Tag::has(&*db.get().await, music, &TagKey::Title)?);
+    let ack = run(&db, &delete).await;
+    assert_eq!(ack.status, 200);
+    assert!(!Tag::has(&*db.get().await, music, &TagKey::Title)?);
+
+    let ack = run(
+        &db,
+        &format!(r#"{{"id": 3, "cmd": "listen", "music_id": {}}}"#, music.0),
+    )
+    .await;
+    assert_eq!(ack.status, 200);
+    let history = LogEntry::by_music(&*db.get().await, music)?;
+    assert_eq!(history[0].type_, "listen");
+
+    let ack = run(&db, r#"{"id": 4, "cmd": "listen", "music_id": 1000}"#).await;
+    assert_eq!(ack.status, 404);
+
+    let ack = run(&db, r#"{"id": 5, "cmd": "tag_delete"}"#).await;
+    assert_eq!(ack.status, 400);
+    assert!(ack.error.is_some());
+
+    let ack = run(&db, r#"{"id": 6, "cmd": "dance"}"#).await;
+    assert_eq!(ack.status, 400);
+
+    assert!(commands::parse("refresh").is_none());
+
+    Ok(())
+}
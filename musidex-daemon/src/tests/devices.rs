@@ -0,0 +1,198 @@
+use super::*;
+use crate::application::user_handlers;
+use crate::domain::commands;
+use crate::domain::devices::{self, Devices, Registration, RelayedControl};
+use crate::domain::entity::{Music, User, UserID};
+use crate::domain::history::Editor;
+use crate::infrastructure::router::Router;
+use anyhow::{Context, Result};
+use nanoserde::DeJson;
+
+async fn run(db: &Db, devices: &Devices, r: &mut Option<Registration>, msg: &str) -> u16 {
+    run_as(db, devices, r, UserID(1), msg).await
+}
+
+async fn run_as(
+    db: &Db,
+    devices: &Devices,
+    r: &mut Option<Registration>,
+    user_id: UserID,
+    msg: &str,
+) -> u16 {
+    let cmd = commands::parse(msg).expect("not a command");
+    assert!(devices::is_device_command(&cmd));
+    let editor = Editor {
+        user_id,
+        ip: s!(""),
+    };
+    devices::run(db, devices, r, &editor, &cmd, msg)
+        .await
+        .status
+}
+
+/// Last message of this kind sent to the device
+fn last(r: &mut Option<Registration>, kind: &str) -> Option<String> {
+    let rx = &mut r.as_mut().unwrap().rx;
+    let mut found = None;
+    while let Ok(msg) = rx.try_recv() {
+        if msg.contains(&format!(r#""type":"{}""#, kind)) {
+            found = Some(msg);
+        }
+    }
+    found
+}
+
+#[test_log::test(tokio::test)]
+async fn test_devices() -> Result<()> {
+    let db = mk_db().await?;
+    let (song, other_user) = {
+        let c = db.get().await;
+        (Music::mk(&c)?, User::create(&c, s!("other"))?)
+    };
+    let devices = Devices::default();
+    let mut phone = None;
+    let mut desktop = None;
+    let mut stranger = None;
+
+    let control = |target: u64, rest: &str| {
+        format!(
+            r#"{{"id": 1, "cmd": "device_control", "target": {}, {}}}"#,
+            target, rest
+        )
+    };
+    assert_eq!(
+        run(
+            &db,
+            &devices,
+            &mut phone,
+            &control(1, r#""action": "pause""#)
+        )
+        .await,
+        400
+    );
+
+    let register = r#"{"id": 2, "cmd": "device_register", "name": "phone"}"#;
+    assert_eq!(run(&db, &devices, &mut phone, register).await, 200);
+    let register = r#"{"id": 3, "cmd": "device_register", "name": "desktop"}"#;
+    assert_eq!(run(&db, &devices, &mut desktop, register).await, 200);
+    let register = r#"{"id": 4, "cmd": "device_register", "name": "x"}"#;
+    let mut r = None;
+    assert_eq!(
+        run_as(&db, &devices, &mut r, UserID(99), register).await,
+        404
+    );
+    assert_eq!(
+        run_as(&db, &devices, &mut r, UserID(-1), register).await,
+        400
+    );
+    assert_eq!(
+        run_as(&db, &devices, &mut stranger, other_user, register).await,
+        200
+    );
+    // the user in the message is ignored, the device stays the other user's
+    let register = r#"{"id": 5, "cmd": "device_register", "name": "x", "user": 1}"#;
+    assert_eq!(
+        run_as(&db, &devices, &mut stranger, other_user, register).await,
+        200
+    );
+    assert_eq!(stranger.as_ref().unwrap().user, other_user);
+
+    let phone_id = phone.as_ref().unwrap().id;
+    let desktop_id = desktop.as_ref().unwrap().id;
+    let stranger_id = stranger.as_ref().unwrap().id;
+
+    // the phone hears about the desktop, but not about the other user's device
+    let list = last(&mut phone, "devices").context("no device list")?;
+    assert!(list.contains(r#""name":"desktop""#));
+    assert!(!list.contains(r#""name":"x""#));
+    let listed: Vec<u64> = devices.list(UserID(1)).iter().map(|d| d.id).collect();
+    assert_eq!(listed, vec![phone_id, desktop_id]);
+
+    let state = format!(
+        r#"{{"id": 5, "cmd": "device_state", "track": {}, "playing": true, "position": 30, "volume": 70}}"#,
+        song.0
+    );
+    assert_eq!(run(&db, &devices, &mut desktop, &state).await, 200);
+    let d = devices.list(UserID(1)).pop().unwrap();
+    assert_eq!((d.track, d.playing, d.volume), (Some(song.0), true, 70));
+    assert!(d.position >= 30.0 && d.position < 31.0);
+    assert!(last(&mut phone, "devices").is_some());
+
+    // controls are relayed to the target only
+    last(&mut desktop, "device_control");
+    let play = control(
+        desktop_id,
+        &format!(r#""action": "play", "music_id": {}"#, song.0),
+    );
+    assert_eq!(run(&db, &devices, &mut phone, &play).await, 200);
+    let relayed = last(&mut desktop, "device_control").context("nothing relayed")?;
+    let relayed = RelayedControl::deserialize_json(&relayed)?;
+    assert_eq!(
+        (relayed.from, relayed.action.as_str(), relayed.music_id),
+        (phone_id, "play", Some(song.0))
+    );
+    assert!(last(&mut phone, "device_control").is_none());
+
+    let bad = [
+        (
+            control(desktop_id, r#""action": "play", "music_id": 999"#),
+            404,
+        ),
+        (control(desktop_id, r#""action": "volume""#), 400),
+        (control(desktop_id, r#""action": "dance""#), 400),
+        (control(stranger_id, r#""action": "pause""#), 404),
+        // nothing is playing on the phone to transfer
+        (control(desktop_id, r#""action": "transfer""#), 400),
+    ];
+    for (msg, status) in bad {
+        assert_eq!(
+            run(&db, &devices, &mut phone, &msg).await,
+            status,
+            "{}",
+            msg
+        );
+    }
+
+    // transferring playback pauses the source and resumes where it was on the target
+    let transfer = control(
+        phone_id,
+        &format!(r#""action": "transfer", "source": {}"#, desktop_id),
+    );
+    assert_eq!(run(&db, &devices, &mut phone, &transfer).await, 200);
+    let paused = RelayedControl::deserialize_json(&last(&mut desktop, "device_control").unwrap())?;
+    assert_eq!(paused.action, "pause");
+    let play = RelayedControl::deserialize_json(&last(&mut phone, "device_control").unwrap())?;
+    assert_eq!(
+        (play.action.as_str(), play.music_id),
+        ("play", Some(song.0))
+    );
+    assert_eq!(play.volume, Some(70));
+    assert!(play.position.unwrap() >= 30.0);
+
+    // over http
+    let mut router = Router::new();
+    router
+        .state(db.clone())
+        .state(devices.clone())
+        .get("/api/user/:id/devices", user_handlers::devices);
+    let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+    assert_eq!(
+        router.serve(get("/api/user/99/devices")).await?.status(),
+        404
+    );
+    let resp = router.serve(get("/api/user/1/devices")).await?;
+    assert_eq!(resp.status(), 200);
+    let body = hyper::body::to_bytes(resp.into_body()).await?;
+    assert_eq!(
+        String::from_utf8_lossy(&body).matches(r#""name":"#).count(),
+        2
+    );
+
+    // disconnecting forgets the device
+    drop(desktop);
+    assert_eq!(devices.list(UserID(1)).len(), 1);
+    assert!(last(&mut phone, "devices")
+        .unwrap()
+        .contains(r#""devices":[{"#));
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use super::*;
+use crate::domain::encoding::{encode_cbor_v1, SyncEncoding};
+use crate::domain::entity::{Music, Tag, TagKey, Vector};
+use crate::domain::sync::fetch_metadata;
+use anyhow::Result;
+use nanoserde::SerJson;
+
+#[test]
+fn test_encoding_negotiation() {
+    assert_eq!(SyncEncoding::parse(None), Some(SyncEncoding::Json));
+    assert_eq!(SyncEncoding::parse(Some("json")), Some(SyncEncoding::Json));
+    assert_eq!(
+        SyncEncoding::parse(Some("cbor1")),
+        Some(SyncEncoding::CborV1)
+    );
+    assert_eq!(SyncEncoding::parse(Some("cbor2")), None);
+}
+
+fn count(haystack: &[u8], needle: &[u8]) -> usize {
+    haystack
+        .windows(needle.len())
+        .filter(|w| *w == needle)
+        .count()
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_encode_cbor_v1() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let v = Vector((0..64).map(|x| x as f32 * 0.123).collect());
+    for _ in 0..3 {
+        let music = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_vector(music, TagKey::Embedding, v.clone()))?;
+        Tag::insert(&c, Tag::new_text(music, TagKey::Title, s!("some name")))?;
+    }
+
+    let mut meta = fetch_metadata(&c)?;
+    meta.schema.clear();
+    let cbor = encode_cbor_v1(&meta);
+
+    // map of 9 entries, starting with "v": 1
+    assert_eq!(&cbor[..4], &[0xA9, 0x61, b'v', 0x01]);
+    // keys are only written once
+    assert_eq!(count(&cbor, b"embedding"), 1);
+    assert_eq!(count(&cbor, b"title"), 1);
+    // vectors are written as tag 85 typed arrays of 64 f32
+    let mut vector_head = vec![0xD8, 85, 0x59, 0x01, 0x00];
+    vector_head.extend_from_slice(&v.0[0].to_le_bytes());
+    assert_eq!(count(&cbor, &vector_head), 3);
+
+    assert!(cbor.len() < meta.serialize_json().len());
+
+    Ok(())
+}
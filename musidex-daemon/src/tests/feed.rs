@@ -0,0 +1,129 @@
+use super::*;
+use crate::application::feed;
+use crate::domain::entity::{Music, MusicID, Tag, TagKey};
+use crate::infrastructure::router::Router;
+use anyhow::Result;
+
+async fn mk_song(db: &Db, title: &str, order: i64, playlist: Option<&str>) -> Result<MusicID> {
+    let c = db.get().await;
+    let id = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(id, TagKey::Title, title.to_string()))?;
+    Tag::insert(
+        &c,
+        Tag::new_text(id, TagKey::Artist, s!("Simon & Garfunkel")),
+    )?;
+    Tag::insert(
+        &c,
+        Tag::new_text(id, TagKey::LocalMP3, format!("{}.mp3", title)),
+    )?;
+    Tag::insert(
+        &c,
+        Tag::new_text(id, TagKey::Thumbnail, format!("{}.jpg", title)),
+    )?;
+    Tag::insert(&c, Tag::new_integer(id, TagKey::Duration, 3725))?;
+    Tag::insert(
+        &c,
+        Tag::new_integer(id, TagKey::UserLibrary(s!("1")), order),
+    )?;
+    if let Some(p) = playlist {
+        Tag::insert(&c, Tag::new_key(id, TagKey::UserTag(p.to_string())))?;
+    }
+    Ok(id)
+}
+
+#[test_log::test(tokio::test)]
+async fn test_feed() -> Result<()> {
+    let db = mk_db().await?;
+    let old = mk_song(&db, "old", 100, Some("car")).await?;
+    let new = mk_song(&db, "new", 200, None).await?;
+    {
+        // not playable, so not in the feed
+        let c = db.get().await;
+        let id = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_integer(id, TagKey::UserLibrary(s!("1")), 300))?;
+    }
+
+    let mut router = Router::new();
+    router
+        .state(db.clone())
+        .get("/api/user/:id/feed.xml", feed::feed)
+        .get("/api/user/:id/feed_token", feed::token)
+        .post("/api/user/:id/feed_token", feed::new_token);
+    let req = |method: &str, uri: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("host", "music.local:3200")
+            .header("cookie", "cur_user=1")
+            .body(Body::empty())
+            .unwrap()
+    };
+    let body = |resp: hyper::Response<Body>| async {
+        let b = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        String::from_utf8(b.to_vec()).unwrap()
+    };
+
+    // no token yet
+    let resp = router.serve(req("GET", "/api/user/1/feed.xml")).await?;
+    assert_eq!(resp.status(), 401);
+    let resp = router.serve(req("GET", "/api/user/9/feed_token")).await?;
+    assert_eq!(resp.status(), 404);
+    // the token is only given to the user itself
+    for cookie in [None, Some("cur_user=2")] {
+        for method in ["GET", "POST"] {
+            let mut r = Request::builder()
+                .method(method)
+                .uri("/api/user/1/feed_token");
+            if let Some(cookie) = cookie {
+                r = r.header("cookie", cookie);
+            }
+            let resp = router.serve(r.body(Body::empty())?).await?;
+            assert_eq!(resp.status(), 401);
+        }
+    }
+
+    let token = body(router.serve(req("GET", "/api/user/1/feed_token")).await?).await;
+    assert!(token.starts_with(r#"{"token":""#));
+    let token = token[10..token.len() - 2].to_string();
+    assert_eq!(token.len(), 32);
+
+    let resp = router
+        .serve(req("GET", "/api/user/1/feed.xml?token=nope"))
+        .await?;
+    assert_eq!(resp.status(), 401);
+
+    let uri = format!("/api/user/1/feed.xml?token={}", token);
+    let resp = router.serve(req("GET", &uri)).await?;
+    assert_eq!(resp.status(), 200);
+    assert!(resp.headers()["content-type"]
+        .to_str()?
+        .starts_with("application/rss+xml"));
+    let doc = body(resp).await;
+    assert!(doc.contains("<title>default - musidex</title>"));
+    assert!(doc.contains("<itunes:author>Simon &amp; Garfunkel</itunes:author>"));
+    assert!(doc.contains(&format!(
+        r#"<enclosure url="http://music.local:3200/api/stream/{}" length="0" type="audio/mpeg"/>"#,
+        new.0
+    )));
+    assert!(doc.contains(r#"<itunes:image href="http://music.local:3200/storage/new.jpg"/>"#));
+    assert!(doc.contains("<itunes:duration>01:02:05</itunes:duration>"));
+    // top of the library first, and newest for podcast apps
+    assert_eq!(doc.matches("<item>").count(), 2);
+    assert!(doc.find("<title>new</title>") < doc.find("<title>old</title>"));
+    assert!(doc.contains("<pubDate>Sat, 1 Jan 2000 00:03:20 +0000</pubDate>"));
+
+    let resp = router
+        .serve(req("GET", &format!("{}&playlist=car", uri)))
+        .await?;
+    let doc = body(resp).await;
+    assert!(doc.contains("<title>default - car</title>"));
+    assert_eq!(doc.matches("<item>").count(), 1);
+    assert!(doc.contains(&format!("musidex-{}", old.0)));
+
+    // a new token revokes the old one
+    let resp = router.serve(req("POST", "/api/user/1/feed_token")).await?;
+    assert!(!body(resp).await.contains(&token));
+    let resp = router.serve(req("GET", &uri)).await?;
+    assert_eq!(resp.status(), 401);
+    Ok(())
+}
@@ -0,0 +1,180 @@
+use super::*;
+use crate::application::fsck as handlers;
+use crate::domain::entity::{Music, MusicID, Tag, TagKey};
+use crate::domain::fsck::{self, FsckOptions, FsckRuns, Issue};
+use crate::domain::jobs::{self, JobKind};
+use crate::infrastructure::router::Router;
+use anyhow::Result;
+use std::path::Path;
+
+fn kinds(issues: &[Issue]) -> Vec<(MusicID, &str)> {
+    issues
+        .iter()
+        .map(|i| (i.music_id, i.kind.as_str()))
+        .collect()
+}
+
+/// Stands in for ffprobe: the files contain their duration, or garbage
+fn fake_probe(dir: &Path) -> Result<String> {
+    let path = dir.join("probe.sh");
+    std::fs::write(
+        &path,
+        "#!/bin/sh\nfor f; do :; done\ngrep -E '^[0-9.]+$' \"$f\" || exit 1\n",
+    )?;
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_fsck() -> Result<()> {
+    let storage = std::env::temp_dir().join(format!("musidex-fsck-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&storage);
+    std::fs::create_dir_all(&storage)?;
+    let probe = fake_probe(&storage)?;
+    std::fs::write(storage.join("ok.mp3"), "180")?;
+    std::fs::write(storage.join("trunc.mp3"), "60")?;
+    std::fs::write(storage.join("bad.ogg"), "garbage")?;
+
+    let db = mk_db().await?;
+    let (fine, truncated, no_audio, local_gone, local_bad) = {
+        let c = db.get().await;
+        let mk = |audio: Option<(TagKey, &str)>, url: bool| -> Result<MusicID> {
+            let id = Music::mk(&c)?;
+            if url {
+                let v = format!("https://youtu.be/{}", id.0);
+                Tag::insert(&c, Tag::new_text(id, TagKey::YoutubeDLURL, v))?;
+                Tag::insert(
+                    &c,
+                    Tag::new_text(id, TagKey::YoutubeDLWorkerTreated, s!("true")),
+                )?;
+            }
+            if let Some((key, path)) = audio {
+                Tag::insert(&c, Tag::new_text(id, key, path.to_string()))?;
+            }
+            Tag::insert(&c, Tag::new_integer(id, TagKey::Duration, 180))?;
+            Ok(id)
+        };
+        let fine = mk(Some((TagKey::LocalMP3, "ok.mp3")), true)?;
+        let truncated = mk(Some((TagKey::LocalMP3, "trunc.mp3")), true)?;
+        let no_audio = mk(None, true)?;
+        let local_gone = mk(Some((TagKey::LocalMP3, "gone.mp3")), false)?;
+        let local_bad = mk(Some((TagKey::LocalOGG, "bad.ogg")), false)?;
+        Tag::insert(
+            &c,
+            Tag::new_text(fine, TagKey::Thumbnail, s!("missing.jpg")),
+        )?;
+        (fine, truncated, no_audio, local_gone, local_bad)
+    };
+
+    let opts = |repair: bool, probe: Option<&str>| FsckOptions {
+        repair,
+        probe: probe.map(ToString::to_string),
+    };
+    let expected = vec![
+        (fine, fsck::MISSING_FILE),
+        (truncated, fsck::DURATION_MISMATCH),
+        (no_audio, fsck::NO_AUDIO),
+        (local_gone, fsck::MISSING_FILE),
+        (local_bad, fsck::UNDECODABLE),
+    ];
+
+    let report = fsck::fsck(&db, &storage, opts(false, Some(&probe))).await?;
+    assert!(report.probed);
+    assert_eq!((report.musics, report.files), (5, 3));
+    assert_eq!(kinds(&report.issues), expected);
+    assert!(report.issues.iter().all(|i| i.repair.is_none()));
+    assert!(storage.join("trunc.mp3").exists());
+
+    // without probing only the files are checked
+    let report = fsck::fsck(&db, &storage, opts(false, None)).await?;
+    assert!(!report.probed);
+    assert_eq!(
+        kinds(&report.issues),
+        vec![expected[0], expected[2], expected[3]]
+    );
+    let report = fsck::fsck(&db, &storage, opts(false, Some("/nonexistent/ffprobe"))).await?;
+    assert!(!report.probed);
+    assert_eq!(report.issues.len(), 3);
+
+    let report = fsck::fsck(&db, &storage, opts(true, Some(&probe))).await?;
+    let repairs: Vec<Option<&str>> = report.issues.iter().map(|i| i.repair.as_deref()).collect();
+    assert_eq!(
+        repairs,
+        vec![
+            Some("tag dropped"),
+            Some("downloading again"),
+            Some("downloading again"),
+            Some("tag dropped"),
+            None,
+        ]
+    );
+    // the truncated file waits in the trash in case the new download goes wrong
+    assert!(!storage.join("trunc.mp3").exists());
+    let trash = storage.join(report.trash.as_ref().unwrap());
+    assert_eq!(std::fs::read_to_string(trash.join("trunc.mp3"))?, "60");
+    assert!(report.errors.is_empty());
+    assert!(storage.join("bad.ogg").exists());
+    {
+        let c = db.get().await;
+        let queued: Vec<Option<MusicID>> = jobs::list(&c, Some("queued"))?
+            .into_iter()
+            .filter(|j| j.kind == JobKind::YoutubeDL.as_str())
+            .map(|j| j.music_id)
+            .collect();
+        assert_eq!(queued, vec![Some(truncated), Some(no_audio)]);
+        assert!(!Tag::has(&c, fine, &TagKey::Thumbnail)?);
+        assert!(Tag::has(&c, fine, &TagKey::LocalMP3)?);
+        assert!(!Tag::has(&c, truncated, &TagKey::LocalMP3)?);
+        assert!(!Tag::has(&c, local_gone, &TagKey::LocalMP3)?);
+        assert!(Tag::has(&c, local_bad, &TagKey::LocalOGG)?);
+    }
+
+    // what is left can't be repaired, or is waiting for its download
+    let report = fsck::fsck(&db, &storage, opts(false, Some(&probe))).await?;
+    assert_eq!(kinds(&report.issues), vec![(local_bad, fsck::UNDECODABLE)]);
+
+    let mut router = Router::new();
+    router
+        .state(db)
+        .state(FsckRuns::default())
+        .post("/api/admin/fsck", handlers::fsck)
+        .get("/api/admin/fsck/:id", handlers::run);
+    let req = |method: &str, uri: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let body = |resp: hyper::Response<Body>| async {
+        let b = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        String::from_utf8(b.to_vec()).unwrap()
+    };
+    let resp = router
+        .serve(req("POST", "/api/admin/fsck?repair=maybe"))
+        .await?;
+    assert_eq!(resp.status(), 400);
+    let resp = router
+        .serve(req("POST", "/api/admin/fsck?decode=false"))
+        .await?;
+    assert_eq!(resp.status(), 202);
+    assert!(body(resp)
+        .await
+        .starts_with(r#"{"id":1,"status":"running""#));
+    let mut run = String::new();
+    for _ in 0..100 {
+        run = body(router.serve(req("GET", "/api/admin/fsck/1")).await?).await;
+        if !run.contains("running") {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert!(run.contains(r#""status":"done""#));
+    assert!(run.contains(r#""probed":false"#));
+    let resp = router.serve(req("GET", "/api/admin/fsck/2")).await?;
+    assert_eq!(resp.status(), 404);
+
+    std::fs::remove_dir_all(&storage)?;
+    Ok(())
+}
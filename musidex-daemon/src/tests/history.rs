@@ -0,0 +1,117 @@
+use super::*;
+use crate::domain::entity::{Music, Tag, TagKey, UserID};
+use crate::domain::history::{undo, Editor};
+use crate::domain::music::{delete_music, MoveDirection};
+use crate::infrastructure::db::LogEntry;
+use anyhow::Result;
+use hyper::StatusCode;
+
+fn editor() -> Editor {
+    Editor {
+        user_id: UserID(1),
+        ip: s!("127.0.0.1"),
+    }
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_history_snapshots() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+    let e = editor();
+
+    let music = Music::mk(&c)?;
+    let v1 = Tag::new_text(music, TagKey::Title, s!("a"));
+    let v2 = Tag::new_text(music, TagKey::Title, s!("b"));
+    e.insert_tag(&c, v1.clone())?;
+    e.insert_tag(&c, v2.clone())?;
+    e.remove_tag(&c, music, &TagKey::Title)?;
+
+    let history = LogEntry::by_music(&c, music)?;
+    assert_eq!(history.len(), 3);
+
+    assert_eq!(history[0].action, "delete");
+    assert_eq!(history[0].before, Some(v2.clone()));
+    assert_eq!(history[0].after, None);
+
+    assert_eq!(history[1].action, "update");
+    assert_eq!(history[1].before, Some(v1.clone()));
+    assert_eq!(history[1].after, Some(v2));
+
+    assert_eq!(history[2].action, "create");
+    assert_eq!(history[2].before, None);
+    assert_eq!(history[2].after, Some(v1));
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_undo() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+    let e = editor();
+
+    let music = Music::mk(&c)?;
+    let v1 = Tag::new_text(music, TagKey::Title, s!("a"));
+    e.insert_tag(&c, v1.clone())?;
+    e.insert_tag(&c, Tag::new_text(music, TagKey::Title, s!("b")))?;
+
+    let update = LogEntry::by_music(&c, music)?[0].id;
+    assert_eq!(undo(&c, &e, update)?, StatusCode::OK);
+    assert_eq!(Tag::by_id_key(&c, music, &TagKey::Title)?, Some(v1.clone()));
+
+    // the tag changed since, undoing would overwrite it
+    assert_eq!(undo(&c, &e, update)?, StatusCode::CONFLICT);
+
+    e.remove_tag(&c, music, &TagKey::Title)?;
+    let delete = LogEntry::by_music(&c, music)?[0].id;
+    assert_eq!(undo(&c, &e, delete)?, StatusCode::OK);
+    assert_eq!(Tag::by_id_key(&c, music, &TagKey::Title)?, Some(v1));
+
+    let create = LogEntry::by_music(&c, music)?.last().unwrap().id;
+    assert_eq!(undo(&c, &e, create)?, StatusCode::OK);
+    assert_eq!(Tag::by_id_key(&c, music, &TagKey::Title)?, None);
+
+    assert_eq!(undo(&c, &e, 12345)?, StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_history_of_library_edits() -> Result<()> {
+    let db = mk_db().await?;
+    let mut c = db.get().await;
+    let e = editor();
+    let key = TagKey::UserLibrary(s!("1"));
+
+    let m1 = Music::mk(&c)?;
+    let m2 = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_integer(m1, key.clone(), 10))?;
+    Tag::insert(&c, Tag::new_integer(m2, key.clone(), 20))?;
+    Tag::insert(&c, Tag::new_key(m2, TagKey::UserLibrary(s!("2"))))?;
+
+    assert!(Music::move_(
+        &mut c,
+        &e,
+        &key,
+        m1,
+        m2,
+        MoveDirection::Above
+    )?);
+    let moved = LogEntry::by_music(&c, m2)?;
+    assert_eq!(moved.len(), 1);
+    assert_eq!(moved[0].before.as_ref().and_then(|t| t.integer), Some(20));
+    assert_eq!(undo(&c, &e, moved[0].id)?, StatusCode::OK);
+    assert_eq!(
+        Tag::by_id_key(&c, m2, &key)?.and_then(|t| t.integer),
+        Some(20)
+    );
+
+    // another user still has it, so it only leaves the library
+    assert_eq!(delete_music(&c, &e, m2)?, StatusCode::OK);
+    let removed = LogEntry::by_music(&c, m2)?[0].id;
+    assert!(!Tag::has(&c, m2, &key)?);
+    assert_eq!(undo(&c, &e, removed)?, StatusCode::OK);
+    assert!(Tag::has(&c, m2, &key)?);
+
+    Ok(())
+}
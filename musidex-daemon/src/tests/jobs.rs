@@ -0,0 +1,132 @@
+use super::*;
+use crate::application::{handlers, jobs as handlers_jobs};
+use crate::domain::entity::{Music, Tag, TagKey};
+use crate::domain::jobs::{self, JobKind, MAX_ATTEMPTS};
+use crate::domain::worker_youtube_dl::YoutubeDLWorker;
+use crate::infrastructure::router::Router;
+use anyhow::Result;
+use hyper::StatusCode;
+
+#[test_log::test(tokio::test)]
+async fn test_jobs() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+    let v = Music::mk(&c)?;
+
+    // tags queue the work derived from them, only once
+    Tag::insert(&c, Tag::new_text(v, TagKey::Thumbnail, s!("a.jpg")))?;
+    Tag::insert(&c, Tag::new_text(v, TagKey::Thumbnail, s!("b.jpg")))?;
+    Tag::insert(&c, Tag::new_text(v, TagKey::LocalMP3, s!("a.mp3")))?;
+    let kinds: Vec<String> = jobs::list(&c, None)?.into_iter().map(|j| j.kind).collect();
+    assert_eq!(kinds, vec![s!("thumbnail"), s!("neural_embed")]);
+
+    jobs::enqueue(&c, JobKind::YoutubeDL, Some(v))?;
+    jobs::enqueue(&c, JobKind::YoutubeDL, Some(v))?;
+    assert_eq!(jobs::list(&c, Some("queued"))?.len(), 3);
+
+    // a download fails until it runs out of attempts
+    for attempt in 1..=MAX_ATTEMPTS {
+        let job = jobs::claim(&c, JobKind::YoutubeDL)?.unwrap();
+        assert_eq!((job.status.as_str(), job.attempts), ("running", attempt));
+        assert!(jobs::claim(&c, JobKind::YoutubeDL)?.is_none());
+        jobs::fail(&c, &job, "no network")?;
+    }
+    let failed = jobs::list(&c, Some("failed"))?;
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].last_error.as_deref(), Some("no network"));
+    let treated = |c: &rusqlite::Connection| -> Result<Option<String>> {
+        Ok(Tag::by_id_key(c, v, &TagKey::YoutubeDLWorkerTreated)?.and_then(|t| t.text))
+    };
+    assert_eq!(treated(&c)?.as_deref(), Some("error"));
+    assert!(jobs::claim(&c, JobKind::YoutubeDL)?.is_none());
+
+    assert_eq!(jobs::retry(&c, failed[0].id)?, StatusCode::OK);
+    assert_eq!(jobs::retry(&c, failed[0].id)?, StatusCode::CONFLICT);
+    assert_eq!(jobs::retry(&c, 4242)?, StatusCode::NOT_FOUND);
+    assert_eq!(treated(&c)?, None);
+    let job = jobs::claim(&c, JobKind::YoutubeDL)?.unwrap();
+    assert_eq!(job.attempts, 1);
+    assert_eq!(jobs::cancel(&c, job.id)?, StatusCode::CONFLICT);
+
+    // interrupted jobs are queued again on startup
+    jobs::recover(&c)?;
+    assert_eq!(jobs::by_id(&c, job.id)?.unwrap().status, "queued");
+    let job = jobs::claim(&c, JobKind::YoutubeDL)?.unwrap();
+    jobs::done(&c, &job)?;
+    assert!(jobs::by_id(&c, job.id)?.is_none());
+
+    jobs::enqueue(&c, JobKind::YoutubeDL, Some(v))?;
+    for _ in 1..=MAX_ATTEMPTS {
+        let job = jobs::claim(&c, JobKind::YoutubeDL)?.unwrap();
+        jobs::fail(&c, &job, "no network")?;
+    }
+    assert_eq!(jobs::retry_failed(&c)?, 1);
+    assert_eq!(jobs::retry_failed(&c)?, 0);
+    let job = jobs::claim(&c, JobKind::YoutubeDL)?.unwrap();
+    jobs::done(&c, &job)?;
+
+    let thumb = jobs::list(&c, None)?[0].id;
+    assert_eq!(jobs::cancel(&c, thumb)?, StatusCode::OK);
+    assert_eq!(jobs::cancel(&c, thumb)?, StatusCode::NOT_FOUND);
+
+    // downloading a music without url fails its job
+    jobs::enqueue(&c, JobKind::YoutubeDL, Some(v))?;
+    drop(c);
+    let ran = jobs::run_next(&db, JobKind::YoutubeDL, |job| {
+        YoutubeDLWorker::work(&db, job)
+    })
+    .await?;
+    assert!(ran);
+    let job = jobs::list(&*db.get().await, Some("queued"))?
+        .into_iter()
+        .find(|j| j.kind == "youtube_dl")
+        .unwrap();
+    assert!(job.last_error.unwrap().contains("no youtube url"));
+
+    let mut router = Router::new();
+    router
+        .state(db.clone())
+        .get("/api/jobs", handlers_jobs::list)
+        .post("/api/jobs/:id/retry", handlers_jobs::retry)
+        .delete("/api/jobs/:id", handlers_jobs::cancel)
+        .post("/api/music/retry_errors", handlers::retry_on_error);
+    let req = |method: &str, uri: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let resp = router.serve(req("GET", "/api/jobs?status=queued")).await?;
+    let body = hyper::body::to_bytes(resp.into_body()).await?;
+    assert!(String::from_utf8_lossy(&body).contains("\"kind\":\"neural_embed\""));
+    let resp = router.serve(req("GET", "/api/jobs?status=done")).await?;
+    assert_eq!(resp.status(), 400);
+    let resp = router.serve(req("POST", "/api/jobs/nope/retry")).await?;
+    assert_eq!(resp.status(), 400);
+    let resp = router
+        .serve(req("POST", &format!("/api/jobs/{}/retry", job.id)))
+        .await?;
+    assert_eq!(resp.status(), 409);
+
+    // the web client retries all the failed downloads at once
+    {
+        let c = db.get().await;
+        // the job already was tried once above
+        for _ in 1..MAX_ATTEMPTS {
+            let job = jobs::claim(&c, JobKind::YoutubeDL)?.unwrap();
+            jobs::fail(&c, &job, "no network")?;
+        }
+        assert_eq!(treated(&c)?.as_deref(), Some("error"));
+    }
+    let resp = router.serve(req("POST", "/api/music/retry_errors")).await?;
+    assert_eq!(resp.status(), 200);
+    assert!(jobs::list(&*db.get().await, Some("failed"))?.is_empty());
+
+    let resp = router
+        .serve(req("DELETE", &format!("/api/jobs/{}", job.id)))
+        .await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(treated(&*db.get().await)?, None);
+    Ok(())
+}
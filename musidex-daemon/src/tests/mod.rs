@@ -5,8 +5,32 @@ use hyper::http::Extensions;
 use hyper::{Body, Request};
 use std::sync::Arc;
 
+mod archive;
+mod backup;
+mod bulk;
+mod clean;
+mod commands;
+mod devices;
+mod encoding;
+mod feed;
+mod fsck;
+mod history;
+mod jobs;
+mod mpd;
 mod music;
+mod mutations;
+mod offline;
+mod party;
+mod playlist;
+mod query;
+mod radio;
+mod schema;
+mod scope;
+mod subsonic;
+mod sync;
 mod tags;
+mod trash;
+mod upnp;
 mod user;
 mod worker_embedding_dimreduce;
 mod worker_neural_embed;
@@ -0,0 +1,305 @@
+use super::*;
+use crate::application::mpd::serve;
+use crate::domain::entity::{Music, MusicID, Tag, TagKey, UserID};
+use crate::domain::player::{Output, PlayState, Player, PlayerState};
+use anyhow::Result;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+
+fn positions(s: &PlayerState) -> Vec<i32> {
+    s.queue.iter().map(|x| x.music_id.0).collect()
+}
+
+#[test]
+fn test_player_queue() {
+    let mut s = PlayerState::default();
+    for i in 1..=4 {
+        s.add(MusicID(i), None);
+    }
+    assert_eq!(s.add(MusicID(9), Some(10)), None);
+    assert_eq!(positions(&s), vec![1, 2, 3, 4]);
+
+    assert!(s.play(Some(1)));
+    assert_eq!(s.state, PlayState::Play);
+
+    // the current song follows the moves and inserts
+    assert!(s.move_item(1, 3));
+    assert_eq!(positions(&s), vec![1, 3, 4, 2]);
+    assert_eq!(s.current, Some(3));
+    s.add(MusicID(5), Some(0));
+    assert_eq!(s.current, Some(4));
+
+    // deleting the current song plays the next one, here none so it stops
+    assert!(s.delete(4, 5));
+    assert_eq!(s.state, PlayState::Stop);
+    assert_eq!(s.current, None);
+
+    assert!(s.play(None));
+    assert_eq!(s.current, Some(0));
+    s.next();
+    assert_eq!(s.current, Some(1));
+    s.previous();
+    assert_eq!(s.current, Some(0));
+
+    s.pause(None);
+    assert_eq!(s.state, PlayState::Pause);
+    s.pause(None);
+    assert_eq!(s.state, PlayState::Play);
+
+    s.clear();
+    assert!(s.queue.is_empty());
+    assert_eq!(s.state, PlayState::Stop);
+    assert!(!s.play(None));
+}
+
+#[test]
+fn test_player_song_ended() {
+    let mut s = PlayerState::default();
+    s.add(MusicID(1), None);
+    s.add(MusicID(2), None);
+    s.play(Some(0));
+
+    // a song end from before the last change is ignored
+    let gen = s.generation;
+    s.seek(0, Duration::from_secs(10));
+    s.song_ended(gen);
+    assert_eq!(s.current, Some(0));
+
+    s.song_ended(s.generation);
+    assert_eq!(s.current, Some(1));
+    s.song_ended(s.generation);
+    assert_eq!(s.state, PlayState::Stop);
+
+    s.repeat = true;
+    s.play(Some(1));
+    s.song_ended(s.generation);
+    assert_eq!(s.current, Some(0));
+
+    s.single = true;
+    s.song_ended(s.generation);
+    assert_eq!(s.current, Some(0));
+    s.repeat = false;
+    s.song_ended(s.generation);
+    assert_eq!(s.state, PlayState::Stop);
+
+    s.single = false;
+    s.consume = true;
+    s.play(Some(0));
+    s.song_ended(s.generation);
+    assert_eq!(positions(&s), vec![2]);
+    assert_eq!(s.current, Some(0));
+    s.song_ended(s.generation);
+    assert!(s.queue.is_empty());
+    assert_eq!(s.state, PlayState::Stop);
+}
+
+struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    w: OwnedWriteHalf,
+}
+
+impl Client {
+    async fn connect(addr: std::net::SocketAddr) -> Result<Client> {
+        let (r, w) = TcpStream::connect(addr).await?.into_split();
+        let mut c = Client {
+            lines: BufReader::new(r).lines(),
+            w,
+        };
+        assert!(c.lines.next_line().await?.unwrap().starts_with("OK MPD"));
+        Ok(c)
+    }
+
+    async fn send(&mut self, cmd: &str) -> Result<()> {
+        self.w.write_all(format!("{}\n", cmd).as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Reads until OK or ACK
+    async fn response(&mut self) -> Result<Vec<String>> {
+        let mut resp = vec![];
+        loop {
+            let line = tokio::time::timeout(Duration::from_secs(2), self.lines.next_line())
+                .await
+                .map_err(|_| anyhow!("no response after {:?}", resp))??
+                .unwrap();
+            let done = line == "OK" || line.starts_with("ACK");
+            resp.push(line);
+            if done {
+                return Ok(resp);
+            }
+        }
+    }
+
+    async fn cmd(&mut self, cmd: &str) -> Result<Vec<String>> {
+        self.send(cmd).await?;
+        self.response().await
+    }
+}
+
+async fn mk_library(db: &Db) -> Result<(MusicID, MusicID)> {
+    let c = db.get().await;
+    let lib = TagKey::UserLibrary(s!("1"));
+    let a = Music::mk(&c)?;
+    let b = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(a, TagKey::Title, s!("Song A")))?;
+    Tag::insert(&c, Tag::new_text(a, TagKey::Artist, s!("Artist \"One\"")))?;
+    Tag::insert(&c, Tag::new_text(a, TagKey::LocalMP3, s!("a.mp3")))?;
+    Tag::insert(&c, Tag::new_integer(a, TagKey::Duration, 1))?;
+    Tag::insert(&c, Tag::new_text(b, TagKey::Title, s!("Song B")))?;
+    Tag::insert(&c, Tag::new_text(b, TagKey::Artist, s!("Two")))?;
+    Tag::insert(&c, Tag::new_text(b, TagKey::LocalMP3, s!("b.mp3")))?;
+    Tag::insert(&c, Tag::new_text(b, TagKey::UserTag(s!("chill")), s!("")))?;
+    Tag::insert(&c, Tag::new_integer(a, lib.clone(), 200))?;
+    Tag::insert(&c, Tag::new_integer(b, lib, 100))?;
+    Ok((a, b))
+}
+
+#[test_log::test(tokio::test)]
+async fn test_mpd_protocol() -> Result<()> {
+    let db = mk_db().await?;
+    let (a, b) = mk_library(&db).await?;
+    let player = Player::new(Output::Http, PathBuf::from("./storage/"));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(serve(listener, db.clone(), player.clone(), UserID(1)));
+
+    let mut c = Client::connect(addr).await?;
+    assert_eq!(c.cmd("ping").await?, vec!["OK"]);
+    assert_eq!(
+        c.cmd("nope").await?,
+        vec!["ACK [5@0] {nope} unknown command \"nope\""]
+    );
+
+    let listall = c.cmd("listall").await?;
+    assert_eq!(
+        listall,
+        vec![
+            format!("file: {}.mp3", a.0),
+            format!("file: {}.mp3", b.0),
+            s!("OK")
+        ]
+    );
+
+    let found = c.cmd(r#"find artist "Artist \"One\"""#).await?;
+    assert_eq!(found[0], format!("file: {}.mp3", a.0));
+    assert!(found.contains(&s!("Title: Song A")));
+    let found = c.cmd(r#"search "(title contains 'song b')""#).await?;
+    assert_eq!(found[0], format!("file: {}.mp3", b.0));
+    assert_eq!(
+        c.cmd("list artist").await?,
+        vec!["Artist: Artist \"One\"", "Artist: Two", "OK"]
+    );
+    assert_eq!(c.cmd("listplaylists").await?, vec!["playlist: chill", "OK"]);
+
+    // a second client waiting for changes
+    let mut idle = Client::connect(addr).await?;
+    idle.send("idle player").await?;
+
+    assert_eq!(
+        c.cmd(&format!("addid {}.mp3", b.0)).await?,
+        vec!["Id: 0", "OK"]
+    );
+    assert_eq!(
+        c.cmd(&format!(
+            "command_list_ok_begin\nadd {}.mp3\nplay 1\ncommand_list_end",
+            a.0
+        ))
+        .await?,
+        vec!["list_OK", "list_OK", "OK"]
+    );
+    assert_eq!(idle.response().await?, vec!["changed: player", "OK"]);
+
+    let status = c.cmd("status").await?;
+    assert!(status.contains(&s!("state: play")));
+    assert!(status.contains(&s!("song: 1")));
+    assert!(status.contains(&s!("songid: 1")));
+    assert!(status.contains(&s!("playlistlength: 2")));
+
+    let current = c.cmd("currentsong").await?;
+    assert_eq!(current[0], format!("file: {}.mp3", a.0));
+    assert!(current.contains(&s!("Pos: 1")));
+    assert!(current.contains(&s!("Id: 1")));
+
+    // errors stop the command list
+    assert_eq!(
+        c.cmd("command_list_begin\nstop\nplay 5\nclear\ncommand_list_end")
+            .await?,
+        vec!["ACK [2@1] {play} bad song index"]
+    );
+    assert_eq!(player.get().state, PlayState::Stop);
+    assert_eq!(player.get().queue.len(), 2);
+
+    // noidle, events of the own client are reported too
+    idle.send("idle playlist").await?;
+    assert_eq!(idle.response().await?, vec!["changed: playlist", "OK"]);
+    idle.send("idle mixer").await?;
+    idle.send("noidle").await?;
+    assert_eq!(idle.response().await?, vec!["OK"]);
+
+    assert_eq!(c.cmd("deleteid 0").await?, vec!["OK"]);
+    let queue = c.cmd("playlistinfo").await?;
+    assert_eq!(queue[0], format!("file: {}.mp3", a.0));
+    assert!(queue.contains(&s!("Pos: 0")));
+    assert_eq!(
+        c.cmd("deleteid 0").await?,
+        vec!["ACK [50@0] {deleteid} no such song"]
+    );
+
+    // songs that were never downloaded are skipped when adding everything
+    let unplayable = {
+        let c = db.get().await;
+        let id = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_integer(id, TagKey::UserLibrary(s!("1")), 300))?;
+        id
+    };
+    assert_eq!(c.cmd("clear").await?, vec!["OK"]);
+    assert_eq!(c.cmd(r#"add """#).await?, vec!["OK"]);
+    assert_eq!(player.get().queue.len(), 2);
+    assert_eq!(
+        c.cmd(&format!("addid {}.mp3", unplayable.0)).await?,
+        vec!["ACK [50@0] {addid} no playable source"]
+    );
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_mpd_http_output() -> Result<()> {
+    let storage = std::env::temp_dir().join(format!("musidex-mpd-{}", std::process::id()));
+    std::fs::create_dir_all(&storage)?;
+    std::fs::write(storage.join("a.mp3"), [1u8; 500])?;
+    std::fs::write(storage.join("b.mp3"), [2u8; 500])?;
+
+    let db = mk_db().await?;
+    let (a, b) = mk_library(&db).await?;
+    let player = Player::new(Output::Http, storage.clone());
+    player.start(db);
+    let mut audio = player.listen();
+
+    player.update(|s| {
+        s.add(a, None);
+        s.add(b, None);
+        s.play(None)
+    });
+
+    // a lasts 1 second so it is sent at 500 bytes per second, b has no duration so it is
+    // sent at the default bitrate
+    let mut received = vec![];
+    while received.len() < 1000 {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), audio.recv()).await??;
+        received.extend_from_slice(&chunk);
+    }
+    assert_eq!(&received[..500], &[1u8; 500]);
+    assert_eq!(&received[500..], &[2u8; 500]);
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(player.get().state, PlayState::Stop);
+
+    std::fs::remove_dir_all(storage)?;
+    Ok(())
+}
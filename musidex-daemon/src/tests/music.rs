@@ -1,9 +1,18 @@
 use super::*;
-use crate::domain::entity::{Music, MusicID, Tag, TagKey, UserID};
+use crate::domain::entity::{Music, MusicID, MusicMerge, Tag, TagKey, UserID};
+use crate::domain::history::Editor;
 use crate::domain::music::{delete_music, MoveDirection};
 use crate::domain::sync::fetch_metadata;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use hyper::StatusCode;
+use rusqlite::Connection;
+
+fn editor(uid: i32) -> Editor {
+    Editor {
+        user_id: UserID(uid),
+        ip: s!("127.0.0.1"),
+    }
+}
 
 #[test_log::test(tokio::test)]
 pub async fn test_mk_music() -> Result<()> {
@@ -48,6 +57,91 @@ pub async fn test_merge_music() -> Result<()> {
     Ok(())
 }
 
+#[test_log::test(tokio::test)]
+pub async fn test_unmerge_music() -> Result<()> {
+    let db = mk_db().await?;
+    let mut c = db.get().await;
+    let v = Music::mk(&c)?;
+    let v2 = Music::mk(&c)?;
+
+    Tag::insert(&c, Tag::new_integer(v, TagKey::UserLibrary(s!("0")), 1))?;
+    Tag::insert(&c, Tag::new_integer(v2, TagKey::UserLibrary(s!("1")), 2))?;
+    Tag::insert(&c, Tag::new_text(v, TagKey::Artist, s!("a")))?;
+    Tag::insert(&c, Tag::new_text(v2, TagKey::Artist, s!("b")))?;
+
+    let merge_id = Music::merge(&mut c, v, v2)?;
+
+    let merge = MusicMerge::by_id(&c, merge_id)?.context("no merge")?;
+    assert_eq!(merge.into_id, v);
+    assert_eq!(merge.merged_id, v2);
+    assert_eq!(
+        merge.moved,
+        vec![Tag::new_integer(v2, TagKey::UserLibrary(s!("1")), 2)]
+    );
+    assert_eq!(
+        merge.discarded,
+        vec![Tag::new_text(v2, TagKey::Artist, s!("b"))]
+    );
+    assert_eq!(MusicMerge::by_into_id(&c, v)?, vec![merge]);
+
+    assert_eq!(Music::unmerge(&mut c, merge_id)?, StatusCode::OK);
+    assert_eq!(Music::unmerge(&mut c, merge_id)?, StatusCode::CONFLICT);
+    assert_eq!(Music::unmerge(&mut c, 1234)?, StatusCode::NOT_FOUND);
+
+    let meta = fetch_metadata(&c)?;
+    assert_eq!(meta.musics, vec![v, v2]);
+    let mut tags = meta.tags.unwrap();
+    tags.sort_by_key(|t| (t.music_id.0, t.key.to_string()));
+    assert_eq!(
+        tags,
+        vec![
+            Tag::new_text(v, TagKey::Artist, s!("a")),
+            Tag::new_integer(v, TagKey::UserLibrary(s!("0")), 1),
+            Tag::new_text(v2, TagKey::Artist, s!("b")),
+            Tag::new_integer(v2, TagKey::UserLibrary(s!("1")), 2),
+        ]
+    );
+
+    // a moved tag edited after the merge isn't overwritten
+    let merge_id = Music::merge(&mut c, v, v2)?;
+    Tag::insert(&c, Tag::new_integer(v, TagKey::UserLibrary(s!("1")), 3))?;
+    assert_eq!(Music::unmerge(&mut c, merge_id)?, StatusCode::CONFLICT);
+    assert!(!Music::exists(&c, v2)?);
+    assert_eq!(
+        Tag::by_id_key(&c, v, &TagKey::UserLibrary(s!("1")))?.and_then(|t| t.integer),
+        Some(3)
+    );
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_merged_tag_provenance() -> Result<()> {
+    let db = mk_db().await?;
+    let mut c = db.get().await;
+    let v = Music::mk(&c)?;
+    let v2 = Music::mk(&c)?;
+    let v3 = Music::mk(&c)?;
+
+    Tag::insert(&c, Tag::new_text(v, TagKey::YoutubeDLVideoID, s!("aaa")))?;
+    Tag::insert(&c, Tag::new_text(v2, TagKey::YoutubeDLVideoID, s!("bbb")))?;
+    Tag::insert(&c, Tag::new_text(v3, TagKey::YoutubeDLVideoID, s!("ccc")))?;
+
+    Music::merge(&mut c, v2, v3)?;
+    Music::merge(&mut c, v, v2)?;
+
+    let find =
+        |c: &Connection, id: &str| MusicMerge::find_merged_tag(c, &TagKey::YoutubeDLVideoID, id);
+    assert_eq!(find(&c, "bbb")?, Some(v));
+    assert_eq!(find(&c, "ccc")?, Some(v));
+    assert_eq!(find(&c, "aaa")?, None);
+
+    Music::delete(&c, v)?;
+    assert_eq!(find(&c, "ccc")?, None);
+
+    Ok(())
+}
+
 #[test_log::test(tokio::test)]
 pub async fn test_update_cascade() -> Result<()> {
     let db = mk_db().await?;
@@ -71,10 +165,25 @@ pub async fn test_update_cascade() -> Result<()> {
     let id6 = Music::mk(&c)?;
     Tag::insert(&c, Tag::new_integer(id6, key.clone(), 19))?;
 
-    assert!(Music::move_(&mut c, &key, id1, id4, MoveDirection::Above)?);
-    assert!(Music::move_(&mut c, &key, id1, id6, MoveDirection::Below)?);
+    assert!(Music::move_(
+        &mut c,
+        &editor(1),
+        &key,
+        id1,
+        id4,
+        MoveDirection::Above
+    )?);
+    assert!(Music::move_(
+        &mut c,
+        &editor(1),
+        &key,
+        id1,
+        id6,
+        MoveDirection::Below
+    )?);
     assert!(!Music::move_(
         &mut c,
+        &editor(1),
         &key,
         MusicID(-1),
         id1,
@@ -125,7 +234,7 @@ pub async fn test_delete_request_zero_user() -> Result<()> {
     let meta = fetch_metadata(&c)?;
     assert_eq!(meta.musics.len(), 1);
 
-    delete_music(&c, UserID(0), v)?;
+    delete_music(&c, &editor(0), v)?;
 
     let meta = fetch_metadata(&c)?;
     assert_eq!(meta.musics.len(), 1);
@@ -143,7 +252,7 @@ pub async fn test_delete_request_one_user() -> Result<()> {
     let meta = fetch_metadata(&c)?;
     assert_eq!(meta.musics.len(), 1);
 
-    delete_music(&c, UserID(0), v)?;
+    delete_music(&c, &editor(0), v)?;
 
     let meta = fetch_metadata(&c)?;
     assert_eq!(meta.musics.len(), 0);
@@ -161,7 +270,7 @@ pub async fn test_delete_request_one_user_wrong_user() -> Result<()> {
     let meta = fetch_metadata(&c)?;
     assert_eq!(meta.musics.len(), 1);
 
-    assert_eq!(delete_music(&c, UserID(-1), v)?, StatusCode::FORBIDDEN);
+    assert_eq!(delete_music(&c, &editor(-1), v)?, StatusCode::FORBIDDEN);
 
     let meta = fetch_metadata(&c)?;
     assert_eq!(meta.musics.len(), 1);
@@ -180,12 +289,12 @@ pub async fn test_delete_request_two_user() -> Result<()> {
     let meta = fetch_metadata(&c)?;
     assert_eq!(meta.musics.len(), 1);
 
-    delete_music(&c, UserID(0), v)?;
+    delete_music(&c, &editor(0), v)?;
 
     let meta = fetch_metadata(&c)?;
     assert_eq!(meta.musics.len(), 1);
 
-    delete_music(&c, UserID(1), v)?;
+    delete_music(&c, &editor(1), v)?;
 
     let meta = fetch_metadata(&c)?;
     assert_eq!(meta.musics.len(), 0);
@@ -0,0 +1,134 @@
+use super::*;
+use crate::domain::entity::{Music, MusicID, Tag, TagKey, UserID};
+use crate::domain::history::Editor;
+use crate::domain::mutations::{apply, Mutation};
+use crate::domain::sync::head_seq;
+use anyhow::Result;
+
+fn editor() -> Editor {
+    Editor {
+        user_id: UserID(1),
+        ip: s!("127.0.0.1"),
+    }
+}
+
+fn mutation(ts: i64, op: &str, music_id: MusicID) -> Mutation {
+    Mutation {
+        ts,
+        op: s!(op),
+        music_id,
+        key: None,
+        text: None,
+        integer: None,
+        date: None,
+        library: None,
+        id_base: None,
+        direction: None,
+    }
+}
+
+fn set(ts: i64, music_id: MusicID, key: TagKey, text: &str) -> Mutation {
+    Mutation {
+        key: Some(key),
+        text: Some(s!(text)),
+        ..mutation(ts, "set", music_id)
+    }
+}
+
+fn statuses(m: &crate::domain::mutations::MutationResults) -> Vec<u16> {
+    m.results.iter().map(|r| r.status).collect()
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_mutations_last_writer_wins() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+    let now = chrono::Utc::now().timestamp_millis();
+
+    let music = Music::mk(&c)?;
+    let music2 = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(music, TagKey::Title, s!("server")))?;
+
+    let res = apply(
+        &c,
+        &editor(),
+        vec![
+            // edited offline before the server change
+            set(now - 3_600_000, music, TagKey::Title, "old"),
+            // edited after, twice in the same batch
+            set(now + 1000, music2, TagKey::Title, "a"),
+            set(now + 2000, music2, TagKey::Title, "b"),
+            mutation(now, "delete", MusicID(1000)),
+        ],
+    )?;
+    assert_eq!(statuses(&res), vec![409, 200, 200, 404]);
+    assert_eq!(res.seq, head_seq(&c)?);
+
+    let title = |id| Tag::by_id_key(&c, id, &TagKey::Title).map(|t| t.and_then(|t| t.text));
+    assert_eq!(title(music)?, Some(s!("server")));
+    assert_eq!(title(music2)?, Some(s!("b")));
+
+    let res = apply(
+        &c,
+        &editor(),
+        vec![
+            mutation(now - 3_600_000, "delete", music),
+            Mutation {
+                key: Some(TagKey::Title),
+                ..mutation(now + 3000, "remove", music2)
+            },
+        ],
+    )?;
+    assert_eq!(statuses(&res), vec![409, 200]);
+    assert!(Music::exists(&c, music)?);
+    assert_eq!(title(music2)?, None);
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_mutations_library_order() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+    let now = chrono::Utc::now().timestamp_millis();
+    let lib = TagKey::UserLibrary(s!("1"));
+    let order = |id| Tag::by_id_key(&c, id, &lib).map(|t| t.and_then(|t| t.integer));
+
+    let a = Music::mk(&c)?;
+    let b = Music::mk(&c)?;
+    let d = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_integer(a, lib.clone(), 100))?;
+    Tag::insert(&c, Tag::new_integer(b, lib.clone(), 200))?;
+
+    let res = apply(
+        &c,
+        &editor(),
+        vec![
+            // position taken on the server in the meantime
+            Mutation {
+                key: Some(lib.clone()),
+                integer: Some(200),
+                ..mutation(now, "set", d)
+            },
+            Mutation {
+                library: Some(s!("1")),
+                id_base: Some(a),
+                direction: Some(s!("below")),
+                ..mutation(now, "move", b)
+            },
+            Mutation {
+                library: Some(s!("2")),
+                id_base: Some(a),
+                direction: Some(s!("below")),
+                ..mutation(now, "move", b)
+            },
+        ],
+    )?;
+    assert_eq!(statuses(&res), vec![200, 200, 404]);
+
+    assert_eq!(order(d)?, Some(300));
+    assert_eq!(order(b)?, Some(99));
+    assert_eq!(order(a)?, Some(100));
+
+    Ok(())
+}
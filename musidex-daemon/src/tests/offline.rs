@@ -0,0 +1,92 @@
+use super::*;
+use crate::domain::commands;
+use crate::domain::entity::{Music, Tag, TagKey, UserID};
+use crate::domain::history::Editor;
+use crate::domain::offline::{manifest, ManifestOptions, ManifestOrder};
+use anyhow::Result;
+use std::path::PathBuf;
+
+fn opts(order: ManifestOrder, budget: Option<u64>) -> ManifestOptions {
+    ManifestOptions {
+        playlist: None,
+        budget,
+        order,
+    }
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_offline_manifest() -> Result<()> {
+    let storage: PathBuf =
+        std::env::temp_dir().join(format!("musidex-offline-{}", std::process::id()));
+    std::fs::create_dir_all(&storage)?;
+    std::fs::write(storage.join("a.mp3"), [b'a'; 10])?;
+    std::fs::write(storage.join("b.m4a"), [b'b'; 20])?;
+
+    let db = mk_db().await?;
+    let (a, b) = {
+        let c = db.get().await;
+        let lib = TagKey::UserLibrary(s!("1"));
+        let a = Music::mk(&c)?;
+        let b = Music::mk(&c)?;
+        let not_downloaded = Music::mk(&c)?;
+        let not_in_library = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_text(a, TagKey::LocalMP3, s!("a.mp3")))?;
+        Tag::insert(&c, Tag::new_text(b, TagKey::LocalM4A, s!("b.m4a")))?;
+        Tag::insert(&c, Tag::new_text(b, TagKey::Thumbnail, s!("b.jpg")))?;
+        Tag::insert(
+            &c,
+            Tag::new_text(not_in_library, TagKey::LocalMP3, s!("a.mp3")),
+        )?;
+        Tag::insert(&c, Tag::new_integer(a, lib.clone(), 100))?;
+        Tag::insert(&c, Tag::new_integer(b, lib.clone(), 200))?;
+        Tag::insert(&c, Tag::new_integer(not_downloaded, lib, 300))?;
+        (a, b)
+    };
+
+    let m = manifest(
+        &db,
+        storage.clone(),
+        UserID(1),
+        opts(ManifestOrder::Library, None),
+    )
+    .await?;
+    let ids: Vec<_> = m.musics.iter().map(|x| x.music_id).collect();
+    assert_eq!(ids, vec![b, a]);
+    assert_eq!(m.total_size, 30);
+    assert_eq!(m.musics[0].format, "m4a");
+    assert_eq!(m.musics[0].thumbnail.as_deref(), Some("b.jpg"));
+    assert_eq!(m.musics[1].hash, "3495ff69d34671d1e15b33a63c1379fdedd3a32a");
+
+    let m = manifest(
+        &db,
+        storage.clone(),
+        UserID(1),
+        opts(ManifestOrder::Library, Some(25)),
+    )
+    .await?;
+    assert_eq!(m.musics.len(), 1);
+    assert_eq!(m.candidates, 2);
+    assert_eq!(m.total_size, 20);
+
+    // listened musics go first
+    let e = Editor {
+        user_id: UserID(1),
+        ip: s!(""),
+    };
+    let msg = format!(r#"{{"id": 1, "cmd": "listen", "music_id": {}}}"#, a.0);
+    let ack = commands::run(&db, &e, &commands::parse(&msg).unwrap(), &msg).await;
+    assert_eq!(ack.status, 200);
+
+    let m = manifest(
+        &db,
+        storage.clone(),
+        UserID(1),
+        opts(ManifestOrder::Listens, None),
+    )
+    .await?;
+    let ids: Vec<_> = m.musics.iter().map(|x| x.music_id).collect();
+    assert_eq!(ids, vec![a, b]);
+
+    std::fs::remove_dir_all(&storage)?;
+    Ok(())
+}
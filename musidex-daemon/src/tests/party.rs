@@ -0,0 +1,153 @@
+use super::*;
+use crate::domain::commands;
+use crate::domain::entity::Music;
+use crate::domain::party::{self, server_time, ClockReply, Membership, Parties};
+use anyhow::Result;
+use nanoserde::DeJson;
+use std::time::Duration;
+
+async fn run(db: &Db, parties: &Parties, m: &mut Option<Membership>, msg: &str) -> String {
+    let cmd = commands::parse(msg).expect("not a command");
+    assert!(party::is_party_command(&cmd));
+    party::run(db, parties, m, &cmd, msg).await
+}
+
+#[derive(DeJson)]
+struct Reply {
+    status: u16,
+}
+
+#[test_log::test(tokio::test)]
+async fn test_party() -> Result<()> {
+    let db = mk_db().await?;
+    let (a, b) = {
+        let c = db.get().await;
+        (Music::mk(&c)?, Music::mk(&c)?)
+    };
+    let parties = Parties::default();
+    macro_rules! status {
+        ($m: expr, $msg: expr) => {
+            Reply::deserialize_json(&run(&db, &parties, &mut $m, &$msg).await)?.status
+        };
+    }
+    let mut host = None;
+    let mut guest = None;
+
+    let t = server_time();
+    let clock = run(
+        &db,
+        &parties,
+        &mut guest,
+        r#"{"id": 1, "cmd": "party_clock", "client_time": 12.5}"#,
+    )
+    .await;
+    let clock = ClockReply::deserialize_json(&clock)?;
+    assert_eq!(
+        (clock.kind.as_str(), clock.id, clock.client_time),
+        ("clock", 1, 12.5)
+    );
+    assert!(clock.server_time >= t && clock.server_time <= server_time());
+
+    let control = |action: &str| format!(r#"{{"id": 2, "cmd": "party_control", {}}}"#, action);
+    assert_eq!(status!(host, control(r#""action": "pause""#)), 400);
+
+    assert_eq!(status!(host, r#"{"id": 3, "cmd": "party_create"}"#), 200);
+    let code = host.as_ref().unwrap().party.code.clone();
+    assert_eq!(code.len(), 6);
+
+    let join = |code: &str| format!(r#"{{"id": 4, "cmd": "party_join", "code": "{}"}}"#, code);
+    assert_eq!(status!(guest, join("nope")), 404);
+    assert!(guest.is_none());
+    assert_eq!(status!(guest, join(&code.to_lowercase())), 200);
+    let (host_id, guest_id) = (
+        host.as_ref().unwrap().member,
+        guest.as_ref().unwrap().member,
+    );
+
+    let view = guest.as_ref().unwrap().party.view(guest_id);
+    assert_eq!(view.code, code);
+    assert!(!view.host);
+    assert_eq!(
+        (view.hosts, view.members),
+        (vec![host_id], vec![host_id, guest_id])
+    );
+
+    // only hosts control the party
+    let enqueue = |id: i32| control(&format!(r#""action": "enqueue", "music_id": {}"#, id));
+    assert_eq!(status!(guest, enqueue(a.0)), 403);
+    assert_eq!(status!(host, enqueue(1000)), 404);
+
+    let rx = &mut guest.as_mut().unwrap().rx;
+    rx.borrow_and_update();
+    assert_eq!(status!(host, enqueue(a.0)), 200);
+    assert_eq!(status!(host, enqueue(b.0)), 200);
+    // the guest is told about it
+    assert!(guest.as_ref().unwrap().rx.has_changed()?);
+
+    let view = || guest.as_ref().unwrap().party.view(guest_id);
+    assert_eq!(
+        (view().queue, view().current, view().playing),
+        (vec![a.0, b.0], Some(0), false)
+    );
+
+    assert_eq!(
+        status!(host, control(r#""action": "seek", "position": 10"#)),
+        200
+    );
+    assert_eq!(status!(host, control(r#""action": "play""#)), 200);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let v = view();
+    assert!(v.playing);
+    assert!(v.position >= 10.09 && v.position < 11.0, "{}", v.position);
+    // the timeline is what matters, views at different times agree
+    let v2 = view();
+    assert!((v2.position - (v.position + (v2.at - v.at) / 1000.0)).abs() < 0.001);
+
+    assert_eq!(status!(host, control(r#""action": "pause""#)), 200);
+    let paused = view().position;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(view().position, paused);
+
+    // several hosts seeing the end of the track only skip it once
+    assert_eq!(
+        status!(host, control(r#""action": "next", "from": 0"#)),
+        200
+    );
+    assert_eq!(
+        status!(host, control(r#""action": "next", "from": 0"#)),
+        200
+    );
+    assert_eq!((view().current, view().position), (Some(1), 0.0));
+    assert_eq!(
+        status!(host, control(r#""action": "play", "index": 5"#)),
+        400
+    );
+    assert_eq!(status!(host, control(r#""action": "dance""#)), 400);
+
+    let promote = control(&format!(r#""action": "promote", "member": {}"#, guest_id));
+    assert_eq!(status!(host, promote), 200);
+    assert!(view().host);
+
+    // the party goes on when the host leaves, and ends with its last member
+    assert_eq!(status!(host, r#"{"id": 5, "cmd": "party_leave"}"#), 200);
+    assert!(host.is_none());
+    assert_eq!(
+        (view().hosts, view().members),
+        (vec![guest_id], vec![guest_id])
+    );
+    assert!(parties.get(&code).is_some());
+    drop(guest);
+    assert!(parties.get(&code).is_none());
+
+    // a new host is picked when the last one leaves
+    let mut first = None;
+    let mut second = None;
+    status!(first, r#"{"id": 6, "cmd": "party_create"}"#);
+    let code = first.as_ref().unwrap().party.code.clone();
+    status!(second, join(&code));
+    let second_id = second.as_ref().unwrap().member;
+    drop(first);
+    assert!(second.as_ref().unwrap().party.view(second_id).host);
+
+    Ok(())
+}
@@ -0,0 +1,236 @@
+use super::*;
+use crate::application::playlist as handlers;
+use crate::domain::entity::{Music, MusicID, Tag, TagKey, User, UserID};
+use crate::domain::history::Editor;
+use crate::domain::playlist::{self, youtube_id, Format, Locations, PlaylistEntry};
+use crate::infrastructure::db::LogEntry;
+use crate::infrastructure::router::Router;
+use anyhow::Result;
+
+const BASE: &str = "http://music.local:3200";
+
+fn editor(user_id: UserID) -> Editor {
+    Editor {
+        user_id,
+        ip: s!("127.0.0.1"),
+    }
+}
+
+#[test]
+fn test_playlist_parse() {
+    assert_eq!(
+        youtube_id("https://www.youtube.com/watch?v=abc&t=3"),
+        Some("abc")
+    );
+    assert_eq!(youtube_id("https://youtu.be/abc?t=3"), Some("abc"));
+    assert_eq!(
+        youtube_id("https://music.youtube.com/watch?v=abc"),
+        Some("abc")
+    );
+    assert_eq!(youtube_id("https://example.com/watch?v=abc"), None);
+    assert_eq!(youtube_id("https://youtube.com/channel/x"), None);
+
+    let m3u = "#EXTM3U\n#EXTINF:200,Daft Punk - One More Time\n/music/one.mp3\n\n\
+               #EXTINF:-1,Just a title\nhttps://youtu.be/abc\nrelative.ogg\n";
+    assert_eq!(
+        playlist::parse(m3u),
+        vec![
+            PlaylistEntry {
+                location: Some(s!("/music/one.mp3")),
+                title: Some(s!("One More Time")),
+                artist: Some(s!("Daft Punk")),
+                hash: None,
+            },
+            PlaylistEntry {
+                location: Some(s!("https://youtu.be/abc")),
+                title: Some(s!("Just a title")),
+                ..Default::default()
+            },
+            PlaylistEntry {
+                location: Some(s!("relative.ogg")),
+                ..Default::default()
+            },
+        ]
+    );
+
+    let xspf = r#"<?xml version="1.0"?><playlist version="1" xmlns="http://xspf.org/ns/0/">
+        <trackList>
+          <track><location>file:///a%20b.mp3</location><title>Rock &amp; Roll</title>
+            <creator>Led Zeppelin</creator><identifier>sha1:123abc</identifier></track>
+          <track><title>Only a title</title></track>
+        </trackList></playlist>"#;
+    assert_eq!(
+        playlist::parse(xspf),
+        vec![
+            PlaylistEntry {
+                location: Some(s!("file:///a%20b.mp3")),
+                title: Some(s!("Rock & Roll")),
+                artist: Some(s!("Led Zeppelin")),
+                hash: Some(s!("123abc")),
+            },
+            PlaylistEntry {
+                title: Some(s!("Only a title")),
+                ..Default::default()
+            },
+        ]
+    );
+}
+
+async fn mk_song(db: &Db, title: &str, artist: &str, tags: Vec<(TagKey, &str)>) -> Result<MusicID> {
+    let c = db.get().await;
+    let id = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(id, TagKey::Title, title.to_string()))?;
+    Tag::insert(&c, Tag::new_text(id, TagKey::Artist, artist.to_string()))?;
+    for (key, v) in tags {
+        Tag::insert(&c, Tag::new_text(id, key, v.to_string()))?;
+    }
+    Ok(id)
+}
+
+fn library(c: &rusqlite::Connection, user: UserID) -> Result<Vec<MusicID>> {
+    let mut tags = Tag::by_key(c, &TagKey::UserLibrary(user.0.to_string()))?;
+    tags.sort_by_key(|t| -t.integer.unwrap_or(0));
+    Ok(tags.into_iter().map(|t| t.music_id).collect())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_playlist_import_export() -> Result<()> {
+    let db = mk_db().await?;
+    let yt = mk_song(
+        &db,
+        "Video",
+        "Someone",
+        vec![
+            (TagKey::YoutubeDLURL, "https://www.youtube.com/watch?v=vid1"),
+            (TagKey::YoutubeDLVideoID, "vid1"),
+        ],
+    )
+    .await?;
+    let file = mk_song(&db, "File", "Someone", vec![(TagKey::LocalMP3, "file.mp3")]).await?;
+    let hashed = mk_song(&db, "Hashed", "Other", vec![(TagKey::LocalM4A, "h.m4a")]).await?;
+    let named = mk_song(&db, "One More Time", "Daft Punk", vec![]).await?;
+    let user = {
+        let c = db.get().await;
+        c.execute(
+            "INSERT INTO file_hashes (path, size, mtime, hash) VALUES ('h.m4a', 1, 1, 'deadbeef');",
+            [],
+        )?;
+        User::create(&c, s!("importer"))?
+    };
+
+    let doc = "#EXTM3U\n\
+         #EXTINF:1,x\nhttps://youtu.be/vid1\n\
+         #EXTINF:1,x\n/home/me/Music/file.mp3\n\
+         #EXTINF:1,daft punk - one more time\nnothing.mp3\n\
+         https://youtu.be/unknown\n\
+         #EXTINF:1,Nobody - Nothing\nnowhere.mp3\n";
+    let mut entries = playlist::parse(doc);
+    entries.push(PlaylistEntry {
+        hash: Some(s!("deadbeef")),
+        ..Default::default()
+    });
+
+    let mut c = db.get().await;
+    let report = playlist::import(&mut c, &editor(user), BASE, Some("car"), &entries)?;
+    assert_eq!(report.matched, vec![yt.0, file.0, named.0, hashed.0]);
+    assert_eq!(report.queued, vec![s!("https://youtu.be/unknown")]);
+    assert_eq!(report.unmatched, vec![s!("Nobody - Nothing")]);
+    // in the order of the playlist, and tagged with its name
+    assert_eq!(library(&c, user)?, vec![yt, file, named, hashed]);
+    assert!(Tag::has(&c, named, &TagKey::UserTag(s!("car")))?);
+    // both tags are in the history, so the import can be undone
+    assert_eq!(LogEntry::by_music(&c, named)?.len(), 2);
+
+    // importing again doesn't duplicate anything
+    playlist::import(&mut c, &editor(user), BASE, Some("car"), &entries)?;
+    assert_eq!(library(&c, user)?.len(), 4);
+
+    let users = User::list(&c)?;
+    let owner = users.iter().find(|u| u.id == user).unwrap().clone();
+    let m3u8 = playlist::export(
+        &c,
+        owner.clone(),
+        Some("car"),
+        &Locations::Local,
+        Format::M3u8,
+    )?;
+    assert!(m3u8.starts_with("#EXTM3U\n#PLAYLIST:importer - car\n"));
+    assert!(m3u8.contains("#EXTINF:-1,Someone - File\nfile.mp3\n"));
+    // not downloaded, the YouTube url goes instead
+    assert!(m3u8.contains("https://www.youtube.com/watch?v=vid1\n"));
+    // nothing to point to
+    assert!(!m3u8.contains("One More Time"));
+
+    let xspf = playlist::export(&c, owner, None, &Locations::Stream(s!(BASE)), Format::Xspf)?;
+    assert!(xspf.contains(&format!(
+        "<track><location>http://music.local:3200/api/stream/{}</location><title>Hashed</title>\
+         <creator>Other</creator><identifier>sha1:deadbeef</identifier></track>",
+        hashed.0
+    )));
+
+    // our own exports go back in as they came out
+    let report = playlist::import(
+        &mut c,
+        &editor(UserID(1)),
+        BASE,
+        None,
+        &playlist::parse(&xspf),
+    )?;
+    assert_eq!(report.matched, vec![yt.0, file.0, hashed.0]);
+    // stream urls of other servers point to their musics, not ours
+    let other = format!("http://elsewhere/api/stream/{}\n", named.0);
+    let report = playlist::import(
+        &mut c,
+        &editor(UserID(1)),
+        BASE,
+        None,
+        &playlist::parse(&other),
+    )?;
+    assert!(report.matched.is_empty());
+    drop(c);
+
+    let mut router = Router::new();
+    router
+        .state(db.clone())
+        .get("/api/user/:id/playlist.xspf", handlers::export_xspf)
+        .get("/api/user/:id/playlist.m3u8", handlers::export_m3u8)
+        .post("/api/user/:id/playlist/import", handlers::import);
+    let req = |method: &str, uri: &str, body: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let resp = router
+        .serve(req("GET", "/api/user/99/playlist.m3u8", ""))
+        .await?;
+    assert_eq!(resp.status(), 404);
+    let uri = format!("/api/user/{}/playlist.m3u8?paths=nope", user.0);
+    assert_eq!(router.serve(req("GET", &uri, "")).await?.status(), 400);
+    let uri = format!("/api/user/{}/playlist.xspf?playlist=car", user.0);
+    let resp = router.serve(req("GET", &uri, "")).await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "application/xspf+xml");
+    assert_eq!(
+        resp.headers()["content-disposition"],
+        "attachment; filename=\"car.xspf\""
+    );
+
+    let uri = format!("/api/user/{}/playlist/import?playlist=road", user.0);
+    let resp = router.serve(req("POST", &uri, "")).await?;
+    assert_eq!(resp.status(), 400);
+    let resp = router.serve(req("POST", &uri, "file.mp3\n")).await?;
+    assert_eq!(resp.status(), 200);
+    let body = hyper::body::to_bytes(resp.into_body()).await?;
+    assert_eq!(
+        String::from_utf8_lossy(&body),
+        format!(r#"{{"matched":[{}],"queued":[],"unmatched":[]}}"#, file.0)
+    );
+    assert!(Tag::has(
+        &*db.get().await,
+        file,
+        &TagKey::UserTag(s!("road"))
+    )?);
+    Ok(())
+}
@@ -0,0 +1,38 @@
+use super::*;
+use crate::domain::entity::{Music, Tag, TagKey};
+use crate::domain::query::{KeyPattern, Query};
+use crate::domain::sync::fetch_metadata_filtered;
+use anyhow::Result;
+
+#[test_log::test(tokio::test)]
+pub async fn test_fetch_metadata_filtered() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let m1 = Music::mk(&c)?;
+    let m2 = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(m1, TagKey::Artist, s!("Daft Punk")))?;
+    Tag::insert(&c, Tag::new_text(m1, TagKey::Title, s!("One More Time")))?;
+    Tag::insert(&c, Tag::new_integer(m1, TagKey::UserLibrary(s!("1")), 100))?;
+    Tag::insert(&c, Tag::new_text(m2, TagKey::Artist, s!("Justice")))?;
+    Tag::insert(&c, Tag::new_integer(m2, TagKey::UserLibrary(s!("2")), 100))?;
+
+    let q = Query::parse("has:user_library:1")?;
+    let meta = fetch_metadata_filtered(&c, Some(&q), None)?;
+    assert_eq!(meta.musics, vec![m1]);
+    assert_eq!(meta.tags.unwrap().len(), 3);
+
+    let fields = KeyPattern::parse_list("artist,user_library:*");
+    let meta = fetch_metadata_filtered(&c, None, Some(&fields))?;
+    assert_eq!(meta.musics.len(), 2);
+    let tags = meta.tags.unwrap();
+    assert_eq!(tags.len(), 4);
+    assert!(tags.iter().all(|t| t.key != TagKey::Title));
+
+    let q = Query::parse("artist~justice")?;
+    let meta = fetch_metadata_filtered(&c, Some(&q), Some(&KeyPattern::parse_list("title")))?;
+    assert_eq!(meta.musics, vec![m2]);
+    assert!(meta.tags.unwrap().is_empty());
+
+    Ok(())
+}
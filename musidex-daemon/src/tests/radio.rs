@@ -0,0 +1,229 @@
+use super::*;
+use crate::application::radio;
+use crate::domain::entity::{Music, MusicID, Tag, TagKey, UserID, Vector};
+use crate::domain::radio::{icy_metadata, next_song, IcyWriter, RadioChunk, Radios};
+use crate::infrastructure::router::Router;
+use anyhow::{Context, Result};
+use hyper::body::{Bytes, HttpBody};
+use std::path::Path;
+use std::time::Duration;
+use tinyrand::{Seeded, StdRand};
+
+fn chunk(data: &[u8], title: &str) -> RadioChunk {
+    RadioChunk {
+        data: Bytes::copy_from_slice(data),
+        title: title.into(),
+    }
+}
+
+#[test]
+fn test_icy_writer() {
+    let meta = icy_metadata("AC/DC - It's a long way");
+    assert_eq!(meta[0] as usize * 16 + 1, meta.len());
+    assert!(meta[1..].starts_with("StreamTitle='AC/DC - It\u{2019}s a long way';".as_bytes()));
+
+    let mut w = IcyWriter::new(4);
+    let out = w.write(&chunk(&[1, 2, 3], "a"));
+    assert_eq!(out, vec![1, 2, 3]);
+    let out = w.write(&chunk(&[4, 5, 6, 7, 8, 9], "a"));
+    let mut expected = vec![4];
+    expected.extend(icy_metadata("a"));
+    expected.extend([5, 6, 7, 8, 0, 9]);
+    assert_eq!(out, expected);
+    // the title is sent again when it changes
+    let out = w.write(&chunk(&[10, 11, 12], "b"));
+    let mut expected = vec![10, 11, 12];
+    expected.extend(icy_metadata("b"));
+    assert_eq!(out, expected);
+}
+
+async fn mk_song(
+    db: &Db,
+    title: &str,
+    mp3: Option<&str>,
+    embedding: Option<Vec<f32>>,
+) -> Result<MusicID> {
+    let c = db.get().await;
+    let id = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(id, TagKey::Title, title.to_string()))?;
+    Tag::insert(&c, Tag::new_text(id, TagKey::Artist, s!("Artist")))?;
+    Tag::insert(
+        &c,
+        Tag::new_integer(id, TagKey::UserLibrary(s!("1")), id.0 as i64),
+    )?;
+    match mp3 {
+        Some(path) => Tag::insert(&c, Tag::new_text(id, TagKey::LocalMP3, path.to_string()))?,
+        None => Tag::insert(&c, Tag::new_text(id, TagKey::LocalM4A, s!("x.m4a")))?,
+    }
+    if let Some(v) = embedding {
+        Tag::insert(&c, Tag::new_vector(id, TagKey::Embedding, Vector(v)))?;
+    }
+    Ok(id)
+}
+
+#[test_log::test(tokio::test)]
+async fn test_radio_next_song() -> Result<()> {
+    let db = mk_db().await?;
+    let current = mk_song(&db, "current", Some("c.mp3"), Some(vec![1.0, 0.0])).await?;
+    let mut close = vec![];
+    for i in 0..5 {
+        let v = vec![1.0, i as f32 * 0.1];
+        close.push(mk_song(&db, "close", Some("c.mp3"), Some(v)).await?);
+    }
+    let far = mk_song(&db, "far", Some("f.mp3"), Some(vec![-1.0, 0.0])).await?;
+    let no_embedding = mk_song(&db, "none", Some("n.mp3"), None).await?;
+    let m4a = mk_song(&db, "m4a", None, Some(vec![1.0, 0.0])).await?;
+    {
+        let c = db.get().await;
+        let other = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_text(other, TagKey::LocalMP3, s!("o.mp3")))?;
+        Tag::insert(
+            &c,
+            Tag::new_vector(other, TagKey::Embedding, Vector(vec![1.0, 0.0])),
+        )?;
+    }
+
+    let c = db.get().await;
+    let mut rng = StdRand::seed(42);
+    for _ in 0..50 {
+        let next = next_song(&c, UserID(1), Some(current), &[], &mut rng)?.context("no song")?;
+        assert!(close.contains(&next), "{:?} isn't similar", next);
+    }
+
+    // recently played songs are avoided
+    let next = next_song(&c, UserID(1), Some(current), &close, &mut rng)?;
+    assert_eq!(next, Some(far));
+
+    // without embedding anything playable goes
+    let mut picked = vec![];
+    for _ in 0..200 {
+        picked
+            .push(next_song(&c, UserID(1), Some(no_embedding), &[], &mut rng)?.context("no song")?);
+    }
+    assert!(picked.contains(&far) && picked.contains(&current));
+    assert!(!picked.contains(&m4a) && !picked.contains(&no_embedding));
+
+    // nothing else to play
+    assert_eq!(next_song(&c, UserID(2), None, &[], &mut rng)?, None);
+    Ok(())
+}
+
+async fn recv(rx: &mut tokio::sync::broadcast::Receiver<RadioChunk>) -> Result<RadioChunk> {
+    Ok(tokio::time::timeout(Duration::from_secs(5), rx.recv()).await??)
+}
+
+#[test_log::test(tokio::test)]
+async fn test_radio_stream() -> Result<()> {
+    let storage = std::env::temp_dir().join(format!("musidex-radio-{}", std::process::id()));
+    std::fs::create_dir_all(&storage)?;
+    std::fs::write(storage.join("a.mp3"), [1u8; 20000])?;
+    std::fs::write(storage.join("b.mp3"), [2u8; 20000])?;
+
+    let db = mk_db().await?;
+    let a = mk_song(&db, "A", Some("a.mp3"), None).await?;
+    let b = mk_song(&db, "B", Some("b.mp3"), None).await?;
+    {
+        let c = db.get().await;
+        Tag::insert(&c, Tag::new_integer(a, TagKey::Duration, 1))?;
+        Tag::insert(&c, Tag::new_integer(b, TagKey::Duration, 1))?;
+    }
+    let radios = Radios::new(db.clone(), storage.clone());
+
+    // everyone hears the same thing
+    let radio = radios.get(UserID(1));
+    let mut rx1 = radio.listen();
+    let mut rx2 = radio.listen();
+    let first = recv(&mut rx1).await?;
+    let same = recv(&mut rx2).await?;
+    assert_eq!((&first.data, &first.title), (&same.data, &same.title));
+    let (first_id, other_title) = match &*first.title {
+        "Artist - A" => (a, "Artist - B"),
+        _ => (b, "Artist - A"),
+    };
+    assert_eq!(radio.status().playing, Some(first_id.0));
+    assert_eq!(radio.status().listeners, 2);
+    drop(rx2);
+
+    // the other song comes next, since the first one was just played
+    loop {
+        let chunk = recv(&mut rx1).await?;
+        if chunk.title != first.title {
+            assert_eq!(&*chunk.title, other_title);
+            break;
+        }
+    }
+
+    radio.enqueue(first_id);
+    assert_eq!(radio.status().queue, vec![first_id.0]);
+    radio.skip();
+    loop {
+        let chunk = recv(&mut rx1).await?;
+        if chunk.title == first.title {
+            break;
+        }
+    }
+    assert!(radio.status().queue.is_empty());
+
+    // over http, with metadata
+    let mut router = Router::new();
+    router
+        .state(db)
+        .state(radios)
+        .get("/api/radio/:user", radio::status)
+        .get("/api/radio/:user/stream", radio::stream)
+        .post("/api/radio/:user/control", radio::control);
+
+    let req = |method: &str, uri: &str, body: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Icy-MetaData", "1")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let resp = router.serve(req("GET", "/api/radio/5/stream", "")).await?;
+    assert_eq!(resp.status(), 404);
+    let resp = router
+        .serve(req(
+            "POST",
+            "/api/radio/1/control",
+            r#"{"action": "enqueue", "music_id": 999}"#,
+        ))
+        .await?;
+    assert_eq!(resp.status(), 400);
+    let resp = router
+        .serve(req(
+            "POST",
+            "/api/radio/1/control",
+            r#"{"action": "enqueue", "music_id": 2}"#,
+        ))
+        .await?;
+    assert_eq!(resp.status(), 200);
+    let status = hyper::body::to_bytes(resp.into_body()).await?;
+    assert!(String::from_utf8_lossy(&status).contains(r#""queue":[2]"#));
+
+    let mut resp = router.serve(req("GET", "/api/radio/1/stream", "")).await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "audio/mpeg");
+    assert_eq!(resp.headers()["icy-metaint"], "16000");
+    let mut received = vec![];
+    while received.len() < 16001 {
+        let data = tokio::time::timeout(Duration::from_secs(5), resp.body_mut().data())
+            .await?
+            .context("stream ended")??;
+        received.extend_from_slice(&data);
+    }
+    let len = received[16000] as usize * 16;
+    assert!(len > 0);
+    while received.len() < 16001 + len {
+        let data = resp.body_mut().data().await.context("stream ended")??;
+        received.extend_from_slice(&data);
+    }
+    let meta = String::from_utf8_lossy(&received[16001..16001 + len]).to_string();
+    assert!(meta.starts_with("StreamTitle='Artist - "), "{}", meta);
+
+    drop(rx1);
+    drop(resp);
+    std::fs::remove_dir_all(Path::new(&storage))?;
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use super::*;
+use crate::domain::entity::{Music, Tag, TagKey, TagSchema, TagValueType, Vector};
+use crate::domain::sync::fetch_metadata;
+use anyhow::{Context, Result};
+
+#[test_log::test(tokio::test)]
+pub async fn test_find_schema() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let schemas = TagSchema::list(&c)?;
+
+    let s = TagSchema::find(&schemas, &TagKey::Title).context("no title schema")?;
+    assert_eq!(s.value_type, TagValueType::Text);
+    assert!(s.user_editable);
+
+    let s = TagSchema::find(&schemas, &TagKey::UserLibrary(s!("3"))).context("no library")?;
+    assert_eq!(s.key, "user_library:");
+    assert_eq!(s.value_type, TagValueType::Integer);
+
+    assert!(TagSchema::find(&schemas, &TagKey::Other(s!("titel"))).is_none());
+    assert!(!TagSchema::is_synced(&schemas, &TagKey::FullEmbedding));
+    assert!(TagSchema::is_synced(&schemas, &TagKey::Embedding));
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_validate_tag() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let schemas = TagSchema::list(&c)?;
+    let music = Music::mk(&c)?;
+
+    let ok = |t: Tag| TagSchema::validate(&schemas, &t).is_ok();
+
+    assert!(ok(Tag::new_text(music, TagKey::Title, s!("a"))));
+    assert!(ok(Tag::new_key(music, TagKey::UserTag(s!("chill")))));
+    assert!(ok(Tag::new_integer(music, TagKey::UserLibrary(s!("1")), 3)));
+
+    assert!(!ok(Tag::new_text(
+        music,
+        TagKey::Other(s!("titel")),
+        s!("a")
+    )));
+    assert!(!ok(Tag::new_text(music, TagKey::LocalMP3, s!("a.mp3"))));
+    assert!(!ok(Tag::new_key(music, TagKey::Title)));
+    assert!(!ok(Tag::new_key(music, TagKey::UserLibrary(s!("1")))));
+
+    let mut t = Tag::new_text(music, TagKey::Artist, s!("a"));
+    t.vector = Some(Vector(vec![1.0]));
+    assert!(!ok(t));
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_metadata_schema() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let music = Music::mk(&c)?;
+    Tag::insert(
+        &c,
+        Tag::new_vector(music, TagKey::FullEmbedding, Vector(vec![1.0])),
+    )?;
+
+    let meta = fetch_metadata(&c)?;
+    assert!(meta.tags.unwrap().is_empty());
+    assert!(meta.schema.iter().any(|s| s.key == "user_tag:"));
+
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use super::*;
+use crate::domain::entity::{Music, Tag, TagKey, UserID};
+use crate::domain::scope::{LibraryOwners, SyncScope};
+use crate::domain::sync::{fetch_changes, fetch_metadata, head_seq};
+use anyhow::Result;
+
+fn lib(id: crate::domain::entity::MusicID, user: i32) -> Tag {
+    Tag::new_integer(id, TagKey::UserLibrary(user.to_string()), 100)
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_scope_snapshot() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let mine = Music::mk(&c)?;
+    let theirs = Music::mk(&c)?;
+    let shared = Music::mk(&c)?;
+    Tag::insert(&c, lib(mine, 1))?;
+    Tag::insert(&c, lib(theirs, 2))?;
+    Tag::insert(&c, Tag::new_text(theirs, TagKey::Title, s!("a")))?;
+    Tag::insert(&c, Tag::new_text(shared, TagKey::Title, s!("b")))?;
+    c.execute(
+        "INSERT INTO config (key, value) VALUES ('secret_token', 'x'), ('volume', '1')",
+        [],
+    )?;
+
+    let mut scope = SyncScope::new(UserID(1));
+    let meta = scope.apply(&c, &LibraryOwners::load(&c)?, fetch_metadata(&c)?)?;
+
+    assert_eq!(meta.musics, vec![mine, shared]);
+    let tags = meta.tags.unwrap();
+    assert_eq!(tags.len(), 2);
+    assert!(tags.contains(&lib(mine, 1)));
+    assert!(tags.iter().all(|t| t.music_id != theirs));
+    assert_eq!(meta.settings, vec![(s!("volume"), s!("1"))]);
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_scope_patches() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let music = Music::mk(&c)?;
+    Tag::insert(&c, lib(music, 2))?;
+    Tag::insert(&c, Tag::new_text(music, TagKey::Title, s!("a")))?;
+
+    let mut scope = SyncScope::new(UserID(1));
+    scope.apply(&c, &LibraryOwners::load(&c)?, fetch_metadata(&c)?)?;
+    let seq = head_seq(&c)?;
+
+    // other users' changes are not seen
+    Tag::insert(&c, Tag::new_text(music, TagKey::Title, s!("b")))?;
+    let m = scope.apply(
+        &c,
+        &LibraryOwners::load(&c)?,
+        fetch_changes(&c, seq)?.unwrap(),
+    )?;
+    assert!(m.musics.is_empty());
+    assert!(m.patches.unwrap().is_empty());
+    let seq = m.seq;
+
+    // the music enters the scope, all its tags are sent
+    Tag::insert(&c, lib(music, 1))?;
+    let m = scope.apply(
+        &c,
+        &LibraryOwners::load(&c)?,
+        fetch_changes(&c, seq)?.unwrap(),
+    )?;
+    assert_eq!(m.musics, vec![music]);
+    let patches = m.patches.unwrap();
+    assert_eq!(patches.len(), 2);
+    assert!(patches.iter().all(|p| p.kind == "add"));
+    assert!(patches.iter().any(|p| p.tag.key == TagKey::Title));
+    let seq = m.seq;
+
+    // library changes of a music that stays in the scope don't send its tags again
+    Tag::insert(&c, lib(music, 3))?;
+    Tag::insert(
+        &c,
+        Tag::new_integer(music, TagKey::UserLibrary(s!("1")), 50),
+    )?;
+    let m = scope.apply(
+        &c,
+        &LibraryOwners::load(&c)?,
+        fetch_changes(&c, seq)?.unwrap(),
+    )?;
+    let patches = m.patches.unwrap();
+    assert_eq!(patches.len(), 1);
+    assert_eq!(patches[0].kind, "update");
+    let seq = m.seq;
+
+    // and leaves it
+    Tag::remove(&c, music, &TagKey::UserLibrary(s!("1")))?;
+    let m = scope.apply(
+        &c,
+        &LibraryOwners::load(&c)?,
+        fetch_changes(&c, seq)?.unwrap(),
+    )?;
+    assert!(m.musics.is_empty());
+    let patches = m.patches.unwrap();
+    assert_eq!(patches.len(), 2);
+    assert!(patches.iter().all(|p| p.kind == "remove"));
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_scope_resume() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let music = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(music, TagKey::Title, s!("a")))?;
+    let seq = head_seq(&c)?;
+
+    // without library changes the patches are enough
+    Tag::insert(&c, Tag::new_text(music, TagKey::Title, s!("b")))?;
+    let mut scope = SyncScope::new(UserID(1));
+    let m = scope.apply(
+        &c,
+        &LibraryOwners::load(&c)?,
+        fetch_changes(&c, seq)?.unwrap(),
+    )?;
+    assert!(m.tags.is_none());
+    assert_eq!(m.patches.unwrap().len(), 1);
+
+    // what the client saw at its cursor is unknown, it gets a snapshot
+    Tag::insert(&c, lib(music, 2))?;
+    let mut scope = SyncScope::new(UserID(1));
+    let m = scope.apply(
+        &c,
+        &LibraryOwners::load(&c)?,
+        fetch_changes(&c, seq)?.unwrap(),
+    )?;
+    assert!(m.musics.is_empty());
+    assert!(m.tags.unwrap().is_empty());
+
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use super::*;
+use crate::application::subsonic;
+use crate::domain::entity::{Music, Tag, TagKey, User, UserID};
+use crate::domain::library::{decode_id, encode_id, Library, UNKNOWN_ARTIST};
+use crate::domain::subsonic::{authenticate, set_password, SubsonicError};
+use crate::domain::trash;
+use crate::infrastructure::router::Router;
+use anyhow::Result;
+use std::collections::HashMap;
+
+fn params(v: &[(&str, &str)]) -> HashMap<String, String> {
+    v.iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_subsonic_ids() {
+    let id = encode_id("ar", "Daft Punk");
+    assert_eq!(decode_id("ar", &id).as_deref(), Some("Daft Punk"));
+    assert_eq!(decode_id("al", &id), None);
+    assert_eq!(decode_id("ar", "ar-zz"), None);
+    assert_eq!(decode_id("ar", "12"), None);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_subsonic_auth() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let auth = |p: &[(&str, &str)]| authenticate(&c, &params(p)).map(|x| x.map(|u| u.id));
+
+    // no password set yet
+    assert_eq!(
+        auth(&[("u", "default"), ("p", "sesame")])?,
+        Err(SubsonicError::WrongCredentials)
+    );
+
+    set_password(&c, UserID(1), "sesame")?;
+
+    assert_eq!(auth(&[("u", "default"), ("p", "sesame")])?, Ok(UserID(1)));
+    assert_eq!(
+        auth(&[("u", "default"), ("p", "enc:736573616d65")])?,
+        Ok(UserID(1))
+    );
+    assert_eq!(
+        auth(&[
+            ("u", "default"),
+            ("t", "26719a1196d2a940705a59634eb18eab"),
+            ("s", "c19b2d")
+        ])?,
+        Ok(UserID(1))
+    );
+    assert_eq!(
+        auth(&[
+            ("u", "default"),
+            ("t", "26719a1196d2a940705a59634eb18eab"),
+            ("s", "x")
+        ])?,
+        Err(SubsonicError::WrongCredentials)
+    );
+    assert_eq!(
+        auth(&[("u", "default"), ("p", "wrong")])?,
+        Err(SubsonicError::WrongCredentials)
+    );
+    assert_eq!(
+        auth(&[("u", "nobody"), ("p", "sesame")])?,
+        Err(SubsonicError::WrongCredentials)
+    );
+    assert_eq!(
+        auth(&[("u", "default")])?,
+        Err(SubsonicError::MissingParameter)
+    );
+    assert_eq!(
+        auth(&[("p", "sesame")])?,
+        Err(SubsonicError::MissingParameter)
+    );
+
+    set_password(&c, UserID(1), "other")?;
+    assert_eq!(
+        auth(&[("u", "default"), ("p", "sesame")])?,
+        Err(SubsonicError::WrongCredentials)
+    );
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_subsonic_library() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let lib = TagKey::UserLibrary(s!("1"));
+    let a = Music::mk(&c)?;
+    let b = Music::mk(&c)?;
+    let no_artist = Music::mk(&c)?;
+    let not_in_library = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(a, TagKey::Title, s!("One More Time")))?;
+    Tag::insert(&c, Tag::new_text(a, TagKey::Artist, s!("Daft Punk")))?;
+    Tag::insert(&c, Tag::new_text(a, TagKey::LocalMP3, s!("a.mp3")))?;
+    Tag::insert(&c, Tag::new_text(a, TagKey::Thumbnail, s!("a.jpg")))?;
+    Tag::insert(&c, Tag::new_text(a, TagKey::UserTag(s!("dance")), s!("")))?;
+    Tag::insert(&c, Tag::new_text(b, TagKey::Title, s!("Around the World")))?;
+    Tag::insert(&c, Tag::new_text(b, TagKey::Artist, s!("Daft Punk")))?;
+    Tag::insert(&c, Tag::new_integer(b, TagKey::Duration, 429))?;
+    Tag::insert(&c, Tag::new_text(b, TagKey::UserTag(s!("dance")), s!("")))?;
+    Tag::insert(&c, Tag::new_text(no_artist, TagKey::Title, s!("Untitled")))?;
+    Tag::insert(&c, Tag::new_text(not_in_library, TagKey::Title, s!("Nope")))?;
+    Tag::insert(&c, Tag::new_integer(a, lib.clone(), 100))?;
+    Tag::insert(&c, Tag::new_integer(b, lib.clone(), 300))?;
+    Tag::insert(&c, Tag::new_integer(no_artist, lib, 200))?;
+
+    let user = User::list(&c)?.remove(0);
+    let library = Library::load(&c, user)?;
+
+    let ids: Vec<_> = library.songs.iter().map(|s| s.id).collect();
+    assert_eq!(ids, vec![b, no_artist, a]);
+
+    let song = library.song(a).unwrap();
+    assert_eq!(song.path.as_deref(), Some("a.mp3"));
+    assert_eq!(song.cover.as_deref(), Some("a.jpg"));
+    assert_eq!(library.song(b).unwrap().duration, Some(429));
+    assert!(library.song(not_in_library).is_none());
+
+    let artists = library.artists();
+    assert_eq!(
+        artists.keys().copied().collect::<Vec<_>>(),
+        vec!["Daft Punk", UNKNOWN_ARTIST]
+    );
+    assert_eq!(artists["Daft Punk"].len(), 2);
+
+    let playlists = library.playlists();
+    assert_eq!(playlists.len(), 1);
+    assert_eq!(playlists["dance"].len(), 2);
+
+    let search = |q: &str| -> Vec<_> { library.search(q).iter().map(|s| s.id).collect() };
+    assert_eq!(search(""), vec![b, no_artist, a]);
+    assert_eq!(search("daft"), vec![b, a]);
+    assert_eq!(search("daft world"), vec![b]);
+    assert_eq!(search("\"untitled\""), vec![no_artist]);
+    assert_eq!(search("nope"), vec![]);
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_subsonic_rest() -> Result<()> {
+    let db = mk_db().await?;
+    let (not_in_library, trashed) = {
+        let c = db.get().await;
+        set_password(&c, UserID(1), "sesame")?;
+        for i in 0..60 {
+            let id = Music::mk(&c)?;
+            Tag::insert(
+                &c,
+                Tag::new_text(id, TagKey::Artist, format!("artist {}", i)),
+            )?;
+            Tag::insert(&c, Tag::new_integer(id, TagKey::UserLibrary(s!("1")), i))?;
+        }
+        let id = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_text(id, TagKey::LocalMP3, s!("other.mp3")))?;
+        let trashed = Music::mk(&c)?;
+        Tag::insert(
+            &c,
+            Tag::new_text(trashed, TagKey::LocalMP3, s!("trashed.mp3")),
+        )?;
+        Tag::insert(
+            &c,
+            Tag::new_integer(trashed, TagKey::UserLibrary(s!("1")), 100),
+        )?;
+        trash::trash(&c, UserID(1), trashed)?;
+        (id, trashed)
+    };
+
+    let mut router = Router::new();
+    router
+        .state(db.clone())
+        .get("/rest/:method", subsonic::rest);
+    let get = |method: &str, query: &str| {
+        let uri = format!("/rest/{}?u=default&p=sesame&{}", method, query);
+        let router = &router;
+        async move {
+            let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let resp = router.serve(req).await.unwrap();
+            let b = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            String::from_utf8(b.to_vec()).unwrap()
+        }
+    };
+
+    let albums = get("getAlbumList2.view", "type=alphabeticalByName").await;
+    assert_eq!(albums.matches("<album ").count(), 10);
+    let albums = get("getAlbumList2.view", "type=alphabeticalByName&size=50").await;
+    assert_eq!(albums.matches("<album ").count(), 50);
+    let albums = get("getAlbumList2", "type=alphabeticalByName&size=50&offset=50").await;
+    assert_eq!(albums.matches("<album ").count(), 10);
+
+    let search = get("search3", "query=artist&songCount=5&artistCount=0").await;
+    assert_eq!(search.matches("<song ").count(), 5);
+    assert_eq!(search.matches("<artist ").count(), 0);
+
+    // other users' musics can't be streamed
+    let resp = get("stream", &format!("id={}", not_in_library.0)).await;
+    assert!(resp.contains(r#"code="70""#));
+
+    // neither can the trashed ones
+    let resp = get("stream", &format!("id={}", trashed.0)).await;
+    assert!(resp.contains(r#"code="70""#));
+    let resp = get("download", &format!("id={}", trashed.0)).await;
+    assert!(resp.contains(r#"code="70""#));
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use super::*;
+use crate::domain::entity::{Music, Tag, TagKey};
+use crate::domain::sync::{fetch_changes, fetch_metadata, head_seq};
+use anyhow::Result;
+
+fn kinds(m: &crate::domain::entity::MusidexMetadata) -> Vec<(&str, &TagKey)> {
+    m.patches
+        .as_ref()
+        .unwrap()
+        .iter()
+        .map(|p| (p.kind.as_str(), &p.tag.key))
+        .collect()
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_fetch_changes() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let music = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(music, TagKey::Title, s!("a")))?;
+    Tag::insert(&c, Tag::new_text(music, TagKey::Artist, s!("a")))?;
+
+    let seq = fetch_metadata(&c)?.seq;
+    assert_eq!(seq, head_seq(&c)?);
+
+    Tag::insert(&c, Tag::new_text(music, TagKey::Title, s!("b")))?;
+    Tag::insert(&c, Tag::new_text(music, TagKey::Title, s!("c")))?;
+    Tag::remove(&c, music, &TagKey::Artist)?;
+    Tag::insert(&c, Tag::new_text(music, TagKey::Duration, s!("a")))?;
+    // added then removed, the client never needs to know
+    Tag::insert(&c, Tag::new_text(music, TagKey::Thumbnail, s!("a")))?;
+    Tag::remove(&c, music, &TagKey::Thumbnail)?;
+
+    let changes = fetch_changes(&c, seq)?.unwrap();
+    assert_eq!(changes.seq, head_seq(&c)?);
+    assert!(changes.tags.is_none());
+    assert_eq!(
+        kinds(&changes),
+        vec![
+            ("update", &TagKey::Title),
+            ("remove", &TagKey::Artist),
+            ("add", &TagKey::Duration),
+        ]
+    );
+    assert_eq!(
+        changes.patches.as_ref().unwrap()[0].tag.text.as_deref(),
+        Some("c")
+    );
+
+    let nothing = fetch_changes(&c, changes.seq)?.unwrap();
+    assert_eq!(nothing.seq, changes.seq);
+    assert!(nothing.patches.unwrap().is_empty());
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_fetch_changes_unknown_cursor() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let music = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(music, TagKey::Title, s!("a")))?;
+    Tag::insert(&c, Tag::new_text(music, TagKey::Artist, s!("a")))?;
+
+    let head = head_seq(&c)?;
+    assert!(fetch_changes(&c, head + 1)?.is_none());
+    assert!(fetch_changes(&c, -1)?.is_none());
+
+    c.execute("DELETE FROM changelog WHERE seq < ?1", [head])?;
+    assert!(fetch_changes(&c, 0)?.is_none());
+    assert!(fetch_changes(&c, head - 1)?.is_some());
+
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use super::*;
+use crate::application::{handlers, user_handlers};
+use crate::domain::clean;
+use crate::domain::entity::{Music, Tag, TagKey, User, UserID};
+use crate::domain::history::Editor;
+use crate::domain::library::Library;
+use crate::domain::music::delete_music;
+use crate::domain::sync::{fetch_changes, fetch_metadata};
+use crate::domain::trash::{self, TrashPurgeWorker};
+use crate::domain::upload;
+use crate::infrastructure::router::Router;
+use anyhow::Result;
+use hyper::StatusCode;
+
+#[test_log::test(tokio::test)]
+async fn test_trash() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+    let v = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_key(v, TagKey::UserLibrary(s!("1"))))?;
+    Tag::insert(&c, Tag::new_text(v, TagKey::Title, s!("Song")))?;
+    let before = fetch_metadata(&c)?.seq;
+    let editor = Editor {
+        user_id: UserID(1),
+        ip: s!("127.0.0.1"),
+    };
+
+    assert_eq!(delete_music(&c, &editor, v)?, StatusCode::OK);
+    let meta = fetch_metadata(&c)?;
+    assert!(meta.musics.is_empty() && meta.tags.unwrap().is_empty());
+    assert!(Music::exists(&c, v)?);
+    assert!(Tag::has(&c, v, &TagKey::Title)?);
+
+    // clients that had the music see its tags removed
+    let changes = fetch_changes(&c, before)?.unwrap();
+    let patches = changes.patches.unwrap();
+    assert_eq!(patches.len(), 2);
+    assert!(patches.iter().all(|p| p.kind == "remove"));
+    let trashed = changes.seq;
+
+    let entries = trash::list(&c, UserID(1))?;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].music_id, v);
+    assert_eq!(entries[0].title.as_deref(), Some("Song"));
+    assert!(trash::list(&c, UserID(2))?.is_empty());
+
+    assert_eq!(trash::restore(&c, UserID(2), v)?, StatusCode::FORBIDDEN);
+    assert_eq!(trash::restore(&c, UserID(1), v)?, StatusCode::OK);
+    assert_eq!(trash::restore(&c, UserID(1), v)?, StatusCode::NOT_FOUND);
+    assert_eq!(fetch_metadata(&c)?.musics, vec![v]);
+    let changes = fetch_changes(&c, trashed)?.unwrap();
+    let patches = changes.patches.unwrap();
+    assert_eq!(patches.len(), 2);
+    assert!(patches.iter().all(|p| p.kind == "add"));
+
+    // clean leaves the trash to the purge
+    let lone = Music::mk(&c)?;
+    trash::trash(&c, UserID(1), lone)?;
+    drop(c);
+    let storage = std::env::temp_dir().join(format!("musidex-trash-{}", std::process::id()));
+    std::fs::create_dir_all(&storage)?;
+    let report = clean::clean(&db, &storage, UserID(1), true).await?;
+    assert!(report.musics.is_empty());
+    std::fs::remove_dir_all(&storage)?;
+
+    let worker = TrashPurgeWorker::new(db.clone(), 7);
+    assert_eq!(worker.step().await?, 0);
+    db.get().await.execute(
+        "UPDATE trash SET deleted_at = DATETIME('now', '-8 days');",
+        [],
+    )?;
+    assert_eq!(worker.step().await?, 1);
+    assert!(!Music::exists(&*db.get().await, lone)?);
+
+    let mut router = Router::new();
+    router
+        .state(db.clone())
+        .delete("/api/music/:id", handlers::delete_music_handler)
+        .post("/api/music/:id/restore", handlers::restore_music)
+        .get("/api/user/:id/trash", user_handlers::trash);
+    let req = |method: &str, uri: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("cookie", "cur_user=1")
+            .body(Body::empty())
+            .unwrap()
+    };
+    let resp = router
+        .serve(req("DELETE", &format!("/api/music/{}", v.0)))
+        .await?;
+    assert_eq!(resp.status(), 200);
+    let resp = router.serve(req("GET", "/api/user/1/trash")).await?;
+    let body = hyper::body::to_bytes(resp.into_body()).await?;
+    assert!(String::from_utf8_lossy(&body).contains("\"title\":\"Song\""));
+    let resp = router.serve(req("GET", "/api/user/42/trash")).await?;
+    assert_eq!(resp.status(), 404);
+    let resp = router
+        .serve(req("POST", &format!("/api/music/{}/restore", v.0)))
+        .await?;
+    assert_eq!(resp.status(), 200);
+    assert!(trash::ids(&*db.get().await)?.is_empty());
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+async fn test_trash_hidden() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+    let user = User::list(&c)?.remove(0);
+    let lib = TagKey::UserLibrary(user.id.0.to_string());
+    let kept = Music::mk(&c)?;
+    let trashed = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_integer(kept, lib.clone(), 1))?;
+    Tag::insert(&c, Tag::new_integer(trashed, lib.clone(), 2))?;
+    Tag::insert(
+        &c,
+        Tag::new_text(trashed, TagKey::YoutubeDLVideoID, s!("dQw4w9WgXcQ")),
+    )?;
+    assert_eq!(upload::id_exists(&c, "dQw4w9WgXcQ")?, Some(trashed));
+
+    trash::trash(&c, user.id, trashed)?;
+    let ids: Vec<_> = Library::load(&c, user.clone())?
+        .songs
+        .iter()
+        .map(|s| s.id)
+        .collect();
+    assert_eq!(ids, vec![kept]);
+    let ids: Vec<_> = Tag::by_key_visible(&c, &lib)?
+        .iter()
+        .map(|t| t.music_id)
+        .collect();
+    assert_eq!(ids, vec![kept]);
+
+    // uploading the video again downloads a new music instead of conflicting
+    assert_eq!(upload::id_exists(&c, "dQw4w9WgXcQ")?, None);
+    Ok(())
+}
@@ -0,0 +1,289 @@
+use super::*;
+use crate::application::upnp;
+use crate::domain::entity::{Music, MusicID, Tag, TagKey, UserID};
+use crate::domain::upnp::{Object, UpnpDevice, CONTENT_DIRECTORY, DEVICE_TYPE};
+use crate::infrastructure::router::Router;
+use crate::infrastructure::ssdp::Ssdp;
+use crate::infrastructure::xml;
+use anyhow::{Context, Result};
+use hyper::server::conn::AddrIncoming;
+use hyper::Server;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+#[test]
+fn test_upnp_object_ids() {
+    let objects = [
+        Object::Root,
+        Object::User(UserID(1)),
+        Object::All(UserID(1)),
+        Object::Artists(UserID(2)),
+        Object::Playlists(UserID(1)),
+        Object::Artist(UserID(1), s!("AC/DC")),
+        Object::Playlist(UserID(1), s!("chill")),
+        Object::Item(
+            Box::new(Object::Artist(UserID(1), s!("AC/DC"))),
+            MusicID(12),
+        ),
+    ];
+    for obj in objects {
+        assert_eq!(Object::parse(&obj.id()), Some(obj));
+    }
+    assert_eq!(
+        Object::parse("u1/artists/12"),
+        Some(Object::Artist(UserID(1), s!("\u{12}")))
+    );
+    assert_eq!(Object::parse("u1/artists/zz"), None);
+    assert_eq!(Object::parse("u1/nope"), None);
+    assert_eq!(Object::parse("u1/all/12/3"), None);
+    assert_eq!(Object::parse("x"), None);
+}
+
+#[test]
+fn test_xml_element_text() {
+    let doc = r#"<s:Body><u:Browse xmlns:u="x"><ObjectID>u1/all</ObjectID><Filter/>
+        <SearchCriteria>dc:title contains &quot;a&amp;b&quot;</SearchCriteria></u:Browse></s:Body>"#;
+    assert_eq!(
+        xml::element_text(doc, "ObjectID").as_deref(),
+        Some("u1/all")
+    );
+    assert_eq!(xml::element_text(doc, "Filter").as_deref(), Some(""));
+    assert_eq!(
+        xml::element_text(doc, "SearchCriteria").as_deref(),
+        Some("dc:title contains \"a&b\"")
+    );
+    assert_eq!(xml::element_text(doc, "Nope"), None);
+}
+
+/// Plain HTTP/1.1 request, returns the status code and the body
+async fn http(addr: std::net::SocketAddr, req: String) -> Result<(u16, String)> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(req.as_bytes()).await?;
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).await?;
+    let (head, body) = resp.split_once("\r\n\r\n").context("no body")?;
+    let status = head.split(' ').nth(1).context("no status")?.parse()?;
+    Ok((status, body.to_string()))
+}
+
+async fn soap(addr: std::net::SocketAddr, action: &str, args: &str) -> Result<(u16, String)> {
+    let body = format!(
+        "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">\
+        <s:Body><u:{} xmlns:u=\"{}\">{}</u:{}></s:Body></s:Envelope>",
+        action, CONTENT_DIRECTORY, args, action
+    );
+    http(
+        addr,
+        format!(
+            "POST /upnp/control/content_directory HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\
+            Content-Type: text/xml\r\nSOAPACTION: \"{}#{}\"\r\nContent-Length: {}\r\n\r\n{}",
+            addr,
+            CONTENT_DIRECTORY,
+            action,
+            body.len(),
+            body
+        ),
+    )
+    .await
+}
+
+fn browse_args(id: &str, flag: &str) -> String {
+    format!(
+        "<ObjectID>{}</ObjectID><BrowseFlag>{}</BrowseFlag><Filter>*</Filter>\
+        <StartingIndex>0</StartingIndex><RequestedCount>0</RequestedCount><SortCriteria></SortCriteria>",
+        id, flag
+    )
+}
+
+#[test_log::test(tokio::test)]
+async fn test_upnp_control_point() -> Result<()> {
+    let db = mk_db().await?;
+    let (a, b) = {
+        let c = db.get().await;
+        let lib = TagKey::UserLibrary(s!("1"));
+        let a = Music::mk(&c)?;
+        let b = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_text(a, TagKey::Title, s!("Rock & Roll")))?;
+        Tag::insert(&c, Tag::new_text(a, TagKey::Artist, s!("Led Zeppelin")))?;
+        Tag::insert(&c, Tag::new_text(a, TagKey::LocalMP3, s!("a.mp3")))?;
+        Tag::insert(&c, Tag::new_integer(a, TagKey::Duration, 220))?;
+        Tag::insert(
+            &c,
+            Tag::new_text(a, TagKey::CompressedThumbnail, s!("a.jpg")),
+        )?;
+        Tag::insert(&c, Tag::new_text(b, TagKey::Title, s!("Kashmir")))?;
+        Tag::insert(&c, Tag::new_text(b, TagKey::Artist, s!("Led Zeppelin")))?;
+        Tag::insert(&c, Tag::new_text(b, TagKey::LocalM4A, s!("b.m4a")))?;
+        Tag::insert(&c, Tag::new_text(b, TagKey::UserTag(s!("epic")), s!("")))?;
+        Tag::insert(&c, Tag::new_integer(a, lib.clone(), 200))?;
+        Tag::insert(&c, Tag::new_integer(b, lib, 100))?;
+        (a, b)
+    };
+    let device = UpnpDevice::load(&*db.get().await, s!("Test server"))?;
+    // the uuid is kept
+    let uuid = device.uuid.clone();
+    assert_eq!(UpnpDevice::load(&*db.get().await, s!("x"))?.uuid, uuid);
+
+    let mut router = Router::new();
+    router
+        .state(db)
+        .state(device)
+        .get("/upnp/description.xml", upnp::description)
+        .get("/upnp/:service", upnp::scpd)
+        .post("/upnp/control/:service", upnp::control);
+    let incoming = AddrIncoming::bind(&([127, 0, 0, 1], 0).into())?;
+    let http_addr = incoming.local_addr();
+    tokio::spawn(Server::builder(incoming).serve(router.into_service()));
+
+    let ssdp_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let ssdp_addr = ssdp_socket.local_addr()?;
+    let ssdp = Ssdp {
+        uuid: uuid.clone(),
+        http_port: http_addr.port(),
+        types: vec![s!(DEVICE_TYPE), s!(CONTENT_DIRECTORY)],
+    };
+    tokio::spawn(ssdp.serve(ssdp_socket));
+
+    // discovery
+    let cp = UdpSocket::bind("127.0.0.1:0").await?;
+    cp.send_to(
+        b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\n\
+        ST: urn:schemas-upnp-org:device:MediaServer:1\r\n\r\n",
+        ssdp_addr,
+    )
+    .await?;
+    let mut buf = vec![0; 2048];
+    let (n, _) = tokio::time::timeout(Duration::from_secs(2), cp.recv_from(&mut buf)).await??;
+    let resp = String::from_utf8_lossy(&buf[..n]).to_string();
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(resp.contains(&format!("USN: uuid:{}::{}\r\n", uuid, DEVICE_TYPE)));
+    let location = resp
+        .split("\r\n")
+        .find_map(|l| l.strip_prefix("LOCATION: "))
+        .context("no location")?;
+    assert_eq!(
+        location,
+        format!("http://127.0.0.1:{}/upnp/description.xml", http_addr.port())
+    );
+
+    // searches for other devices aren't answered
+    cp.send_to(
+        b"M-SEARCH * HTTP/1.1\r\nMAN: \"ssdp:discover\"\r\nST: urn:schemas-upnp-org:device:MediaRenderer:1\r\n\r\n",
+        ssdp_addr,
+    )
+    .await?;
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), cp.recv_from(&mut buf))
+            .await
+            .is_err()
+    );
+
+    let (status, desc) = http(
+        http_addr,
+        s!("GET /upnp/description.xml HTTP/1.1\r\nConnection: close\r\n\r\n"),
+    )
+    .await?;
+    assert_eq!(status, 200);
+    assert!(desc.contains(&format!("<UDN>uuid:{}</UDN>", uuid)));
+    assert!(desc.contains("<friendlyName>Test server</friendlyName>"));
+    let scpd_url = xml::element_text(&desc, "SCPDURL").context("no scpd")?;
+    let (status, scpd) = http(
+        http_addr,
+        format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", scpd_url),
+    )
+    .await?;
+    assert_eq!(status, 200);
+    assert!(scpd.contains("<name>Browse</name>"));
+
+    // browsing
+    let result = |resp: &str| xml::element_text(resp, "Result").unwrap_or_default();
+
+    let (status, resp) = soap(
+        http_addr,
+        "Browse",
+        &browse_args("0", "BrowseDirectChildren"),
+    )
+    .await?;
+    assert_eq!(status, 200);
+    let didl = result(&resp);
+    assert!(didl.contains("<container id=\"u1\" parentID=\"0\""));
+    assert!(didl.contains("<dc:title>default</dc:title>"));
+    assert_eq!(
+        xml::element_text(&resp, "TotalMatches").as_deref(),
+        Some("1")
+    );
+
+    let (_, resp) = soap(
+        http_addr,
+        "Browse",
+        &browse_args("u1/artists", "BrowseDirectChildren"),
+    )
+    .await?;
+    let didl = result(&resp);
+    let artist_id = Object::Artist(UserID(1), s!("Led Zeppelin")).id();
+    assert!(didl.contains(&format!("<container id=\"{}\"", artist_id)));
+    assert!(didl.contains("childCount=\"2\""));
+
+    let (_, resp) = soap(
+        http_addr,
+        "Browse",
+        &browse_args(&artist_id, "BrowseDirectChildren"),
+    )
+    .await?;
+    let didl = result(&resp);
+    assert!(didl.contains("<dc:title>Rock &amp; Roll</dc:title>"));
+    assert!(didl.contains(&format!(
+        "<res protocolInfo=\"http-get:*:audio/mpeg:*\" duration=\"0:03:40.000\">http://{}/api/stream/{}</res>",
+        http_addr, a.0
+    )));
+    assert!(didl.contains(&format!(
+        "<upnp:albumArtURI>http://{}/storage/a.jpg</upnp:albumArtURI>",
+        http_addr
+    )));
+    assert!(didl.contains("http-get:*:audio/mp4:*"));
+
+    let item = Object::Item(Box::new(Object::Playlist(UserID(1), s!("epic"))), b).id();
+    let (_, resp) = soap(http_addr, "Browse", &browse_args(&item, "BrowseMetadata")).await?;
+    let didl = result(&resp);
+    assert!(didl.contains(&format!("<item id=\"{}\" parentID=\"u1/playlists/", item)));
+    assert!(didl.contains("<dc:title>Kashmir</dc:title>"));
+
+    let (status, resp) = soap(
+        http_addr,
+        "Browse",
+        &browse_args("u1/all/999", "BrowseMetadata"),
+    )
+    .await?;
+    assert_eq!(status, 500);
+    assert_eq!(
+        xml::element_text(&resp, "errorCode").as_deref(),
+        Some("701")
+    );
+
+    // searching
+    let (_, resp) = soap(
+        http_addr,
+        "Search",
+        "<ContainerID>0</ContainerID><SearchCriteria>upnp:class derivedfrom &quot;object.item.audioItem&quot; \
+        and dc:title contains &quot;kash&quot;</SearchCriteria><Filter>*</Filter>\
+        <StartingIndex>0</StartingIndex><RequestedCount>10</RequestedCount><SortCriteria></SortCriteria>",
+    )
+    .await?;
+    let didl = result(&resp);
+    assert!(didl.contains("<dc:title>Kashmir</dc:title>"));
+    assert!(!didl.contains("Rock"));
+    assert_eq!(
+        xml::element_text(&resp, "NumberReturned").as_deref(),
+        Some("1")
+    );
+
+    let (status, resp) = soap(http_addr, "Nope", "").await?;
+    assert_eq!(status, 500);
+    assert_eq!(
+        xml::element_text(&resp, "errorCode").as_deref(),
+        Some("401")
+    );
+
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use super::*;
-use crate::domain::entity::{Music, Tag, TagKey};
+use crate::domain::entity::{Music, Tag, TagKey, Vector};
 use crate::domain::worker_neural_embed::needs_embedding;
 use anyhow::Result;
 
@@ -8,19 +8,24 @@ pub async fn test_insert_tag() -> Result<()> {
     let db = mk_db().await?;
     let c = db.get().await;
 
-    let _ = Music::mk(&c)?;
+    let none = Music::mk(&c)?;
     let music = Music::mk(&c)?;
-    let music2 = Music::mk(&c)?;
+    let long = Music::mk(&c)?;
 
     Tag::insert(&c, Tag::new_text(music, TagKey::LocalMP3, s!("hi.mp3")))?;
-    Tag::insert(&c, Tag::new_text(music2, TagKey::LocalMP3, s!("hi.mp3")))?;
+    Tag::insert(&c, Tag::new_text(long, TagKey::LocalMP3, s!("hi.mp3")))?;
+    Tag::insert(&c, Tag::new_integer(long, TagKey::Duration, 3600))?;
 
-    assert!(needs_embedding(&c)?);
+    assert!(!needs_embedding(&c, none)?);
+    assert!(needs_embedding(&c, music)?);
+    assert!(!needs_embedding(&c, long)?);
 
-    Tag::insert(&c, Tag::new_text(music, TagKey::Embedding, s!("alrite")))?;
-    Tag::insert(&c, Tag::new_text(music2, TagKey::Embedding, s!("alrite")))?;
+    Tag::insert(
+        &c,
+        Tag::new_vector(music, TagKey::FullEmbedding, Vector(vec![1.0, 0.0])),
+    )?;
 
-    assert!(!needs_embedding(&c)?);
+    assert!(!needs_embedding(&c, music)?);
 
     Ok(())
 }
@@ -1,25 +1,26 @@
 use super::*;
 use crate::domain::entity::{Music, Tag, TagKey};
-use crate::domain::worker_thumbnail_resize::find_candidate;
+use crate::domain::worker_thumbnail_resize::needs_compression;
 use anyhow::Result;
 
 #[test_log::test(tokio::test)]
-pub async fn test_find_candidate() -> Result<()> {
+pub async fn test_needs_compression() -> Result<()> {
     let db = mk_db().await?;
     let c = db.get().await;
 
     let music = Music::mk(&c)?;
+    assert!(needs_compression(&c, music)?.is_none());
 
     Tag::insert(&c, Tag::new_text(music, TagKey::Thumbnail, s!("hi.jpg")))?;
 
-    assert_eq!(Some(music), find_candidate(&c)?);
+    assert_eq!(needs_compression(&c, music)?, Some(s!("hi.jpg")));
 
     Tag::insert(
         &c,
         Tag::new_text(music, TagKey::CompressedThumbnail, s!("smol.hi.jpg")),
     )?;
 
-    assert!(find_candidate(&c)?.is_none());
+    assert!(needs_compression(&c, music)?.is_none());
 
     Ok(())
 }
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use hyper::{Body, Response, StatusCode};
+use hyper::{Body, Request, Response, StatusCode};
 use std::path::Path;
 use std::str::FromStr;
 use tokio::io::{AsyncReadExt, AsyncSeekExt, BufReader, SeekFrom};
@@ -62,12 +62,27 @@ pub fn row_missing_opt<T>(
     }
 }
 
+/// Compares without stopping at the first difference, so response times don't leak secrets
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 pub fn res_status(status: StatusCode) -> Response<Body> {
     let mut r = Response::new(Body::empty());
     *r.status_mut() = status;
     r
 }
 
+/// Url of the server as seen by the client, for documents that need absolute urls
+pub fn base_url(req: &Request<Body>) -> String {
+    let header = |name| req.headers().get(name).and_then(|x| x.to_str().ok());
+    format!(
+        "{}://{}",
+        header("x-forwarded-proto").unwrap_or("http"),
+        header("host").unwrap_or("localhost")
+    )
+}
+
 pub async fn get_file_range<P: AsRef<Path>>(
     file_path: P,
     (start, end): (u64, u64),
@@ -28,13 +28,15 @@ export const LoadBeforeApp = () => {
     const [syncProblem, setSyncProblem] = useState(false);
 
     const ws = useRef<ReconnectingWebSocket | undefined>(undefined);
+    const seq = useRef<number | undefined>(undefined);
+    seq.current = loadedMeta ? metadata.seq : undefined;
 
     useEffect(() => {
         if (!loadedMeta) {
             return;
         }
         if (ws.current === undefined) {
-            ws.current = API.metadataWSInit();
+            ws.current = API.metadataWSInit(() => seq.current);
             ws.current.binaryType = "arraybuffer";
         }
 
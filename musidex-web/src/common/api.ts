@@ -7,7 +7,17 @@ export type RawMusidexMetadata = {
     tags?: Tag[];
     users: User[];
     settings: [string, string][];
+    schema?: TagSchema[];
     patches?: patch[];
+    seq?: number;
+}
+
+export type TagSchema = {
+    key: string;
+    value_type: 'none' | 'text' | 'integer' | 'date' | 'vector';
+    user_editable: boolean;
+    synced: boolean;
+    display_name: string;
 }
 
 type patch = { kind: 'add' | 'update' | 'remove', tag: Tag }
@@ -35,13 +45,18 @@ export const API = {
         return apiURL;
     },
 
-    metadataWSInit(): ReconnectingWebSocket {
+    // since is called on every (re)connection so that only the changes are sent back
+    metadataWSInit(since: () => number | undefined): ReconnectingWebSocket {
         let prefix = "ws";
         if (apiURL.startsWith("https")) {
             prefix = "wss";
         }
 
-        return new ReconnectingWebSocket(prefix + "://" + host + "/api/metadata/ws");
+        return new ReconnectingWebSocket(() => {
+            const seq = since();
+            const query = seq !== undefined ? "?since=" + seq : "";
+            return prefix + "://" + host + "/api/metadata/ws" + query;
+        });
     },
 
     async testConnection(localApiUrl: string): Promise<boolean> {
@@ -39,6 +39,7 @@ export type MusidexMetadata = {
     playable: Set<number>;
     fuse_document: IndexedMusic[];
     unique_user_tags: Set<string>;
+    seq: number;
 }
 
 export function getTags(meta: MusidexMetadata, id: number | undefined): Tags | undefined {
@@ -58,6 +59,7 @@ export function makeRawMeta(meta: MusidexMetadata): RawMusidexMetadata {
         users: meta.users,
         settings: meta.settings_l,
         tags: meta.tags,
+        seq: meta.seq,
     };
 }
 
@@ -75,6 +77,7 @@ export function newMetadata(raw: RawMusidexMetadata, previous?: MusidexMetadata)
         tags: raw.tags || previous?.tags || [],
         playable: new Set(),
         unique_user_tags: new Set(),
+        seq: raw.seq ?? previous?.seq ?? 0,
     };
 
     if (raw.patches) {